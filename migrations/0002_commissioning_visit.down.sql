@@ -0,0 +1,2 @@
+ALTER TABLE beamline DROP COLUMN commissioning_codes;
+ALTER TABLE beamline DROP COLUMN commissioning_visit;
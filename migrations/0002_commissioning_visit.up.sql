@@ -0,0 +1,4 @@
+-- Alternative visit template for commissioning visits
+ALTER TABLE beamline ADD COLUMN commissioning_visit TEXT CHECK (length(commissioning_visit) > 0);
+-- Comma separated proposal codes using the commissioning template - defaults to 'cm'
+ALTER TABLE beamline ADD COLUMN commissioning_codes TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE beamline DROP COLUMN detector_replacement;
+ALTER TABLE beamline DROP COLUMN detector_collapse;
+ALTER TABLE beamline DROP COLUMN detector_lowercase;
@@ -0,0 +1,4 @@
+-- Detector name normalisation rules - defaults preserve case, collapse runs and use '_'
+ALTER TABLE beamline ADD COLUMN detector_lowercase BOOLEAN;
+ALTER TABLE beamline ADD COLUMN detector_collapse BOOLEAN;
+ALTER TABLE beamline ADD COLUMN detector_replacement TEXT CHECK (length(detector_replacement) = 1);
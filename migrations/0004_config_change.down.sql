@@ -0,0 +1,2 @@
+DROP INDEX config_change_beamline;
+DROP TABLE config_change;
@@ -0,0 +1,13 @@
+-- Audit trail of changes made to beamline configurations
+CREATE TABLE config_change (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    beamline TEXT NOT NULL,
+    -- Name of the configuration field that was changed
+    field TEXT NOT NULL,
+    -- Values are NULL when a field is unset (or for the old values of a new beamline)
+    old_value TEXT,
+    new_value TEXT,
+    changed_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE INDEX config_change_beamline ON config_change (beamline, id);
@@ -0,0 +1,2 @@
+-- Minimum scan number for a beamline - the next scan number is always above this
+ALTER TABLE beamline ADD COLUMN scan_number_floor INTEGER CHECK (scan_number_floor >= 0);
@@ -0,0 +1,2 @@
+DROP INDEX scan_allocation_beamline;
+DROP TABLE scan_allocation;
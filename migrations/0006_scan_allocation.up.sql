@@ -0,0 +1,10 @@
+-- Record of every scan number allocated
+CREATE TABLE scan_allocation (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    beamline TEXT NOT NULL,
+    scan_number INTEGER NOT NULL,
+    visit TEXT NOT NULL,
+    allocated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+);
+
+CREATE INDEX scan_allocation_beamline ON scan_allocation (beamline, id);
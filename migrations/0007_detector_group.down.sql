@@ -0,0 +1 @@
+DROP TABLE detector_group;
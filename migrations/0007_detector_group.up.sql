@@ -0,0 +1,7 @@
+-- Alternative detector templates for groups of detectors on a beamline
+CREATE TABLE detector_group (
+    beamline TEXT NOT NULL,
+    name TEXT NOT NULL CHECK (length(name) > 0),
+    template TEXT NOT NULL CHECK (length(template) > 0),
+    PRIMARY KEY (beamline, name)
+);
@@ -0,0 +1,7 @@
+-- Alternative visit templates for visits with specific proposal codes
+CREATE TABLE visit_code_template (
+    beamline TEXT NOT NULL,
+    code TEXT NOT NULL CHECK (length(code) > 0),
+    template TEXT NOT NULL CHECK (length(template) > 0),
+    PRIMARY KEY (beamline, code)
+);
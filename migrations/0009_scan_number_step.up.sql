@@ -0,0 +1,2 @@
+-- Amount the scan number advances by for each scan - defaults to 1
+ALTER TABLE beamline ADD COLUMN scan_number_step INTEGER CHECK (scan_number_step > 0);
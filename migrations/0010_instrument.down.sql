@@ -0,0 +1 @@
+ALTER TABLE beamline DROP COLUMN instrument;
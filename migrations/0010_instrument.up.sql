@@ -0,0 +1,2 @@
+-- Instrument used in paths if it differs from the beamline name
+ALTER TABLE beamline ADD COLUMN instrument TEXT CHECK (length(instrument) > 0);
@@ -0,0 +1,2 @@
+-- Whether detector names that need normalising are rejected - NULL keeps rewriting invalid names
+ALTER TABLE beamline ADD COLUMN detector_reject_invalid BOOLEAN;
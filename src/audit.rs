@@ -0,0 +1,303 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use futures::TryStreamExt as _;
+
+use crate::cli::{AuditOptions, ExportOptions};
+use crate::db_service::{AllocationFilter, OpenError, ScanAllocation, SqliteScanPathService};
+
+/// Print the most recent scan numbers allocated for a beamline, oldest first. If following,
+/// continue to poll the DB and print any new allocations until the process is stopped.
+pub async fn print_allocations(db: &Path, opts: AuditOptions) -> Result<(), AuditError> {
+    let db = SqliteScanPathService::open_existing(db, true)
+        .await
+        .map_err(AuditError::Open)?;
+    let mut last = print_new(&db, &opts.beamline, None, opts.limit).await?;
+    if opts.follow {
+        let mut interval = tokio::time::interval(Duration::from_secs(opts.interval));
+        loop {
+            interval.tick().await;
+            last = print_new(&db, &opts.beamline, last, u32::MAX)
+                .await?
+                .or(last);
+        }
+    }
+    Ok(())
+}
+
+/// Print up to `limit` of the allocations made after the given ID, returning the ID of the
+/// latest one printed
+async fn print_new(
+    db: &SqliteScanPathService,
+    beamline: &str,
+    after: Option<i64>,
+    limit: u32,
+) -> Result<Option<i64>, sqlx::Error> {
+    let allocations = db.scan_allocations(beamline, after, limit).await?;
+    for allocation in allocations.iter().rev() {
+        println!("{}", format_allocation(allocation));
+    }
+    Ok(allocations.first().map(|a| a.id))
+}
+
+/// Write every allocation matching the given options to stdout as CSV
+pub async fn export_allocations(db: &Path, opts: ExportOptions) -> Result<(), ExportError> {
+    let db = SqliteScanPathService::open_existing(db, true)
+        .await
+        .map_err(ExportError::Open)?;
+    let filter = AllocationFilter {
+        beamline: opts.beamline,
+        from: opts.from.map(|d| d.to_string()),
+        until: opts.until.map(|d| d.to_string()),
+    };
+    write_csv(&db, &filter, BufWriter::new(io::stdout().lock())).await
+}
+
+/// Write allocations as CSV, one line at a time as they are read from the DB
+async fn write_csv(
+    db: &SqliteScanPathService,
+    filter: &AllocationFilter,
+    mut out: impl Write,
+) -> Result<(), ExportError> {
+    writeln!(out, "beamline,scan_number,visit,allocated_at")?;
+    let mut allocations = db.export_scan_allocations(filter);
+    while let Some(allocation) = allocations.try_next().await? {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            csv_field(&allocation.beamline),
+            allocation.scan_number,
+            csv_field(&allocation.visit),
+            csv_field(&allocation.allocated_at)
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Quote a CSV field if it contains any characters that would otherwise break the format
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Open(OpenError),
+    Db(sqlx::Error),
+}
+
+impl Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Open(e) => write!(f, "Could not open DB: {e}"),
+            AuditError::Db(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for AuditError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AuditError::Open(e) => Some(e),
+            AuditError::Db(e) => Some(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AuditError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Db(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Open(OpenError),
+    Db(sqlx::Error),
+    Io(io::Error),
+}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Open(e) => write!(f, "Could not open DB: {e}"),
+            ExportError::Db(e) => write!(f, "Error reading allocations: {e}"),
+            ExportError::Io(e) => write!(f, "Error writing allocations: {e}"),
+        }
+    }
+}
+
+impl Error for ExportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExportError::Open(e) => Some(e),
+            ExportError::Db(e) => Some(e),
+            ExportError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(value: sqlx::Error) -> Self {
+        Self::Db(value)
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+fn format_allocation(allocation: &ScanAllocation) -> String {
+    format!(
+        "{}\t{}\t{}",
+        allocation.allocated_at, allocation.scan_number, allocation.visit
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Days, Utc};
+
+    use super::{csv_field, format_allocation, print_new, write_csv};
+    use crate::db_service::{
+        AllocationFilter, BeamlineConfigurationUpdate, ScanAllocation, SqliteScanPathService,
+    };
+    use crate::paths::{DetectorTemplate, PathSpec as _, ScanTemplate, VisitTemplate};
+
+    #[test]
+    fn allocation_line() {
+        let allocation = ScanAllocation {
+            id: 3,
+            beamline: "i22".into(),
+            scan_number: 1234,
+            visit: "cm12345-3".into(),
+            allocated_at: "2024-06-01T12:00:00.000Z".into(),
+        };
+        assert_eq!(
+            format_allocation(&allocation),
+            "2024-06-01T12:00:00.000Z\t1234\tcm12345-3"
+        );
+    }
+
+    async fn add_beamline(db: &SqliteScanPathService, name: &str) {
+        BeamlineConfigurationUpdate {
+            scan_number: Some(122),
+            visit: VisitTemplate::new_checked("/tmp/{instrument}/data/{year}/{visit}").ok(),
+            scan: ScanTemplate::new_checked("{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").ok(),
+            ..BeamlineConfigurationUpdate::empty(name)
+        }
+        .insert_new(db)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn latest_printed_id() {
+        let db = SqliteScanPathService::memory().await;
+        assert_eq!(print_new(&db, "i22", None, 10).await.unwrap(), None);
+        add_beamline(&db, "i22").await;
+        for _ in 0..2 {
+            db.next_scan_configuration("i22", "cm12345-3", None)
+                .await
+                .unwrap();
+        }
+        let last = print_new(&db, "i22", None, 10).await.unwrap();
+        assert!(last.is_some());
+        // Nothing new to print so there is no latest ID
+        assert_eq!(print_new(&db, "i22", last, 10).await.unwrap(), None);
+    }
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(csv_field("cm12345-3"), "cm12345-3");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+
+    /// Export allocations with the given filter and return the CSV lines without the header
+    /// or timestamps
+    async fn export(db: &SqliteScanPathService, filter: AllocationFilter) -> Vec<String> {
+        let mut out = Vec::new();
+        write_csv(db, &filter, &mut out).await.unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("beamline,scan_number,visit,allocated_at")
+        );
+        lines
+            .map(|line| line.rsplit_once(',').unwrap().0.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn export_allocations() {
+        let db = SqliteScanPathService::memory().await;
+        assert!(export(&db, AllocationFilter::default()).await.is_empty());
+        add_beamline(&db, "i22").await;
+        add_beamline(&db, "b21").await;
+        for (bl, visit) in [
+            ("i22", "cm12345-3"),
+            ("b21", "cm2345-1"),
+            ("i22", "cm12345-4"),
+        ] {
+            db.next_scan_configuration(bl, visit, None).await.unwrap();
+        }
+        assert_eq!(
+            export(&db, AllocationFilter::default()).await,
+            ["i22,123,cm12345-3", "b21,123,cm2345-1", "i22,124,cm12345-4"]
+        );
+        let i22 = || AllocationFilter {
+            beamline: Some("i22".into()),
+            ..AllocationFilter::default()
+        };
+        assert_eq!(
+            export(&db, i22()).await,
+            ["i22,123,cm12345-3", "i22,124,cm12345-4"]
+        );
+
+        let today = Utc::now().date_naive();
+        let tomorrow = (today + Days::new(1)).to_string();
+        let yesterday = (today - Days::new(1)).to_string();
+        let in_range = AllocationFilter {
+            from: Some(yesterday.clone()),
+            until: Some(tomorrow.clone()),
+            ..i22()
+        };
+        assert_eq!(export(&db, in_range).await.len(), 2);
+        let future = AllocationFilter {
+            from: Some(tomorrow),
+            ..i22()
+        };
+        assert!(export(&db, future).await.is_empty());
+        let past = AllocationFilter {
+            until: Some(yesterday),
+            ..i22()
+        };
+        assert!(export(&db, past).await.is_empty());
+    }
+}
@@ -13,17 +13,31 @@
 // limitations under the License.
 
 use std::env;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{ArgAction, Args, Parser, Subcommand};
+use chrono::NaiveDate;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{
+    ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use tracing::Level;
+use tracing_subscriber::filter::Directive;
 use url::Url;
 
+use crate::paths::{TemplateFieldRule, TemplateKind, TemplatePolicies};
+
 #[derive(Debug, Parser)]
 pub struct Cli {
-    #[clap(short, long, default_value = "numtracker.db", env = "NUMTRACKER_DB")]
-    pub(crate) db: PathBuf,
+    /// The path to the SQLite database
+    #[clap(short, long, env = DB_ENV, default_value = DEFAULT_DB)]
+    db: PathBuf,
+    /// Where the DB path was taken from
+    #[clap(skip)]
+    db_source: DbPathSource,
     #[clap(flatten, next_help_heading = "Logging/Debug")]
     verbose: Verbosity,
     #[clap(flatten, next_help_heading = "Tracing and Logging")]
@@ -43,26 +57,424 @@ pub struct TracingOptions {
 }
 
 #[derive(Debug, Subcommand)]
+// Only one command is ever parsed so the size of the serve options doesn't matter
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Run the server to respond to visit and scan path requests
     Serve(ServeOptions),
     /// Generate the graphql schema
     Schema,
+    /// Print the resolved configuration for a single beamline as JSON
+    Config(ConfigOptions),
+    /// Apply any pending DB migrations
+    Migrate(MigrateOptions),
+    /// Print the most recent scan numbers allocated for a beamline
+    Audit(AuditOptions),
+    /// Write every scan number allocated to stdout as CSV
+    Export(ExportOptions),
+    /// Set the latest scan number for a beamline. The service should not be running.
+    ResetScanNumber(ResetOptions),
+    /// Render two templates against sample values and show how the paths differ
+    DiffTemplate(DiffTemplateOptions),
+}
+
+#[derive(Debug, Parser)]
+pub struct ResetOptions {
+    /// The beamline to reset the scan number for
+    pub beamline: String,
+    /// The new latest scan number. The next scan allocated will be one more than this.
+    pub scan_number: u32,
+    /// Allow the scan number to be lowered
+    ///
+    /// Lowering the scan number means scan numbers will be reused and existing data may be
+    /// overwritten.
+    #[clap(long)]
+    pub force: bool,
+    /// Do not ask for confirmation before making the change
+    #[clap(short, long)]
+    pub yes: bool,
+    /// The root directory for external number tracking
+    #[clap(long, env = "NUMTRACKER_ROOT_DIRECTORY")]
+    pub root_directory: Option<PathBuf>,
+    /// JSON file of per-beamline overrides used by the service
+    ///
+    /// Only the tracker directories are used so that the same directory is checked and reset
+    /// as the service would use.
+    #[clap(long, env = "NUMTRACKER_CONFIG_OVERLAY")]
+    pub config_overlay: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct DiffTemplateOptions {
+    /// The kind of template being compared
+    #[clap(long, value_enum)]
+    pub kind: TemplateKind,
+    #[clap(flatten)]
+    pub old: OldTemplate,
+    /// The candidate template
+    pub new: String,
+    /// A sample value for a placeholder, eg `--sample scan_number=1234`. May be repeated.
+    #[clap(short, long = "sample", value_parser = sample_field)]
+    pub samples: Vec<(String, String)>,
+}
+
+/// The template that a candidate template is compared against
+#[derive(Debug, PartialEq, Eq)]
+pub enum OldTemplate {
+    /// A template given on the command line
+    Template(String),
+    /// The template currently configured for a beamline
+    Beamline(String),
+}
+
+/// The arguments that [`OldTemplate`] is parsed from. Exactly one is required.
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+struct OldTemplateArgs {
+    /// The template to compare against
+    #[clap(long)]
+    old: Option<String>,
+    /// Compare against the template currently configured for this beamline
+    ///
+    /// The beamline's instrument is also used as the sample value for `instrument` if one is
+    /// not given.
+    #[clap(long)]
+    beamline: Option<String>,
+}
+
+impl FromArgMatches for OldTemplate {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        match OldTemplateArgs::from_arg_matches(matches)? {
+            OldTemplateArgs { old: Some(old), .. } => Ok(Self::Template(old)),
+            OldTemplateArgs {
+                beamline: Some(beamline),
+                ..
+            } => Ok(Self::Beamline(beamline)),
+            _ => Err(clap::Error::raw(
+                ErrorKind::MissingRequiredArgument,
+                "One of --old or --beamline is required",
+            )),
+        }
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for OldTemplate {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        OldTemplateArgs::augment_args(cmd)
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        OldTemplateArgs::augment_args_for_update(cmd)
+    }
+}
+
+/// Parse a `field=value` pair used as a sample value for a template placeholder
+fn sample_field(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((field, value)) if !field.is_empty() => Ok((field.into(), value.into())),
+        _ => Err(format!("Expected field=value, not {arg:?}")),
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct AuditOptions {
+    /// The beamline to show allocations for
+    pub beamline: String,
+    /// The number of recent allocations to print
+    #[clap(short = 'n', long, default_value_t = 20)]
+    pub limit: u32,
+    /// Keep checking for new allocations and print them as they are made
+    #[clap(short, long)]
+    pub follow: bool,
+    /// How often (in seconds) to check for new allocations when following
+    #[clap(
+        long,
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "follow"
+    )]
+    pub interval: u64,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportOptions {
+    /// Only export allocations for this beamline
+    #[clap(long)]
+    pub beamline: Option<String>,
+    /// Only export allocations made on or after this date (YYYY-MM-DD, UTC)
+    #[clap(long)]
+    pub from: Option<NaiveDate>,
+    /// Only export allocations made on or before this date (YYYY-MM-DD, UTC)
+    #[clap(long)]
+    pub until: Option<NaiveDate>,
+}
+
+#[derive(Debug, Parser)]
+pub struct MigrateOptions {
+    /// Report whether the DB schema is up to date without changing it
+    ///
+    /// Exits with 0 if the schema is up to date, 2 if there are pending migrations and 3 if the
+    /// DB has migrations applied that are unknown to this version of numtracker.
+    #[clap(long)]
+    pub check_only: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ConfigOptions {
+    /// The beamline to show the configuration for
+    pub beamline: String,
+    /// The root directory for external number tracking
+    #[clap(long, env = "NUMTRACKER_ROOT_DIRECTORY")]
+    pub root_directory: Option<PathBuf>,
+    /// JSON file of per-beamline overrides used by the service
+    ///
+    /// Only the tracker directories are used so that the same directory is reported as the
+    /// service would use.
+    #[clap(long, env = "NUMTRACKER_CONFIG_OVERLAY")]
+    pub config_overlay: Option<PathBuf>,
+    /// How to report the beamline not having a tracker directory
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        env = "NUMTRACKER_MISSING_TRACKER_DIRECTORY"
+    )]
+    pub missing_tracker_directory: MissingTrackerDirectory,
+}
+
+/// What to do when a beamline has no directory for external number tracking
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MissingTrackerDirectory {
+    /// Use the DB as the only source of scan numbers
+    #[default]
+    Allow,
+    /// Use the DB as the only source of scan numbers but log a warning
+    Warn,
+    /// Refuse to allocate scan numbers. Failing to update a tracker directory is also an error.
+    Deny,
+}
+
+/// Whether to check that visit directories exist before returning them
+///
+/// The service may not have access to the filesystem the directories are on so, when the check
+/// is enabled, directories that cannot be seen are reported as unknown rather than missing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VisitDirectoryCheck {
+    /// Do not check visit directories
+    #[default]
+    Off,
+    /// Report whether the visit directory exists
+    Report,
+    /// Report whether the visit directory exists and fail requests for visits whose directory
+    /// is known not to exist
+    Require,
+}
+
+/// Whether subdirectories may include hidden directories (those starting with `.`)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HiddenSubdirectories {
+    /// Hidden directories are allowed unless a request denies them
+    #[default]
+    Allow,
+    /// Hidden directories are denied unless a request allows them
+    OptIn,
+    /// Hidden directories are never allowed
+    Deny,
 }
 
 #[derive(Debug, Parser)]
 pub struct ServeOptions {
     /// The IP for this to service to be bound to
-    #[clap(short = 'H', long, default_value_t = Ipv4Addr::UNSPECIFIED, env="NUMTRACKER_HOST")]
-    host: Ipv4Addr,
+    ///
+    /// Use `::` to accept both IPv6 and IPv4 connections on systems supporting dual-stack sockets
+    #[clap(short = 'H', long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED), env="NUMTRACKER_HOST")]
+    host: IpAddr,
     /// The port to open for requests
     #[clap(short, long, default_value_t = 8000, env = "NUMTRACKER_PORT")]
     port: u16,
+    /// Listen on a Unix domain socket at this path instead of a TCP port
+    ///
+    /// The host and port are ignored if this is given. The socket file is removed when the
+    /// service is stopped.
+    #[clap(long, env = "NUMTRACKER_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
     /// The root directory for external number tracking
     #[clap(long, env = "NUMTRACKER_ROOT_DIRECTORY")]
     root_directory: Option<PathBuf>,
+    /// Create missing beamline directories in the root directory when they are first needed
+    ///
+    /// Off by default so that a missing mount is not masked by creating an empty directory
+    #[clap(
+        long,
+        requires = "root_directory",
+        env = "NUMTRACKER_CREATE_DIRECTORIES"
+    )]
+    create_directories: bool,
+    /// How long (in seconds) to keep the file for a previous scan number after it is replaced
+    ///
+    /// Files are removed by the next allocation for the same beamline after this has passed.
+    /// The default of 0 removes them as soon as they are replaced.
+    #[clap(
+        long,
+        default_value_t = 0,
+        requires = "root_directory",
+        env = "NUMTRACKER_NUMBER_FILE_GRACE"
+    )]
+    number_file_grace: u64,
+    /// JSON file of per-beamline overrides applied on top of the configuration in the DB
+    ///
+    /// Overrides are read once at startup and are never written to the DB.
+    #[clap(long, env = "NUMTRACKER_CONFIG_OVERLAY")]
+    config_overlay: Option<PathBuf>,
+    /// How to handle scans for beamlines without a tracker directory
+    ///
+    /// Deployments that need to stay compatible with GDA should require every beamline to have
+    /// a directory.
+    /// When directories are required, a scan also fails (without using up its number) if the
+    /// new number cannot be written to the beamline's directory.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        env = "NUMTRACKER_MISSING_TRACKER_DIRECTORY"
+    )]
+    missing_tracker_directory: MissingTrackerDirectory,
+    /// Log (at debug level) the value of every field used when rendering a path
+    ///
+    /// Intended for diagnosing individual failing requests and should not be left enabled in
+    /// production.
+    #[clap(long, env = "NUMTRACKER_LOG_RENDER_CONTEXT")]
+    log_render_context: bool,
+    /// Reject all mutations, eg while the DB is being maintained
+    ///
+    /// Queries are served as normal. Mutations fail without reading from or writing to the DB.
+    #[clap(long, env = "NUMTRACKER_READ_ONLY")]
+    read_only: bool,
+    /// Allow clients to request how long each stage of their request took
+    ///
+    /// Requests with an 'X-Debug-Timings' header get the time spent on authorization, DB
+    /// lookups and rendering paths in the response extensions. Intended for diagnosing slow
+    /// requests and should not be left enabled in production.
+    #[clap(long, env = "NUMTRACKER_DEBUG_TIMINGS")]
+    debug_timings: bool,
+    /// A field that every template of a kind must use, eg 'visit=proposal'
+    ///
+    /// Can be given multiple times. Only applies to templates set after startup.
+    #[clap(
+        long = "require-template-field",
+        env = "NUMTRACKER_REQUIRE_TEMPLATE_FIELDS",
+        value_delimiter = ','
+    )]
+    required_template_fields: Vec<TemplateFieldRule>,
+    /// A field that no template of a kind may use, eg 'visit=detector'
+    ///
+    /// Can be given multiple times. Only applies to templates set after startup.
+    #[clap(
+        long = "forbid-template-field",
+        env = "NUMTRACKER_FORBID_TEMPLATE_FIELDS",
+        value_delimiter = ','
+    )]
+    forbidden_template_fields: Vec<TemplateFieldRule>,
+    /// The proposal codes that visits may use, eg 'cm,mx'
+    ///
+    /// Any code is accepted if none are given.
+    #[clap(long, value_delimiter = ',', env = "NUMTRACKER_PROPOSAL_CODES")]
+    proposal_codes: Vec<String>,
+    /// The beamlines this server answers for, eg 'i22,b21'
+    ///
+    /// Requests for any other beamline fail as if the beamline did not exist, even if it is
+    /// configured in the DB. This allows several deployments to share a DB while each serving
+    /// a subset of its beamlines. Every beamline is served if none are given.
+    #[clap(
+        long = "serve-beamlines",
+        value_delimiter = ',',
+        env = "NUMTRACKER_SERVE_BEAMLINES"
+    )]
+    served_beamlines: Vec<String>,
+    /// The most detectors that can be requested in a single `detectors` field
+    #[clap(long, default_value_t = 1000, env = "NUMTRACKER_MAX_DETECTORS")]
+    max_detectors: usize,
+    /// The smallest response (in bytes) that will be compressed for clients that accept it
+    #[clap(long, default_value_t = 1024, env = "NUMTRACKER_COMPRESSION_THRESHOLD")]
+    compression_threshold: u16,
+    /// Whether to check that visit directories exist when their paths are requested
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        env = "NUMTRACKER_VISIT_DIRECTORY_CHECK"
+    )]
+    visit_directory_check: VisitDirectoryCheck,
+    /// The deepest subdirectory that any request may use
+    ///
+    /// Requests may set their own (lower) limit
+    #[clap(long, env = "NUMTRACKER_SUBDIRECTORY_MAX_DEPTH")]
+    subdirectory_max_depth: Option<usize>,
+    /// The deepest subdirectory allowed for requests that do not set their own limit
+    ///
+    /// Defaults to the maximum depth
+    #[clap(long, env = "NUMTRACKER_SUBDIRECTORY_DEFAULT_DEPTH")]
+    subdirectory_default_depth: Option<usize>,
+    /// Whether subdirectories may include hidden directories
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        env = "NUMTRACKER_HIDDEN_SUBDIRECTORIES"
+    )]
+    hidden_subdirectories: HiddenSubdirectories,
+    /// How long (in seconds) the idempotency key of a scan request is remembered
+    ///
+    /// Retrying a scan request with the same key within this window returns the original scan
+    /// instead of allocating a new one.
+    #[clap(long, default_value_t = 600, env = "NUMTRACKER_IDEMPOTENCY_WINDOW")]
+    idempotency_window: u64,
+    /// The most scan numbers that can be allocated per second for each beamline
+    ///
+    /// Requests over the limit are refused instead of allocating a scan number. There is no
+    /// limit by default.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "NUMTRACKER_SCAN_RATE_LIMIT"
+    )]
+    scan_rate_limit: Option<u32>,
     #[clap(flatten, next_help_heading = "Authorization")]
     pub policy: Option<PolicyOptions>,
+    /// Include the reachability of the policy server in the readiness check (/readyz)
+    #[clap(long, requires = "policy_host", env = "NUMTRACKER_READY_CHECK_POLICY")]
+    ready_check_policy: bool,
+    #[clap(flatten, next_help_heading = "Database")]
+    pub pool: PoolOptions,
+}
+
+/// Sizing of the database connection pool
+///
+/// SQLite allows many concurrent readers but only a single writer so, while more connections
+/// allow more queries to be served at once, writes (eg scan number updates) are serialised
+/// regardless of the pool size.
+#[derive(Debug, Parser)]
+pub struct PoolOptions {
+    /// The minimum number of DB connections to keep open
+    #[clap(
+        long = "db-min-connections",
+        default_value_t = 0,
+        env = "NUMTRACKER_DB_MIN_CONNECTIONS"
+    )]
+    pub min_connections: u32,
+    /// The maximum number of DB connections to open at once
+    #[clap(
+        long = "db-max-connections",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u32).range(1..),
+        env = "NUMTRACKER_DB_MAX_CONNECTIONS"
+    )]
+    pub max_connections: u32,
 }
 
 #[derive(Debug, Default, Parser)]
@@ -83,6 +495,50 @@ pub struct PolicyOptions {
     /// eg. v1/data/diamond/policy/admin/configure_beamline
     #[clap(long, required = false)]
     pub admin_query: String,
+    /// The audiences accepted for authorization tokens
+    ///
+    /// Each audience is checked in turn and access is granted if the policy
+    /// server accepts the token for any of them.
+    #[clap(
+        long = "audience",
+        required = false,
+        value_delimiter = ',',
+        default_value = "account"
+    )]
+    pub audiences: Vec<String>,
+    /// Seconds to keep idle connections to the policy server open for reuse
+    ///
+    /// Reusing connections avoids a new TLS handshake for each authorization request.
+    #[clap(long = "policy-idle-timeout", required = false, default_value_t = 90)]
+    pub pool_idle_timeout: u64,
+    /// The maximum number of idle connections to keep open to the policy server
+    #[clap(long = "policy-max-idle", required = false, default_value_t = 32)]
+    pub pool_max_idle: usize,
+    /// Seconds between TCP keep-alive probes on connections to the policy server (0 to disable)
+    #[clap(long = "policy-keepalive", required = false, default_value_t = 60)]
+    pub tcp_keepalive: u64,
+    /// The parts of the visit included in access requests sent to the policy server
+    #[clap(
+        long = "policy-visit-fields",
+        required = false,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "proposal,session"
+    )]
+    pub visit_fields: Vec<PolicyVisitField>,
+}
+
+/// A part of a visit that can be sent to the policy server when checking access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PolicyVisitField {
+    /// The proposal code, sent as `code`, eg "cm"
+    Code,
+    /// The proposal number, sent as `proposal`, eg 12345
+    Proposal,
+    /// The session number, sent as `visit`, eg 3
+    Session,
+    /// The full visit, sent as `visit_name`, eg "cm12345-3"
+    Visit,
 }
 
 #[derive(Debug, Args)]
@@ -93,11 +549,66 @@ struct Verbosity {
     /// Disable all output to stderr/stdout
     #[clap(short, long, global = true, conflicts_with = "verbose")]
     quiet: bool,
+    /// Set the level of logs for a single module, eg `numtracker::db_service=warn`
+    ///
+    /// May be repeated or given as a comma separated list. Modules without a filter use the
+    /// level set by the verbose flags.
+    #[clap(
+        long = "log-filter",
+        global = true,
+        env = "NUMTRACKER_LOG_FILTER",
+        value_delimiter = ','
+    )]
+    filters: Vec<Directive>,
+}
+
+/// The environment variable used for the DB path if it is not given on the command line
+const DB_ENV: &str = "NUMTRACKER_DB";
+/// The DB path used if it is not given on the command line or via the environment
+const DEFAULT_DB: &str = "numtracker.db";
+
+/// Where the path to the DB was taken from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DbPathSource {
+    Flag,
+    Env,
+    #[default]
+    Default,
+}
+
+impl Display for DbPathSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbPathSource::Flag => f.write_str("--db flag"),
+            DbPathSource::Env => write!(f, "{DB_ENV} environment variable"),
+            DbPathSource::Default => f.write_str("default"),
+        }
+    }
+}
+
+impl From<Option<ValueSource>> for DbPathSource {
+    fn from(value: Option<ValueSource>) -> Self {
+        match value {
+            Some(ValueSource::CommandLine) => Self::Flag,
+            Some(ValueSource::EnvVariable) => Self::Env,
+            _ => Self::Default,
+        }
+    }
 }
 
 impl Cli {
     pub fn init() -> Self {
-        Self::parse()
+        Self::from_matches(&Self::command().get_matches()).unwrap_or_else(|e| e.exit())
+    }
+    /// Build the options from parsed arguments, recording where the DB path came from
+    fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut cli = Self::from_arg_matches(matches)?;
+        cli.db_source = matches.value_source("db").into();
+        Ok(cli)
+    }
+    /// The path to the DB and where it was taken from
+    pub fn db(&self) -> (PathBuf, DbPathSource) {
+        (self.db.clone(), self.db_source)
     }
     pub fn tracing(&self) -> &TracingOptions {
         &self.tracing
@@ -105,6 +616,10 @@ impl Cli {
     pub fn log_level(&self) -> Option<Level> {
         self.verbose.log_level()
     }
+    /// Per-module filters applied on top of the log level
+    pub fn log_filters(&self) -> &[Directive] {
+        &self.verbose.filters
+    }
 }
 impl Verbosity {
     pub fn log_level(&self) -> Option<Level> {
@@ -132,12 +647,79 @@ impl Verbosity {
 }
 
 impl ServeOptions {
-    pub(crate) fn addr(&self) -> (Ipv4Addr, u16) {
-        (self.host, self.port)
+    pub(crate) fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
     }
     pub(crate) fn root_directory(&self) -> Option<PathBuf> {
         self.root_directory.clone()
     }
+    pub(crate) fn create_directories(&self) -> bool {
+        self.create_directories
+    }
+    pub(crate) fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+    pub(crate) fn config_overlay(&self) -> Option<&Path> {
+        self.config_overlay.as_deref()
+    }
+    pub(crate) fn number_file_grace(&self) -> Duration {
+        Duration::from_secs(self.number_file_grace)
+    }
+    pub(crate) fn ready_check_policy(&self) -> bool {
+        self.ready_check_policy
+    }
+    pub(crate) fn missing_tracker_directory(&self) -> MissingTrackerDirectory {
+        self.missing_tracker_directory
+    }
+    pub(crate) fn log_render_context(&self) -> bool {
+        self.log_render_context
+    }
+    pub(crate) fn read_only(&self) -> bool {
+        self.read_only
+    }
+    pub(crate) fn debug_timings(&self) -> bool {
+        self.debug_timings
+    }
+    pub(crate) fn max_detectors(&self) -> usize {
+        self.max_detectors
+    }
+    pub(crate) fn compression_threshold(&self) -> u16 {
+        self.compression_threshold
+    }
+    pub(crate) fn proposal_codes(&self) -> Vec<String> {
+        self.proposal_codes.clone()
+    }
+    pub(crate) fn served_beamlines(&self) -> Vec<String> {
+        self.served_beamlines.clone()
+    }
+    pub(crate) fn template_policies(&self) -> TemplatePolicies {
+        TemplatePolicies::new(
+            &self.required_template_fields,
+            &self.forbidden_template_fields,
+        )
+    }
+    pub(crate) fn visit_directory_check(&self) -> VisitDirectoryCheck {
+        self.visit_directory_check
+    }
+    pub(crate) fn subdirectory_max_depth(&self) -> Option<usize> {
+        self.subdirectory_max_depth
+    }
+    /// The default depth limit, which can never be deeper than the maximum
+    pub(crate) fn subdirectory_default_depth(&self) -> Option<usize> {
+        match (self.subdirectory_default_depth, self.subdirectory_max_depth) {
+            (Some(default), Some(max)) => Some(default.min(max)),
+            (default, max) => default.or(max),
+        }
+    }
+    pub(crate) fn hidden_subdirectories(&self) -> HiddenSubdirectories {
+        self.hidden_subdirectories
+    }
+    pub(crate) fn idempotency_window(&self) -> Duration {
+        Duration::from_secs(self.idempotency_window)
+    }
+    pub(crate) fn scan_rate_limit(&self) -> Option<u32> {
+        self.scan_rate_limit
+    }
 }
 
 impl TracingOptions {
@@ -152,17 +734,71 @@ impl TracingOptions {
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::env;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
 
     use assert_matches::assert_matches;
+    use chrono::NaiveDate;
     use clap::error::ErrorKind;
-    use clap::Parser;
+    use clap::{CommandFactory as _, Parser};
+    use rstest::rstest;
     use tracing::Level;
 
-    use super::Cli;
+    use super::{
+        Cli, DbPathSource, HiddenSubdirectories, MissingTrackerDirectory, OldTemplate,
+        PolicyVisitField, TemplateKind, VisitDirectoryCheck,
+    };
     use crate::cli::Command;
+    use crate::paths::{BeamlineField, DetectorField, ScanField, TemplateFieldRule};
     const APP: &str = "numtracker";
 
+    #[rstest]
+    #[case::flag(&["--db", "flag.db"], Some("env.db"), "flag.db", DbPathSource::Flag)]
+    #[case::flag_only(&["--db", "flag.db"], None, "flag.db", DbPathSource::Flag)]
+    #[case::env(&[], Some("env.db"), "env.db", DbPathSource::Env)]
+    #[case::default(&[], None, "numtracker.db", DbPathSource::Default)]
+    fn db_path_precedence(
+        #[case] args: &[&str],
+        #[case] env: Option<&'static str>,
+        #[case] expected: &str,
+        #[case] source: DbPathSource,
+    ) {
+        // Tests share the process environment so each case that sets the variable uses its own
+        let var = env.map(|value| {
+            let var = if args.is_empty() {
+                "NUMTRACKER_TEST_DB_ENV"
+            } else {
+                "NUMTRACKER_TEST_DB_FLAG"
+            };
+            env::set_var(var, value);
+            var
+        });
+        let matches = Cli::command()
+            .mut_arg("db", |arg| arg.env(var))
+            .try_get_matches_from([APP].iter().chain(args).chain(&["serve"]))
+            .unwrap();
+        let cli = Cli::from_matches(&matches).unwrap();
+        assert_eq!(cli.db(), (PathBuf::from(expected), source));
+    }
+
+    #[test]
+    fn empty_db_env() {
+        env::set_var("NUMTRACKER_TEST_DB_EMPTY", "");
+        let err = Cli::command()
+            .mut_arg("db", |arg| arg.env("NUMTRACKER_TEST_DB_EMPTY"))
+            .try_get_matches_from([APP, "serve"])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn db_env_in_help() {
+        let help = Cli::command().render_long_help().to_string();
+        assert!(help.contains("[env: NUMTRACKER_DB="), "{help}");
+        assert!(help.contains("[default: numtracker.db]"), "{help}");
+    }
+
     #[test]
     fn serve_defaults() {
         let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
@@ -175,8 +811,12 @@ mod tests {
         let Command::Serve(cmd) = cli.command else {
             panic!("Unexpected subcommand: {:?}", cli.command);
         };
-        assert_eq!(cmd.addr(), ("0.0.0.0".parse().unwrap(), 8000));
+        assert_eq!(cmd.addr(), "0.0.0.0:8000".parse().unwrap());
         assert_eq!(cmd.root_directory(), None);
+        assert!(!cmd.create_directories());
+        assert!(!cmd.ready_check_policy());
+        assert_eq!(cmd.pool.min_connections, 0);
+        assert_eq!(cmd.pool.max_connections, 10);
 
         assert_matches!(cmd.policy, None);
     }
@@ -197,11 +837,85 @@ mod tests {
         let Command::Serve(cmd) = cli.command else {
             panic!("Unexpected subcommand: {:?}", cli.command);
         };
-        assert_eq!(cmd.addr(), ("127.0.0.1".parse().unwrap(), 8765));
+        assert_eq!(cmd.addr(), "127.0.0.1:8765".parse().unwrap());
         assert_eq!(cmd.root_directory, Some("/tmp/trackers".into()));
         assert_matches!(cmd.policy, None);
     }
 
+    #[test]
+    fn ipv6_host() {
+        let cli = Cli::try_parse_from([APP, "serve", "--host", "::", "--port", "8765"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.addr(), "[::]:8765".parse().unwrap());
+    }
+
+    #[test]
+    fn invalid_host() {
+        let err = Cli::try_parse_from([APP, "serve", "--host", "not.an.address"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn create_directories() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--root-directory",
+            "/tmp/trackers",
+            "--create-directories",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.create_directories());
+    }
+
+    #[test]
+    fn number_file_grace() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.number_file_grace(), Duration::ZERO);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--root-directory",
+            "/tmp/trackers",
+            "--number-file-grace",
+            "30",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.number_file_grace(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn create_directories_without_root() {
+        let err = Cli::try_parse_from([APP, "serve", "--create-directories"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn pool_options() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--db-min-connections",
+            "2",
+            "--db-max-connections",
+            "4",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.pool.min_connections, 2);
+        assert_eq!(cmd.pool.max_connections, 4);
+    }
+
+    #[test]
+    fn zero_max_connections() {
+        let err = Cli::try_parse_from([APP, "serve", "--db-max-connections", "0"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
     #[test]
     fn policy_arguments() {
         let cli = Cli::try_parse_from([
@@ -221,6 +935,109 @@ mod tests {
         assert_eq!(policy.policy_host, "opa.example.com");
         assert_eq!(policy.admin_query, "demo/admin_check");
         assert_eq!(policy.access_query, "demo/access_check");
+        assert_eq!(policy.audiences, ["account"]);
+        assert_eq!(policy.pool_idle_timeout, 90);
+        assert_eq!(policy.pool_max_idle, 32);
+        assert_eq!(policy.tcp_keepalive, 60);
+        assert_eq!(
+            policy.visit_fields,
+            [PolicyVisitField::Proposal, PolicyVisitField::Session]
+        );
+    }
+
+    #[test]
+    fn policy_visit_fields() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--policy",
+            "opa.example.com",
+            "--admin-query",
+            "demo/admin_check",
+            "--access-query",
+            "demo/access_check",
+            "--policy-visit-fields",
+            "code,visit",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        let policy = assert_matches!(cmd.policy, Some(plc) => plc);
+        assert_eq!(
+            policy.visit_fields,
+            [PolicyVisitField::Code, PolicyVisitField::Visit]
+        );
+    }
+
+    #[test]
+    fn policy_connection_options() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--policy",
+            "opa.example.com",
+            "--admin-query",
+            "demo/admin_check",
+            "--access-query",
+            "demo/access_check",
+            "--policy-idle-timeout",
+            "300",
+            "--policy-max-idle",
+            "4",
+            "--policy-keepalive",
+            "0",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        let policy = assert_matches!(cmd.policy, Some(plc) => plc);
+        assert_eq!(policy.pool_idle_timeout, 300);
+        assert_eq!(policy.pool_max_idle, 4);
+        assert_eq!(policy.tcp_keepalive, 0);
+    }
+
+    #[test]
+    fn multiple_audiences() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--policy",
+            "opa.example.com",
+            "--admin-query",
+            "demo/admin_check",
+            "--access-query",
+            "demo/access_check",
+            "--audience",
+            "account,numtracker",
+            "--audience",
+            "other",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        let policy = assert_matches!(cmd.policy, Some(plc) => plc);
+        assert_eq!(policy.audiences, ["account", "numtracker", "other"]);
+    }
+
+    #[test]
+    fn ready_check_policy() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--policy",
+            "opa.example.com",
+            "--admin-query",
+            "demo/admin_check",
+            "--access-query",
+            "demo/access_check",
+            "--ready-check-policy",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.ready_check_policy());
+    }
+
+    #[test]
+    fn ready_check_without_policy() {
+        let err = Cli::try_parse_from([APP, "serve", "--ready-check-policy"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
     }
 
     #[test]
@@ -283,6 +1100,42 @@ mod tests {
         assert_eq!(err.kind(), ErrorKind::ArgumentConflict);
     }
 
+    #[test]
+    fn log_filters() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "-v",
+            "serve",
+            "--log-filter",
+            "numtracker::db_service=warn",
+            "--log-filter",
+            "numtracker::graphql::auth=debug,h2=off",
+        ])
+        .unwrap();
+        assert_eq!(cli.log_level(), Some(Level::INFO));
+        assert_eq!(
+            cli.log_filters()
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "numtracker::db_service=warn",
+                "numtracker::graphql::auth=debug",
+                "h2=off"
+            ]
+        );
+
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        assert!(cli.log_filters().is_empty());
+    }
+
+    #[test]
+    fn invalid_log_filter() {
+        let err =
+            Cli::try_parse_from([APP, "--log-filter", "db_service=loud", "serve"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
     #[test]
     fn max_verbosity() {
         let cli = Cli::try_parse_from([APP, "-vvv", "serve"]).unwrap();
@@ -322,6 +1175,358 @@ mod tests {
         assert_eq!(cli.tracing().level(), Level::DEBUG);
     }
 
+    #[test]
+    fn config_command() {
+        let cli = Cli::try_parse_from([APP, "config", "i22"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Config(cmd) => cmd);
+        assert_eq!(cmd.beamline, "i22");
+        assert_eq!(cmd.root_directory, None);
+
+        let cli = Cli::try_parse_from([APP, "config", "i22", "--root-directory", "/tmp/trackers"])
+            .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Config(cmd) => cmd);
+        assert_eq!(cmd.root_directory, Some("/tmp/trackers".into()));
+        assert_eq!(cmd.config_overlay, None);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "config",
+            "i22",
+            "--config-overlay",
+            "/tmp/overlay.json",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Config(cmd) => cmd);
+        assert_eq!(cmd.config_overlay, Some("/tmp/overlay.json".into()));
+    }
+
+    #[test]
+    fn missing_tracker_directory() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let Command::Serve(cmd) = cli.command else {
+            panic!("Unexpected subcommand: {:?}", cli.command);
+        };
+        assert_eq!(
+            cmd.missing_tracker_directory(),
+            MissingTrackerDirectory::Allow
+        );
+
+        let cli =
+            Cli::try_parse_from([APP, "serve", "--missing-tracker-directory", "deny"]).unwrap();
+        let Command::Serve(cmd) = cli.command else {
+            panic!("Unexpected subcommand: {:?}", cli.command);
+        };
+        assert_eq!(
+            cmd.missing_tracker_directory(),
+            MissingTrackerDirectory::Deny
+        );
+
+        let cli =
+            Cli::try_parse_from([APP, "config", "i22", "--missing-tracker-directory", "warn"])
+                .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Config(cmd) => cmd);
+        assert_eq!(cmd.missing_tracker_directory, MissingTrackerDirectory::Warn);
+    }
+
+    #[test]
+    fn log_render_context() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(!cmd.log_render_context());
+
+        let cli = Cli::try_parse_from([APP, "serve", "--log-render-context"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.log_render_context());
+    }
+
+    #[test]
+    fn read_only() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(!cmd.read_only());
+
+        let cli = Cli::try_parse_from([APP, "serve", "--read-only"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.read_only());
+    }
+
+    #[test]
+    fn debug_timings() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(!cmd.debug_timings());
+
+        let cli = Cli::try_parse_from([APP, "serve", "--debug-timings"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.debug_timings());
+    }
+
+    #[test]
+    fn template_field_rules() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--require-template-field",
+            "visit=proposal",
+            "--forbid-template-field",
+            "scan=subdirectory,detector=year",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(
+            cmd.required_template_fields,
+            [TemplateFieldRule::Visit(BeamlineField::Proposal)]
+        );
+        assert_eq!(
+            cmd.forbidden_template_fields,
+            [
+                TemplateFieldRule::Scan(ScanField::Subdirectory),
+                TemplateFieldRule::Detector(DetectorField::Scan(ScanField::Beamline(
+                    BeamlineField::Year
+                )))
+            ]
+        );
+
+        let err = Cli::try_parse_from([APP, "serve", "--forbid-template-field", "visit=detector"])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn proposal_codes() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.proposal_codes().is_empty());
+
+        let cli = Cli::try_parse_from([APP, "serve", "--proposal-codes", "cm,mx"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.proposal_codes(), ["cm", "mx"]);
+    }
+
+    #[test]
+    fn served_beamlines() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert!(cmd.served_beamlines().is_empty());
+
+        let cli = Cli::try_parse_from([APP, "serve", "--serve-beamlines", "i22,b21"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.served_beamlines(), ["i22", "b21"]);
+    }
+
+    #[test]
+    fn max_detectors() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.max_detectors(), 1000);
+
+        let cli = Cli::try_parse_from([APP, "serve", "--max-detectors", "12"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.max_detectors(), 12);
+    }
+
+    #[test]
+    fn unix_socket() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.unix_socket(), None);
+
+        let cli =
+            Cli::try_parse_from([APP, "serve", "--unix-socket", "/run/numtracker.sock"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.unix_socket(), Some(Path::new("/run/numtracker.sock")));
+    }
+
+    #[test]
+    fn compression_threshold() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.compression_threshold(), 1024);
+
+        let cli = Cli::try_parse_from([APP, "serve", "--compression-threshold", "0"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.compression_threshold(), 0);
+    }
+
+    #[test]
+    fn visit_directory_check() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.visit_directory_check(), VisitDirectoryCheck::Off);
+
+        let cli =
+            Cli::try_parse_from([APP, "serve", "--visit-directory-check", "require"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.visit_directory_check(), VisitDirectoryCheck::Require);
+    }
+
+    #[test]
+    fn subdirectory_limits() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.subdirectory_max_depth(), None);
+        assert_eq!(cmd.subdirectory_default_depth(), None);
+        assert_eq!(cmd.hidden_subdirectories(), HiddenSubdirectories::Allow);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "serve",
+            "--subdirectory-max-depth",
+            "4",
+            "--hidden-subdirectories",
+            "opt-in",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.subdirectory_max_depth(), Some(4));
+        assert_eq!(cmd.subdirectory_default_depth(), Some(4));
+        assert_eq!(cmd.hidden_subdirectories(), HiddenSubdirectories::OptIn);
+
+        // The default can be stricter than the maximum but not looser
+        for (default, expected) in [("2", 2), ("6", 4)] {
+            let cli = Cli::try_parse_from([
+                APP,
+                "serve",
+                "--subdirectory-max-depth",
+                "4",
+                "--subdirectory-default-depth",
+                default,
+            ])
+            .unwrap();
+            let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+            assert_eq!(cmd.subdirectory_default_depth(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn idempotency_window() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.idempotency_window(), Duration::from_secs(600));
+
+        let cli = Cli::try_parse_from([APP, "serve", "--idempotency-window", "30"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.idempotency_window(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn scan_rate_limit() {
+        let cli = Cli::try_parse_from([APP, "serve"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.scan_rate_limit(), None);
+
+        let cli = Cli::try_parse_from([APP, "serve", "--scan-rate-limit", "5"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Serve(cmd) => cmd);
+        assert_eq!(cmd.scan_rate_limit(), Some(5));
+
+        let err = Cli::try_parse_from([APP, "serve", "--scan-rate-limit", "0"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn reset_scan_number_command() {
+        let cli = Cli::try_parse_from([APP, "reset-scan-number", "i22", "100"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::ResetScanNumber(cmd) => cmd);
+        assert_eq!(cmd.beamline, "i22");
+        assert_eq!(cmd.scan_number, 100);
+        assert!(!cmd.force);
+        assert!(!cmd.yes);
+        assert_eq!(cmd.config_overlay, None);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "reset-scan-number",
+            "i22",
+            "100",
+            "--force",
+            "-y",
+            "--config-overlay",
+            "/tmp/overlay.json",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::ResetScanNumber(cmd) => cmd);
+        assert!(cmd.force);
+        assert!(cmd.yes);
+        assert_eq!(cmd.config_overlay, Some("/tmp/overlay.json".into()));
+    }
+
+    #[test]
+    fn audit_command() {
+        let cli = Cli::try_parse_from([APP, "audit", "i22"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Audit(cmd) => cmd);
+        assert_eq!(cmd.beamline, "i22");
+        assert_eq!(cmd.limit, 20);
+        assert!(!cmd.follow);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "audit",
+            "i22",
+            "-n",
+            "5",
+            "--follow",
+            "--interval",
+            "10",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Audit(cmd) => cmd);
+        assert_eq!(cmd.limit, 5);
+        assert!(cmd.follow);
+        assert_eq!(cmd.interval, 10);
+
+        let err = Cli::try_parse_from([APP, "audit", "i22", "--interval", "10"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+
+        let err =
+            Cli::try_parse_from([APP, "audit", "i22", "--follow", "--interval", "0"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn export_command() {
+        let cli = Cli::try_parse_from([APP, "export"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Export(cmd) => cmd);
+        assert_eq!(cmd.beamline, None);
+        assert_eq!(cmd.from, None);
+        assert_eq!(cmd.until, None);
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "export",
+            "--beamline",
+            "i22",
+            "--from",
+            "2024-01-01",
+            "--until",
+            "2024-06-30",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::Export(cmd) => cmd);
+        assert_eq!(cmd.beamline.as_deref(), Some("i22"));
+        assert_eq!(cmd.from, NaiveDate::from_ymd_opt(2024, 1, 1));
+        assert_eq!(cmd.until, NaiveDate::from_ymd_opt(2024, 6, 30));
+
+        let err = Cli::try_parse_from([APP, "export", "--from", "June"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn migrate_command() {
+        let cli = Cli::try_parse_from([APP, "migrate"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Migrate(cmd) => cmd);
+        assert!(!cmd.check_only);
+
+        let cli = Cli::try_parse_from([APP, "migrate", "--check-only"]).unwrap();
+        let cmd = assert_matches!(cli.command, Command::Migrate(cmd) => cmd);
+        assert!(cmd.check_only);
+    }
+
+    #[test]
+    fn config_requires_beamline() {
+        let err = Cli::try_parse_from([APP, "config"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
     #[test]
     fn schema_command() {
         let cli = Cli::try_parse_from([APP, "schema"]).unwrap();
@@ -329,4 +1534,69 @@ mod tests {
             panic!("Unexpected command: {:?}", cli.command);
         };
     }
+
+    #[test]
+    fn diff_template_command() {
+        let cli = Cli::try_parse_from([
+            APP,
+            "diff-template",
+            "--kind",
+            "scan",
+            "--old",
+            "{instrument}-{scan_number}",
+            "{subdirectory}/{instrument}-{scan_number}",
+            "-s",
+            "instrument=i22",
+            "--sample",
+            "scan_number=1234",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::DiffTemplate(cmd) => cmd);
+        assert_eq!(cmd.kind, TemplateKind::Scan);
+        assert_eq!(
+            cmd.old,
+            OldTemplate::Template("{instrument}-{scan_number}".into())
+        );
+        assert_eq!(cmd.new, "{subdirectory}/{instrument}-{scan_number}");
+        assert_eq!(
+            cmd.samples,
+            [
+                ("instrument".into(), "i22".into()),
+                ("scan_number".into(), "1234".into())
+            ]
+        );
+
+        let cli = Cli::try_parse_from([
+            APP,
+            "diff-template",
+            "--kind",
+            "visit",
+            "--beamline",
+            "i22",
+            "/tmp/{instrument}/{year}/{visit}",
+        ])
+        .unwrap();
+        let cmd = assert_matches!(cli.command, Command::DiffTemplate(cmd) => cmd);
+        assert_eq!(cmd.old, OldTemplate::Beamline("i22".into()));
+        assert!(cmd.samples.is_empty());
+    }
+
+    #[rstest]
+    #[case::no_old(&["--kind", "scan", "{scan_number}"], ErrorKind::MissingRequiredArgument)]
+    #[case::both(
+        &["--kind", "scan", "--old", "{scan_number}", "--beamline", "i22", "{scan_number}"],
+        ErrorKind::ArgumentConflict
+    )]
+    #[case::bad_sample(
+        &["--kind", "scan", "--old", "{scan_number}", "{scan_number}", "-s", "scan_number"],
+        ErrorKind::ValueValidation
+    )]
+    #[case::empty_field(
+        &["--kind", "scan", "--old", "{scan_number}", "{scan_number}", "-s", "=12"],
+        ErrorKind::ValueValidation
+    )]
+    fn invalid_diff_template_args(#[case] args: &[&str], #[case] kind: ErrorKind) {
+        let err = Cli::try_parse_from([APP, "diff-template"].iter().chain(args)).unwrap_err();
+        assert_eq!(err.kind(), kind);
+    }
 }
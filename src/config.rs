@@ -0,0 +1,278 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::cli::{ConfigOptions, MissingTrackerDirectory};
+use crate::db_service::{ConfigurationError, OpenError, SqliteScanPathService};
+use crate::numtracker::{DirectoryStatus, InvalidExtension, NumTracker};
+use crate::overlay::{ConfigOverlay, OverlayError};
+use crate::paths::InvalidPathTemplate;
+
+/// The complete configuration for a beamline with any defaults resolved
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResolvedConfiguration {
+    beamline: String,
+    instrument: String,
+    visit_template: String,
+    scan_template: String,
+    detector_template: String,
+    commissioning_visit_template: Option<String>,
+    commissioning_proposal_codes: Vec<String>,
+    latest_scan_number: u32,
+    scan_number_floor: Option<u32>,
+    scan_number_step: u32,
+    tracker_file_extension: String,
+    tracker_file_extension_defaulted: bool,
+    /// Whether the beamline has a directory in the tracker root directory
+    tracker_directory: bool,
+    /// Whether the tracker directory currently exists and can be read
+    tracker_directory_status: DirectoryStatus,
+    /// The highest number in the tracker directory if there is one for this beamline
+    tracker_scan_number: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ConfigInfoError {
+    Configuration(ConfigurationError),
+    Template(&'static str, InvalidPathTemplate),
+    Extension(InvalidExtension),
+    Tracker(std::io::Error),
+    Db(OpenError),
+    Overlay(OverlayError),
+    MissingTrackerDirectory(String),
+}
+
+impl Display for ConfigInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigInfoError::Configuration(e) => write!(f, "{e}"),
+            ConfigInfoError::Template(kind, e) => write!(f, "Invalid {kind} template: {e}"),
+            ConfigInfoError::Extension(e) => write!(f, "Invalid tracker file extension: {e}"),
+            ConfigInfoError::Tracker(e) => write!(f, "Could not read tracker directory: {e}"),
+            ConfigInfoError::Db(e) => write!(f, "Could not open DB: {e}"),
+            ConfigInfoError::Overlay(e) => write!(f, "{e}"),
+            ConfigInfoError::MissingTrackerDirectory(bl) => {
+                write!(f, "Beamline {bl:?} does not have a tracker directory")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigInfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigInfoError::Configuration(e) => Some(e),
+            ConfigInfoError::Template(_, e) => Some(e),
+            ConfigInfoError::Extension(e) => Some(e),
+            ConfigInfoError::Tracker(e) => Some(e),
+            ConfigInfoError::Db(e) => Some(e),
+            ConfigInfoError::Overlay(e) => Some(e),
+            ConfigInfoError::MissingTrackerDirectory(_) => None,
+        }
+    }
+}
+
+impl From<ConfigurationError> for ConfigInfoError {
+    fn from(value: ConfigurationError) -> Self {
+        Self::Configuration(value)
+    }
+}
+
+impl From<std::io::Error> for ConfigInfoError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Tracker(value)
+    }
+}
+
+/// Print the resolved configuration of a beamline as a single JSON object. A beamline without
+/// a tracker directory is reported according to the given `MissingTrackerDirectory` option.
+pub async fn print_configuration(db: &Path, opts: ConfigOptions) -> Result<(), ConfigInfoError> {
+    let db = SqliteScanPathService::open_existing(db, true)
+        .await
+        .map_err(ConfigInfoError::Db)?;
+    let overlay = match opts.config_overlay {
+        Some(path) => ConfigOverlay::load(&path).map_err(ConfigInfoError::Overlay)?,
+        None => ConfigOverlay::default(),
+    };
+    let nt = NumTracker::for_root_directory(opts.root_directory)?
+        .with_directories(overlay.tracker_directories());
+    let config = resolve(&db, &nt, &opts.beamline).await?;
+    if !config.tracker_directory {
+        match opts.missing_tracker_directory {
+            MissingTrackerDirectory::Allow => {}
+            MissingTrackerDirectory::Warn => {
+                warn!(
+                    "Beamline {:?} does not have a tracker directory",
+                    config.beamline
+                )
+            }
+            MissingTrackerDirectory::Deny => {
+                return Err(ConfigInfoError::MissingTrackerDirectory(config.beamline));
+            }
+        }
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&config).expect("Configuration is always serializable")
+    );
+    Ok(())
+}
+
+async fn resolve(
+    db: &SqliteScanPathService,
+    nt: &NumTracker,
+    beamline: &str,
+) -> Result<ResolvedConfiguration, ConfigInfoError> {
+    let conf = db.current_configuration(beamline).await?;
+    let template = |kind| move |e| ConfigInfoError::Template(kind, e);
+    let tracker = nt
+        .for_beamline(beamline, conf.extension())
+        .await
+        .map_err(ConfigInfoError::Extension)?;
+    Ok(ResolvedConfiguration {
+        beamline: conf.name().into(),
+        instrument: conf.instrument_name().into(),
+        visit_template: conf.visit().map_err(template("visit"))?.to_string(),
+        scan_template: conf.scan().map_err(template("scan"))?.to_string(),
+        detector_template: conf.detector().map_err(template("detector"))?.to_string(),
+        commissioning_visit_template: conf
+            .commissioning_visit()
+            .transpose()
+            .map_err(template("commissioning visit"))?
+            .map(|t| t.to_string()),
+        commissioning_proposal_codes: conf.commissioning_codes().map(String::from).collect(),
+        latest_scan_number: conf.scan_number(),
+        scan_number_floor: conf.scan_number_floor(),
+        scan_number_step: conf.scan_number_step(),
+        tracker_file_extension: conf.tracker_extension().into(),
+        tracker_file_extension_defaulted: conf.extension().is_none(),
+        tracker_directory: tracker.has_directory(),
+        tracker_directory_status: nt.directory_status(beamline).await,
+        tracker_scan_number: tracker.prev().await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use assert_matches::assert_matches;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::{resolve, ConfigInfoError};
+    use crate::db_service::{
+        BeamlineConfigurationUpdate, ConfigurationError, SqliteScanPathService,
+    };
+    use crate::numtracker::{DirectoryStatus, NumTracker};
+    use crate::overlay::ConfigOverlay;
+    use crate::paths::{DetectorTemplate, PathSpec as _, ScanTemplate, VisitTemplate};
+
+    async fn db() -> SqliteScanPathService {
+        let db = SqliteScanPathService::memory().await;
+        BeamlineConfigurationUpdate {
+            scan_number: Some(122),
+            visit: VisitTemplate::new_checked("/tmp/{instrument}/data/{year}/{visit}").ok(),
+            scan: ScanTemplate::new_checked("{subdirectory}/{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked(
+                "{subdirectory}/{instrument}-{scan_number}-{detector}",
+            )
+            .ok(),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn resolved_configuration() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        fs::File::create(root.path().join("i22").join("121.i22")).unwrap();
+        let nt = NumTracker::for_root_directory(Some(root.path())).unwrap();
+
+        let conf = resolve(&db().await, &nt, "i22").await.unwrap();
+        assert_eq!(
+            serde_json::to_value(conf).unwrap(),
+            json!({
+                "beamline": "i22",
+                "instrument": "i22",
+                "visitTemplate": "/tmp/{instrument}/data/{year}/{visit}",
+                "scanTemplate": "{subdirectory}/{instrument}-{scan_number}",
+                "detectorTemplate": "{subdirectory}/{instrument}-{scan_number}-{detector}",
+                "commissioningVisitTemplate": null,
+                "commissioningProposalCodes": ["cm"],
+                "latestScanNumber": 122,
+                "scanNumberFloor": null,
+                "scanNumberStep": 1,
+                "trackerFileExtension": "i22",
+                "trackerFileExtensionDefaulted": true,
+                "trackerDirectory": true,
+                "trackerDirectoryStatus": "available",
+                "trackerScanNumber": 121,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn overlaid_tracker_directory() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        fs::File::create(root.path().join("i22").join("121.i22")).unwrap();
+        let sandbox = tempdir().unwrap();
+        fs::File::create(sandbox.path().join("130.i22")).unwrap();
+        let overlay: ConfigOverlay = serde_json::from_value(json!({
+            "beamlines": {"i22": {"trackerDirectory": sandbox.path()}}
+        }))
+        .unwrap();
+        let nt = NumTracker::for_root_directory(Some(root.path()))
+            .unwrap()
+            .with_directories(overlay.tracker_directories());
+
+        let conf = resolve(&db().await, &nt, "i22").await.unwrap();
+        assert!(conf.tracker_directory);
+        assert_eq!(conf.tracker_scan_number, Some(130));
+    }
+
+    #[tokio::test]
+    async fn without_tracker_directory() {
+        let nt = NumTracker::for_root_directory(None::<&str>).unwrap();
+        let conf = resolve(&db().await, &nt, "i22").await.unwrap();
+        assert_eq!(conf.tracker_scan_number, None);
+        assert!(!conf.tracker_directory);
+        assert_eq!(conf.tracker_directory_status, DirectoryStatus::Unconfigured);
+    }
+
+    #[tokio::test]
+    async fn missing_beamline() {
+        let nt = NumTracker::for_root_directory(None::<&str>).unwrap();
+        let err = resolve(&db().await, &nt, "b21").await.unwrap_err();
+        assert_matches!(
+            err,
+            ConfigInfoError::Configuration(ConfigurationError::MissingBeamline(_))
+        );
+        assert_eq!(
+            err.to_string(),
+            r#"No configuration available for beamline "b21""#
+        );
+    }
+}
@@ -12,18 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeSet;
 use std::fmt;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
-use error::{ConfigurationError, NewConfigurationError};
-use sqlx::sqlite::{SqliteConnectOptions, SqliteRow};
-use sqlx::{query_as, FromRow, QueryBuilder, Row, Sqlite, SqlitePool};
+pub use error::{
+    ConfigurationError, NewConfigurationError, NextScanError, OpenError, UpdateConfigurationError,
+};
+use futures::stream::BoxStream;
+use sqlx::migrate::Migrate as _;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow};
+use sqlx::{
+    query, query_as, query_scalar, ConnectOptions as _, FromRow, QueryBuilder, Row, Sqlite,
+    SqliteConnection, SqlitePool, Transaction,
+};
 use tracing::{info, instrument, trace};
 
+use crate::cli::PoolOptions;
 use crate::paths::{
-    BeamlineField, DetectorField, DetectorTemplate, InvalidPathTemplate, PathSpec, ScanField,
-    ScanTemplate, VisitTemplate,
+    BeamlineField, DetectorField, DetectorNormalisation, DetectorTemplate, InvalidPathTemplate,
+    PathSpec, ScanField, ScanTemplate, VisitTemplate,
 };
 use crate::template::PathTemplate;
 
@@ -32,15 +43,38 @@ type SqliteTemplateResult<F> = Result<PathTemplate<F>, InvalidPathTemplate>;
 #[derive(Clone)]
 pub struct SqliteScanPathService {
     pool: SqlitePool,
+    beamlines: Arc<BeamlineNames>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct NumtrackerConfig {
-    pub directory: String,
-    pub extension: String,
+/// How long the cached set of beamline names is used before being read from the DB again. Names
+/// added via this service invalidate the cache immediately so this only limits how long changes
+/// made by other processes take to be seen.
+const BEAMLINE_NAMES_TTL: Duration = Duration::from_secs(60);
+
+/// Cache of the names of all configured beamlines
+#[derive(Default)]
+struct BeamlineNames(Mutex<Option<(Instant, Arc<BTreeSet<String>>)>>);
+
+impl BeamlineNames {
+    fn get(&self) -> Option<Arc<BTreeSet<String>>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .filter(|(read, _)| read.elapsed() < BEAMLINE_NAMES_TTL)
+            .map(|(_, names)| names.clone())
+    }
+
+    fn set(&self, names: Arc<BTreeSet<String>>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some((Instant::now(), names));
+    }
+
+    fn invalidate(&self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct RawPathTemplate<F>(String, PhantomData<F>);
 
 impl<Spec> RawPathTemplate<Spec>
@@ -58,7 +92,7 @@ impl<F> From<String> for RawPathTemplate<F> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BeamlineConfiguration {
     name: String,
     scan_number: u32,
@@ -66,25 +100,107 @@ pub struct BeamlineConfiguration {
     scan: RawPathTemplate<ScanTemplate>,
     detector: RawPathTemplate<DetectorTemplate>,
     extension: Option<String>,
+    commissioning_visit: Option<RawPathTemplate<VisitTemplate>>,
+    commissioning_codes: Option<String>,
+    detector_lowercase: Option<bool>,
+    detector_collapse: Option<bool>,
+    detector_replacement: Option<String>,
+    detector_reject_invalid: Option<bool>,
+    scan_number_floor: Option<u32>,
+    scan_number_step: Option<u32>,
+    instrument: Option<String>,
+    /// Visit templates for specific proposal codes, ordered by code
+    visit_code_templates: Vec<VisitCodeTemplate>,
 }
 
+/// Proposal codes that use the commissioning visit template if none are configured
+const DEFAULT_COMMISSIONING_CODES: &str = "cm";
+
 impl BeamlineConfiguration {
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// The instrument used for the `{instrument}` field of templates - the configured
+    /// instrument if there is one, otherwise the beamline name
+    pub fn instrument_name(&self) -> &str {
+        self.instrument.as_deref().unwrap_or(&self.name)
+    }
+
     pub fn scan_number(&self) -> u32 {
         self.scan_number
     }
 
+    /// The number that scan numbers for this beamline will always be above, if any
+    pub fn scan_number_floor(&self) -> Option<u32> {
+        self.scan_number_floor
+    }
+
+    /// The amount the scan number advances by for each scan (default 1)
+    pub fn scan_number_step(&self) -> u32 {
+        self.scan_number_step.unwrap_or(1)
+    }
+
+    /// Use this configuration to generate paths for a specific scan instead of the latest one
+    pub fn with_scan_number(self, scan_number: u32) -> Self {
+        Self {
+            scan_number,
+            ..self
+        }
+    }
+
     pub fn extension(&self) -> Option<&str> {
         self.extension.as_deref()
     }
 
+    /// The extension used for tracker files - the configured extension if there is one,
+    /// otherwise the beamline name
+    pub fn tracker_extension(&self) -> &str {
+        self.extension().unwrap_or(&self.name)
+    }
+
     pub fn visit(&self) -> SqliteTemplateResult<BeamlineField> {
         self.visit.as_template()
     }
 
+    /// The visit template to use for visits with the given proposal code. A template configured
+    /// for the specific code takes precedence. Otherwise, visits with one of the commissioning
+    /// codes use the commissioning template if one is configured and all other visits use the
+    /// default visit template.
+    pub fn visit_for(&self, code: Option<&str>) -> SqliteTemplateResult<BeamlineField> {
+        if let Some(template) =
+            code.and_then(|code| self.visit_code_templates.iter().find(|t| t.code == code))
+        {
+            return template.template();
+        }
+        match (&self.commissioning_visit, code) {
+            (Some(template), Some(code)) if self.commissioning_codes().any(|c| c == code) => {
+                template.as_template()
+            }
+            _ => self.visit(),
+        }
+    }
+
+    /// The visit templates configured for specific proposal codes, ordered by code
+    pub fn visit_code_templates(&self) -> &[VisitCodeTemplate] {
+        &self.visit_code_templates
+    }
+
+    pub fn commissioning_visit(&self) -> Option<SqliteTemplateResult<BeamlineField>> {
+        self.commissioning_visit
+            .as_ref()
+            .map(RawPathTemplate::as_template)
+    }
+
+    /// The proposal codes that use the commissioning visit template
+    pub fn commissioning_codes(&self) -> impl Iterator<Item = &str> {
+        split_codes(
+            self.commissioning_codes
+                .as_deref()
+                .unwrap_or(DEFAULT_COMMISSIONING_CODES),
+        )
+    }
+
     pub fn scan(&self) -> SqliteTemplateResult<ScanField> {
         self.scan.as_template()
     }
@@ -92,11 +208,387 @@ impl BeamlineConfiguration {
     pub fn detector(&self) -> SqliteTemplateResult<DetectorField> {
         self.detector.as_template()
     }
+
+    /// The rules used to convert detector names into file names. Any rules not configured for
+    /// this beamline use the defaults.
+    pub fn detector_normalisation(&self) -> DetectorNormalisation {
+        let default = DetectorNormalisation::default();
+        DetectorNormalisation {
+            lowercase: self.detector_lowercase.unwrap_or(default.lowercase),
+            collapse: self.detector_collapse.unwrap_or(default.collapse),
+            replacement: self
+                .detector_replacement
+                .as_deref()
+                .and_then(|r| r.chars().next())
+                .unwrap_or(default.replacement),
+        }
+    }
+
+    /// Whether detector names that would be changed by normalisation (other than by changing
+    /// their case) are rejected instead of being rewritten
+    pub fn detector_reject_invalid(&self) -> bool {
+        self.detector_reject_invalid.unwrap_or(false)
+    }
+}
+
+impl BeamlineConfiguration {
+    /// Add the visit templates configured for this beamline's proposal codes
+    async fn with_visit_code_templates(self, conn: &mut SqliteConnection) -> sqlx::Result<Self> {
+        let visit_code_templates = load_visit_code_templates(conn, &self.name).await?;
+        Ok(Self {
+            visit_code_templates,
+            ..self
+        })
+    }
+
+    /// The configurable fields of this beamline and their values as they are recorded in the
+    /// audit trail of configuration changes
+    fn audit_fields(&self) -> [(&'static str, Option<String>); 14] {
+        [
+            ("scan_number", Some(self.scan_number.to_string())),
+            ("visit", Some(self.visit.0.clone())),
+            ("scan", Some(self.scan.0.clone())),
+            ("detector", Some(self.detector.0.clone())),
+            ("extension", self.extension.clone()),
+            (
+                "commissioning_visit",
+                self.commissioning_visit.as_ref().map(|t| t.0.clone()),
+            ),
+            ("commissioning_codes", self.commissioning_codes.clone()),
+            (
+                "detector_lowercase",
+                self.detector_lowercase.map(|b| b.to_string()),
+            ),
+            (
+                "detector_collapse",
+                self.detector_collapse.map(|b| b.to_string()),
+            ),
+            ("detector_replacement", self.detector_replacement.clone()),
+            (
+                "detector_reject_invalid",
+                self.detector_reject_invalid.map(|b| b.to_string()),
+            ),
+            (
+                "scan_number_floor",
+                self.scan_number_floor.map(|n| n.to_string()),
+            ),
+            (
+                "scan_number_step",
+                self.scan_number_step.map(|n| n.to_string()),
+            ),
+            ("instrument", self.instrument.clone()),
+        ]
+    }
+}
+
+/// Record each field that differs between two versions of a beamline's configuration. If there
+/// is no previous version, every field that is set in the new version is recorded.
+async fn record_changes(
+    conn: &mut SqliteConnection,
+    old: Option<&BeamlineConfiguration>,
+    new: &BeamlineConfiguration,
+) -> sqlx::Result<()> {
+    for change in changed_fields(old, new) {
+        record_change(&mut *conn, &new.name, change).await?;
+    }
+    Ok(())
+}
+
+async fn record_change(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    change: FieldChange,
+) -> sqlx::Result<()> {
+    let FieldChange {
+        field,
+        old_value,
+        new_value,
+    } = change;
+    trace!(
+        beamline,
+        field,
+        ?old_value,
+        ?new_value,
+        "Recording config change"
+    );
+    query!(
+        "INSERT INTO config_change (beamline, field, old_value, new_value) VALUES (?, ?, ?, ?)",
+        beamline,
+        field,
+        old_value,
+        new_value
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// The changes that setting the templates for groups of detectors would make. Each group is
+/// recorded as a separate field, eg `detector_group.scalers`.
+async fn detector_group_changes(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    groups: &[(String, PathTemplate<DetectorField>)],
+) -> sqlx::Result<Vec<FieldChange>> {
+    let mut changes = Vec::new();
+    for (name, template) in groups {
+        let old_value = query_scalar!(
+            "SELECT template FROM detector_group WHERE beamline = ? AND name = ?",
+            beamline,
+            name
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        let new_value = Some(template.to_string());
+        if old_value != new_value {
+            changes.push(FieldChange {
+                field: format!("detector_group.{name}"),
+                old_value,
+                new_value,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// The change that setting (or removing if `None`) the visit template for a proposal code would
+/// make, if any. Each code is recorded as a separate field, eg `visit_code_template.in`.
+async fn visit_code_template_change(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    code: &str,
+    template: Option<&PathTemplate<BeamlineField>>,
+) -> sqlx::Result<Option<FieldChange>> {
+    let old_value = query_scalar!(
+        "SELECT template FROM visit_code_template WHERE beamline = ? AND code = ?",
+        beamline,
+        code
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let new_value = template.map(|t| t.to_string());
+    Ok((old_value != new_value).then(|| FieldChange {
+        field: format!("visit_code_template.{code}"),
+        old_value,
+        new_value,
+    }))
+}
+
+/// The changes that setting the visit templates for proposal codes would make
+async fn visit_code_template_changes(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    templates: &[(String, Option<PathTemplate<BeamlineField>>)],
+) -> sqlx::Result<Vec<FieldChange>> {
+    let mut changes = Vec::new();
+    for (code, template) in templates {
+        changes.extend(visit_code_template_change(conn, beamline, code, template.as_ref()).await?);
+    }
+    Ok(changes)
+}
+
+/// Set (or remove) the visit templates for proposal codes, recording any that change
+async fn set_visit_code_templates(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    templates: &[(String, Option<PathTemplate<BeamlineField>>)],
+) -> sqlx::Result<()> {
+    for (code, template) in templates {
+        let Some(change) =
+            visit_code_template_change(&mut *conn, beamline, code, template.as_ref()).await?
+        else {
+            continue;
+        };
+        match &change.new_value {
+            Some(template) => {
+                query!(
+                    "INSERT INTO visit_code_template (beamline, code, template) VALUES (?, ?, ?)
+                ON CONFLICT (beamline, code) DO UPDATE SET template = excluded.template",
+                    beamline,
+                    code,
+                    template
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+            None => {
+                query!(
+                    "DELETE FROM visit_code_template WHERE beamline = ? AND code = ?",
+                    beamline,
+                    code
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+        };
+        record_change(&mut *conn, beamline, change).await?;
+    }
+    Ok(())
+}
+
+/// The visit templates configured for a beamline's proposal codes, ordered by code
+async fn load_visit_code_templates(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+) -> sqlx::Result<Vec<VisitCodeTemplate>> {
+    Ok(query!(
+        "SELECT code, template FROM visit_code_template WHERE beamline = ? ORDER BY code",
+        beamline
+    )
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| VisitCodeTemplate {
+        code: row.code,
+        template: row.template.into(),
+    })
+    .collect())
+}
+
+/// Set the templates for groups of detectors, recording any that change
+async fn set_detector_groups(
+    conn: &mut SqliteConnection,
+    beamline: &str,
+    groups: &[(String, PathTemplate<DetectorField>)],
+) -> sqlx::Result<()> {
+    for change in detector_group_changes(&mut *conn, beamline, groups).await? {
+        let name = change
+            .field
+            .strip_prefix("detector_group.")
+            .expect("Group changes are prefixed");
+        query!(
+            "INSERT INTO detector_group (beamline, name, template) VALUES (?, ?, ?)
+            ON CONFLICT (beamline, name) DO UPDATE SET template = excluded.template",
+            beamline,
+            name,
+            change.new_value
+        )
+        .execute(&mut *conn)
+        .await?;
+        record_change(&mut *conn, beamline, change).await?;
+    }
+    Ok(())
+}
+
+/// The fields that differ between two versions of a beamline's configuration. If there is no
+/// previous version, every field that is set in the new version is included.
+fn changed_fields(
+    old: Option<&BeamlineConfiguration>,
+    new: &BeamlineConfiguration,
+) -> Vec<FieldChange> {
+    let old_values = old.map(|o| o.audit_fields().map(|(_, value)| value));
+    new.audit_fields()
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, (field, new_value))| {
+            let old_value = old_values.as_ref().and_then(|values| values[i].clone());
+            (old_value != new_value).then_some(FieldChange {
+                field: field.into(),
+                old_value,
+                new_value,
+            })
+        })
+        .collect()
+}
+
+/// A change that would be made to a field of a beamline's configuration
+#[derive(Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// An alternative detector template used for a named group of detectors
+#[derive(Debug)]
+pub struct DetectorGroup {
+    pub name: String,
+    template: RawPathTemplate<DetectorTemplate>,
+}
+
+impl DetectorGroup {
+    pub fn template(&self) -> SqliteTemplateResult<DetectorField> {
+        self.template.as_template()
+    }
+}
+
+/// An alternative visit template used for visits with a specific proposal code
+#[derive(Debug, Clone)]
+pub struct VisitCodeTemplate {
+    pub code: String,
+    template: RawPathTemplate<VisitTemplate>,
+}
+
+impl VisitCodeTemplate {
+    pub fn template(&self) -> SqliteTemplateResult<BeamlineField> {
+        self.template.as_template()
+    }
+}
+
+/// A single change to a field of a beamline's configuration
+#[derive(Debug)]
+pub struct ConfigChange {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    /// The time (RFC 3339, UTC) the change was made
+    pub changed_at: String,
+}
+
+/// A scan number that was allocated for a beamline
+#[derive(Debug)]
+pub struct ScanAllocation {
+    /// Increasing ID that can be used to find later allocations
+    pub id: i64,
+    pub beamline: String,
+    pub scan_number: i64,
+    pub visit: String,
+    /// The time (RFC 3339, UTC) the number was allocated
+    pub allocated_at: String,
+}
+
+/// A scan number that has been allocated but not yet committed to the DB
+///
+/// The number is only kept if [`PendingScan::commit`] is called. Dropping the pending scan
+/// releases the number so that it will be allocated again by the next request.
+pub struct PendingScan {
+    tx: Transaction<'static, Sqlite>,
+    configuration: BeamlineConfiguration,
+}
+
+impl PendingScan {
+    pub fn scan_number(&self) -> u32 {
+        self.configuration.scan_number()
+    }
+
+    /// Keep the allocated number and return the configuration it was allocated for
+    pub async fn commit(self) -> Result<BeamlineConfiguration, NextScanError> {
+        self.tx.commit().await?;
+        Ok(self.configuration)
+    }
+}
+
+impl fmt::Debug for PendingScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingScan")
+            .field("configuration", &self.configuration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Which scan allocations to include in an export. Unset fields do not filter anything.
+#[derive(Debug, Default)]
+pub struct AllocationFilter {
+    pub beamline: Option<String>,
+    /// The first date (`YYYY-MM-DD`, UTC) to include allocations from
+    pub from: Option<String>,
+    /// The last date (`YYYY-MM-DD`, UTC) to include allocations from
+    pub until: Option<String>,
 }
 
 impl<'r> FromRow<'r, SqliteRow> for BeamlineConfiguration {
     fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
-        Ok(DbBeamlineConfig {
+        DbBeamlineConfig {
             id: None,
             name: row.try_get("name")?,
             scan_number: row.try_get("scan_number")?,
@@ -104,8 +596,17 @@ impl<'r> FromRow<'r, SqliteRow> for BeamlineConfiguration {
             scan: row.try_get::<String, _>("scan")?,
             detector: row.try_get::<String, _>("detector")?,
             fallback_extension: row.try_get::<Option<String>, _>("fallback_extension")?,
+            commissioning_visit: row.try_get::<Option<String>, _>("commissioning_visit")?,
+            commissioning_codes: row.try_get::<Option<String>, _>("commissioning_codes")?,
+            detector_lowercase: row.try_get::<Option<bool>, _>("detector_lowercase")?,
+            detector_collapse: row.try_get::<Option<bool>, _>("detector_collapse")?,
+            detector_replacement: row.try_get::<Option<String>, _>("detector_replacement")?,
+            detector_reject_invalid: row.try_get::<Option<bool>, _>("detector_reject_invalid")?,
+            scan_number_floor: row.try_get::<Option<i64>, _>("scan_number_floor")?,
+            scan_number_step: row.try_get::<Option<i64>, _>("scan_number_step")?,
+            instrument: row.try_get::<Option<String>, _>("instrument")?,
         }
-        .into())
+        .try_into()
     }
 }
 
@@ -117,28 +618,95 @@ pub struct BeamlineConfigurationUpdate {
     pub scan: Option<PathTemplate<ScanField>>,
     pub detector: Option<PathTemplate<DetectorField>>,
     pub extension: Option<String>,
+    pub commissioning_visit: Option<PathTemplate<BeamlineField>>,
+    pub commissioning_codes: Option<Vec<String>>,
+    pub detector_lowercase: Option<bool>,
+    pub detector_collapse: Option<bool>,
+    pub detector_replacement: Option<char>,
+    /// Reject detector names with invalid characters instead of normalising them
+    pub detector_reject_invalid: Option<bool>,
+    pub scan_number_floor: Option<u32>,
+    /// The amount the scan number advances by for each scan. Must be positive.
+    pub scan_number_step: Option<u32>,
+    /// The instrument used in paths if it is not the same as the beamline name
+    pub instrument: Option<String>,
+    /// Templates for groups of detectors. Groups not included are left unchanged.
+    pub detector_groups: Option<Vec<(String, PathTemplate<DetectorField>)>>,
+    /// Visit templates for specific proposal codes. Codes without a template have their
+    /// template removed and codes not included are left unchanged.
+    pub visit_code_templates: Option<Vec<(String, Option<PathTemplate<BeamlineField>>)>>,
 }
 
 impl BeamlineConfigurationUpdate {
     fn is_empty(&self) -> bool {
-        self.scan_number.is_none()
+        !self.updates_beamline()
+            && self.detector_groups.is_none()
+            && self.visit_code_templates.is_none()
+    }
+
+    /// Whether any fields of the beamline table are updated
+    fn updates_beamline(&self) -> bool {
+        !(self.scan_number.is_none()
             && self.visit.is_none()
             && self.scan.is_none()
             && self.detector.is_none()
             && self.extension.is_none()
+            && self.commissioning_visit.is_none()
+            && self.commissioning_codes.is_none()
+            && self.detector_lowercase.is_none()
+            && self.detector_collapse.is_none()
+            && self.detector_replacement.is_none()
+            && self.detector_reject_invalid.is_none()
+            && self.scan_number_floor.is_none()
+            && self.scan_number_step.is_none()
+            && self.instrument.is_none())
     }
 
+    /// Update an existing beamline's configuration. Returns `None` if the beamline does not
+    /// exist. An update that does not set any fields is an error.
     pub async fn update_beamline(
         &self,
         db: &SqliteScanPathService,
-    ) -> Result<Option<BeamlineConfiguration>, sqlx::Error> {
+    ) -> Result<Option<BeamlineConfiguration>, UpdateConfigurationError> {
         if self.is_empty() {
-            return match db.current_configuration(&self.name).await {
-                Ok(bc) => Ok(Some(bc)),
-                Err(ConfigurationError::MissingBeamline(_)) => Ok(None),
-                Err(ConfigurationError::Db(e)) => Err(e),
-            };
+            return Err(UpdateConfigurationError::NothingToUpdate(self.name.clone()));
+        }
+        let mut tx = db.pool.begin().await?;
+        let old = query_as!(
+            DbBeamlineConfig,
+            "SELECT * FROM beamline WHERE name = ?",
+            self.name
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let new = if self.updates_beamline() {
+            let new: Option<BeamlineConfiguration> = self
+                .update_query()
+                .build_query_as()
+                .fetch_optional(&mut *tx)
+                .await?;
+            if let (Some(old), Some(new)) = (old, &new) {
+                record_changes(&mut tx, Some(&old.try_into()?), new).await?;
+            }
+            new
+        } else {
+            old.map(BeamlineConfiguration::try_from).transpose()?
+        };
+        if let (Some(groups), Some(_)) = (&self.detector_groups, &new) {
+            set_detector_groups(&mut tx, &self.name, groups).await?;
+        }
+        if let (Some(templates), Some(_)) = (&self.visit_code_templates, &new) {
+            set_visit_code_templates(&mut tx, &self.name, templates).await?;
         }
+        let new = match new {
+            Some(new) => Some(new.with_visit_code_templates(&mut tx).await?),
+            None => None,
+        };
+        tx.commit().await?;
+        Ok(new)
+    }
+    /// Build the query to update the beamline table. There must be at least one field to update.
+    fn update_query(&self) -> QueryBuilder<'_, Sqlite> {
         let mut q: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE beamline SET ");
         let mut fields = q.separated(", ");
         if let Some(num) = self.scan_number {
@@ -158,11 +726,45 @@ impl BeamlineConfigurationUpdate {
             fields.push_bind_unseparated(detector.to_string());
         }
         if let Some(ext) = &self.extension {
-            if ext != &self.name {
-                // extension defaults to beamline name
-                fields.push("fallback_extension=");
-                fields.push_bind_unseparated(ext);
-            }
+            // extension defaults to beamline name so it is cleared instead of stored
+            fields.push("fallback_extension=");
+            fields.push_bind_unseparated((ext != &self.name).then_some(ext));
+        }
+        if let Some(visit) = &self.commissioning_visit {
+            fields.push("commissioning_visit=");
+            fields.push_bind_unseparated(visit.to_string());
+        }
+        if let Some(codes) = &self.commissioning_codes {
+            fields.push("commissioning_codes=");
+            fields.push_bind_unseparated(codes.join(","));
+        }
+        if let Some(lowercase) = self.detector_lowercase {
+            fields.push("detector_lowercase=");
+            fields.push_bind_unseparated(lowercase);
+        }
+        if let Some(collapse) = self.detector_collapse {
+            fields.push("detector_collapse=");
+            fields.push_bind_unseparated(collapse);
+        }
+        if let Some(replacement) = self.detector_replacement {
+            fields.push("detector_replacement=");
+            fields.push_bind_unseparated(replacement.to_string());
+        }
+        if let Some(reject) = self.detector_reject_invalid {
+            fields.push("detector_reject_invalid=");
+            fields.push_bind_unseparated(reject);
+        }
+        if let Some(floor) = self.scan_number_floor {
+            fields.push("scan_number_floor=");
+            fields.push_bind_unseparated(floor);
+        }
+        if let Some(step) = self.scan_number_step {
+            fields.push("scan_number_step=");
+            fields.push_bind_unseparated(step);
+        }
+        if let Some(instrument) = &self.instrument {
+            fields.push("instrument=");
+            fields.push_bind_unseparated(instrument);
         }
         q.push(" WHERE name = ");
         q.push_bind(&self.name);
@@ -173,8 +775,84 @@ impl BeamlineConfigurationUpdate {
             query = q.sql(),
             "Updating beamline configuration",
         );
-
-        q.build_query_as().fetch_optional(&db.pool).await
+        q
+    }
+    /// Find the changes that [`update_beamline`](Self::update_beamline) would make without
+    /// modifying the DB. Returns `None` if the beamline does not exist.
+    pub async fn dry_run(
+        &self,
+        db: &SqliteScanPathService,
+    ) -> Result<Option<Vec<FieldChange>>, sqlx::Error> {
+        let Some(mut current) = query_as!(
+            DbBeamlineConfig,
+            "SELECT * FROM beamline WHERE name = ?",
+            self.name
+        )
+        .fetch_optional(&db.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let old = BeamlineConfiguration::try_from(current.clone())?;
+        self.apply_to(&mut current);
+        let mut changes = changed_fields(Some(&old), &current.try_into()?);
+        if self.detector_groups.is_some() || self.visit_code_templates.is_some() {
+            let mut conn = db.pool.acquire().await?;
+            if let Some(groups) = &self.detector_groups {
+                changes.extend(detector_group_changes(&mut conn, &self.name, groups).await?);
+            }
+            if let Some(templates) = &self.visit_code_templates {
+                changes
+                    .extend(visit_code_template_changes(&mut conn, &self.name, templates).await?);
+            }
+        }
+        Ok(Some(changes))
+    }
+    /// Apply this update to an existing configuration in the same way as the `UPDATE` query
+    /// built by [`update_beamline`](Self::update_beamline)
+    fn apply_to(&self, config: &mut DbBeamlineConfig) {
+        if let Some(num) = self.scan_number {
+            config.scan_number = i64::from(num);
+        }
+        if let Some(visit) = &self.visit {
+            config.visit = visit.to_string();
+        }
+        if let Some(scan) = &self.scan {
+            config.scan = scan.to_string();
+        }
+        if let Some(detector) = &self.detector {
+            config.detector = detector.to_string();
+        }
+        if let Some(ext) = &self.extension {
+            config.fallback_extension = (ext != &self.name).then(|| ext.clone());
+        }
+        if let Some(visit) = &self.commissioning_visit {
+            config.commissioning_visit = Some(visit.to_string());
+        }
+        if let Some(codes) = &self.commissioning_codes {
+            config.commissioning_codes = Some(codes.join(","));
+        }
+        if let Some(lowercase) = self.detector_lowercase {
+            config.detector_lowercase = Some(lowercase);
+        }
+        if let Some(collapse) = self.detector_collapse {
+            config.detector_collapse = Some(collapse);
+        }
+        if let Some(replacement) = self.detector_replacement {
+            config.detector_replacement = Some(replacement.to_string());
+        }
+        if let Some(reject) = self.detector_reject_invalid {
+            config.detector_reject_invalid = Some(reject);
+        }
+        if let Some(floor) = self.scan_number_floor {
+            config.scan_number_floor = Some(i64::from(floor));
+        }
+        if let Some(step) = self.scan_number_step {
+            config.scan_number_step = Some(i64::from(step));
+        }
+        if let Some(instrument) = &self.instrument {
+            config.instrument = Some(instrument.clone());
+        }
     }
     pub async fn insert_new(
         self,
@@ -188,11 +866,32 @@ impl BeamlineConfigurationUpdate {
             scan: self.scan.ok_or("scan")?.to_string(),
             detector: self.detector.ok_or("detector")?.to_string(),
             fallback_extension: self.extension,
+            commissioning_visit: self.commissioning_visit.map(|t| t.to_string()),
+            commissioning_codes: self.commissioning_codes.map(|c| c.join(",")),
+            detector_lowercase: self.detector_lowercase,
+            detector_collapse: self.detector_collapse,
+            detector_replacement: self.detector_replacement.map(String::from),
+            detector_reject_invalid: self.detector_reject_invalid,
+            scan_number_floor: self.scan_number_floor.map(i64::from),
+            scan_number_step: self.scan_number_step.map(i64::from),
+            instrument: self.instrument,
         };
-        Ok(dbc.insert_into(db).await?)
+        let mut tx = db.pool.begin().await?;
+        let bc = dbc.insert_into(&mut tx).await?;
+        record_changes(&mut tx, None, &bc).await?;
+        if let Some(groups) = &self.detector_groups {
+            set_detector_groups(&mut tx, &bc.name, groups).await?;
+        }
+        if let Some(templates) = &self.visit_code_templates {
+            set_visit_code_templates(&mut tx, &bc.name, templates).await?;
+        }
+        let bc = bc.with_visit_code_templates(&mut tx).await?;
+        tx.commit().await?;
+        db.beamlines.invalidate();
+        Ok(bc)
     }
-    #[cfg(test)]
-    fn empty(name: impl Into<String>) -> Self {
+    /// An update for the named beamline that does not change any fields
+    pub(crate) fn empty(name: impl Into<String>) -> Self {
         Self {
             name: name.into(),
             scan_number: None,
@@ -200,11 +899,22 @@ impl BeamlineConfigurationUpdate {
             scan: None,
             detector: None,
             extension: None,
+            commissioning_visit: None,
+            commissioning_codes: None,
+            detector_lowercase: None,
+            detector_collapse: None,
+            detector_replacement: None,
+            detector_reject_invalid: None,
+            scan_number_floor: None,
+            scan_number_step: None,
+            instrument: None,
+            detector_groups: None,
+            visit_code_templates: None,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DbBeamlineConfig {
     #[allow(unused)] // unused but allows use of 'SELECT * ...' queries
     id: Option<i64>,
@@ -214,89 +924,341 @@ struct DbBeamlineConfig {
     scan: String,
     detector: String,
     fallback_extension: Option<String>,
+    commissioning_visit: Option<String>,
+    commissioning_codes: Option<String>,
+    detector_lowercase: Option<bool>,
+    detector_collapse: Option<bool>,
+    detector_replacement: Option<String>,
+    detector_reject_invalid: Option<bool>,
+    scan_number_floor: Option<i64>,
+    scan_number_step: Option<i64>,
+    instrument: Option<String>,
 }
 
 impl DbBeamlineConfig {
     pub async fn insert_into(
         self,
-        db: &SqliteScanPathService,
+        conn: &mut SqliteConnection,
     ) -> sqlx::Result<BeamlineConfiguration> {
         let bc = query_as!(
             DbBeamlineConfig,
             "INSERT INTO beamline
-                (name, scan_number, visit, scan, detector, fallback_extension)
+                (name, scan_number, visit, scan, detector, fallback_extension,
+                 commissioning_visit, commissioning_codes,
+                 detector_lowercase, detector_collapse, detector_replacement,
+                 detector_reject_invalid, scan_number_floor, scan_number_step, instrument)
             VALUES
-                (?,?,?,?,?,?)
+                (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)
             RETURNING *",
             self.name,
             self.scan_number,
             self.visit,
             self.scan,
             self.detector,
-            self.fallback_extension
+            self.fallback_extension,
+            self.commissioning_visit,
+            self.commissioning_codes,
+            self.detector_lowercase,
+            self.detector_collapse,
+            self.detector_replacement,
+            self.detector_reject_invalid,
+            self.scan_number_floor,
+            self.scan_number_step,
+            self.instrument
         )
-        .fetch_one(&db.pool)
+        .fetch_one(conn)
         .await?;
-        Ok(bc.into())
+        bc.try_into()
     }
 }
 
-impl From<DbBeamlineConfig> for BeamlineConfiguration {
-    fn from(value: DbBeamlineConfig) -> Self {
-        Self {
+/// Read a number stored as an integer in the DB. Values out of range are reported as an error
+/// decoding the column rather than being trusted.
+fn column_u32(column: &str, value: i64) -> sqlx::Result<u32> {
+    u32::try_from(value).map_err(|e| sqlx::Error::ColumnDecode {
+        index: column.into(),
+        source: Box::new(e),
+    })
+}
+
+impl TryFrom<DbBeamlineConfig> for BeamlineConfiguration {
+    type Error = sqlx::Error;
+
+    fn try_from(value: DbBeamlineConfig) -> sqlx::Result<Self> {
+        Ok(Self {
             name: value.name,
-            scan_number: u32::try_from(value.scan_number).expect("Out of scan numbers"),
+            scan_number: column_u32("scan_number", value.scan_number)?,
             visit: value.visit.into(),
             scan: value.scan.into(),
             detector: value.detector.into(),
-            extension: value.fallback_extension,
-        }
+            // An empty extension would give tracker files with no extension so treat it the
+            // same as no extension being set
+            extension: value.fallback_extension.filter(|ext| !ext.is_empty()),
+            commissioning_visit: value.commissioning_visit.map(RawPathTemplate::from),
+            commissioning_codes: value.commissioning_codes,
+            detector_lowercase: value.detector_lowercase,
+            detector_collapse: value.detector_collapse,
+            detector_replacement: value.detector_replacement,
+            detector_reject_invalid: value.detector_reject_invalid,
+            scan_number_floor: value
+                .scan_number_floor
+                .map(|n| column_u32("scan_number_floor", n))
+                .transpose()?,
+            scan_number_step: value
+                .scan_number_step
+                .map(|n| column_u32("scan_number_step", n))
+                .transpose()?,
+            instrument: value.instrument,
+            visit_code_templates: Vec::new(),
+        })
     }
 }
 
+/// Split a comma separated list of proposal codes
+fn split_codes(codes: &str) -> impl Iterator<Item = &str> {
+    codes.split(',').map(str::trim).filter(|c| !c.is_empty())
+}
+
 impl SqliteScanPathService {
     #[instrument]
-    pub async fn connect(filename: &Path) -> Result<Self, sqlx::Error> {
+    pub async fn connect(filename: &Path, pool: &PoolOptions) -> Result<Self, sqlx::Error> {
         info!("Connecting to SQLite DB");
         let opts = SqliteConnectOptions::new()
             .create_if_missing(true)
             .filename(filename);
-        let pool = SqlitePool::connect_with(opts).await?;
+        let pool = SqlitePoolOptions::new()
+            .min_connections(pool.min_connections)
+            .max_connections(pool.max_connections)
+            .connect_with(opts)
+            .await?;
+        info!(
+            min_connections = pool.options().get_min_connections(),
+            max_connections = pool.options().get_max_connections(),
+            "DB connection pool configured"
+        );
         sqlx::migrate!().run(&pool).await?;
-        Ok(Self { pool })
+        Ok(Self::new(pool))
+    }
+
+    /// Connect to a DB that must already exist and be up to date, for commands that should not
+    /// change the DB's schema. Unlike [`connect`](Self::connect), the DB is never created or
+    /// migrated.
+    #[instrument]
+    pub async fn open_existing(filename: &Path, read_only: bool) -> Result<Self, OpenError> {
+        info!("Opening existing SQLite DB");
+        let opts = SqliteConnectOptions::new()
+            .filename(filename)
+            .read_only(read_only);
+        let pool = SqlitePoolOptions::new()
+            .min_connections(0)
+            .max_connections(1)
+            .connect_with(opts)
+            .await?;
+        match schema_status(&mut *pool.acquire().await?).await? {
+            SchemaStatus::UpToDate => Ok(Self::new(pool)),
+            status => Err(OpenError::Schema(status)),
+        }
+    }
+
+    fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            beamlines: Default::default(),
+        }
+    }
+
+    /// Close every connection in the pool, waiting for any in use to be returned first. The
+    /// pool is shared between clones of this service so any other clones can no longer be used
+    /// afterwards - their queries fail with [`sqlx::Error::PoolClosed`].
+    pub async fn close(self) {
+        info!("Closing DB connection pool");
+        self.pool.close().await;
+    }
+
+    /// Check that the DB can be queried
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
     }
 
     pub async fn current_configuration(
         &self,
         beamline: &str,
     ) -> Result<BeamlineConfiguration, ConfigurationError> {
-        query_as!(
+        let mut conn = self.pool.acquire().await?;
+        let bc: BeamlineConfiguration = query_as!(
             DbBeamlineConfig,
             "SELECT * FROM beamline WHERE name = ?",
             beamline
         )
-        .fetch_optional(&self.pool)
+        .fetch_optional(&mut *conn)
         .await?
-        .map(BeamlineConfiguration::from)
-        .ok_or(ConfigurationError::MissingBeamline(beamline.into()))
+        .ok_or(ConfigurationError::MissingBeamline(beamline.into()))?
+        .try_into()?;
+        Ok(bc.with_visit_code_templates(&mut conn).await?)
     }
 
+    /// Allocate the next scan number for a beamline. The new number is the beamline's step (one
+    /// unless configured otherwise) more than the highest of the current number in the DB, the
+    /// given number (eg from a tracker directory) and the beamline's configured floor. The
+    /// allocation is recorded along with the visit it was for.
     pub async fn next_scan_configuration(
         &self,
         beamline: &str,
+        visit: &str,
         current_high: Option<u32>,
-    ) -> Result<BeamlineConfiguration, ConfigurationError> {
+    ) -> Result<BeamlineConfiguration, NextScanError> {
+        self.reserve_next_scan(beamline, visit, current_high)
+            .await?
+            .commit()
+            .await
+    }
+
+    /// Allocate the next scan number as [`next_scan_configuration`] but without committing it
+    ///
+    /// The beamline is locked against other allocations until the returned scan is committed
+    /// or dropped so it should not be held any longer than needed.
+    ///
+    /// [`next_scan_configuration`]: Self::next_scan_configuration
+    pub async fn reserve_next_scan(
+        &self,
+        beamline: &str,
+        visit: &str,
+        current_high: Option<u32>,
+    ) -> Result<PendingScan, NextScanError> {
         let exp = current_high.unwrap_or(0);
-        query_as!(
+        let mut tx = self.pool.begin().await?;
+        let next = query_as!(
             DbBeamlineConfig,
-            "UPDATE beamline SET scan_number = max(scan_number, ?) + 1 WHERE name = ? RETURNING *",
+            "UPDATE beamline
+            SET scan_number =
+                max(scan_number, ?, coalesce(scan_number_floor, 0)) + coalesce(scan_number_step, 1)
+            WHERE name = ?
+            RETURNING *",
             exp,
             beamline
         )
-        .fetch_optional(&self.pool)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| NextScanError::MissingBeamline(beamline.into()))?;
+        if u32::try_from(next.scan_number).is_err() {
+            // Dropping the transaction rolls back the update
+            return Err(NextScanError::Overflow(beamline.into()));
+        }
+        query!(
+            "INSERT INTO scan_allocation (beamline, scan_number, visit) VALUES (?, ?, ?)",
+            beamline,
+            next.scan_number,
+            visit
+        )
+        .execute(&mut *tx)
+        .await?;
+        let configuration = BeamlineConfiguration::try_from(next)?
+            .with_visit_code_templates(&mut tx)
+            .await?;
+        Ok(PendingScan { tx, configuration })
+    }
+
+    /// The names of all configured beamlines. The names are cached so may not include beamlines
+    /// added by other processes in the last minute.
+    pub async fn beamlines(&self) -> Result<Arc<BTreeSet<String>>, sqlx::Error> {
+        if let Some(names) = self.beamlines.get() {
+            return Ok(names);
+        }
+        trace!("Reading beamline names from DB");
+        let names = Arc::new(
+            query_scalar!("SELECT name FROM beamline")
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+        );
+        self.beamlines.set(names.clone());
+        Ok(names)
+    }
+
+    /// The most recent changes made to a beamline's configuration, newest first
+    pub async fn configuration_changes(
+        &self,
+        beamline: &str,
+        limit: u32,
+    ) -> Result<Vec<ConfigChange>, sqlx::Error> {
+        query_as!(
+            ConfigChange,
+            "SELECT field, old_value, new_value, changed_at
+            FROM config_change
+            WHERE beamline = ?
+            ORDER BY id DESC
+            LIMIT ?",
+            beamline,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// The alternative detector templates configured for a beamline, ordered by name
+    pub async fn detector_groups(&self, beamline: &str) -> sqlx::Result<Vec<DetectorGroup>> {
+        Ok(query!(
+            "SELECT name, template FROM detector_group WHERE beamline = ? ORDER BY name",
+            beamline
+        )
+        .fetch_all(&self.pool)
         .await?
-        .map(BeamlineConfiguration::from)
-        .ok_or(ConfigurationError::MissingBeamline(beamline.into()))
+        .into_iter()
+        .map(|row| DetectorGroup {
+            name: row.name,
+            template: row.template.into(),
+        })
+        .collect())
+    }
+
+    /// The most recent scan numbers allocated for a beamline, newest first. If `after` is given,
+    /// only allocations made after the one with that ID are included.
+    pub async fn scan_allocations(
+        &self,
+        beamline: &str,
+        after: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<ScanAllocation>, sqlx::Error> {
+        let after = after.unwrap_or(0);
+        query_as!(
+            ScanAllocation,
+            "SELECT id, beamline, scan_number, visit, allocated_at
+            FROM scan_allocation
+            WHERE beamline = ? AND id > ?
+            ORDER BY id DESC
+            LIMIT ?",
+            beamline,
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Every scan number allocated that matches the filter, oldest first. Allocations are
+    /// streamed from the DB as they are read so the whole table is never held in memory.
+    pub fn export_scan_allocations<'a>(
+        &'a self,
+        filter: &'a AllocationFilter,
+    ) -> BoxStream<'a, Result<ScanAllocation, sqlx::Error>> {
+        query_as!(
+            ScanAllocation,
+            "SELECT id, beamline, scan_number, visit, allocated_at
+            FROM scan_allocation
+            WHERE (? IS NULL OR beamline = ?)
+                AND (? IS NULL OR date(allocated_at) >= ?)
+                AND (? IS NULL OR date(allocated_at) <= ?)
+            ORDER BY id",
+            filter.beamline,
+            filter.beamline,
+            filter.from,
+            filter.from,
+            filter.until,
+            filter.until
+        )
+        .fetch(&self.pool)
     }
 
     #[cfg(test)]
@@ -308,36 +1270,134 @@ impl SqliteScanPathService {
     }
 
     #[cfg(test)]
-    async fn memory() -> Self {
+    pub(crate) async fn memory() -> Self {
         let pool = SqlitePool::connect(":memory:").await.unwrap();
         sqlx::migrate!().run(&pool).await.unwrap();
-        Self { pool }
+        Self::new(pool)
     }
 }
 
-impl fmt::Debug for SqliteScanPathService {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // This is a bit misleading when the 'db' field doesn't exist but is the most useful
-        // information when debugging the state of the service
-        f.debug_struct("SqliteScanPathService")
-            .field("db", &self.pool.connect_options().get_filename())
-            .finish()
-    }
+/// How the migrations applied to a DB compare to the migrations built into this binary
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaStatus {
+    UpToDate,
+    /// The (version, description) of each migration that has not been applied
+    Behind(Vec<(i64, String)>),
+    /// The versions of applied migrations that are not known to this binary
+    Ahead(Vec<i64>),
 }
 
-mod error {
-    use std::error::Error;
-    use std::fmt::{self, Display};
-
-    #[derive(Debug)]
-    pub enum ConfigurationError {
-        MissingBeamline(String),
-        Db(sqlx::Error),
+impl SchemaStatus {
+    /// The exit code to use when reporting this status from the CLI
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            SchemaStatus::UpToDate => 0,
+            SchemaStatus::Behind(_) => 2,
+            SchemaStatus::Ahead(_) => 3,
+        }
     }
+}
 
-    impl Display for ConfigurationError {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match self {
+impl fmt::Display for SchemaStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaStatus::UpToDate => f.write_str("DB schema is up to date"),
+            SchemaStatus::Behind(pending) => {
+                f.write_str("DB schema is behind. Pending migrations:")?;
+                for (version, description) in pending {
+                    write!(f, "\n    {version}: {description}")?;
+                }
+                Ok(())
+            }
+            SchemaStatus::Ahead(unknown) => {
+                f.write_str("DB schema is ahead. Unknown migrations:")?;
+                for version in unknown {
+                    write!(f, "\n    {version}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compare the migrations applied to the DB at the given path with those built into this binary
+/// without applying any of them.
+#[instrument]
+pub async fn check_schema(filename: &Path) -> Result<SchemaStatus, sqlx::Error> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(filename)
+        .read_only(true)
+        .connect()
+        .await?;
+    schema_status(&mut conn).await
+}
+
+/// Apply any pending migrations to the DB at the given path, creating it if it does not exist
+pub async fn apply_migrations(filename: &Path) -> Result<SchemaStatus, sqlx::Error> {
+    let pool = PoolOptions {
+        min_connections: 0,
+        max_connections: 1,
+    };
+    let db = SqliteScanPathService::connect(filename, &pool).await?;
+    schema_status(&mut *db.pool.acquire().await?).await
+}
+
+async fn schema_status(conn: &mut SqliteConnection) -> Result<SchemaStatus, sqlx::Error> {
+    let tracked = query_scalar::<_, bool>(
+        "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let applied = if tracked {
+        conn.list_applied_migrations().await?
+    } else {
+        vec![]
+    };
+    let migrator = sqlx::migrate!();
+    let unknown = applied
+        .iter()
+        .map(|m| m.version)
+        .filter(|v| !migrator.version_exists(*v))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Ok(SchemaStatus::Ahead(unknown));
+    }
+    let pending = migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .map(|m| (m.version, m.description.to_string()))
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        Ok(SchemaStatus::UpToDate)
+    } else {
+        Ok(SchemaStatus::Behind(pending))
+    }
+}
+
+impl fmt::Debug for SqliteScanPathService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // This is a bit misleading when the 'db' field doesn't exist but is the most useful
+        // information when debugging the state of the service
+        f.debug_struct("SqliteScanPathService")
+            .field("db", &self.pool.connect_options().get_filename())
+            .finish()
+    }
+}
+
+mod error {
+    use std::error::Error;
+    use std::fmt::{self, Display};
+
+    #[derive(Debug)]
+    pub enum ConfigurationError {
+        MissingBeamline(String),
+        Db(sqlx::Error),
+    }
+
+    impl Display for ConfigurationError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
                 ConfigurationError::MissingBeamline(bl) => {
                     write!(f, "No configuration available for beamline {bl:?}")
                 }
@@ -361,6 +1421,40 @@ mod error {
         }
     }
 
+    /// Error returned when an existing DB could not be opened
+    #[derive(Debug)]
+    pub enum OpenError {
+        Db(sqlx::Error),
+        /// The DB's schema does not match the migrations built into this binary
+        Schema(super::SchemaStatus),
+    }
+
+    impl Display for OpenError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                OpenError::Db(e) => write!(f, "{e}"),
+                OpenError::Schema(status) => {
+                    write!(f, "{status}\nRun 'numtracker migrate' to update the DB")
+                }
+            }
+        }
+    }
+
+    impl Error for OpenError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                OpenError::Db(e) => Some(e),
+                OpenError::Schema(_) => None,
+            }
+        }
+    }
+
+    impl From<sqlx::Error> for OpenError {
+        fn from(value: sqlx::Error) -> Self {
+            Self::Db(value)
+        }
+    }
+
     #[derive(Debug)]
     pub enum NewConfigurationError {
         MissingField(String),
@@ -376,6 +1470,113 @@ mod error {
             }
         }
     }
+    impl Error for NewConfigurationError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                NewConfigurationError::MissingField(_) => None,
+                NewConfigurationError::Db(e) => Some(e),
+            }
+        }
+    }
+    /// Error returned when an existing configuration could not be updated
+    #[derive(Debug)]
+    pub enum UpdateConfigurationError {
+        /// The update did not set any fields
+        NothingToUpdate(String),
+        Db(sqlx::Error),
+    }
+    impl Display for UpdateConfigurationError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                UpdateConfigurationError::NothingToUpdate(bl) => {
+                    write!(f, "No fields given to update for beamline {bl:?}")
+                }
+                UpdateConfigurationError::Db(e) => write!(f, "Error updating configuration: {e}"),
+            }
+        }
+    }
+    impl Error for UpdateConfigurationError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                UpdateConfigurationError::NothingToUpdate(_) => None,
+                UpdateConfigurationError::Db(e) => Some(e),
+            }
+        }
+    }
+    impl From<sqlx::Error> for UpdateConfigurationError {
+        fn from(value: sqlx::Error) -> Self {
+            Self::Db(value)
+        }
+    }
+    /// Error returned when a new scan number could not be allocated
+    #[derive(Debug)]
+    pub enum NextScanError {
+        MissingBeamline(String),
+        /// The beamline has run out of scan numbers
+        Overflow(String),
+        /// The DB was locked by another connection. Retrying the request may succeed.
+        Busy(sqlx::Error),
+        Db(sqlx::Error),
+        /// The new number could not be written to a tracker directory that is required
+        Tracker(std::io::Error),
+    }
+
+    impl NextScanError {
+        /// Whether the same request may succeed if it is retried
+        pub fn is_retryable(&self) -> bool {
+            matches!(self, NextScanError::Busy(_))
+        }
+    }
+
+    impl Display for NextScanError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                NextScanError::MissingBeamline(bl) => {
+                    write!(f, "No configuration available for beamline {bl:?}")
+                }
+                NextScanError::Overflow(bl) => {
+                    write!(f, "No scan numbers remaining for beamline {bl:?}")
+                }
+                NextScanError::Busy(e) => write!(f, "Database is busy: {e}"),
+                NextScanError::Db(e) => write!(f, "Error allocating scan number: {e}"),
+                NextScanError::Tracker(e) => {
+                    write!(f, "Could not update tracker directory: {e}")
+                }
+            }
+        }
+    }
+
+    impl Error for NextScanError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                NextScanError::MissingBeamline(_) | NextScanError::Overflow(_) => None,
+                NextScanError::Busy(e) | NextScanError::Db(e) => Some(e),
+                NextScanError::Tracker(e) => Some(e),
+            }
+        }
+    }
+
+    impl From<sqlx::Error> for NextScanError {
+        fn from(value: sqlx::Error) -> Self {
+            // SQLite reports extended result codes - the primary code is the lowest byte
+            const SQLITE_BUSY: i32 = 5;
+            const SQLITE_LOCKED: i32 = 6;
+            let busy = match &value {
+                sqlx::Error::PoolTimedOut => true,
+                sqlx::Error::Database(e) => e
+                    .code()
+                    .and_then(|code| code.parse::<i32>().ok())
+                    .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)),
+                _ => false,
+            };
+            if busy {
+                Self::Busy(value)
+            } else {
+                Self::Db(value)
+            }
+        }
+    }
+
     impl From<&str> for NewConfigurationError {
         fn from(value: &str) -> Self {
             Self::MissingField(value.into())
@@ -390,15 +1591,27 @@ mod error {
 
 #[cfg(test)]
 mod db_tests {
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
     use rstest::{fixture, rstest};
     use sqlx::error::{DatabaseError as _, ErrorKind};
-    use sqlx::sqlite::SqliteError;
+    use sqlx::sqlite::{SqliteConnectOptions, SqliteError};
+    use sqlx::{query, ConnectOptions as _, SqlitePool};
     use tokio::test;
 
-    use super::SqliteScanPathService;
-    use crate::db_service::error::{ConfigurationError, NewConfigurationError};
-    use crate::db_service::{BeamlineConfiguration, BeamlineConfigurationUpdate};
-    use crate::paths::{DetectorTemplate, PathSpec, ScanTemplate, VisitTemplate};
+    use super::{
+        apply_migrations, check_schema, schema_status, SchemaStatus, SqliteScanPathService,
+    };
+    use crate::db_service::error::{
+        ConfigurationError, NewConfigurationError, NextScanError, OpenError,
+        UpdateConfigurationError,
+    };
+    use crate::db_service::{BeamlineConfiguration, BeamlineConfigurationUpdate, FieldChange};
+    use crate::paths::{
+        DetectorNormalisation, DetectorTemplate, PathSpec, ScanTemplate, VisitTemplate,
+    };
 
     /// Remove repeated .await.unwrap() noise from tests
     macro_rules! ok {
@@ -431,7 +1644,6 @@ mod db_tests {
     #[fixture]
     fn update() -> BeamlineConfigurationUpdate {
         BeamlineConfigurationUpdate {
-            name: "i22".into(),
             scan_number: Some(122),
             visit: VisitTemplate::new_checked("/tmp/{instrument}/data/{year}/{visit}").ok(),
             scan: ScanTemplate::new_checked("{subdirectory}/{instrument}-{scan_number}").ok(),
@@ -440,9 +1652,102 @@ mod db_tests {
             )
             .ok(),
             extension: Some("ext".into()),
+            ..BeamlineConfigurationUpdate::empty("i22")
         }
     }
 
+    #[test]
+    async fn migrated_db_is_up_to_date() {
+        let db = SqliteScanPathService::memory().await;
+        let mut conn = db.pool.acquire().await.unwrap();
+        let status = ok!(schema_status(&mut conn));
+        assert_eq!(status, SchemaStatus::UpToDate);
+        assert_eq!(status.exit_code(), 0);
+    }
+
+    #[rstest]
+    #[test]
+    async fn closed_pool(#[future(awt)] db: SqliteScanPathService) {
+        let other = db.clone();
+        db.close().await;
+        let err = other.current_configuration("i22").await.unwrap_err();
+        assert_matches!(err, ConfigurationError::Db(sqlx::Error::PoolClosed));
+        assert_eq!(
+            err.to_string(),
+            "Error reading configuration: attempted to acquire a connection on a closed pool"
+        );
+        assert_matches!(other.ping().await, Err(sqlx::Error::PoolClosed));
+    }
+
+    #[test]
+    async fn new_db_is_behind() {
+        let mut conn = ok!(SqlitePool::connect(":memory:"))
+            .acquire()
+            .await
+            .unwrap();
+        let status = ok!(schema_status(&mut conn));
+        let SchemaStatus::Behind(pending) = &status else {
+            panic!("Unexpected schema status: {status:?}");
+        };
+        assert_eq!(pending[0], (1, "init".into()));
+        assert_eq!(pending.len(), sqlx::migrate!().iter().count() / 2);
+        assert_eq!(status.exit_code(), 2);
+    }
+
+    #[test]
+    async fn unknown_migration_is_ahead() {
+        let db = SqliteScanPathService::memory().await;
+        let mut conn = db.pool.acquire().await.unwrap();
+        query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (9999, 'future', TRUE, x'00', 0)",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let status = ok!(schema_status(&mut conn));
+        assert_eq!(status, SchemaStatus::Ahead(vec![9999]));
+        assert_eq!(status.exit_code(), 3);
+    }
+
+    #[test]
+    async fn check_schema_does_not_modify_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("numtracker.db");
+        std::fs::File::create(&path).unwrap();
+        let status = ok!(check_schema(&path));
+        assert_matches::assert_matches!(status, SchemaStatus::Behind(_));
+        // Checking again gives the same result as nothing was applied
+        assert_eq!(ok!(check_schema(&path)), status);
+        assert_eq!(ok!(apply_migrations(&path)), SchemaStatus::UpToDate);
+        assert_eq!(ok!(check_schema(&path)), SchemaStatus::UpToDate);
+    }
+
+    #[test]
+    async fn open_existing_does_not_create_or_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("numtracker.db");
+        let e = SqliteScanPathService::open_existing(&path, true)
+            .await
+            .unwrap_err();
+        assert_matches!(e, OpenError::Db(_));
+        assert!(!path.exists());
+
+        std::fs::File::create(&path).unwrap();
+        let e = SqliteScanPathService::open_existing(&path, false)
+            .await
+            .unwrap_err();
+        assert_matches!(e, OpenError::Schema(SchemaStatus::Behind(_)));
+        assert_matches!(ok!(check_schema(&path)), SchemaStatus::Behind(_));
+
+        ok!(apply_migrations(&path));
+        let db = ok!(SqliteScanPathService::open_existing(&path, true));
+        assert_matches!(
+            db.current_configuration("i22").await,
+            Err(ConfigurationError::MissingBeamline(_))
+        );
+    }
+
     #[test]
     async fn empty_db_has_no_config() {
         let db = SqliteScanPathService::memory().await;
@@ -513,17 +1818,76 @@ mod db_tests {
     #[rstest]
     #[test]
     async fn incrementing_scan_numbers(#[future(awt)] db: SqliteScanPathService) {
-        let s1 = ok!(db.next_scan_configuration("i22", None));
-        let s2 = ok!(db.next_scan_configuration("i22", None));
+        let s1 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
+        let s2 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
         assert_eq!(s1.scan_number() + 1, s2.scan_number());
     }
 
+    #[rstest]
+    #[case::above_current(Some(1000), None, 1001)]
+    #[case::below_current(Some(100), None, 123)]
+    #[case::below_directory(Some(1000), Some(2000), 2001)]
+    #[case::unset(None, None, 123)]
+    #[tokio::test]
+    async fn scan_number_floor(
+        #[future(awt)] db: SqliteScanPathService,
+        #[case] floor: Option<u32>,
+        #[case] directory: Option<u32>,
+        #[case] expected: u32,
+    ) {
+        if floor.is_some() {
+            ok!(BeamlineConfigurationUpdate {
+                scan_number_floor: floor,
+                ..BeamlineConfigurationUpdate::empty("i22")
+            }
+            .update_beamline(&db));
+        }
+        let next = ok!(db.next_scan_configuration("i22", "cm12345-3", directory));
+        assert_eq!(next.scan_number(), expected);
+        assert_eq!(next.scan_number_floor(), floor);
+        // Once above the floor, numbers continue from the current value
+        let next = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
+        assert_eq!(next.scan_number(), expected + 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_number_step(#[future(awt)] db: SqliteScanPathService) {
+        ok!(BeamlineConfigurationUpdate {
+            scan_number_step: Some(5),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .update_beamline(&db));
+        let s1 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
+        let s2 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
+        assert_eq!(s1.scan_number(), 127);
+        assert_eq!(s2.scan_number(), 132);
+        assert_eq!(s2.scan_number_step(), 5);
+        // The step is added to the highest of the DB, directory and floor numbers
+        let s3 = ok!(db.next_scan_configuration("i22", "cm12345-3", Some(1000)));
+        assert_eq!(s3.scan_number(), 1005);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn zero_scan_number_step(#[future(awt)] db: SqliteScanPathService) {
+        let err = BeamlineConfigurationUpdate {
+            scan_number_step: Some(0),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .update_beamline(&db)
+        .await;
+        assert!(err.is_err());
+        let conf = ok!(db.current_configuration("i22"));
+        assert_eq!(conf.scan_number_step(), 1);
+    }
+
     #[rstest]
     #[test]
     async fn overriding_scan_number_updates_db(#[future(awt)] db: SqliteScanPathService) {
-        let s1 = ok!(db.next_scan_configuration("i22", None));
-        let s2 = ok!(db.next_scan_configuration("i22", Some(1234)));
-        let s3 = ok!(db.next_scan_configuration("i22", None));
+        let s1 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
+        let s2 = ok!(db.next_scan_configuration("i22", "cm12345-3", Some(1234)));
+        let s3 = ok!(db.next_scan_configuration("i22", "cm12345-3", None));
         assert_eq!(s1.scan_number(), 123);
         assert_eq!(s2.scan_number(), 1235);
         assert_eq!(s3.scan_number(), 1236);
@@ -532,7 +1896,7 @@ mod db_tests {
     #[rstest]
     #[test]
     async fn lower_scan_override_is_ignored(#[future(awt)] db: SqliteScanPathService) {
-        let s1 = ok!(db.next_scan_configuration("i22", Some(42)));
+        let s1 = ok!(db.next_scan_configuration("i22", "cm12345-3", Some(42)));
         assert_eq!(s1.scan_number(), 123);
     }
 
@@ -540,12 +1904,162 @@ mod db_tests {
     #[test]
     async fn incrementing_missing_beamline(#[future(awt)] db: SqliteScanPathService) {
         let e = err!(
-            ConfigurationError::MissingBeamline,
-            db.next_scan_configuration("b21", None)
+            NextScanError::MissingBeamline,
+            db.next_scan_configuration("b21", "cm12345-3", None)
         );
         assert_eq!(e, "b21")
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn extension_reset_to_beamline_name(#[future(awt)] db: SqliteScanPathService) {
+        let mut upd = Update::empty("i22");
+        upd.extension = Some("ext".into());
+        let bc = ok!(upd.update_beamline(&db)).expect("Updated beamline missing");
+        assert_eq!(bc.extension(), Some("ext"));
+
+        // An update of only the extension is not empty even if it is the default
+        upd.extension = Some("i22".into());
+        let bc = ok!(upd.update_beamline(&db)).expect("Updated beamline missing");
+        assert_eq!(bc.extension(), None);
+        assert_eq!(bc.tracker_extension(), "i22");
+    }
+
+    #[rstest]
+    #[case::null(None, None, "i22")]
+    #[case::empty(Some(""), None, "i22")]
+    #[case::valid(Some("ext"), Some("ext"), "ext")]
+    #[tokio::test]
+    async fn stored_extension(
+        #[future(awt)] db: SqliteScanPathService,
+        #[case] stored: Option<&str>,
+        #[case] extension: Option<&str>,
+        #[case] tracker: &str,
+    ) {
+        ok!(
+            query("UPDATE beamline SET fallback_extension = ? WHERE name = 'i22'")
+                .bind(stored)
+                .execute(&db.pool)
+        );
+        let conf = ok!(db.current_configuration("i22"));
+        assert_eq!(conf.extension(), extension);
+        assert_eq!(conf.tracker_extension(), tracker);
+    }
+
+    #[rstest]
+    #[test]
+    async fn incrementing_past_max_scan_number(#[future(awt)] db: SqliteScanPathService) {
+        ok!(
+            query("UPDATE beamline SET scan_number = ? WHERE name = 'i22'")
+                .bind(i64::from(u32::MAX))
+                .execute(&db.pool)
+        );
+        let e = err!(
+            NextScanError::Overflow,
+            db.next_scan_configuration("i22", "cm12345-3", None)
+        );
+        assert_eq!(e, "i22");
+        // The failed allocation should not have changed the stored number
+        assert_eq!(ok!(db.current_configuration("i22")).scan_number(), u32::MAX);
+    }
+
+    #[rstest]
+    #[test]
+    async fn allocations_are_recorded(#[future(awt)] db: SqliteScanPathService) {
+        ok!(db.next_scan_configuration("i22", "cm12345-1", None));
+        ok!(db.next_scan_configuration("i22", "cm12345-2", Some(200)));
+        let allocations = ok!(db.scan_allocations("i22", None, 10));
+        let summary = allocations
+            .iter()
+            .map(|a| (a.scan_number, a.visit.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, [(201, "cm12345-2"), (123, "cm12345-1")]);
+        assert!(allocations[0].id > allocations[1].id);
+        assert!(ok!(db.scan_allocations("b21", None, 10)).is_empty());
+    }
+
+    #[rstest]
+    #[test]
+    async fn allocations_after_id(#[future(awt)] db: SqliteScanPathService) {
+        for _ in 0..3 {
+            ok!(db.next_scan_configuration("i22", "cm12345-1", None));
+        }
+        let latest = ok!(db.scan_allocations("i22", None, 2));
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].scan_number, 125);
+
+        let newer = ok!(db.scan_allocations("i22", Some(latest[1].id), 10));
+        assert_eq!(newer.len(), 1);
+        assert_eq!(newer[0].scan_number, 125);
+        assert!(ok!(db.scan_allocations("i22", Some(latest[0].id), 10)).is_empty());
+    }
+
+    #[rstest]
+    #[test]
+    async fn tracker_number_past_max(#[future(awt)] db: SqliteScanPathService) {
+        err!(
+            NextScanError::Overflow,
+            db.next_scan_configuration("i22", "cm12345-3", Some(u32::MAX))
+        );
+        assert_eq!(ok!(db.current_configuration("i22")).scan_number(), 122);
+        assert!(ok!(db.scan_allocations("i22", None, 10)).is_empty());
+    }
+
+    #[test]
+    async fn next_scan_error_kinds() {
+        let busy = NextScanError::from(sqlx::Error::PoolTimedOut);
+        assert!(matches!(busy, NextScanError::Busy(_)));
+        assert!(busy.is_retryable());
+        let db = NextScanError::from(sqlx::Error::RowNotFound);
+        assert!(matches!(db, NextScanError::Db(_)));
+        assert!(!db.is_retryable());
+        assert!(!NextScanError::Overflow("i22".into()).is_retryable());
+    }
+
+    #[test]
+    async fn locked_db_is_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = SqliteConnectOptions::new()
+            .filename(dir.path().join("numtracker.db"))
+            .create_if_missing(true)
+            // Fail immediately instead of waiting for the lock to be released
+            .busy_timeout(Duration::ZERO);
+        let pool = SqlitePool::connect_with(opts.clone()).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let db = SqliteScanPathService::new(pool);
+        ok!(update().insert_new(&db));
+
+        let mut lock = opts.connect().await.unwrap();
+        ok!(query("BEGIN EXCLUSIVE").execute(&mut lock));
+        let e = db
+            .next_scan_configuration("i22", "cm12345-3", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, NextScanError::Busy(_)), "{e:?}");
+        assert!(e.is_retryable());
+    }
+
+    #[rstest]
+    #[case::floor("scan_number_floor")]
+    #[case::step("scan_number_step")]
+    #[tokio::test]
+    async fn out_of_range_numbers(#[future(awt)] db: SqliteScanPathService, #[case] column: &str) {
+        ok!(query(&format!(
+            "UPDATE beamline SET {column} = 5000000000 WHERE name = 'i22'"
+        ))
+        .execute(&db.pool));
+        let e = db.current_configuration("i22").await.unwrap_err();
+        assert_matches!(
+            e,
+            ConfigurationError::Db(sqlx::Error::ColumnDecode { index, .. }) if index == column
+        );
+        let e = db
+            .next_scan_configuration("i22", "cm12345-3", None)
+            .await
+            .unwrap_err();
+        assert_matches!(e, NextScanError::Overflow(_));
+    }
+
     #[rstest]
     #[test]
     async fn current_configuration(#[future(awt)] db: SqliteScanPathService) {
@@ -568,6 +2082,57 @@ mod db_tests {
             panic!("Missing extension");
         };
         assert_eq!(ext, "ext");
+        assert_eq!(conf.tracker_extension(), "ext");
+        assert!(conf.commissioning_visit().is_none());
+        assert_eq!(conf.commissioning_codes().collect::<Vec<_>>(), ["cm"]);
+        assert_eq!(
+            conf.detector_normalisation(),
+            DetectorNormalisation::default()
+        );
+    }
+
+    #[rstest]
+    #[case::commissioning(Some("cm"), "/commissioning/{instrument}/{visit}")]
+    #[case::other_code(Some("mx"), "/tmp/{instrument}/data/{year}/{visit}")]
+    #[case::unknown_code(None, "/tmp/{instrument}/data/{year}/{visit}")]
+    #[tokio::test]
+    async fn commissioning_visit_template(
+        #[future(awt)] db: SqliteScanPathService,
+        #[case] code: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let mut upd = Update::empty("i22");
+        upd.commissioning_visit =
+            VisitTemplate::new_checked("/commissioning/{instrument}/{visit}").ok();
+        let bc = ok!(upd.update_beamline(&db)).expect("Updated beamline missing");
+        assert_eq!(bc.visit_for(code).unwrap().to_string(), expected);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn commissioning_codes(#[future(awt)] db: SqliteScanPathService) {
+        let mut upd = Update::empty("i22");
+        upd.commissioning_visit =
+            VisitTemplate::new_checked("/commissioning/{instrument}/{visit}").ok();
+        upd.commissioning_codes = Some(vec!["cm".into(), "nt".into()]);
+        let bc = ok!(upd.update_beamline(&db)).expect("Updated beamline missing");
+        assert_eq!(bc.commissioning_codes().collect::<Vec<_>>(), ["cm", "nt"]);
+        assert_eq!(
+            bc.visit_for(Some("nt")).unwrap().to_string(),
+            "/commissioning/{instrument}/{visit}"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn codes_without_commissioning_template(#[future(awt)] db: SqliteScanPathService) {
+        let mut upd = Update::empty("i22");
+        upd.commissioning_codes = Some(vec!["cm".into()]);
+        let bc = ok!(upd.update_beamline(&db)).expect("Updated beamline missing");
+        assert_eq!(
+            bc.visit_for(Some("cm")).unwrap().to_string(),
+            "/tmp/{instrument}/data/{year}/{visit}"
+        );
     }
 
     type Update = BeamlineConfigurationUpdate;
@@ -588,6 +2153,27 @@ mod db_tests {
     #[case::extension(
             |u: &mut Update| u.extension = Some("new".into()),
             |u: BeamlineConfiguration| assert_eq!(u.extension().unwrap(), "new"))]
+    #[case::detector_lowercase(
+            |u: &mut Update| u.detector_lowercase = Some(true),
+            |u: BeamlineConfiguration| assert!(u.detector_normalisation().lowercase))]
+    #[case::detector_collapse(
+            |u: &mut Update| u.detector_collapse = Some(false),
+            |u: BeamlineConfiguration| assert!(!u.detector_normalisation().collapse))]
+    #[case::scan_number_floor(
+            |u: &mut Update| u.scan_number_floor = Some(5000),
+            |u: BeamlineConfiguration| assert_eq!(u.scan_number_floor(), Some(5000)))]
+    #[case::scan_number_step(
+            |u: &mut Update| u.scan_number_step = Some(10),
+            |u: BeamlineConfiguration| assert_eq!(u.scan_number_step(), 10))]
+    #[case::detector_reject_invalid(
+            |u: &mut Update| u.detector_reject_invalid = Some(true),
+            |u: BeamlineConfiguration| assert!(u.detector_reject_invalid()))]
+    #[case::instrument(
+            |u: &mut Update| u.instrument = Some("BL22I".into()),
+            |u: BeamlineConfiguration| assert_eq!(u.instrument_name(), "BL22I"))]
+    #[case::detector_replacement(
+            |u: &mut Update| u.detector_replacement = Some('-'),
+            |u: BeamlineConfiguration| assert_eq!(u.detector_normalisation().replacement, '-'))]
     #[tokio::test]
     async fn update_existing(
         #[future(awt)] db: SqliteScanPathService,
@@ -600,10 +2186,378 @@ mod db_tests {
         check(bc)
     }
 
+    #[rstest]
+    #[case::existing("i22")]
+    #[case::missing("b21")]
+    #[tokio::test]
+    async fn empty_update(#[future(awt)] db: SqliteScanPathService, #[case] beamline: &str) {
+        let upd = BeamlineConfigurationUpdate::empty(beamline);
+        let bl = err!(
+            UpdateConfigurationError::NothingToUpdate,
+            upd.update_beamline(&db)
+        );
+        assert_eq!(bl, beamline);
+        assert_eq!(
+            UpdateConfigurationError::NothingToUpdate("i22".into()).to_string(),
+            "No fields given to update for beamline \"i22\""
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn dry_run(#[future(awt)] db: SqliteScanPathService) {
+        let upd = Update {
+            scan_number: Some(200),
+            scan: ScanTemplate::new_checked("new-{scan_number}").ok(),
+            // unchanged so not included
+            extension: Some("ext".into()),
+            detector_lowercase: Some(true),
+            ..Update::empty("i22")
+        };
+        let diff = ok!(upd.dry_run(&db)).expect("Beamline missing");
+        assert_eq!(
+            diff,
+            vec![
+                FieldChange {
+                    field: "scan_number".into(),
+                    old_value: Some("122".into()),
+                    new_value: Some("200".into()),
+                },
+                FieldChange {
+                    field: "scan".into(),
+                    old_value: Some("{subdirectory}/{instrument}-{scan_number}".into()),
+                    new_value: Some("new-{scan_number}".into()),
+                },
+                FieldChange {
+                    field: "detector_lowercase".into(),
+                    old_value: None,
+                    new_value: Some("true".into()),
+                },
+            ]
+        );
+        // Nothing was written
+        let bc = ok!(db.current_configuration("i22"));
+        assert_eq!(bc.scan_number(), 122);
+        assert_eq!(
+            bc.scan().unwrap().to_string(),
+            "{subdirectory}/{instrument}-{scan_number}"
+        );
+        assert_eq!(changes(&db, "i22").await.len(), 5);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn dry_run_matches_update(#[future(awt)] db: SqliteScanPathService) {
+        let upd = Update {
+            visit: VisitTemplate::new_checked("/new/{instrument}/{visit}").ok(),
+            commissioning_codes: Some(vec!["cm".into(), "nt".into()]),
+            scan_number_floor: Some(5000),
+            ..Update::empty("i22")
+        };
+        let diff = ok!(upd.dry_run(&db)).expect("Beamline missing");
+        ok!(upd.update_beamline(&db));
+        let recorded = changes(&db, "i22").await;
+        let mut recorded = recorded[..diff.len()].to_vec();
+        // recorded changes are newest first
+        recorded.reverse();
+        assert_eq!(
+            diff.into_iter()
+                .map(|c| (c.field, c.old_value, c.new_value))
+                .collect::<Vec<_>>(),
+            recorded
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn detector_groups(#[future(awt)] db: SqliteScanPathService) {
+        assert!(ok!(db.detector_groups("i22")).is_empty());
+        let upd = Update {
+            detector_groups: Some(vec![
+                (
+                    "scalers".into(),
+                    DetectorTemplate::new_checked("scalers/{instrument}-{scan_number}-{detector}")
+                        .unwrap(),
+                ),
+                (
+                    "area".into(),
+                    DetectorTemplate::new_checked("{detector}/{instrument}-{scan_number}").unwrap(),
+                ),
+            ]),
+            ..Update::empty("i22")
+        };
+        let diff = ok!(upd.dry_run(&db)).expect("Beamline missing");
+        assert_eq!(
+            diff,
+            vec![
+                FieldChange {
+                    field: "detector_group.scalers".into(),
+                    old_value: None,
+                    new_value: Some("scalers/{instrument}-{scan_number}-{detector}".into()),
+                },
+                FieldChange {
+                    field: "detector_group.area".into(),
+                    old_value: None,
+                    new_value: Some("{detector}/{instrument}-{scan_number}".into()),
+                },
+            ]
+        );
+        assert!(ok!(db.detector_groups("i22")).is_empty());
+
+        // Updating only groups leaves the rest of the configuration unchanged
+        let bc = ok!(upd.update_beamline(&db)).expect("Beamline missing");
+        assert_eq!(bc.scan_number(), 122);
+        let groups = ok!(db.detector_groups("i22"))
+            .into_iter()
+            .map(|g| (g.name.clone(), g.template().unwrap().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                (
+                    "area".into(),
+                    "{detector}/{instrument}-{scan_number}".into()
+                ),
+                (
+                    "scalers".into(),
+                    "scalers/{instrument}-{scan_number}-{detector}".into()
+                ),
+            ]
+        );
+        assert_eq!(
+            changes(&db, "i22").await[..2],
+            [
+                (
+                    "detector_group.area".into(),
+                    None,
+                    Some("{detector}/{instrument}-{scan_number}".into())
+                ),
+                (
+                    "detector_group.scalers".into(),
+                    None,
+                    Some("scalers/{instrument}-{scan_number}-{detector}".into())
+                ),
+            ]
+        );
+
+        // Groups are replaced individually and unchanged groups are not recorded
+        let upd = Update {
+            detector_groups: Some(vec![(
+                "area".into(),
+                DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").unwrap(),
+            )]),
+            ..Update::empty("i22")
+        };
+        ok!(upd.update_beamline(&db));
+        assert_eq!(ok!(db.detector_groups("i22")).len(), 2);
+        assert!(ok!(upd.dry_run(&db)).expect("Beamline missing").is_empty());
+        assert_eq!(
+            changes(&db, "i22").await[0],
+            (
+                "detector_group.area".into(),
+                Some("{detector}/{instrument}-{scan_number}".into()),
+                Some("{instrument}-{scan_number}-{detector}".into())
+            )
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn visit_code_templates(#[future(awt)] db: SqliteScanPathService) {
+        let code_templates = |bc: &BeamlineConfiguration| {
+            bc.visit_code_templates()
+                .iter()
+                .map(|t| (t.code.clone(), t.template().unwrap().to_string()))
+                .collect::<Vec<_>>()
+        };
+        let current = ok!(db.current_configuration("i22"));
+        assert!(current.visit_code_templates().is_empty());
+        let upd = Update {
+            visit_code_templates: Some(vec![(
+                "in".into(),
+                Some(VisitTemplate::new_checked("/industrial/{instrument}/{visit}").unwrap()),
+            )]),
+            ..Update::empty("i22")
+        };
+        let diff = ok!(upd.dry_run(&db)).expect("Beamline missing");
+        assert_eq!(
+            diff,
+            vec![FieldChange {
+                field: "visit_code_template.in".into(),
+                old_value: None,
+                new_value: Some("/industrial/{instrument}/{visit}".into()),
+            }]
+        );
+        assert!(code_templates(&ok!(db.current_configuration("i22"))).is_empty());
+
+        let bc = ok!(upd.update_beamline(&db)).expect("Beamline missing");
+        assert_eq!(bc.scan_number(), 122);
+        let expected = vec![("in".into(), "/industrial/{instrument}/{visit}".into())];
+        assert_eq!(code_templates(&bc), expected);
+        assert_eq!(
+            code_templates(&ok!(db.current_configuration("i22"))),
+            expected
+        );
+        assert_eq!(
+            changes(&db, "i22").await[0],
+            (
+                "visit_code_template.in".into(),
+                None,
+                Some("/industrial/{instrument}/{visit}".into())
+            )
+        );
+        // Only visits with the code use the template
+        assert_eq!(
+            bc.visit_for(Some("in")).unwrap().to_string(),
+            "/industrial/{instrument}/{visit}"
+        );
+        assert_eq!(
+            bc.visit_for(Some("mx")).unwrap().to_string(),
+            "/tmp/{instrument}/data/{year}/{visit}"
+        );
+        assert!(ok!(upd.dry_run(&db)).expect("Beamline missing").is_empty());
+
+        let remove = Update {
+            visit_code_templates: Some(vec![("in".into(), None), ("mx".into(), None)]),
+            ..Update::empty("i22")
+        };
+        let bc = ok!(remove.update_beamline(&db)).expect("Beamline missing");
+        assert!(code_templates(&bc).is_empty());
+        assert!(code_templates(&ok!(db.current_configuration("i22"))).is_empty());
+        assert_eq!(
+            changes(&db, "i22").await[0],
+            (
+                "visit_code_template.in".into(),
+                Some("/industrial/{instrument}/{visit}".into()),
+                None
+            )
+        );
+        assert!(ok!(remove.dry_run(&db))
+            .expect("Beamline missing")
+            .is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn dry_run_missing_beamline(#[future(awt)] db: SqliteScanPathService) {
+        let upd = Update {
+            scan_number: Some(200),
+            ..Update::empty("b21")
+        };
+        assert!(ok!(upd.dry_run(&db)).is_none());
+    }
+
+    /// Get the (field, old, new) values of the recorded changes for a beamline
+    async fn changes(
+        db: &SqliteScanPathService,
+        beamline: &str,
+    ) -> Vec<(String, Option<String>, Option<String>)> {
+        ok!(db.configuration_changes(beamline, 100))
+            .into_iter()
+            .map(|c| (c.field, c.old_value, c.new_value))
+            .collect()
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn new_beamline_records_set_fields(#[future(awt)] db: SqliteScanPathService) {
+        let set = |field: &str, value: &str| (field.to_string(), None, Some(value.to_string()));
+        assert_eq!(
+            changes(&db, "i22").await,
+            vec![
+                set("extension", "ext"),
+                set(
+                    "detector",
+                    "{subdirectory}/{instrument}-{scan_number}-{detector}"
+                ),
+                set("scan", "{subdirectory}/{instrument}-{scan_number}"),
+                set("visit", "/tmp/{instrument}/data/{year}/{visit}"),
+                set("scan_number", "122"),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn update_records_changed_fields(#[future(awt)] db: SqliteScanPathService) {
+        let upd = Update {
+            scan: ScanTemplate::new_checked("new-{scan_number}").ok(),
+            // unchanged so not recorded
+            extension: Some("ext".into()),
+            detector_replacement: Some('-'),
+            ..Update::empty("i22")
+        };
+        ok!(upd.update_beamline(&db));
+        let changes = changes(&db, "i22").await;
+        assert_eq!(
+            changes[..2],
+            [
+                ("detector_replacement".into(), None, Some("-".into())),
+                (
+                    "scan".into(),
+                    Some("{subdirectory}/{instrument}-{scan_number}".into()),
+                    Some("new-{scan_number}".into())
+                ),
+            ]
+        );
+        assert_eq!(changes.len(), 7);
+    }
+
     #[rstest]
     #[tokio::test]
-    async fn empty_update(#[future(awt)] db: SqliteScanPathService) {
-        let upd = BeamlineConfigurationUpdate::empty("b21");
+    async fn change_history_is_per_beamline(#[future(awt)] db: SqliteScanPathService) {
+        ok!(Update {
+            scan_number: Some(200),
+            ..Update::empty("i22")
+        }
+        .update_beamline(&db));
+        assert!(changes(&db, "b21").await.is_empty());
+        let latest = ok!(db.configuration_changes("i22", 1));
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].field, "scan_number");
+        assert_eq!(latest[0].old_value.as_deref(), Some("122"));
+        assert_eq!(latest[0].new_value.as_deref(), Some("200"));
+        chrono::DateTime::parse_from_rfc3339(&latest[0].changed_at).unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn failed_update_records_nothing(#[future(awt)] db: SqliteScanPathService) {
+        let upd = Update {
+            scan_number: Some(200),
+            ..Update::empty("b21")
+        };
         assert!(ok!(upd.update_beamline(&db)).is_none());
+        assert!(changes(&db, "b21").await.is_empty());
+        assert_eq!(changes(&db, "i22").await.len(), 5);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn beamline_names_include_new_beamlines(
+        #[future(awt)] db: SqliteScanPathService,
+        update: Update,
+    ) {
+        assert_eq!(*ok!(db.beamlines()), BTreeSet::from(["i22".into()]));
+        ok!(Update {
+            name: "b21".into(),
+            ..update
+        }
+        .insert_new(&db));
+        assert_eq!(
+            *ok!(db.beamlines()),
+            BTreeSet::from(["b21".into(), "i22".into()])
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn beamline_names_are_cached(#[future(awt)] db: SqliteScanPathService) {
+        assert_eq!(ok!(db.beamlines()).len(), 1);
+        ok!(query("DELETE FROM beamline").execute(&db.pool));
+        // Removed outside of the service so the cached names are still used
+        assert_eq!(ok!(db.beamlines()).len(), 1);
+        db.beamlines.invalidate();
+        assert!(ok!(db.beamlines()).is_empty());
     }
 }
@@ -0,0 +1,310 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use crate::cli::{DiffTemplateOptions, OldTemplate};
+use crate::db_service::{ConfigurationError, OpenError, SqliteScanPathService};
+use crate::paths::{InvalidPathTemplate, SampleContext, TemplateKind};
+
+#[derive(Debug)]
+pub enum DiffError {
+    Db(OpenError),
+    Configuration(ConfigurationError),
+    /// The template currently configured for the beamline is not valid
+    Configured(InvalidPathTemplate),
+    /// One of the templates could not be rendered. The first field is the template (old or new)
+    /// that failed.
+    Render(&'static str, String),
+}
+
+impl Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::Db(e) => write!(f, "Could not open DB: {e}"),
+            DiffError::Configuration(e) => write!(f, "{e}"),
+            DiffError::Configured(e) => write!(f, "Configured template is invalid: {e}"),
+            DiffError::Render(which, e) => write!(f, "Could not render {which} template: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiffError::Db(e) => Some(e),
+            DiffError::Configuration(e) => Some(e),
+            DiffError::Configured(e) => Some(e),
+            DiffError::Render(..) => None,
+        }
+    }
+}
+
+impl From<ConfigurationError> for DiffError {
+    fn from(value: ConfigurationError) -> Self {
+        Self::Configuration(value)
+    }
+}
+
+/// A single path component in the difference between two rendered paths
+#[derive(Debug, PartialEq, Eq)]
+enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl Display for Change<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Same(c) => write!(f, "  {c}"),
+            Change::Removed(c) => write!(f, "- {c}"),
+            Change::Added(c) => write!(f, "+ {c}"),
+        }
+    }
+}
+
+/// Render an old and new template against the same sample values and print both paths along
+/// with the path components that differ between them
+pub async fn print_template_diff(db: &Path, opts: DiffTemplateOptions) -> Result<(), DiffError> {
+    let mut sample = opts.samples.into_iter().collect::<SampleContext>();
+    let old = match opts.old {
+        OldTemplate::Template(old) => old,
+        OldTemplate::Beamline(beamline) => {
+            let (template, instrument) = configured_template(db, &beamline, opts.kind).await?;
+            sample.set_default("instrument", &instrument);
+            template
+        }
+    };
+    let old_path = sample
+        .render_as(opts.kind, &old)
+        .map_err(|e| DiffError::Render("old", e))?;
+    let new_path = sample
+        .render_as(opts.kind, &opts.new)
+        .map_err(|e| DiffError::Render("new", e))?;
+    println!("old: {old_path}");
+    println!("new: {new_path}");
+    if old_path == new_path {
+        println!("Both templates render to the same path");
+    } else {
+        println!();
+        for change in diff_components(&old_path, &new_path) {
+            println!("{change}");
+        }
+    }
+    Ok(())
+}
+
+/// The text of the template of the given kind currently configured for a beamline and the
+/// instrument used when rendering it
+async fn configured_template(
+    db: &Path,
+    beamline: &str,
+    kind: TemplateKind,
+) -> Result<(String, String), DiffError> {
+    let db = SqliteScanPathService::open_existing(db, true)
+        .await
+        .map_err(DiffError::Db)?;
+    let conf = db.current_configuration(beamline).await?;
+    let template = match kind {
+        TemplateKind::Visit => conf.visit().map(|t| t.to_string()),
+        TemplateKind::Scan => conf.scan().map(|t| t.to_string()),
+        TemplateKind::Detector => conf.detector().map(|t| t.to_string()),
+    };
+    let template = template.map_err(DiffError::Configured)?;
+    Ok((template, conf.instrument_name().into()))
+}
+
+/// Split a path into its components, keeping the root of an absolute path as its own component
+fn components(path: &str) -> Vec<&str> {
+    let (root, rest) = match path.strip_prefix('/') {
+        Some(rest) => (Some("/"), rest),
+        None => (None, path),
+    };
+    root.into_iter()
+        .chain(rest.split('/').filter(|c| !c.is_empty()))
+        .collect()
+}
+
+/// Compare two paths component by component using the longest common subsequence of their
+/// components so that inserted or removed directories do not mark the rest of the path as
+/// changed
+fn diff_components<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old = components(old);
+    let new = components(new);
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut changes = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changes.push(Change::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes.extend(old[i..].iter().map(|c| Change::Removed(c)));
+    changes.extend(new[j..].iter().map(|c| Change::Added(c)));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::{components, diff_components, Change};
+    use crate::paths::{SampleContext, TemplateKind};
+
+    fn sample() -> SampleContext {
+        [
+            ("instrument", "i22"),
+            ("year", "2024"),
+            ("visit", "cm12345-3"),
+            ("proposal", "12345"),
+            ("scan_number", "1234"),
+            ("subdirectory", "sub"),
+        ]
+        .into_iter()
+        .map(|(f, v)| (f.into(), v.into()))
+        .collect()
+    }
+
+    #[rstest]
+    #[case::absolute("/tmp/i22/data", &["/", "tmp", "i22", "data"])]
+    #[case::relative("sub/i22-1234", &["sub", "i22-1234"])]
+    #[case::empty_components("sub//i22-1234/", &["sub", "i22-1234"])]
+    fn path_components(#[case] path: &str, #[case] expected: &[&str]) {
+        assert_eq!(components(path), expected);
+    }
+
+    #[test]
+    fn replaced_component() {
+        assert_eq!(
+            diff_components("/tmp/i22/data/2024", "/tmp/i22/raw/2024"),
+            [
+                Change::Same("/"),
+                Change::Same("tmp"),
+                Change::Same("i22"),
+                Change::Removed("data"),
+                Change::Added("raw"),
+                Change::Same("2024"),
+            ]
+        );
+    }
+
+    #[test]
+    fn inserted_component() {
+        assert_eq!(
+            diff_components("i22-1234", "sub/i22-1234"),
+            [Change::Added("sub"), Change::Same("i22-1234")]
+        );
+    }
+
+    #[test]
+    fn removed_trailing_components() {
+        assert_eq!(
+            diff_components("/data/i22/2024", "/data"),
+            [
+                Change::Same("/"),
+                Change::Same("data"),
+                Change::Removed("i22"),
+                Change::Removed("2024"),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_paths() {
+        assert_eq!(
+            diff_components("sub/i22-1234", "sub/i22-1234"),
+            [Change::Same("sub"), Change::Same("i22-1234")]
+        );
+    }
+
+    #[test]
+    fn change_display() {
+        let lines = [
+            Change::Same("tmp"),
+            Change::Removed("data"),
+            Change::Added("raw"),
+        ]
+        .map(|c| c.to_string());
+        assert_eq!(lines, ["  tmp", "- data", "+ raw"]);
+    }
+
+    #[rstest]
+    #[case::visit(
+        TemplateKind::Visit,
+        "/tmp/{instrument}/{year}/{visit}",
+        "/tmp/i22/2024/cm12345-3"
+    )]
+    #[case::scan(
+        TemplateKind::Scan,
+        "{subdirectory}/{instrument}-{scan_number}",
+        "sub/i22-1234"
+    )]
+    #[case::detector(
+        TemplateKind::Detector,
+        "{instrument}-{scan_number}-{detector}",
+        "i22-1234-saxs"
+    )]
+    fn render_kinds(#[case] kind: TemplateKind, #[case] template: &str, #[case] expected: &str) {
+        let mut sample = sample();
+        sample.set_default("detector", "saxs");
+        assert_eq!(sample.render_as(kind, template).unwrap(), expected);
+    }
+
+    #[test]
+    fn render_validates_template() {
+        let err = sample()
+            .render_as(TemplateKind::Scan, "/{scan_number}")
+            .unwrap_err();
+        assert_eq!(err, "Path should be relative");
+    }
+
+    #[test]
+    fn render_missing_sample() {
+        let err = sample().render_as(TemplateKind::Detector, "{scan_number}-{detector}");
+        assert_matches!(err, Err(e) if e == "No sample values given for fields: detector");
+    }
+
+    #[test]
+    fn sample_default_does_not_replace_given_value() {
+        let mut sample = sample();
+        sample.set_default("instrument", "b21");
+        assert_eq!(
+            sample
+                .render_as(TemplateKind::Scan, "{instrument}-{scan_number}")
+                .unwrap(),
+            "i22-1234"
+        );
+    }
+}
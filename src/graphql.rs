@@ -12,614 +12,6664 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::any;
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::Display;
 use std::future::Future;
-use std::path::{Component, Path, PathBuf};
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt as _;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use std::{any, io};
 
 use async_graphql::extensions::Tracing;
 use async_graphql::http::GraphiQLSource;
 use async_graphql::registry::{MetaType, MetaTypeId, Registry};
 use async_graphql::{
-    Context, EmptySubscription, InputObject, InputType, InputValueError, InputValueResult, Object,
-    Scalar, ScalarType, Schema, SimpleObject, Value,
+    Context, Description, EmptySubscription, Enum, ErrorExtensions, InputObject, InputType,
+    InputValueError, InputValueResult, Name, Object, ParseRequestError, ResultExt, Scalar,
+    ScalarType, Schema, SimpleObject, Union, Value,
 };
+use async_graphql_axum::rejection::GraphQLRejection;
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use auth::{AuthError, PolicyCheck};
-use axum::response::{Html, IntoResponse};
+use auth::PolicyCheck;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{Extension, Router};
+use axum::{Extension, Json, Router};
 use axum_extra::headers::authorization::Bearer;
 use axum_extra::headers::Authorization;
 use axum_extra::TypedHeader;
-use chrono::{Datelike, Local};
-use tokio::net::TcpListener;
-use tracing::{info, instrument, trace, warn};
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use health::Health;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use opentelemetry::metrics::{Counter, Meter};
+use opentelemetry::{global, KeyValue};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal;
+use tokio::sync::{watch, OnceCell};
+use tokio::task::JoinSet;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tracing::{debug, info, info_span, instrument, trace, warn, Instrument as _};
+use uuid::Uuid;
 
-use crate::cli::ServeOptions;
+use crate::cli::{
+    HiddenSubdirectories, MissingTrackerDirectory, ServeOptions, VisitDirectoryCheck,
+};
 use crate::db_service::{
-    BeamlineConfiguration, BeamlineConfigurationUpdate, SqliteScanPathService,
+    BeamlineConfiguration, BeamlineConfigurationUpdate, ConfigChange, ConfigurationError,
+    DetectorGroup, FieldChange, NextScanError, SqliteScanPathService, UpdateConfigurationError,
+    VisitCodeTemplate,
 };
-use crate::numtracker::NumTracker;
+use crate::numtracker::{DirectoryStatus, NumTracker};
+use crate::overlay::{ConfigOverlay, OverlayError};
 use crate::paths::{
-    BeamlineField, DetectorField, DetectorTemplate, PathSpec, ScanField, ScanTemplate,
-    VisitTemplate,
+    BeamlineField, DetectorField, DetectorNormalisation, DetectorTemplate, FieldPolicy,
+    InvalidPathTemplate, PathSpec, Radix, SampleContext, ScanField, ScanTemplate, TemplateKind,
+    TemplatePolicies, VisitTemplate,
 };
 use crate::template::{FieldSource, PathTemplate};
+use crate::visit::{InvalidVisit, Proposal, ProposalCodes, Visit};
 
 mod auth;
+mod health;
 
-pub async fn serve_graphql(db: &Path, opts: ServeOptions) {
-    let db = SqliteScanPathService::connect(db)
+pub async fn serve_graphql(db: &Path, opts: ServeOptions) -> Result<(), ServeError> {
+    let db = SqliteScanPathService::connect(db, &opts.pool)
         .await
         .expect("Unable to open DB");
+    let overlay = match opts.config_overlay() {
+        Some(path) => ConfigOverlay::load(path).map_err(ServeError::Overlay)?,
+        None => ConfigOverlay::default(),
+    };
     let directory_numtracker = NumTracker::for_root_directory(opts.root_directory())
-        .expect("Could not read external directories");
-    info!("Serving graphql endpoints on {:?}", opts.addr());
+        .expect("Could not read external directories")
+        .with_directories(overlay.tracker_directories())
+        .create_missing(opts.create_directories())
+        .removal_grace(opts.number_file_grace());
     let addr = opts.addr();
+    let unix_socket = opts.unix_socket().map(Path::to_path_buf);
+    let check_policy = opts.ready_check_policy();
+    let missing_tracker_directory = opts.missing_tracker_directory();
+    let log_render_context = LogRenderContext(opts.log_render_context());
+    let read_only = ReadOnly(opts.read_only());
+    let debug_timings = DebugTimings(opts.debug_timings());
+    let max_detectors = MaxDetectors(opts.max_detectors());
+    let template_policies = opts.template_policies();
+    let proposal_codes = ProposalCodes::new(opts.proposal_codes());
+    let served_beamlines = ServedBeamlines::new(opts.served_beamlines());
+    let compression = compression(opts.compression_threshold());
+    let idempotency_keys = IdempotencyKeys::new(opts.idempotency_window());
+    let scan_rate_limit = ScanRateLimit::new(opts.scan_rate_limit());
+    let visit_directory_check = opts.visit_directory_check();
+    let subdirectory_limits = SubdirectoryLimits {
+        max_depth: opts.subdirectory_max_depth(),
+        default_depth: opts.subdirectory_default_depth(),
+        hidden: opts.hidden_subdirectories(),
+    };
+    let policy = opts.policy.map(PolicyCheck::new);
+    let readiness = Readiness {
+        db: db.clone(),
+        policy: policy.clone().filter(|_| check_policy),
+    };
     let schema = Schema::build(Query, Mutation, EmptySubscription)
         .extension(Tracing)
         .limit_directives(32)
-        .data(db)
+        .data(db.clone())
         .data(directory_numtracker)
-        .data(opts.policy.map(PolicyCheck::new))
+        .data(missing_tracker_directory)
+        .data(log_render_context)
+        .data(PathMetrics::new(&global::meter(env!("CARGO_PKG_NAME"))))
+        .data(read_only)
+        .data(max_detectors)
+        .data(template_policies)
+        .data(proposal_codes)
+        .data(served_beamlines)
+        .data(overlay)
+        .data(idempotency_keys)
+        .data(scan_rate_limit)
+        .data(visit_directory_check)
+        .data(subdirectory_limits)
+        .data(policy)
+        .data::<Box<dyn Clock>>(Box::new(SystemClock))
         .finish();
     let app = Router::new()
-        .route("/graphql", post(graphql_handler))
+        .route(GRAPHQL_PATH, post(graphql_handler))
         .route("/graphiql", get(graphiql))
-        .layer(Extension(schema));
-    let listener = TcpListener::bind(addr)
-        .await
-        .unwrap_or_else(|_| panic!("Port {:?} in use", addr));
-    axum::serve(listener, app)
-        .await
-        .expect("Can't serve graphql endpoint");
-}
-
-pub fn graphql_schema() {
-    let schema = Schema::new(Query, Mutation, EmptySubscription);
-    println!("{}", schema.sdl());
+        .route("/readyz", get(readyz))
+        .fallback(not_found)
+        .layer(Extension(schema))
+        .layer(Extension(readiness))
+        .layer(Extension(debug_timings))
+        .layer(compression);
+    let result = match unix_socket {
+        Some(path) => {
+            info!("Serving graphql endpoints on {}", path.display());
+            let listener = bind_unix(&path)?;
+            serve_unix(listener, app, shutdown_signal()).await;
+            Ok(())
+        }
+        None => {
+            info!("Serving graphql endpoints on {addr:?}");
+            let listener = bind(addr).await?;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .map_err(ServeError::Serve)
+        }
+    };
+    // No more requests are being handled so the DB can be closed cleanly
+    db.close().await;
+    result
 }
 
-async fn graphiql() -> impl IntoResponse {
-    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+/// Compress responses of at least `threshold` bytes for clients that accept gzip or brotli
+/// encoding. Smaller responses are not worth the overhead.
+fn compression(threshold: u16) -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(SizeAbove::new(threshold)))
 }
 
-#[instrument(skip_all)]
-async fn graphql_handler(
-    schema: Extension<Schema<Query, Mutation, EmptySubscription>>,
-    auth_token: Option<TypedHeader<Authorization<Bearer>>>,
-    req: GraphQLRequest,
-) -> GraphQLResponse {
-    schema
-        .execute(req.into_inner().data(auth_token.map(|header| header.0)))
+/// Bind to the given address. Binding to the unspecified IPv6 address (`::`) will also accept
+/// IPv4 connections on systems that support dual-stack sockets.
+async fn bind(addr: SocketAddr) -> Result<TcpListener, ServeError> {
+    TcpListener::bind(addr)
         .await
-        .into()
+        .map_err(|source| ServeError::Bind { addr, source })
 }
 
-/// Read-only API for GraphQL
-struct Query;
-
-/// Read-write API for GraphQL
-struct Mutation;
+/// Bind a Unix domain socket at the given path. A socket file left behind by a previous instance
+/// that did not shut down cleanly is replaced but a socket that is still accepting connections,
+/// or any other existing file, is an error.
+fn bind_unix(path: &Path) -> Result<UnixListener, ServeError> {
+    let bind_error = |source| ServeError::BindUnix {
+        path: path.to_path_buf(),
+        source,
+    };
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(bind_error(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "socket is in use by another process",
+            )));
+        }
+        warn!("Removing stale socket file {}", path.display());
+        std::fs::remove_file(path).map_err(bind_error)?;
+    }
+    UnixListener::bind(path).map_err(bind_error)
+}
 
-/// GraphQL type to mimic a key-value pair from the map type that GraphQL doesn't have
-#[derive(SimpleObject)]
-struct DetectorPath {
-    name: String,
-    path: String,
+/// Serve requests on a Unix domain socket until `shutdown` completes, then wait for open
+/// connections to finish their current requests and remove the socket file.
+async fn serve_unix(listener: UnixListener, app: Router, shutdown: impl Future<Output = ()>) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+    let (stop, stopping) = watch::channel(());
+    let mut connections = JoinSet::new();
+    tokio::select! {
+        () = accept_unix(&listener, app, &mut connections, &stopping) => {},
+        () = shutdown => {},
+    }
+    drop(listener);
+    stop.send_replace(());
+    debug!(
+        "Waiting for {} open connections to close",
+        connections.len()
+    );
+    while connections.join_next().await.is_some() {}
+    if let Some(path) = path {
+        debug!("Removing socket file {}", path.display());
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove socket file {}: {e}", path.display());
+        }
+    }
 }
 
-/// GraphQL type to provide path data for a specific visit
-struct VisitPath {
-    visit: String,
-    info: BeamlineConfiguration,
+/// Accept connections until the listener is dropped, serving each in its own task in
+/// `connections`. Connections finish their current request and close once `stopping` changes.
+/// Failing to accept a connection (eg because the process has run out of file descriptors) is
+/// logged rather than stopping the server.
+async fn accept_unix(
+    listener: &UnixListener,
+    app: Router,
+    connections: &mut JoinSet<()>,
+    stopping: &watch::Receiver<()>,
+) {
+    loop {
+        // Tidy up connections that have already closed
+        while connections.try_join_next().is_some() {}
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept connection: {e}");
+                // Give the process a chance to recover before trying again
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let mut stopping = stopping.clone();
+        connections.spawn(async move {
+            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            let mut conn = pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = stopping.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Error serving connection: {e}");
+            }
+        });
+    }
 }
 
-/// GraphQL type to provide path data for the next scan for a given visit
-struct ScanPaths {
-    visit: VisitPath,
-    subdirectory: Subdirectory,
+/// Wait for the process to be asked to stop (SIGINT or SIGTERM)
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => _ = sig.recv().await,
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {e}");
+                futures::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
 }
 
-/// Error to be returned when a path contains non-unicode characters
+/// Error preventing the graphql endpoints from being served
 #[derive(Debug)]
-struct NonUnicodePath;
-
-/// Try and convert a path to a string (via `OsString`), returning a `NonUnicodePath`
-/// error if not possible
-fn path_to_string(path: PathBuf) -> Result<String, NonUnicodePath> {
-    path.into_os_string()
-        .into_string()
-        .map_err(|_| NonUnicodePath)
+pub enum ServeError {
+    Bind { addr: SocketAddr, source: io::Error },
+    BindUnix { path: PathBuf, source: io::Error },
+    Overlay(OverlayError),
+    Serve(io::Error),
 }
 
-impl Display for NonUnicodePath {
+impl Display for ServeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Path contains non-unicode characters")
+        match self {
+            ServeError::Bind { addr, source } => {
+                write!(f, "Could not bind to {addr}: ")?;
+                match source.kind() {
+                    io::ErrorKind::AddrInUse => f.write_str("address is already in use"),
+                    io::ErrorKind::AddrNotAvailable => {
+                        f.write_str("address is not available on this host")
+                    }
+                    io::ErrorKind::PermissionDenied => {
+                        f.write_str("permission denied (ports below 1024 may require privileges)")
+                    }
+                    _ => write!(f, "{source}"),
+                }
+            }
+            ServeError::BindUnix { path, source } => {
+                write!(f, "Could not bind to {}: {source}", path.display())
+            }
+            ServeError::Overlay(e) => write!(f, "{e}"),
+            ServeError::Serve(e) => write!(f, "Error serving graphql endpoints: {e}"),
+        }
     }
 }
 
-impl Error for NonUnicodePath {}
-
-#[Object]
-impl VisitPath {
-    #[instrument(skip(self))]
-    async fn visit(&self) -> &str {
-        &self.visit
-    }
-    #[instrument(skip(self))]
-    async fn beamline(&self) -> &str {
-        &self.info.name()
-    }
-    #[instrument(skip(self))]
-    async fn directory(&self) -> async_graphql::Result<String> {
-        Ok(path_to_string(self.info.visit()?.render(self))?)
+impl Error for ServeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServeError::Bind { source, .. } => Some(source),
+            ServeError::BindUnix { source, .. } => Some(source),
+            ServeError::Overlay(e) => Some(e),
+            ServeError::Serve(e) => Some(e),
+        }
     }
 }
 
-impl FieldSource<BeamlineField> for VisitPath {
-    fn resolve(&self, field: &BeamlineField) -> Cow<'_, str> {
-        match field {
-            BeamlineField::Year => Local::now().year().to_string().into(),
-            BeamlineField::Visit => self.visit.as_str().into(),
-            BeamlineField::Proposal => self
-                .visit
-                .split('-')
-                .next()
-                .expect("There is always one section for a split")
-                .into(),
-            BeamlineField::Instrument => self.info.name().into(),
+/// The dependencies that need to be available for the service to handle requests
+#[derive(Clone)]
+struct Readiness {
+    db: SqliteScanPathService,
+    /// The policy server to check if it should be included in the readiness check
+    policy: Option<PolicyCheck>,
+}
+
+impl Readiness {
+    /// How long to wait for the policy server before considering it unavailable
+    const POLICY_TIMEOUT: Duration = Duration::from_secs(2);
+
+    async fn check(&self) -> Result<(), String> {
+        self.db
+            .ping()
+            .await
+            .map_err(|e| format!("Database is not available: {e}"))?;
+        if let Some(policy) = &self.policy {
+            policy
+                .ping(Self::POLICY_TIMEOUT)
+                .await
+                .map_err(|e| format!("Policy server is not available: {e:?}"))?;
         }
+        Ok(())
     }
 }
 
-#[Object]
-impl ScanPaths {
-    /// The visit used to generate this scan information. Should be the same as the visit passed in
-    #[instrument(skip(self))]
-    async fn visit(&self) -> &VisitPath {
-        &self.visit
+async fn readyz(Extension(ready): Extension<Readiness>) -> StatusCode {
+    match ready.check().await {
+        Ok(()) => StatusCode::OK,
+        Err(reason) => {
+            warn!("Readiness check failed: {reason}");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
     }
+}
 
-    /// The root scan file for this scan. The path has no extension so that the format can be
-    /// chosen by the client.
-    #[instrument(skip(self))]
-    async fn scan_file(&self) -> async_graphql::Result<String> {
-        Ok(path_to_string(self.visit.info.scan()?.render(self))?)
-    }
+pub fn graphql_schema() {
+    let schema = Schema::new(Query, Mutation, EmptySubscription);
+    println!("{}", schema.sdl());
+}
 
-    /// The scan number for this scan. This should be unique for the requested beamline.
-    #[instrument(skip(self))]
-    async fn scan_number(&self) -> u32 {
-        self.visit.info.scan_number()
-    }
+/// The route the GraphQL API is served from. GraphiQL uses the same path so that the two
+/// always agree.
+const GRAPHQL_PATH: &str = "/graphql";
 
-    /// The paths where the given detectors should write their files.
-    ///
-    /// Detector names are normalised before being used in file names by replacing any
-    /// non-alphanumeric characters with '_'. If there are duplicate names in the list
-    /// of detectors after this normalisation, there will be duplicate paths in the
-    /// results.
-    // TODO: The docs here reference the implementation specific behaviour in the normalisation
-    #[instrument(skip(self))]
-    async fn detectors(&self, names: Vec<Detector>) -> async_graphql::Result<Vec<DetectorPath>> {
-        let template = self.visit.info.detector()?;
-        Ok(names
-            .into_iter()
-            .map(|name| {
-                path_to_string(template.render(&(name.as_str(), self))).map(|path| DetectorPath {
-                    name: name.into_string(),
-                    path,
-                })
-            })
-            .collect::<Result<Vec<DetectorPath>, _>>()?)
-    }
+async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint(GRAPHQL_PATH).finish())
 }
 
-#[Object]
-impl BeamlineConfiguration {
-    pub async fn visit_template(&self) -> async_graphql::Result<String> {
-        Ok(self.visit()?.to_string())
-    }
-    pub async fn scan_template(&self) -> async_graphql::Result<String> {
-        Ok(self.scan()?.to_string())
-    }
-    pub async fn detector_template(&self) -> async_graphql::Result<String> {
-        Ok(self.detector()?.to_string())
-    }
-    pub async fn latest_scan_number(&self) -> async_graphql::Result<u32> {
-        Ok(self.scan_number())
-    }
+/// Header used by clients to request a breakdown of how long their request took
+const DEBUG_TIMINGS_HEADER: &str = "x-debug-timings";
+
+/// Whether clients may request timing breakdowns via the [`DEBUG_TIMINGS_HEADER`]
+#[derive(Debug, Clone, Copy)]
+struct DebugTimings(bool);
+
+#[instrument(skip_all)]
+async fn graphql_handler(
+    schema: Extension<Schema<Query, Mutation, EmptySubscription>>,
+    auth_token: Option<TypedHeader<Authorization<Bearer>>>,
+    debug_timings: Option<Extension<DebugTimings>>,
+    headers: HeaderMap,
+    req: Result<GraphQLRequest, GraphQLRejection>,
+) -> Response {
+    let req = match req {
+        Ok(req) => req,
+        Err(rejection) => return ErrorResponse::from(rejection).into_response(),
+    };
+    let req = req.into_inner().data(auth_token.map(|header| header.0));
+    let timed = debug_timings.is_some_and(|Extension(DebugTimings(enabled))| enabled)
+        && headers.contains_key(DEBUG_TIMINGS_HEADER);
+    let response = if timed {
+        execute_timed(&schema, req).await
+    } else {
+        execute_tagged(&schema, req).await
+    };
+    GraphQLResponse::from(response).into_response()
 }
 
-impl FieldSource<ScanField> for ScanPaths {
-    fn resolve(&self, field: &ScanField) -> Cow<'_, str> {
-        match field {
-            ScanField::Subdirectory => self.subdirectory.to_string().into(),
-            ScanField::ScanNumber => self.visit.info.scan_number().to_string().into(),
-            ScanField::Beamline(bl) => self.visit.resolve(bl),
+async fn not_found() -> ErrorResponse {
+    ErrorResponse::new(StatusCode::NOT_FOUND, "NOT_FOUND", "No route found")
+}
+
+/// Error returned for requests that fail before reaching the schema, so that clients get the
+/// same JSON shape (`{"error": {"code": ..., "message": ...}}`) wherever a request fails.
+#[derive(Debug)]
+struct ErrorResponse {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ErrorResponse {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
         }
     }
 }
 
-impl FieldSource<DetectorField> for (&str, &ScanPaths) {
-    fn resolve(&self, field: &DetectorField) -> Cow<'_, str> {
-        match field {
-            DetectorField::Detector => self.0.into(),
-            DetectorField::Scan(s) => self.1.resolve(s),
+impl From<GraphQLRejection> for ErrorResponse {
+    fn from(rejection: GraphQLRejection) -> Self {
+        match rejection.0 {
+            ParseRequestError::PayloadTooLarge => Self::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "PAYLOAD_TOO_LARGE",
+                "Request body is too large",
+            ),
+            err => Self::new(StatusCode::BAD_REQUEST, "BAD_REQUEST", err.to_string()),
         }
     }
 }
 
-#[Object]
-impl Query {
-    #[instrument(skip(self, ctx))]
-    async fn paths(
-        &self,
-        ctx: &Context<'_>,
-        beamline: String,
-        visit: String,
-    ) -> async_graphql::Result<VisitPath> {
-        let db = ctx.data::<SqliteScanPathService>()?;
-        let info = db.current_configuration(&beamline).await?;
-        Ok(VisitPath { visit, info })
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": {"code": self.code, "message": self.message}
+        });
+        (self.status, Json(body)).into_response()
     }
+}
 
-    #[instrument(skip(self, ctx))]
-    async fn configuration(
-        &self,
-        ctx: &Context<'_>,
-        beamline: String,
-    ) -> async_graphql::Result<BeamlineConfiguration> {
-        check_auth(ctx, |policy, token| policy.check_admin(token, &beamline)).await?;
-        let db = ctx.data::<SqliteScanPathService>()?;
-        trace!("Getting config for {beamline:?}");
-        Ok(db.current_configuration(&beamline).await?)
+/// Execute a request under a span carrying a newly generated request ID. The ID is also returned
+/// to the client in the response extensions so that failures reported by users can be matched to
+/// the server logs.
+async fn execute_tagged(
+    schema: &Schema<Query, Mutation, EmptySubscription>,
+    req: async_graphql::Request,
+) -> async_graphql::Response {
+    let request_id = Uuid::new_v4().to_string();
+    let span = info_span!("graphql_request", request_id);
+    let mut response = async {
+        let response = execute(schema, req).await;
+        for err in &response.errors {
+            warn!(request_id, "Error handling request: {}", err.message);
+        }
+        response
     }
+    .instrument(span)
+    .await;
+    response
+        .extensions
+        .insert("requestId".into(), Value::String(request_id));
+    response
 }
 
-#[Object]
-impl Mutation {
-    /// Access scan file locations for the next scan
-    #[instrument(skip(self, ctx))]
-    async fn scan<'ctx>(
-        &self,
-        ctx: &Context<'ctx>,
-        beamline: String,
-        visit: String,
-        sub: Option<Subdirectory>,
-    ) -> async_graphql::Result<ScanPaths> {
-        check_auth(ctx, |policy, token| {
-            policy.check_access(token, &beamline, &visit)
-        })
-        .await?;
-        let db = ctx.data::<SqliteScanPathService>()?;
-        let nt = ctx.data::<NumTracker>()?;
-        // There is a race condition here if a process increments the file
-        // while the DB is being queried or between the two queries but there
-        // isn't much we can do from here.
-        let current = db.current_configuration(&beamline).await?;
-        let dir = nt.for_beamline(&beamline, current.extension()).await?;
+/// Execute a request while recording how long each stage of resolving it takes. The totals are
+/// added to the response extensions as `timings`.
+async fn execute_timed(
+    schema: &Schema<Query, Mutation, EmptySubscription>,
+    req: async_graphql::Request,
+) -> async_graphql::Response {
+    let timings = Arc::new(Timings::default());
+    let mut response = execute_tagged(schema, req.data(timings.clone())).await;
+    response
+        .extensions
+        .insert("timings".into(), timings.to_value());
+    response
+}
 
-        let next_scan = db
-            .next_scan_configuration(&beamline, dir.prev().await?)
-            .await?;
+/// Execute a request against the schema, adding any warnings raised while resolving it to the
+/// extensions of the response.
+async fn execute(
+    schema: &Schema<Query, Mutation, EmptySubscription>,
+    req: async_graphql::Request,
+) -> async_graphql::Response {
+    let warnings = Arc::new(Warnings::default());
+    let mut response = schema.execute(req.data(warnings.clone())).await;
+    let warnings = warnings.take();
+    if !warnings.is_empty() {
+        response.extensions.insert(
+            "warnings".into(),
+            Value::List(warnings.into_iter().map(Value::String).collect()),
+        );
+    }
+    response
+}
 
-        if let Err(e) = dir.set(next_scan.scan_number()).await {
-            warn!("Failed to increment fallback tracker directory: {e}");
+/// Advisory messages raised while resolving a request. These do not prevent the request
+/// succeeding but are returned to the client in the response extensions.
+#[derive(Debug, Default)]
+struct Warnings(Mutex<Vec<String>>);
+
+impl Warnings {
+    /// Add a warning to the current request if warnings are being collected
+    fn raise(ctx: &Context<'_>, msg: String) {
+        trace!("Request warning: {msg}");
+        if let Some(warnings) = ctx.data_opt::<Arc<Warnings>>() {
+            warnings
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(msg);
         }
+    }
 
-        Ok(ScanPaths {
-            visit: VisitPath {
-                visit,
-                info: next_scan,
-            },
-            subdirectory: sub.unwrap_or_default(),
+    /// The number of warnings raised so far in the current request
+    fn count(ctx: &Context<'_>) -> usize {
+        ctx.data_opt::<Arc<Warnings>>().map_or(0, |warnings| {
+            warnings
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len()
         })
     }
 
-    #[instrument(skip(self, ctx))]
-    async fn configure<'ctx>(
-        &self,
-        ctx: &Context<'ctx>,
-        beamline: String,
-        config: ConfigurationUpdates,
-    ) -> async_graphql::Result<BeamlineConfiguration> {
-        check_auth(ctx, |pc, token| pc.check_admin(token, &beamline)).await?;
-        let db = ctx.data::<SqliteScanPathService>()?;
-        trace!("Configuring: {beamline}: {config:?}");
-        let upd = config.into_update(beamline);
-        match upd.update_beamline(db).await? {
-            Some(bc) => Ok(bc),
-            None => Ok(upd.insert_new(db).await?),
-        }
+    /// The warnings raised in the current request after the first `start` warnings
+    fn since(ctx: &Context<'_>, start: usize) -> Vec<String> {
+        ctx.data_opt::<Arc<Warnings>>()
+            .map(|warnings| {
+                let warnings = warnings.0.lock().unwrap_or_else(PoisonError::into_inner);
+                warnings.iter().skip(start).cloned().collect()
+            })
+            .unwrap_or_default()
     }
-}
 
-async fn check_auth<'ctx, Check, R>(ctx: &Context<'ctx>, check: Check) -> async_graphql::Result<()>
-where
-    Check: Fn(&'ctx PolicyCheck, Option<&'ctx Authorization<Bearer>>) -> R,
-    R: Future<Output = Result<(), AuthError>>,
-{
-    if let Some(policy) = ctx.data::<Option<PolicyCheck>>()? {
-        trace!("Auth enabled: checking token");
-        let token = ctx.data::<Option<Authorization<Bearer>>>()?;
-        check(policy, token.as_ref())
-            .await
-            .inspect_err(|e| info!("Authorization failed: {e:?}"))
-            .map_err(async_graphql::Error::from)
-    } else {
-        trace!("No authorization configured");
-        Ok(())
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap_or_else(PoisonError::into_inner))
     }
 }
 
-#[derive(Debug, InputObject)]
-struct ConfigurationUpdates {
-    visit: Option<InputTemplate<VisitTemplate>>,
-    scan: Option<InputTemplate<ScanTemplate>>,
-    detector: Option<InputTemplate<DetectorTemplate>>,
-    scan_number: Option<u32>,
-    extension: Option<String>,
-}
+/// Time spent on each stage (eg "auth", "db", "render") of resolving a request. Only collected
+/// for requests that ask for it.
+#[derive(Debug, Default)]
+struct Timings(Mutex<BTreeMap<&'static str, Duration>>);
 
-impl ConfigurationUpdates {
-    fn into_update(self, name: String) -> BeamlineConfigurationUpdate {
-        BeamlineConfigurationUpdate {
-            name,
-            scan_number: self.scan_number,
-            visit: self.visit.map(|t| t.0),
-            scan: self.scan.map(|t| t.0),
-            detector: self.detector.map(|t| t.0),
-            extension: self.extension,
+impl Timings {
+    /// Add time spent on a stage to the current request if timings are being collected. Stages
+    /// that happen more than once in a request, eg DB lookups, are summed.
+    fn record(ctx: &Context<'_>, stage: &'static str, elapsed: Duration) {
+        if let Some(timings) = ctx.data_opt::<Arc<Timings>>() {
+            *timings
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(stage)
+                .or_default() += elapsed;
         }
     }
-}
 
-#[derive(Debug)]
-struct InputTemplate<S: PathSpec>(PathTemplate<S::Field>);
+    /// Await a future, recording the time it took against the given stage
+    async fn time<F: Future>(ctx: &Context<'_>, stage: &'static str, fut: F) -> F::Output {
+        let start = Instant::now();
+        let output = fut.await;
+        Self::record(ctx, stage, start.elapsed());
+        output
+    }
 
-impl<S, F> InputType for InputTemplate<S>
-where
-    F: Send + Sync + TryFrom<String> + Display,
-    S: PathSpec<Field = F> + Send + Sync,
-{
-    type RawValueType = PathTemplate<F>;
-    fn parse(value: Option<Value>) -> InputValueResult<Self> {
-        match value {
-            Some(Value::String(txt)) => match S::new_checked(&txt) {
-                Ok(pt) => Ok(Self(pt)),
-                Err(e) => Err(InputValueError::custom(e)),
-            },
-            Some(other) => Err(InputValueError::expected_type(other)),
-            None => Err(InputValueError::expected_type(Value::Null)),
-        }
+    /// Call a function, recording the time it took against the given stage
+    fn measure<R>(ctx: &Context<'_>, stage: &'static str, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let output = f();
+        Self::record(ctx, stage, start.elapsed());
+        output
     }
+
+    /// The time spent on each stage in microseconds
     fn to_value(&self) -> Value {
-        Value::String(self.0.to_string())
+        let timings = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        Value::Object(
+            timings
+                .iter()
+                .map(|(stage, time)| {
+                    let micros = u64::try_from(time.as_micros()).unwrap_or(u64::MAX);
+                    (Name::new(stage), Value::from(micros))
+                })
+                .collect(),
+        )
     }
+}
 
-    fn type_name() -> Cow<'static, str> {
-        // best effort remove the `numtracker::paths::` prefix
-        any::type_name::<S>()
-            .split("::")
-            .last()
-            .expect("There is always a last value for a split")
-            .into()
-    }
+/// Source of the current time used when rendering time dependent fields such as the year
+trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
 
-    fn create_type_info(registry: &mut Registry) -> String {
-        registry.create_input_type::<Self, _>(MetaTypeId::Scalar, |_| MetaType::Scalar {
-            name: Self::type_name().into(),
-            description: Some(S::describe().into()),
-            is_valid: Some(Arc::new(|v| matches!(v, Value::String(_)))),
-            visible: None,
-            inaccessible: false,
-            tags: vec![],
-            specified_by_url: None,
-            directive_invocations: vec![],
-        })
-    }
+/// Clock that reads the current time from the system
+struct SystemClock;
 
-    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
-        Some(&self.0)
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
     }
 }
 
-// Derived Default is OK without validation as empty path is a valid subdirectory
-#[derive(Debug, Default)]
-pub struct Subdirectory(String);
+/// Get the current time from the clock configured for the schema
+fn now(ctx: &Context<'_>) -> async_graphql::Result<DateTime<Local>> {
+    Ok(ctx.data::<Box<dyn Clock>>()?.now())
+}
 
-#[derive(Debug)]
-pub enum InvalidSubdirectory {
-    InvalidComponent(usize),
-    AbsolutePath,
+/// Read-only API for GraphQL
+struct Query;
+
+/// Read-write API for GraphQL
+struct Mutation;
+
+/// GraphQL type to mimic a key-value pair from the map type that GraphQL doesn't have
+#[derive(SimpleObject)]
+struct DetectorPath {
+    name: String,
+    path: String,
 }
 
-#[Scalar]
-impl ScalarType for Subdirectory {
-    fn parse(value: Value) -> InputValueResult<Self> {
-        if let Value::String(path) = value {
-            let path = PathBuf::from(&path);
-            let mut new_sub = PathBuf::new();
-            for (i, comp) in path.components().enumerate() {
-                let err = match comp {
-                    Component::CurDir => continue,
-                    Component::Normal(seg) => {
-                        new_sub.push(seg);
-                        continue;
-                    }
-                    Component::RootDir => InvalidSubdirectory::AbsolutePath,
-                    Component::Prefix(_) | Component::ParentDir => {
-                        InvalidSubdirectory::InvalidComponent(i)
-                    }
-                };
-                return Err(InputValueError::custom(err));
+/// The detectors to get paths for in a previously allocated scan
+#[derive(Debug, InputObject)]
+struct ScanDetectors {
+    scan_number: u32,
+    sub: Option<Subdirectory>,
+    #[graphql(default)]
+    detectors: Vec<Detector>,
+    /// Detectors with options for where their files are written. Their paths follow those of
+    /// the plain `detectors`.
+    #[graphql(default)]
+    requests: Vec<DetectorRequest>,
+}
+
+/// The detector paths for a previously allocated scan
+#[derive(SimpleObject)]
+struct ScanDetectorPaths {
+    scan_number: u32,
+    detectors: Vec<DetectorPath>,
+}
+
+/// The templates available for rendering detector paths: the beamline's default detector
+/// template and the templates for any groups of detectors
+struct DetectorTemplates {
+    default: PathTemplate<DetectorField>,
+    groups: HashMap<String, PathTemplate<DetectorField>>,
+}
+
+impl DetectorTemplates {
+    /// Load the templates needed for the given detectors. Group templates are only read from
+    /// the DB if any of the detectors are in a group.
+    async fn load<'d>(
+        ctx: &Context<'_>,
+        info: &BeamlineConfiguration,
+        detectors: impl IntoIterator<Item = &'d DetectorRequest>,
+    ) -> async_graphql::Result<Self> {
+        let default = info
+            .detector()
+            .map_err(unconfigured(info.name(), "detector"))?;
+        let mut groups = HashMap::new();
+        if detectors.into_iter().any(|det| det.group.is_some()) {
+            let db = ctx.data::<SqliteScanPathService>()?;
+            for group in db.detector_groups(info.name()).await? {
+                let template = group
+                    .template()
+                    .map_err(unconfigured(info.name(), "detector group"))?;
+                groups.insert(group.name, template);
             }
-            // path was created from string so shouldn't actually be lossy conversion
-            Ok(Self(new_sub.to_string_lossy().to_string()))
-        } else {
-            Err(InputValueError::expected_type(value))
         }
+        Ok(Self { default, groups })
     }
-    fn to_value(&self) -> Value {
-        Value::String(self.0.to_string())
+
+    fn for_detector(
+        &self,
+        beamline: &str,
+        detector: &DetectorRequest,
+    ) -> async_graphql::Result<&PathTemplate<DetectorField>> {
+        let Some(group) = &detector.group else {
+            return Ok(&self.default);
+        };
+        self.groups.get(group).ok_or_else(|| {
+            async_graphql::Error::new(format!(
+                "Beamline {beamline:?} does not have a detector group {group:?}"
+            ))
+            .extend_with(|_, ext| ext.set("code", "UNKNOWN_DETECTOR_GROUP"))
+        })
     }
 }
 
-impl Display for InvalidSubdirectory {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            InvalidSubdirectory::InvalidComponent(s) => {
-                write!(f, "Segment {s} of path is not valid for a subdirectory")
-            }
-            InvalidSubdirectory::AbsolutePath => f.write_str("Subdirectory cannot be absolute"),
+/// How scan numbers are written in paths so that clients building their own file names can
+/// format them in the same way
+#[derive(SimpleObject)]
+struct ScanNumberFormat {
+    /// The base the scan number is written in, eg 16 for `{scan_number:hex}`
+    radix: u32,
+    /// The format given in the template placeholder, eg `hex`. Plain `{scan_number}`
+    /// placeholders are `dec`.
+    spec: String,
+}
+
+impl ScanNumberFormat {
+    /// The format used by a beamline's scan template. If the template includes more than one
+    /// scan number, the format of the first is used.
+    fn for_beamline(info: &BeamlineConfiguration) -> async_graphql::Result<Self> {
+        let template = info.scan().map_err(unconfigured(info.name(), "scan"))?;
+        let radix = template
+            .referenced_fields()
+            .find_map(|f| match f {
+                ScanField::ScanNumber(radix) => Some(*radix),
+                _ => None,
+            })
+            .unwrap_or(Radix::DECIMAL);
+        Ok(Self {
+            radix: radix.base(),
+            spec: radix.to_string(),
+        })
+    }
+}
+
+/// The result of validating a visit string
+#[derive(Union)]
+enum VisitValidation {
+    Valid(ValidVisit),
+    Invalid(InvalidVisitDetails),
+}
+
+impl VisitValidation {
+    /// Parse a visit and, if the server only accepts some proposal codes, check its code
+    fn check(codes: Option<&ProposalCodes>, visit: &str) -> Self {
+        let visit = visit.parse::<Visit>().and_then(|visit| match codes {
+            Some(codes) => codes.check(&visit.code).map(|_| visit),
+            None => Ok(visit),
+        });
+        match visit {
+            Ok(visit) => Self::Valid(ValidVisit {
+                code: visit.code,
+                proposal: visit.proposal,
+                session: visit.session,
+            }),
+            Err(reason) => Self::Invalid(InvalidVisitDetails {
+                message: reason.to_string(),
+                reason,
+            }),
         }
     }
 }
 
-impl Error for InvalidSubdirectory {}
+/// The components of a valid visit string
+#[derive(SimpleObject)]
+struct ValidVisit {
+    code: String,
+    proposal: u32,
+    session: u16,
+}
 
-impl Display for Subdirectory {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+/// Details of why a visit string is not valid
+#[derive(SimpleObject)]
+struct InvalidVisitDetails {
+    reason: InvalidVisit,
+    message: String,
+}
+
+/// A detector name and the name that would be used for it in file names
+#[derive(SimpleObject)]
+struct NormalisedDetector {
+    name: String,
+    normalised: String,
+    /// Whether another detector in the same request has the same normalised name
+    collision: bool,
+}
+
+/// A beamline and visit to get paths for as part of a batch
+#[derive(Debug, InputObject)]
+struct VisitRequest {
+    beamline: String,
+    visit: String,
+}
+
+/// The paths for one request in a batch, or why they could not be found
+#[derive(Union)]
+enum BatchVisitPath {
+    Paths(Box<VisitPath>),
+    Failed(BatchError),
+}
+
+/// Details of why a single request in a batch failed
+#[derive(SimpleObject)]
+struct BatchError {
+    beamline: String,
+    visit: String,
+    message: String,
+    /// The same code that would have been returned in the error extensions if the request had
+    /// been made on its own
+    code: Option<String>,
+}
+
+impl BatchError {
+    fn new(request: VisitRequest, err: &async_graphql::Error) -> Self {
+        let code = err
+            .extensions
+            .as_ref()
+            .and_then(|ext| match ext.get("code") {
+                Some(Value::String(code)) => Some(code.clone()),
+                _ => None,
+            });
+        Self {
+            beamline: request.beamline,
+            visit: request.visit,
+            message: err.message.clone(),
+            code,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Detector(String);
+/// A value to use for a template placeholder when test rendering a template
+#[derive(Debug, InputObject)]
+struct SampleField {
+    /// The name of the placeholder without braces, eg `scan_number`
+    field: String,
+    value: String,
+}
 
-#[Scalar]
-impl ScalarType for Detector {
-    fn parse(value: Value) -> InputValueResult<Self> {
-        if let Value::String(name) = value {
-            Ok(if name.contains(Self::INVALID) {
-                Self(
-                    name.split(Self::INVALID)
-                        .filter(|s| !s.is_empty())
-                        .collect::<Vec<_>>()
-                        .join("_"),
-                )
-            } else {
-                Self(name)
-            })
-        } else {
-            Err(InputValueError::expected_type(value))
+/// The result of test rendering a template against sample values
+#[derive(Union)]
+enum TemplateRender {
+    Rendered(RenderedTemplate),
+    Invalid(InvalidTemplateDetails),
+}
+
+/// The path produced by rendering a template
+#[derive(SimpleObject)]
+struct RenderedTemplate {
+    path: String,
+}
+
+/// Details of why a template could not be rendered
+#[derive(SimpleObject)]
+struct InvalidTemplateDetails {
+    message: String,
+}
+
+/// GraphQL type to provide path data for a specific visit
+#[derive(Clone)]
+struct VisitPath {
+    visit: String,
+    info: BeamlineConfiguration,
+    /// The time used to resolve any time dependent fields
+    now: DateTime<Local>,
+    /// The start date of the visit if given by the client. Used in place of the current time
+    /// for the year of the visit.
+    visit_date: Option<NaiveDate>,
+    /// Year explicitly requested by the client, eg when backfilling data from a previous year.
+    /// Takes precedence over both the visit date and the current time.
+    year: Option<i32>,
+}
+
+/// Whether each of a beamline's templates can be used to generate paths
+#[derive(SimpleObject)]
+struct TemplateStatus {
+    visit: TemplateValidity,
+    scan: TemplateValidity,
+    detector: TemplateValidity,
+}
+
+/// Whether a single template can be used to generate paths
+#[derive(SimpleObject)]
+struct TemplateValidity {
+    valid: bool,
+    /// The reason the template is not valid
+    error: Option<String>,
+}
+
+impl<F> From<Result<PathTemplate<F>, InvalidPathTemplate>> for TemplateValidity {
+    fn from(value: Result<PathTemplate<F>, InvalidPathTemplate>) -> Self {
+        Self {
+            valid: value.is_ok(),
+            error: value.err().map(|e| e.to_string()),
         }
     }
-    fn to_value(&self) -> Value {
-        Value::String(self.0.clone())
+}
+
+/// GraphQL type to provide path data for the next scan for a given visit
+#[derive(Clone)]
+struct ScanPaths {
+    visit: VisitPath,
+    subdirectory: Subdirectory,
+}
+
+impl ErrorExtensions for ConfigurationError {
+    fn extend(&self) -> async_graphql::Error {
+        let code = match self {
+            ConfigurationError::MissingBeamline(_) => "MISSING_BEAMLINE",
+            ConfigurationError::Db(_) => "DATABASE_ERROR",
+        };
+        async_graphql::Error::new(self.to_string()).extend_with(|_, ext| ext.set("code", code))
     }
 }
 
-impl Detector {
-    const INVALID: fn(char) -> bool = |c| !c.is_ascii_alphanumeric();
-    fn into_string(self) -> String {
-        self.0
+impl ErrorExtensions for UpdateConfigurationError {
+    fn extend(&self) -> async_graphql::Error {
+        let code = match self {
+            UpdateConfigurationError::NothingToUpdate(_) => "NOTHING_TO_UPDATE",
+            UpdateConfigurationError::Db(_) => "DATABASE_ERROR",
+        };
+        async_graphql::Error::new(self.to_string()).extend_with(|_, ext| ext.set("code", code))
     }
-    fn as_str(&self) -> &str {
-        self.0.as_str()
+}
+
+impl ErrorExtensions for NextScanError {
+    fn extend(&self) -> async_graphql::Error {
+        let code = match self {
+            NextScanError::MissingBeamline(_) => "MISSING_BEAMLINE",
+            NextScanError::Overflow(_) => "SCAN_NUMBER_OVERFLOW",
+            NextScanError::Busy(_) => "DATABASE_BUSY",
+            NextScanError::Db(_) => "DATABASE_ERROR",
+            NextScanError::Tracker(_) => "TRACKER_WRITE_FAILED",
+        };
+        async_graphql::Error::new(self.to_string()).extend_with(|_, ext| {
+            ext.set("code", code);
+            ext.set("retryable", self.is_retryable());
+        })
     }
 }
 
-#[cfg(test)]
-mod subdirectory_tests {
-    use async_graphql::{InputType as _, InputValueResult, Number, Value};
+/// Build an error for a beamline that exists but does not have a usable template of the given
+/// kind. This is distinct from the beamline not existing as the remedy is to fix the
+/// beamline's configuration rather than add a new beamline.
+fn unconfigured<'bl>(
+    beamline: &'bl str,
+    template: &'static str,
+) -> impl FnOnce(InvalidPathTemplate) -> async_graphql::Error + 'bl {
+    move |e| {
+        async_graphql::Error::new(format!(
+            "Beamline {beamline:?} does not have a valid {template} template: {e}"
+        ))
+        .extend_with(|_, ext| ext.set("code", "TEMPLATE_NOT_CONFIGURED"))
+    }
+}
 
-    use super::Subdirectory;
-    fn parse_str(sub: &str) -> InputValueResult<Subdirectory> {
-        Subdirectory::parse(Some(Value::String(sub.into())))
+/// The names of the distinct fields used by a template in the order they are first used
+fn field_names<F: Clone + PartialEq + Display>(template: PathTemplate<F>) -> Vec<String> {
+    template
+        .fields_used()
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Error to be returned when a path contains non-unicode characters
+#[derive(Debug)]
+struct NonUnicodePath;
+
+/// The separator used between the segments of paths returned to clients
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Enum)]
+enum PathSeparator {
+    /// Forward slash as used by Linux and other POSIX systems
+    #[default]
+    Slash,
+    /// Backslash as used by Windows, eg for paths on an SMB share
+    Backslash,
+}
+
+/// The most detectors that can be requested in a single call to the `detectors` field, or
+/// across all the scans in a single `scanDetectorPaths` query
+#[derive(Debug, Clone, Copy)]
+struct MaxDetectors(usize);
+
+impl Default for MaxDetectors {
+    fn default() -> Self {
+        Self(1000)
     }
+}
 
-    #[test]
-    fn valid_subdirectory() {
-        parse_str("valid/subdirectory").unwrap();
+impl MaxDetectors {
+    fn from_ctx(ctx: &Context<'_>) -> Self {
+        ctx.data_opt::<Self>().copied().unwrap_or_default()
     }
 
-    #[test]
-    fn invalid_subdirectory() {
-        parse_str("../parent").unwrap_err();
-        parse_str("/absolute/path").unwrap_err();
-        Subdirectory::parse(Some(Value::Number(Number::from_f64(42f64).unwrap()))).unwrap_err();
+    fn check(self, requested: usize) -> async_graphql::Result<()> {
+        if requested > self.0 {
+            return Err(async_graphql::Error::new(format!(
+                "{requested} detectors were requested but at most {} are allowed",
+                self.0
+            ))
+            .extend_with(|_, ext| ext.set("code", "TOO_MANY_DETECTORS")));
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn back_to_value() {
-        let sub = parse_str("./subdirectory").unwrap();
-        assert_eq!(sub.to_value(), Value::String("subdirectory".into()))
+/// Whether the value of every field used to render a path should be logged. This is separate
+/// from the tracing spans and is only meant to be enabled while diagnosing specific requests.
+#[derive(Debug, Clone, Copy, Default)]
+struct LogRenderContext(bool);
+
+impl LogRenderContext {
+    fn from_ctx(ctx: &Context<'_>) -> Self {
+        ctx.data_opt::<Self>().copied().unwrap_or_default()
+    }
+
+    /// Log a single line listing the rendered path and the values used for each field in it.
+    /// The fields are only those available to templates so no request credentials are included.
+    fn log(self, kind: &str, path: &Path, fields: &BTreeMap<String, String>) {
+        if self.0 {
+            debug!("Rendered {kind} {path:?} from {}", render_context(fields));
+        }
     }
 }
 
-#[cfg(test)]
-mod detector_tests {
-    use async_graphql::{InputType as _, Number, Value};
+/// Whether the service has been started in read-only mode, eg during DB maintenance
+#[derive(Debug, Clone, Copy, Default)]
+struct ReadOnly(bool);
 
-    use super::Detector;
+impl ReadOnly {
+    /// Fail if mutations are disabled. Checked before anything else in each mutation so that
+    /// nothing is read from or written to the DB.
+    fn check(ctx: &Context<'_>) -> async_graphql::Result<()> {
+        if ctx.data_opt::<Self>().is_some_and(|ro| ro.0) {
+            return Err(
+                async_graphql::Error::new("Service is in read-only mode").extend_with(|_, ext| {
+                    ext.set("code", "READ_ONLY");
+                }),
+            );
+        }
+        Ok(())
+    }
+}
 
-    #[rstest::rstest]
-    #[case::unchanged("camera", "camera")]
-    #[case::punctuation("foo+bar", "foo_bar")]
-    #[case::multiple_punctuation("foo+-?!bar", "foo_bar")]
-    fn normalised_name(#[case] input: &str, #[case] output: &str) {
-        let det = Detector::parse(Some(Value::String(input.into()))).unwrap();
-        let value = det.to_value();
-        let Value::String(s) = value else {
-            panic!("Unexpected value from detector: {value}");
-        };
-        assert_eq!(s, output);
-        assert_eq!(det.as_str(), output);
-        assert_eq!(det.into_string(), output);
+/// Scans recently allocated for requests that included an idempotency key so that retrying a
+/// request returns the original scan instead of allocating another.
+struct IdempotencyKeys {
+    /// How long a key is remembered after it is first used
+    window: Duration,
+    scans: Mutex<HashMap<(String, String), KeyEntry>>,
+}
+
+/// When a key was first used and the scan allocated for it
+type KeyEntry = (Instant, Arc<OnceCell<KeyedScan>>);
+
+/// The parameters of a scan request that determine the paths returned. A key can only be
+/// reused by a request with the same parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ScanParameters {
+    visit: String,
+    sub: String,
+    visit_date: Option<NaiveDate>,
+    year: Option<i32>,
+}
+
+/// A scan allocated for an idempotency key, along with the request that allocated it and the
+/// warnings raised while allocating it so that they can be repeated for retries
+struct KeyedScan {
+    parameters: ScanParameters,
+    paths: ScanPaths,
+    warnings: Vec<String>,
+}
+
+impl IdempotencyKeys {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            scans: Mutex::default(),
+        }
     }
 
-    #[test]
-    fn invalid_value() {
-        Detector::parse(Number::from_f64(42f64).map(Value::Number)).unwrap_err();
-        Detector::parse(None).unwrap_err();
+    /// Get the (possibly not yet allocated) scan for a key. Keys are scoped to a beamline so
+    /// that clients for different beamlines cannot collide. Concurrent requests with the same
+    /// key share the cell so only one of them allocates a scan.
+    fn scan(&self, beamline: &str, key: &str) -> Arc<OnceCell<KeyedScan>> {
+        let mut scans = self.scans.lock().unwrap_or_else(PoisonError::into_inner);
+        scans.retain(|_, (first_used, _)| first_used.elapsed() < self.window);
+        scans
+            .entry((beamline.into(), key.into()))
+            .or_insert_with(|| (Instant::now(), Arc::default()))
+            .1
+            .clone()
     }
 }
 
-#[cfg(test)]
-mod input_template_tests {
-    use async_graphql::{InputType as _, Value};
+/// The most scans that can be allocated per second for each beamline
+struct ScanRateLimit {
+    limit: Option<u32>,
+    /// The times of the scans allocated for each beamline within the last second
+    allocations: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
 
-    use super::InputTemplate;
-    use crate::paths::{DetectorTemplate, ScanTemplate, VisitTemplate};
+impl ScanRateLimit {
+    const WINDOW: Duration = Duration::from_secs(1);
 
-    #[test]
-    fn valid_visit_template() {
-        InputTemplate::<VisitTemplate>::parse(Some(Value::String(
-            "/tmp/{instrument}/data/{visit}".into(),
-        )))
-        .unwrap();
+    fn new(limit: Option<u32>) -> Self {
+        Self {
+            limit,
+            allocations: Mutex::default(),
+        }
     }
 
-    #[rstest::rstest]
-    #[case::relative("tmp/{instrument}/{visit}")]
-    #[case::missing_instrument("/tmp/{visit}")]
-    #[case::missing_visit("/tmp/{instrument}/data")]
-    #[case::invalid_template("/tmp/{nested{placeholder}}")]
-    fn invalid_visit_template(#[case] path: String) {
-        InputTemplate::<VisitTemplate>::parse(Some(Value::String(path))).unwrap_err();
+    /// Record an allocation for a beamline if it is within the limit, otherwise fail with how
+    /// long the client should wait before trying again.
+    fn allocate(&self, beamline: &str) -> async_graphql::Result<()> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let mut allocations = self
+            .allocations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let recent = allocations.entry(beamline.into()).or_default();
+        let now = Instant::now();
+        while recent
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= Self::WINDOW)
+        {
+            recent.pop_front();
+        }
+        match recent.front() {
+            Some(first) if recent.len() >= limit as usize => {
+                let retry_after = Self::WINDOW.saturating_sub(now.duration_since(*first));
+                let retry_ms = retry_after.as_millis().max(1) as u64;
+                Err(async_graphql::Error::new(format!(
+                    "Too many scans allocated for {beamline:?}, retry after {retry_ms}ms"
+                ))
+                .extend_with(|_, ext| {
+                    ext.set("code", "SCAN_RATE_LIMITED");
+                    ext.set("retryAfterMs", retry_ms);
+                }))
+            }
+            _ => {
+                recent.push_back(now);
+                Ok(())
+            }
+        }
     }
+}
 
-    #[rstest::rstest]
-    #[case::absolute("/tmp/{instrument}")]
-    #[case::missing_scan_number("scan_file")]
-    #[case::invalid_template("tmp/{nested{placeholder}}")]
-    fn invalid_scan(#[case] path: String) {
-        InputTemplate::<ScanTemplate>::parse(Some(Value::String(path))).unwrap_err();
+/// Check whether a directory exists. A directory is only reported as missing if its parent
+/// directory can be seen so that a filesystem that is not mounted (or not accessible) to this
+/// service gives an unknown result instead of falsely reporting directories as absent.
+async fn directory_exists(path: &Path) -> Option<bool> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) => Some(meta.is_dir()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = tokio::fs::metadata(path.parent()?).await.ok()?;
+            parent.is_dir().then_some(false)
+        }
+        Err(e) => {
+            debug!(?path, "Unable to check visit directory: {e}");
+            None
+        }
     }
+}
 
-    #[rstest::rstest]
-    #[case::relative("tmp/{instrument}/{visit}")]
-    #[case::missing_scan_number("{detector}")]
-    #[case::missing_detector("{scan_number}")]
-    #[case::invalid_template("tmp/{nested{placeholder}}")]
-    fn invalid_detector_template(#[case] path: String) {
-        InputTemplate::<DetectorTemplate>::parse(Some(Value::String(path))).unwrap_err();
+fn render_context(fields: &BTreeMap<String, String>) -> String {
+    if fields.is_empty() {
+        return "no fields".into();
+    }
+    fields
+        .iter()
+        .map(|(field, value)| format!("{field}={value:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Metrics recorded while converting rendered paths, built once when the schema is created
+#[derive(Debug)]
+struct PathMetrics {
+    non_unicode_paths: Counter<u64>,
+}
+
+impl PathMetrics {
+    fn new(meter: &Meter) -> Self {
+        Self {
+            non_unicode_paths: meter
+                .u64_counter("non_unicode_paths")
+                .with_description(
+                    "Number of generated paths that could not be converted to strings",
+                )
+                .build(),
+        }
+    }
+}
+
+/// Try and convert a path to a string (via `OsString`) using the given separator, returning a
+/// `NonUnicodePath` error if not possible. Failures are counted per beamline so that beamlines
+/// with problematic directory names can be found.
+fn path_to_string(
+    metrics: Option<&PathMetrics>,
+    beamline: &str,
+    path: PathBuf,
+    separator: PathSeparator,
+) -> Result<String, NonUnicodePath> {
+    let path = path.into_os_string().into_string().map_err(|_| {
+        if let Some(metrics) = metrics {
+            metrics
+                .non_unicode_paths
+                .add(1, &[KeyValue::new("beamline", beamline.to_string())]);
+        }
+        NonUnicodePath
+    })?;
+    Ok(match separator {
+        PathSeparator::Slash => path,
+        PathSeparator::Backslash => path.replace('/', "\\"),
+    })
+}
+
+/// Whether a rendered path has an empty segment, eg from a doubled or trailing separator. The
+/// root of an absolute path is not counted as an empty segment.
+fn has_empty_segment(path: &str) -> bool {
+    let relative = path.strip_prefix('/').unwrap_or(path);
+    relative.split('/').any(str::is_empty)
+}
+
+/// Warn if a rendered path has an empty segment, eg `/tmp/i22//cm12345-3` or `i22-123/`. These
+/// are usually caused by a misconfigured template or an empty field value and some tools do not
+/// handle them correctly, but the path is still returned to the client.
+fn check_empty_segments<F: Display>(
+    ctx: &Context<'_>,
+    beamline: &str,
+    template: &PathTemplate<F>,
+    path: &Path,
+) {
+    let path = path.to_string_lossy();
+    if has_empty_segment(&path) {
+        warn!(beamline, %template, %path, "Rendered path has an empty segment");
+        Warnings::raise(
+            ctx,
+            format!("Path {path:?} rendered from template \"{template}\" has an empty segment"),
+        );
+    }
+}
+
+impl Display for NonUnicodePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Path contains non-unicode characters")
+    }
+}
+
+impl Error for NonUnicodePath {}
+
+#[Object]
+impl VisitPath {
+    #[instrument(skip(self))]
+    async fn visit(&self) -> &str {
+        &self.visit
+    }
+    #[instrument(skip(self))]
+    async fn beamline(&self) -> &str {
+        &self.info.name()
+    }
+    #[instrument(skip(self, ctx))]
+    async fn directory(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] separator: PathSeparator,
+    ) -> async_graphql::Result<String> {
+        let template = self.visit_template()?;
+        let (path, fields) = Timings::measure(ctx, "render", || template.render_debug(self));
+        LogRenderContext::from_ctx(ctx).log("visit directory", &path, &fields);
+        check_empty_segments(ctx, self.info.name(), &template, &path);
+        let metrics = ctx.data_opt::<PathMetrics>();
+        Ok(path_to_string(metrics, self.info.name(), path, separator)?)
+    }
+    /// Whether the visit directory exists. Null if the service is not configured to check, or
+    /// if it cannot tell, eg because the filesystem is not available to the service.
+    #[instrument(skip(self, ctx))]
+    async fn exists(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<bool>> {
+        if ctx
+            .data_opt::<VisitDirectoryCheck>()
+            .copied()
+            .unwrap_or_default()
+            == VisitDirectoryCheck::Off
+        {
+            return Ok(None);
+        }
+        Ok(directory_exists(&self.render_directory()?).await)
+    }
+    /// Whether each of the beamline's templates is valid. Invalid templates are reported here
+    /// instead of causing the query to fail so that the visit directory can still be used.
+    #[instrument(skip(self))]
+    async fn template_status(&self) -> TemplateStatus {
+        TemplateStatus {
+            visit: self.configured_visit_template().into(),
+            scan: self.info.scan().into(),
+            detector: self.info.detector().into(),
+        }
+    }
+    /// The time (RFC 3339) used to resolve any time dependent fields in the paths
+    #[instrument(skip(self))]
+    async fn generated_at(&self) -> String {
+        self.now.to_rfc3339()
+    }
+}
+
+impl VisitPath {
+    /// Make sure the visit directory exists if the server requires it
+    async fn check_directory(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        if ctx.data_opt::<VisitDirectoryCheck>() == Some(&VisitDirectoryCheck::Require) {
+            // Errors rendering the directory are left for the directory field to report
+            if let Ok(dir) = self.render_directory() {
+                if directory_exists(&dir).await == Some(false) {
+                    return Err(async_graphql::Error::new(format!(
+                        "Visit directory {dir:?} does not exist"
+                    ))
+                    .extend_with(|_, ext| ext.set("code", "MISSING_VISIT_DIRECTORY")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The visit template configured for the visit's proposal code. A template configured for
+    /// the specific code takes precedence over the commissioning and default templates.
+    fn configured_visit_template(
+        &self,
+    ) -> Result<PathTemplate<BeamlineField>, InvalidPathTemplate> {
+        self.info.visit_for(self.proposal_code().as_deref())
+    }
+
+    /// The template used for the visit directory. Fails if the visit has no session but the
+    /// template requires one.
+    fn visit_template(&self) -> async_graphql::Result<PathTemplate<BeamlineField>> {
+        let template = self
+            .configured_visit_template()
+            .map_err(unconfigured(self.info.name(), "visit"))?;
+        if self.visit.parse::<Proposal>().is_ok()
+            && template
+                .referenced_fields()
+                .any(|f| f == &BeamlineField::Visit)
+        {
+            return Err(async_graphql::Error::new(format!(
+                "Visit template for beamline {:?} requires a session but {:?} does not have one",
+                self.info.name(),
+                self.visit
+            ))
+            .extend_with(|_, ext| ext.set("code", "MISSING_SESSION")));
+        }
+        Ok(template)
+    }
+
+    fn render_directory(&self) -> async_graphql::Result<PathBuf> {
+        Ok(self.visit_template()?.render(self))
+    }
+
+    /// The proposal code of the visit. Visits may be given as just a proposal (eg `cm12345`)
+    /// when only a visit directory that does not depend on the session is needed.
+    fn proposal_code(&self) -> Option<String> {
+        proposal_code(&self.visit)
+    }
+}
+
+/// The proposal code of a visit, or of a proposal without a session
+fn proposal_code(visit: &str) -> Option<String> {
+    visit
+        .parse::<Visit>()
+        .map(|v| v.code)
+        .or_else(|_| visit.parse::<Proposal>().map(|p| p.code))
+        .ok()
+}
+
+/// Reject visits with proposal codes that are not accepted by the server. Visits that cannot
+/// be parsed are left to fail wherever they are used.
+fn check_proposal_code(ctx: &Context<'_>, visit: &str) -> async_graphql::Result<()> {
+    let (Some(codes), Some(code)) = (ctx.data_opt::<ProposalCodes>(), proposal_code(visit)) else {
+        return Ok(());
+    };
+    codes.check(&code).map_err(|e| {
+        async_graphql::Error::new(format!("{e}: {code:?}"))
+            .extend_with(|_, ext| ext.set("code", "UNKNOWN_PROPOSAL_CODE"))
+    })
+}
+
+impl FieldSource<BeamlineField> for VisitPath {
+    fn resolve(&self, field: &BeamlineField) -> Cow<'_, str> {
+        match field {
+            BeamlineField::Year => self
+                .year
+                .or(self.visit_date.map(|date| date.year()))
+                .unwrap_or(self.now.year())
+                .to_string()
+                .into(),
+            BeamlineField::Visit => self.visit.as_str().into(),
+            BeamlineField::Proposal => self
+                .visit
+                .split('-')
+                .next()
+                .expect("There is always one section for a split")
+                .into(),
+            BeamlineField::Instrument => self.info.instrument_name().into(),
+        }
+    }
+}
+
+#[Object]
+impl ScanPaths {
+    /// The visit used to generate this scan information. Should be the same as the visit passed in
+    #[instrument(skip(self))]
+    async fn visit(&self) -> &VisitPath {
+        &self.visit
+    }
+
+    /// The root scan file for this scan. The path has no extension so that the format can be
+    /// chosen by the client.
+    #[instrument(skip(self, ctx))]
+    async fn scan_file(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] separator: PathSeparator,
+    ) -> async_graphql::Result<String> {
+        let template = self
+            .visit
+            .info
+            .scan()
+            .map_err(unconfigured(self.visit.info.name(), "scan"))?;
+        let (path, fields) = Timings::measure(ctx, "render", || template.render_debug(self));
+        LogRenderContext::from_ctx(ctx).log("scan file", &path, &fields);
+        check_empty_segments(ctx, self.visit.info.name(), &template, &path);
+        let metrics = ctx.data_opt::<PathMetrics>();
+        Ok(path_to_string(
+            metrics,
+            self.visit.info.name(),
+            path,
+            separator,
+        )?)
+    }
+
+    /// The scan number for this scan. This should be unique for the requested beamline.
+    #[instrument(skip(self))]
+    async fn scan_number(&self) -> u32 {
+        self.visit.info.scan_number()
+    }
+
+    /// How the scan number is formatted in the scan file path
+    #[instrument(skip(self))]
+    async fn scan_number_format(&self) -> async_graphql::Result<ScanNumberFormat> {
+        ScanNumberFormat::for_beamline(&self.visit.info)
+    }
+
+    /// The time (RFC 3339) used to resolve any time dependent fields in the paths
+    #[instrument(skip(self))]
+    async fn generated_at(&self) -> String {
+        self.visit.now.to_rfc3339()
+    }
+
+    /// The paths where the given detectors should write their files.
+    ///
+    /// Detector names are normalised before being used in file names by replacing any
+    /// non-alphanumeric characters with '_'. Beamlines can be configured to use a different
+    /// replacement, to lowercase names or to replace each character individually instead of
+    /// collapsing runs of them. If there are duplicate names in the list of detectors after
+    /// this normalisation, there will be duplicate paths in the results unless `distinct` is
+    /// set, in which case only the first detector with each normalised name is included.
+    /// Detectors with the same normalised name but a different subdirectory or group cannot
+    /// be made distinct and are rejected.
+    ///
+    /// Detectors given in `requests` can also be written to their own subdirectory or use the
+    /// template of a detector group. Their paths follow those of the detectors in `names`.
+    // TODO: The docs here reference the implementation specific behaviour in the normalisation
+    #[instrument(skip(self, ctx))]
+    async fn detectors(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] names: Vec<Detector>,
+        #[graphql(default)] requests: Vec<DetectorRequest>,
+        #[graphql(default)] separator: PathSeparator,
+        #[graphql(default)] distinct: bool,
+    ) -> async_graphql::Result<Vec<DetectorPath>> {
+        let names = DetectorRequest::all(names, requests);
+        MaxDetectors::from_ctx(ctx).check(names.len())?;
+        for sub in names.iter().filter_map(|det| det.subdirectory.as_ref()) {
+            sub.check(ctx, None)?;
+        }
+        let templates = DetectorTemplates::load(ctx, &self.visit.info, &names).await?;
+        let names = if distinct {
+            self.distinct_detectors(names)?
+        } else {
+            names
+        };
+        self.detector_paths(ctx, &templates, names, separator)
+    }
+}
+
+#[Object]
+impl ConfigChange {
+    /// The name of the configuration field that was changed
+    async fn field(&self) -> &str {
+        &self.field
+    }
+    /// The value before the change, null if it was not set
+    async fn old_value(&self) -> Option<&str> {
+        self.old_value.as_deref()
+    }
+    /// The value after the change, null if it is no longer set
+    async fn new_value(&self) -> Option<&str> {
+        self.new_value.as_deref()
+    }
+    /// The time (RFC 3339, UTC) the change was made
+    async fn changed_at(&self) -> &str {
+        &self.changed_at
+    }
+}
+
+#[Object]
+impl DetectorGroup {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    #[graphql(name = "template")]
+    async fn raw_template(&self) -> async_graphql::Result<String> {
+        Ok(self
+            .template()
+            .map_err(unconfigured(&self.name, "detector group"))?
+            .to_string())
+    }
+}
+
+#[Object]
+impl VisitCodeTemplate {
+    /// The proposal code of visits that use this template
+    async fn code(&self) -> &str {
+        &self.code
+    }
+    #[graphql(name = "template")]
+    async fn raw_template(&self) -> async_graphql::Result<String> {
+        Ok(self
+            .template()
+            .map_err(unconfigured(&self.code, "visit code"))?
+            .to_string())
+    }
+}
+
+#[Object]
+impl FieldChange {
+    /// The name of the configuration field that would be changed
+    async fn field(&self) -> &str {
+        &self.field
+    }
+    /// The current value, null if it is not set
+    async fn old_value(&self) -> Option<&str> {
+        self.old_value.as_deref()
+    }
+    /// The value after the change, null if it would no longer be set
+    async fn new_value(&self) -> Option<&str> {
+        self.new_value.as_deref()
+    }
+}
+
+#[Object]
+impl BeamlineConfiguration {
+    /// The name of the beamline
+    #[graphql(name = "name")]
+    pub async fn beamline_name(&self) -> &str {
+        self.name()
+    }
+    pub async fn visit_template(&self) -> async_graphql::Result<String> {
+        Ok(self
+            .visit()
+            .map_err(unconfigured(self.name(), "visit"))?
+            .to_string())
+    }
+    pub async fn scan_template(&self) -> async_graphql::Result<String> {
+        Ok(self
+            .scan()
+            .map_err(unconfigured(self.name(), "scan"))?
+            .to_string())
+    }
+    pub async fn detector_template(&self) -> async_graphql::Result<String> {
+        Ok(self
+            .detector()
+            .map_err(unconfigured(self.name(), "detector"))?
+            .to_string())
+    }
+    /// The distinct placeholders used by the visit template
+    pub async fn visit_template_fields(&self) -> async_graphql::Result<Vec<String>> {
+        Ok(field_names(
+            self.visit().map_err(unconfigured(self.name(), "visit"))?,
+        ))
+    }
+    /// The distinct placeholders used by the scan template
+    pub async fn scan_template_fields(&self) -> async_graphql::Result<Vec<String>> {
+        Ok(field_names(
+            self.scan().map_err(unconfigured(self.name(), "scan"))?,
+        ))
+    }
+    /// The distinct placeholders used by the detector template
+    pub async fn detector_template_fields(&self) -> async_graphql::Result<Vec<String>> {
+        Ok(field_names(
+            self.detector()
+                .map_err(unconfigured(self.name(), "detector"))?,
+        ))
+    }
+    pub async fn latest_scan_number(&self) -> async_graphql::Result<u32> {
+        Ok(self.scan_number())
+    }
+    /// The instrument used for the `{instrument}` field of templates. This is the beamline name
+    /// unless a different instrument has been configured.
+    #[graphql(name = "instrument")]
+    pub async fn path_instrument(&self) -> &str {
+        self.instrument_name()
+    }
+    /// The extension used for files in the fallback tracker directory
+    pub async fn tracker_file_extension(&self) -> &str {
+        self.tracker_extension()
+    }
+    /// Whether the tracker file extension is defaulted to the beamline name because no
+    /// extension has been configured
+    pub async fn tracker_file_extension_defaulted(&self) -> bool {
+        self.extension().is_none()
+    }
+    /// The visit template used for visits with one of the commissioning proposal codes
+    pub async fn commissioning_visit_template(&self) -> async_graphql::Result<Option<String>> {
+        Ok(self
+            .commissioning_visit()
+            .transpose()
+            .map_err(unconfigured(self.name(), "commissioning visit"))?
+            .map(|t| t.to_string()))
+    }
+    /// The proposal codes that use the commissioning visit template if one is configured
+    pub async fn commissioning_proposal_codes(&self) -> Vec<&str> {
+        self.commissioning_codes().collect()
+    }
+    /// Whether detector names are converted to lowercase
+    pub async fn detector_lowercase(&self) -> bool {
+        self.detector_normalisation().lowercase
+    }
+    /// Whether runs of invalid characters in detector names are collapsed into one replacement
+    pub async fn detector_collapse(&self) -> bool {
+        self.detector_normalisation().collapse
+    }
+    /// The character used in place of invalid characters in detector names
+    pub async fn detector_replacement(&self) -> String {
+        self.detector_normalisation().replacement.into()
+    }
+    /// Whether detector names containing invalid characters are rejected instead of replaced
+    #[graphql(name = "detectorRejectInvalid")]
+    async fn rejects_invalid_detectors(&self) -> bool {
+        self.detector_reject_invalid()
+    }
+    /// How scan numbers are formatted by the beamline's scan template
+    async fn scan_number_format(&self) -> async_graphql::Result<ScanNumberFormat> {
+        ScanNumberFormat::for_beamline(self)
+    }
+    /// The number that scan numbers for this beamline will always be above, if any
+    #[graphql(name = "scanNumberFloor")]
+    pub async fn floor(&self) -> Option<u32> {
+        self.scan_number_floor()
+    }
+    /// The amount the scan number advances by for each scan
+    #[graphql(name = "scanNumberStep")]
+    pub async fn step(&self) -> u32 {
+        self.scan_number_step()
+    }
+    /// Whether the beamline's fallback tracker directory currently exists and can be read. This
+    /// may be up to a few seconds out of date.
+    pub async fn tracker_directory_status(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<DirectoryStatus> {
+        Ok(ctx
+            .data::<NumTracker>()?
+            .directory_status(self.name())
+            .await)
+    }
+    /// Alternative detector templates used for named groups of detectors
+    pub async fn detector_groups(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<DetectorGroup>> {
+        Ok(ctx
+            .data::<SqliteScanPathService>()?
+            .detector_groups(self.name())
+            .await?)
+    }
+    /// Alternative visit templates used for visits with specific proposal codes
+    #[graphql(name = "visitCodeTemplates")]
+    pub async fn code_templates(&self) -> &[VisitCodeTemplate] {
+        self.visit_code_templates()
+    }
+}
+
+impl ScanPaths {
+    /// Render the paths for each of the given detectors using the template for each detector's
+    /// group, or the default detector template for detectors without a group
+    fn detector_paths(
+        &self,
+        ctx: &Context<'_>,
+        templates: &DetectorTemplates,
+        names: Vec<DetectorRequest>,
+        separator: PathSeparator,
+    ) -> async_graphql::Result<Vec<DetectorPath>> {
+        let log = LogRenderContext::from_ctx(ctx);
+        let metrics = ctx.data_opt::<PathMetrics>();
+        let rules = self.visit.info.detector_normalisation();
+        names
+            .into_iter()
+            .map(|name| {
+                check_detector_name(&self.visit.info, name.as_str())?;
+                let template = templates.for_detector(self.visit.info.name(), &name)?;
+                let normalised = rules.apply(name.as_str());
+                let (path, fields) = Timings::measure(ctx, "render", || {
+                    template.render_debug(&(normalised.as_str(), self))
+                });
+                let path = name.place(path);
+                log.log("detector file", &path, &fields);
+                check_empty_segments(ctx, self.visit.info.name(), template, &path);
+                Ok(DetectorPath {
+                    name: normalised,
+                    path: path_to_string(metrics, self.visit.info.name(), path, separator)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Remove any detectors that duplicate an earlier detector. Detectors whose normalised name
+    /// is the same as an earlier detector's but whose options differ are rejected.
+    fn distinct_detectors(
+        &self,
+        names: Vec<DetectorRequest>,
+    ) -> async_graphql::Result<Vec<DetectorRequest>> {
+        let rules = self.visit.info.detector_normalisation();
+        let mut seen = HashMap::new();
+        let mut distinct = Vec::new();
+        for det in names {
+            let options = (
+                det.subdirectory.as_ref().map(|sub| sub.0.clone()),
+                det.group.clone(),
+            );
+            match seen.entry(rules.apply(det.as_str())) {
+                Entry::Vacant(entry) => {
+                    entry.insert(options);
+                    distinct.push(det);
+                }
+                Entry::Occupied(entry) if *entry.get() == options => {}
+                Entry::Occupied(entry) => {
+                    return Err(async_graphql::Error::new(format!(
+                        "Detector {:?} is requested more than once with different options",
+                        entry.key()
+                    ))
+                    .extend_with(|_, ext| ext.set("code", "CONFLICTING_DETECTORS")));
+                }
+            }
+        }
+        Ok(distinct)
+    }
+
+    /// Find any segments of the subdirectory that duplicate segments generated by the scan
+    /// template itself, eg a subdirectory named for the scan number.
+    fn subdirectory_overlap(&self, template: &PathTemplate<ScanField>) -> Vec<String> {
+        let generated = template
+            .segments()
+            .filter(|seg| {
+                !seg.referenced_fields()
+                    .any(|f| f == &ScanField::Subdirectory)
+            })
+            .map(|seg| seg.render(self))
+            .collect::<HashSet<_>>();
+        self.subdirectory
+            .segments()
+            .filter(|seg| generated.contains(*seg))
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl FieldSource<ScanField> for ScanPaths {
+    fn resolve(&self, field: &ScanField) -> Cow<'_, str> {
+        match field {
+            ScanField::Subdirectory => self.subdirectory.to_string().into(),
+            ScanField::ScanNumber(radix) => radix.format(self.visit.info.scan_number()).into(),
+            ScanField::Beamline(bl) => self.visit.resolve(bl),
+        }
+    }
+}
+
+impl FieldSource<DetectorField> for (&str, &ScanPaths) {
+    fn resolve(&self, field: &DetectorField) -> Cow<'_, str> {
+        match field {
+            DetectorField::Detector => self.0.into(),
+            DetectorField::Scan(s) => self.1.resolve(s),
+        }
+    }
+}
+
+#[Object]
+impl Query {
+    #[instrument(skip(self, ctx))]
+    async fn paths(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+        visit: String,
+        visit_date: Option<VisitDate>,
+        year: Option<i32>,
+    ) -> async_graphql::Result<VisitPath> {
+        let info = served_configuration(ctx, &beamline).await?;
+        let visit_date = visit_date.map(|d| d.0);
+        check_year_override(ctx, &beamline, year, visit_date).await?;
+        check_proposal_code(ctx, &visit)?;
+        let year = year.or_else(|| overlay_year(ctx, &beamline));
+        let paths = VisitPath {
+            visit,
+            info,
+            now: now(ctx)?,
+            visit_date,
+            year,
+        };
+        paths.check_directory(ctx).await?;
+        Ok(paths)
+    }
+
+    /// Get the visit paths for many beamline/visit pairs at once. The configuration for each
+    /// beamline is only read once. Results are in the same order as the requests and any that
+    /// fail (eg for unknown beamlines) are returned as errors in place of their paths.
+    #[instrument(skip(self, ctx))]
+    async fn paths_batch(
+        &self,
+        ctx: &Context<'_>,
+        requests: Vec<VisitRequest>,
+        visit_date: Option<VisitDate>,
+        year: Option<i32>,
+    ) -> async_graphql::Result<Vec<BatchVisitPath>> {
+        let visit_date = visit_date.map(|d| d.0);
+        let now = now(ctx)?;
+        let mut configs = HashMap::new();
+        for request in &requests {
+            if configs.contains_key(&request.beamline) {
+                continue;
+            }
+            let info = match served_configuration(ctx, &request.beamline).await {
+                Ok(info) => check_year_override(ctx, &request.beamline, year, visit_date)
+                    .await
+                    .map(|_| info),
+                Err(e) => Err(e),
+            };
+            configs.insert(request.beamline.clone(), info);
+        }
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            let paths = match &configs[&request.beamline] {
+                Ok(info) => VisitPath {
+                    visit: request.visit.clone(),
+                    info: info.clone(),
+                    now,
+                    visit_date,
+                    year: year.or_else(|| overlay_year(ctx, &request.beamline)),
+                },
+                Err(e) => {
+                    results.push(BatchVisitPath::Failed(BatchError::new(request, e)));
+                    continue;
+                }
+            };
+            let checked = match check_proposal_code(ctx, &paths.visit) {
+                Ok(()) => paths.check_directory(ctx).await,
+                Err(e) => Err(e),
+            };
+            results.push(match checked {
+                Ok(()) => BatchVisitPath::Paths(Box::new(paths)),
+                Err(e) => BatchVisitPath::Failed(BatchError::new(request, &e)),
+            });
+        }
+        Ok(results)
+    }
+
+    #[instrument(skip(self, ctx))]
+    async fn configuration(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+    ) -> async_graphql::Result<BeamlineConfiguration> {
+        check_auth(ctx, Permission::Admin(&beamline)).await?;
+        trace!("Getting config for {beamline:?}");
+        served_configuration(ctx, &beamline).await
+    }
+
+    /// The scan number the next scan on a beamline would be given if it were requested now.
+    /// This does not allocate the number and is not authoritative: another client may have
+    /// taken it by the time a scan is requested. Useful for displaying the upcoming number.
+    ///
+    /// Like the path queries, this needs no authorization. The tracker directory is read
+    /// without waiting for scans that are being allocated.
+    #[instrument(skip(self, ctx))]
+    async fn next_scan_number(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+    ) -> async_graphql::Result<u32> {
+        let nt = ctx.data::<NumTracker>()?;
+        let current = served_configuration(ctx, &beamline).await?;
+        let prev = nt
+            .peek_latest(&beamline, current.extension())
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Failed to read fallback tracker directory: {e}");
+                None
+            });
+        current
+            .scan_number()
+            .max(prev.unwrap_or(0))
+            .max(current.scan_number_floor().unwrap_or(0))
+            .checked_add(current.scan_number_step())
+            .ok_or_else(|| NextScanError::Overflow(beamline).extend())
+    }
+
+    /// Resolve a beamline configuration entity referenced by another federated subgraph. This
+    /// is only reachable through the federation `_entities` query.
+    #[graphql(entity)]
+    #[instrument(skip(self, ctx))]
+    async fn find_beamline_by_name(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<BeamlineConfiguration> {
+        self.configuration(ctx, name).await
+    }
+
+    /// The most recent changes made to a beamline's configuration, newest first
+    #[instrument(skip(self, ctx))]
+    async fn configuration_history(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+        #[graphql(default = 20)] limit: u32,
+    ) -> async_graphql::Result<Vec<ConfigChange>> {
+        check_auth(ctx, Permission::Admin(&beamline)).await?;
+        ServedBeamlines::check(ctx, &beamline)?;
+        let db = ctx.data::<SqliteScanPathService>()?;
+        Ok(db.configuration_changes(&beamline, limit).await?)
+    }
+
+    /// The changes that configuring a beamline would make, without making them
+    #[instrument(skip(self, ctx))]
+    async fn configuration_preview(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+        config: ConfigurationUpdates,
+    ) -> async_graphql::Result<Vec<FieldChange>> {
+        check_auth(ctx, Permission::Admin(&beamline)).await?;
+        ServedBeamlines::check(ctx, &beamline)?;
+        let upd = config.into_update(beamline)?;
+        check_policies(ctx, &upd)?;
+        let db = ctx.data::<SqliteScanPathService>()?;
+        upd.dry_run(db)
+            .await?
+            .ok_or_else(|| ConfigurationError::MissingBeamline(upd.name).extend())
+    }
+
+    /// Get the paths for a scan that has already been allocated a scan number. This does not
+    /// allocate a new scan number.
+    #[instrument(skip(self, ctx))]
+    #[allow(clippy::too_many_arguments)]
+    async fn scan_paths(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+        visit: String,
+        scan_number: u32,
+        sub: Option<Subdirectory>,
+        visit_date: Option<VisitDate>,
+        year: Option<i32>,
+        subdirectory_rules: Option<SubdirectoryRules>,
+    ) -> async_graphql::Result<ScanPaths> {
+        if let Some(sub) = &sub {
+            sub.check(ctx, subdirectory_rules.as_ref())?;
+        }
+        let info = served_configuration(ctx, &beamline)
+            .await?
+            .with_scan_number(scan_number);
+        let visit_date = visit_date.map(|d| d.0);
+        check_year_override(ctx, &beamline, year, visit_date).await?;
+        check_proposal_code(ctx, &visit)?;
+        let year = year.or_else(|| overlay_year(ctx, &beamline));
+        Ok(ScanPaths {
+            visit: VisitPath {
+                visit,
+                info,
+                now: now(ctx)?,
+                visit_date,
+                year,
+            },
+            subdirectory: sub.unwrap_or_default(),
+        })
+    }
+
+    /// Get the detector paths for multiple scans that have already been allocated scan numbers.
+    /// The beamline configuration is only read once for all scans and no new scan numbers are
+    /// allocated.
+    #[instrument(skip(self, ctx))]
+    #[allow(clippy::too_many_arguments)]
+    async fn scan_detector_paths(
+        &self,
+        ctx: &Context<'_>,
+        beamline: String,
+        visit: String,
+        scans: Vec<ScanDetectors>,
+        visit_date: Option<VisitDate>,
+        year: Option<i32>,
+        #[graphql(default)] separator: PathSeparator,
+        subdirectory_rules: Option<SubdirectoryRules>,
+    ) -> async_graphql::Result<Vec<ScanDetectorPaths>> {
+        let scans = scans
+            .into_iter()
+            .map(|scan| {
+                let detectors = DetectorRequest::all(scan.detectors, scan.requests);
+                (scan.scan_number, scan.sub, detectors)
+            })
+            .collect::<Vec<_>>();
+        MaxDetectors::from_ctx(ctx).check(scans.iter().map(|(_, _, dets)| dets.len()).sum())?;
+        let detector_subs = scans
+            .iter()
+            .flat_map(|(_, _, dets)| dets)
+            .filter_map(|det| det.subdirectory.as_ref());
+        for sub in scans
+            .iter()
+            .filter_map(|(_, sub, _)| sub.as_ref())
+            .chain(detector_subs)
+        {
+            sub.check(ctx, subdirectory_rules.as_ref())?;
+        }
+        let info = served_configuration(ctx, &beamline).await?;
+        let visit_date = visit_date.map(|d| d.0);
+        check_year_override(ctx, &beamline, year, visit_date).await?;
+        check_proposal_code(ctx, &visit)?;
+        let year = year.or_else(|| overlay_year(ctx, &beamline));
+        let templates =
+            DetectorTemplates::load(ctx, &info, scans.iter().flat_map(|(_, _, dets)| dets)).await?;
+        let now = now(ctx)?;
+        scans
+            .into_iter()
+            .map(|(scan_number, sub, detectors)| {
+                let paths = ScanPaths {
+                    visit: VisitPath {
+                        visit: visit.clone(),
+                        info: info.clone().with_scan_number(scan_number),
+                        now,
+                        visit_date,
+                        year,
+                    },
+                    subdirectory: sub.unwrap_or_default(),
+                };
+                paths
+                    .detector_paths(ctx, &templates, detectors, separator)
+                    .map(|detectors| ScanDetectorPaths {
+                        scan_number,
+                        detectors,
+                    })
+            })
+            .collect()
+    }
+
+    /// The health of the service's dependencies. Each check is time limited so this can be
+    /// used for a status page even when some dependencies are not responding.
+    #[instrument(skip(self, ctx))]
+    async fn health(&self, ctx: &Context<'_>) -> async_graphql::Result<Health> {
+        let db = ctx.data::<SqliteScanPathService>()?;
+        let policy = ctx
+            .data_opt::<Option<PolicyCheck>>()
+            .and_then(Option::as_ref);
+        let nt = ctx.data_opt::<NumTracker>();
+        Ok(Health::check(db, policy, nt, ctx.data_opt::<ServedBeamlines>()).await)
+    }
+
+    /// The names of all configured beamlines
+    #[instrument(skip(self, ctx))]
+    async fn beamlines(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<String>> {
+        let db = ctx.data::<SqliteScanPathService>()?;
+        Ok(db
+            .beamlines()
+            .await?
+            .iter()
+            .filter(|bl| ServedBeamlines::serves(ctx, bl))
+            .cloned()
+            .collect())
+    }
+
+    /// Show how detector names would be normalised before they are used in file names, using
+    /// the rules configured for a beamline or the default rules if no beamline is given. Names
+    /// that would collide with another name in the list after normalisation are flagged.
+    #[graphql(name = "normalizeDetectors")]
+    #[instrument(skip(self, ctx))]
+    async fn normalise_detectors(
+        &self,
+        ctx: &Context<'_>,
+        names: Vec<String>,
+        beamline: Option<String>,
+    ) -> async_graphql::Result<Vec<NormalisedDetector>> {
+        MaxDetectors::from_ctx(ctx).check(names.len())?;
+        let rules = match beamline {
+            Some(bl) => served_configuration(ctx, &bl)
+                .await?
+                .detector_normalisation(),
+            None => DetectorNormalisation::default(),
+        };
+        let normalised = names
+            .into_iter()
+            .map(|name| {
+                let normalised = rules.apply(&name);
+                (name, normalised)
+            })
+            .collect::<Vec<_>>();
+        let mut counts = HashMap::<&str, usize>::new();
+        for (_, norm) in &normalised {
+            *counts.entry(norm).or_default() += 1;
+        }
+        Ok(normalised
+            .iter()
+            .map(|(name, norm)| NormalisedDetector {
+                name: name.clone(),
+                normalised: norm.clone(),
+                collision: counts[norm.as_str()] > 1,
+            })
+            .collect())
+    }
+
+    /// Check whether a visit string is valid without using it to generate any paths
+    #[instrument(skip(self, ctx))]
+    async fn validate_visit(&self, ctx: &Context<'_>, visit: String) -> VisitValidation {
+        VisitValidation::check(ctx.data_opt(), &visit)
+    }
+
+    /// Check whether each of many visit strings is valid. Results are in the same order as the
+    /// given visits.
+    #[instrument(skip(self, ctx))]
+    async fn validate_visits(
+        &self,
+        ctx: &Context<'_>,
+        visits: Vec<String>,
+    ) -> Vec<VisitValidation> {
+        let codes = ctx.data_opt::<ProposalCodes>();
+        visits
+            .iter()
+            .map(|visit| VisitValidation::check(codes, visit))
+            .collect()
+    }
+
+    /// Render a candidate template using sample values for its placeholders. The template is
+    /// validated in the same way as when it is configured for a beamline but nothing is read
+    /// from or written to the database.
+    #[instrument(skip(self))]
+    async fn render_template(
+        &self,
+        kind: TemplateKind,
+        template: String,
+        sample_context: Vec<SampleField>,
+    ) -> TemplateRender {
+        let sample = sample_context
+            .into_iter()
+            .map(|f| (f.field, f.value))
+            .collect::<SampleContext>();
+        match sample.render_as(kind, &template) {
+            Ok(path) => TemplateRender::Rendered(RenderedTemplate { path }),
+            Err(message) => TemplateRender::Invalid(InvalidTemplateDetails { message }),
+        }
+    }
+}
+
+/// Fail if a beamline rejects invalid detector names and the given name would be changed by
+/// normalisation
+fn check_detector_name(info: &BeamlineConfiguration, name: &str) -> async_graphql::Result<()> {
+    if info.detector_reject_invalid() && info.detector_normalisation().rewrites(name) {
+        return Err(async_graphql::Error::new(format!(
+            "Detector name {name:?} contains invalid characters"
+        ))
+        .extend_with(|_, ext| ext.set("code", "INVALID_DETECTOR")));
+    }
+    Ok(())
+}
+
+/// Check the detectors requested from the `detectors` field of a scan before its number is
+/// allocated so that an invalid name or subdirectory does not use up a scan number. Detectors
+/// that cannot be parsed are left for the field itself to report.
+fn check_requested_detectors(
+    ctx: &Context<'_>,
+    info: &BeamlineConfiguration,
+) -> async_graphql::Result<()> {
+    for field in ctx.field().selection_set() {
+        if field.name() != "detectors" {
+            continue;
+        }
+        let mut args = field.arguments()?;
+        let mut arg = |name: &str| {
+            args.iter()
+                .position(|(arg, _)| arg == name)
+                .map(|i| args.swap_remove(i).1)
+        };
+        let names = Vec::<Detector>::parse(arg("names")).unwrap_or_default();
+        let requests = Vec::<DetectorRequest>::parse(arg("requests")).unwrap_or_default();
+        for detector in DetectorRequest::all(names, requests) {
+            check_detector_name(info, detector.as_str())?;
+            if let Some(sub) = &detector.subdirectory {
+                sub.check(ctx, None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Allocate the next scan number for a visit and build the paths for it
+async fn allocate_scan(
+    ctx: &Context<'_>,
+    current: BeamlineConfiguration,
+    beamline: String,
+    visit: String,
+    sub: Option<Subdirectory>,
+    visit_date: Option<NaiveDate>,
+    year: Option<i32>,
+) -> async_graphql::Result<ScanPaths> {
+    if let Some(rate_limit) = ctx.data_opt::<ScanRateLimit>() {
+        rate_limit.allocate(&beamline)?;
+    }
+    let db = ctx.data::<SqliteScanPathService>()?;
+    let nt = ctx.data::<NumTracker>()?;
+    // There is a race condition here if a process increments the file
+    // while the DB is being queried or between the two queries but there
+    // isn't much we can do from here.
+    let mut dir = nt.for_beamline(&beamline, current.extension()).await?;
+    let missing_tracker_directory = *ctx.data::<MissingTrackerDirectory>()?;
+    if !dir.has_directory() {
+        let msg = format!("Beamline {beamline:?} does not have a tracker directory");
+        match missing_tracker_directory {
+            MissingTrackerDirectory::Allow => {}
+            MissingTrackerDirectory::Warn => {
+                warn!("{msg}");
+                Warnings::raise(ctx, msg);
+            }
+            MissingTrackerDirectory::Deny => {
+                return Err(async_graphql::Error::new(msg)
+                    .extend_with(|_, ext| ext.set("code", "MISSING_TRACKER_DIRECTORY")))
+            }
+        }
+    }
+
+    // The DB is the source of truth so an unreadable tracker directory should not prevent
+    // a scan number being allocated.
+    let prev = dir.prev().await.unwrap_or_else(|e| {
+        warn!("Failed to read fallback tracker directory: {e}");
+        None
+    });
+    let next_scan = if missing_tracker_directory == MissingTrackerDirectory::Deny {
+        // If tracker directories are required, other processes may rely on them being up to
+        // date so failing to write one is an error rather than a warning. The number is only
+        // committed once the tracker has been updated so a failed write does not use it up.
+        let pending = Timings::time(ctx, "db", db.reserve_next_scan(&beamline, &visit, prev))
+            .await
+            .extend()?;
+        if let Err(e) = dir.set(pending.scan_number()).await {
+            return Err(NextScanError::Tracker(e).extend());
+        }
+        Timings::time(ctx, "db", pending.commit()).await.extend()?
+    } else {
+        let next_scan = Timings::time(
+            ctx,
+            "db",
+            db.next_scan_configuration(&beamline, &visit, prev),
+        )
+        .await
+        .extend()?;
+        if let Err(e) = dir.set(next_scan.scan_number()).await {
+            warn!("Failed to increment fallback tracker directory: {e}");
+        }
+        next_scan
+    };
+
+    let paths = ScanPaths {
+        visit: VisitPath {
+            visit,
+            info: next_scan,
+            now: now(ctx)?,
+            visit_date,
+            year,
+        },
+        subdirectory: sub.unwrap_or_default(),
+    };
+    Ok(paths)
+}
+
+/// Warn about any subdirectory segments that the scan template already generates
+fn warn_subdirectory_overlap(ctx: &Context<'_>, paths: &ScanPaths) {
+    if let Ok(template) = paths.visit.info.scan() {
+        for seg in paths.subdirectory_overlap(&template) {
+            Warnings::raise(
+                ctx,
+                format!(
+                    "Subdirectory segment {seg:?} duplicates a segment generated by the scan template"
+                ),
+            );
+        }
+    }
+}
+
+#[Object]
+impl Mutation {
+    /// Access scan file locations for the next scan
+    ///
+    /// If an idempotency key is given, repeating the request with the same key returns the
+    /// scan allocated by the first request instead of allocating another. Reusing a key for a
+    /// request with a different visit, subdirectory, year or visit date is an error. Keys are
+    /// only remembered for a limited time.
+    ///
+    /// If `checkOverlap` is set, a warning is returned for each segment of the subdirectory that
+    /// duplicates a segment the scan template already generates.
+    #[instrument(skip(self, ctx))]
+    #[allow(clippy::too_many_arguments)]
+    async fn scan<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        beamline: String,
+        visit: String,
+        sub: Option<Subdirectory>,
+        visit_date: Option<VisitDate>,
+        year: Option<i32>,
+        idempotency_key: Option<String>,
+        subdirectory_rules: Option<SubdirectoryRules>,
+        #[graphql(default)] check_overlap: bool,
+    ) -> async_graphql::Result<ScanPaths> {
+        ReadOnly::check(ctx)?;
+        // Reject invalid subdirectories before a scan number is used
+        if let Some(sub) = &sub {
+            sub.check(ctx, subdirectory_rules.as_ref())?;
+        }
+        // Check the beamline exists before authorizing so that unknown beamlines fail quickly
+        // without a round trip to the policy server.
+        let current = served_configuration(ctx, &beamline).await?;
+        check_auth(
+            ctx,
+            Permission::Visit {
+                beamline: &beamline,
+                visit: &visit,
+            },
+        )
+        .await?;
+        let visit_date = visit_date.map(|d| d.0);
+        check_year_override(ctx, &beamline, year, visit_date).await?;
+        check_proposal_code(ctx, &visit)?;
+        let year = year.or_else(|| overlay_year(ctx, &beamline));
+        check_requested_detectors(ctx, &current)?;
+        let paths = match idempotency_key {
+            Some(key) => {
+                let parameters = ScanParameters {
+                    visit: visit.clone(),
+                    sub: sub.as_ref().map(|s| s.0.clone()).unwrap_or_default(),
+                    visit_date,
+                    year,
+                };
+                let scan = ctx.data::<IdempotencyKeys>()?.scan(&beamline, &key);
+                let mut allocated = false;
+                let keyed = scan
+                    .get_or_try_init(|| async {
+                        allocated = true;
+                        let start = Warnings::count(ctx);
+                        let paths =
+                            allocate_scan(ctx, current, beamline, visit, sub, visit_date, year)
+                                .await?;
+                        Ok::<_, async_graphql::Error>(KeyedScan {
+                            parameters: parameters.clone(),
+                            paths,
+                            warnings: Warnings::since(ctx, start),
+                        })
+                    })
+                    .await?;
+                if keyed.parameters != parameters {
+                    return Err(async_graphql::Error::new(format!(
+                        "Idempotency key {key:?} was already used for a different scan request"
+                    ))
+                    .extend_with(|_, ext| ext.set("code", "IDEMPOTENCY_KEY_REUSED")));
+                }
+                if !allocated {
+                    for warning in &keyed.warnings {
+                        Warnings::raise(ctx, warning.clone());
+                    }
+                }
+                keyed.paths.clone()
+            }
+            None => allocate_scan(ctx, current, beamline, visit, sub, visit_date, year).await?,
+        };
+        if check_overlap {
+            warn_subdirectory_overlap(ctx, &paths);
+        }
+        Ok(paths)
+    }
+
+    #[instrument(skip(self, ctx))]
+    async fn configure<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        beamline: String,
+        config: ConfigurationUpdates,
+    ) -> async_graphql::Result<BeamlineConfiguration> {
+        ReadOnly::check(ctx)?;
+        check_auth(ctx, Permission::Admin(&beamline)).await?;
+        ServedBeamlines::check(ctx, &beamline)?;
+        let db = ctx.data::<SqliteScanPathService>()?;
+        trace!("Configuring: {beamline}: {config:?}");
+        let upd = config.into_update(beamline)?;
+        check_policies(ctx, &upd)?;
+        match upd.update_beamline(db).await.extend()? {
+            Some(bc) => Ok(bc),
+            None => Ok(upd.insert_new(db).await?),
+        }
+    }
+}
+
+/// The current configuration of a beamline, failing as if the beamline does not exist if it is
+/// not served by this server
+async fn served_configuration(
+    ctx: &Context<'_>,
+    beamline: &str,
+) -> async_graphql::Result<BeamlineConfiguration> {
+    ServedBeamlines::check(ctx, beamline)?;
+    let db = ctx.data::<SqliteScanPathService>()?;
+    Timings::time(ctx, "db", db.current_configuration(beamline))
+        .await
+        .extend()
+}
+
+/// The beamlines this server answers for. Any beamline is served if none are given.
+#[derive(Debug, Default)]
+struct ServedBeamlines(HashSet<String>);
+
+impl ServedBeamlines {
+    fn new(beamlines: impl IntoIterator<Item = String>) -> Self {
+        Self(beamlines.into_iter().collect())
+    }
+
+    fn contains(&self, beamline: &str) -> bool {
+        self.0.is_empty() || self.0.contains(beamline)
+    }
+
+    fn serves(ctx: &Context<'_>, beamline: &str) -> bool {
+        ctx.data_opt::<Self>()
+            .is_none_or(|served| served.contains(beamline))
+    }
+
+    /// Fail with the same error as a beamline missing from the DB if the beamline is not served
+    fn check(ctx: &Context<'_>, beamline: &str) -> async_graphql::Result<()> {
+        if Self::serves(ctx, beamline) {
+            Ok(())
+        } else {
+            Err(ConfigurationError::MissingBeamline(beamline.into()).extend())
+        }
+    }
+}
+
+/// The rights a client needs to make a request
+#[derive(Debug, Clone, Copy)]
+enum Permission<'a> {
+    /// Access to a specific visit on a beamline
+    Visit { beamline: &'a str, visit: &'a str },
+    /// Admin rights for a beamline
+    Admin(&'a str),
+}
+
+async fn check_auth(ctx: &Context<'_>, permission: Permission<'_>) -> async_graphql::Result<()> {
+    if let Some(policy) = ctx.data::<Option<PolicyCheck>>()? {
+        trace!("Auth enabled: checking token");
+        let token = ctx.data::<Option<Authorization<Bearer>>>()?.as_ref();
+        let check = async {
+            match permission {
+                Permission::Visit { beamline, visit } => {
+                    policy.check_access(token, beamline, visit).await
+                }
+                Permission::Admin(beamline) => policy.check_admin(token, beamline).await,
+            }
+        };
+        Timings::time(ctx, "auth", check)
+            .await
+            .inspect_err(|e| info!("Authorization failed: {e:?}"))
+            .map_err(async_graphql::Error::from)
+    } else {
+        trace!("No authorization configured");
+        Ok(())
+    }
+}
+
+/// Overriding the year used in paths could misroute live data so it is only permitted if the
+/// client has admin rights for the beamline. This applies to a year given directly and to one
+/// taken from the visit date. Years that match the current year are not an override.
+async fn check_year_override(
+    ctx: &Context<'_>,
+    beamline: &str,
+    year: Option<i32>,
+    visit_date: Option<NaiveDate>,
+) -> async_graphql::Result<()> {
+    match year.or_else(|| visit_date.map(|date| date.year())) {
+        None => Ok(()),
+        Some(year) if year == now(ctx)?.year() => Ok(()),
+        Some(year) => {
+            debug!(year, "Checking permission to override year");
+            check_auth(ctx, Permission::Admin(beamline)).await
+        }
+    }
+}
+
+/// The year configured for a beamline by the server's config overlay, if any. This is used in
+/// place of the current year but does not override a year requested by the client.
+fn overlay_year(ctx: &Context<'_>, beamline: &str) -> Option<i32> {
+    ctx.data_opt::<ConfigOverlay>()
+        .and_then(|overlay| overlay.year(beamline))
+}
+
+#[derive(Debug, InputObject)]
+struct ConfigurationUpdates {
+    visit: Option<InputTemplate<VisitTemplate>>,
+    scan: Option<InputTemplate<ScanTemplate>>,
+    detector: Option<InputTemplate<DetectorTemplate>>,
+    scan_number: Option<u32>,
+    extension: Option<TrackerExtension>,
+    /// The instrument used for the `{instrument}` field of templates if it is not the same as
+    /// the beamline name
+    instrument: Option<Instrument>,
+    /// Alternative visit template used for commissioning visits
+    commissioning_visit: Option<InputTemplate<VisitTemplate>>,
+    /// The proposal codes that should use the commissioning visit template (default: cm)
+    commissioning_codes: Option<Vec<String>>,
+    /// Convert detector names to lowercase (default: false)
+    detector_lowercase: Option<bool>,
+    /// Collapse runs of invalid characters in detector names into a single replacement
+    /// (default: true)
+    detector_collapse: Option<bool>,
+    /// The character used in place of invalid characters in detector names (default: _)
+    detector_replacement: Option<Replacement>,
+    /// Reject detector names containing invalid characters instead of replacing them, so that
+    /// clients have to correct them (default: false)
+    detector_reject_invalid: Option<bool>,
+    /// The minimum scan number for the beamline. The next scan number will always be above this
+    /// but existing numbers higher than this are not affected.
+    scan_number_floor: Option<u32>,
+    /// The amount the scan number advances by for each scan, eg to leave gaps for numbers
+    /// allocated by another system (default: 1). Must be positive.
+    scan_number_step: Option<u32>,
+    /// Alternative detector templates for named groups of detectors. Groups that are not
+    /// included are left unchanged.
+    detector_groups: Option<Vec<DetectorGroupTemplate>>,
+    /// Alternative visit templates for visits with specific proposal codes. These take
+    /// precedence over the commissioning visit template. A code with a null template has its
+    /// template removed and codes that are not included are left unchanged.
+    visit_code_templates: Option<Vec<ProposalCodeTemplate>>,
+}
+
+/// A visit template used for visits with a specific proposal code
+#[derive(Debug, InputObject)]
+struct ProposalCodeTemplate {
+    code: String,
+    /// The template to use for the code, or null to remove the code's template
+    template: Option<InputTemplate<VisitTemplate>>,
+}
+
+/// A detector template used for a named group of detectors
+#[derive(Debug, InputObject)]
+struct DetectorGroupTemplate {
+    name: String,
+    template: InputTemplate<DetectorTemplate>,
+}
+
+impl ConfigurationUpdates {
+    /// Convert into an update for the DB. Every invalid template is reported in a single error
+    /// (with the details of each in the `errors` extension) so that a client can fix them all
+    /// at once.
+    fn into_update(self, name: String) -> async_graphql::Result<BeamlineConfigurationUpdate> {
+        let mut errors = Vec::new();
+        let visit = self.visit.and_then(|t| t.checked("visit", &mut errors));
+        let scan = self.scan.and_then(|t| t.checked("scan", &mut errors));
+        let detector = self
+            .detector
+            .and_then(|t| t.checked("detector", &mut errors));
+        let commissioning_visit = self
+            .commissioning_visit
+            .and_then(|t| t.checked("commissioningVisit", &mut errors));
+        let detector_groups = self.detector_groups.map(|groups| {
+            groups
+                .into_iter()
+                .filter_map(|group| {
+                    let field = format!("detectorGroups.{}", group.name);
+                    let template = group.template.checked(&field, &mut errors)?;
+                    Some((group.name, template))
+                })
+                .collect()
+        });
+        let visit_code_templates = self.visit_code_templates.map(|templates| {
+            templates
+                .into_iter()
+                .filter_map(|code| {
+                    let field = format!("visitCodeTemplates.{}", code.code);
+                    let template = match code.template {
+                        Some(template) => Some(template.checked(&field, &mut errors)?),
+                        None => None,
+                    };
+                    Some((code.code, template))
+                })
+                .collect()
+        });
+        if self.scan_number_step == Some(0) {
+            errors.push(FieldError {
+                field: "scanNumberStep".into(),
+                message: "Scan number step must be positive".into(),
+            });
+        }
+        if !errors.is_empty() {
+            return Err(invalid_configuration(errors));
+        }
+        Ok(BeamlineConfigurationUpdate {
+            name,
+            scan_number: self.scan_number,
+            visit,
+            scan,
+            detector,
+            extension: self.extension.map(|e| e.0),
+            commissioning_visit,
+            commissioning_codes: self.commissioning_codes,
+            detector_lowercase: self.detector_lowercase,
+            detector_collapse: self.detector_collapse,
+            detector_replacement: self.detector_replacement.map(|r| r.0),
+            detector_reject_invalid: self.detector_reject_invalid,
+            scan_number_floor: self.scan_number_floor,
+            scan_number_step: self.scan_number_step,
+            instrument: self.instrument.map(|i| i.0),
+            detector_groups,
+            visit_code_templates,
+        })
+    }
+}
+
+/// A field of a configuration update that could not be used
+struct FieldError {
+    field: String,
+    message: String,
+}
+
+/// Error for a configuration update with one or more invalid fields
+fn invalid_configuration(errors: Vec<FieldError>) -> async_graphql::Error {
+    let message = errors
+        .iter()
+        .map(|e| e.message.as_str())
+        .collect::<Vec<_>>()
+        .join("; ");
+    async_graphql::Error::new(message).extend_with(|_, ext| {
+        ext.set("code", "INVALID_CONFIGURATION");
+        ext.set(
+            "errors",
+            Value::List(
+                errors
+                    .iter()
+                    .map(|e| {
+                        Value::Object(
+                            [
+                                (Name::new("field"), Value::String(e.field.clone())),
+                                (Name::new("message"), Value::String(e.message.clone())),
+                            ]
+                            .into(),
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+    })
+}
+
+/// Check any new templates against the server's field policies
+fn check_policies(
+    ctx: &Context<'_>,
+    upd: &BeamlineConfigurationUpdate,
+) -> async_graphql::Result<()> {
+    let Some(policies) = ctx.data_opt::<TemplatePolicies>() else {
+        return Ok(());
+    };
+    fn check<S: PathSpec>(
+        template: Option<&PathTemplate<S::Field>>,
+        policy: &FieldPolicy<S::Field>,
+    ) -> async_graphql::Result<()> {
+        match template.map(|t| policy.check(t)) {
+            Some(Err(e)) => Err(async_graphql::Error::new(format!(
+                "Invalid {} template: {e}",
+                S::KIND
+            ))
+            .extend_with(|_, ext| ext.set("code", "TEMPLATE_POLICY"))),
+            _ => Ok(()),
+        }
+    }
+    check::<VisitTemplate>(upd.visit.as_ref(), &policies.visit)?;
+    check::<VisitTemplate>(upd.commissioning_visit.as_ref(), &policies.visit)?;
+    check::<ScanTemplate>(upd.scan.as_ref(), &policies.scan)?;
+    check::<DetectorTemplate>(upd.detector.as_ref(), &policies.detector)?;
+    for (_, template) in upd.detector_groups.iter().flatten() {
+        check::<DetectorTemplate>(Some(template), &policies.detector)?;
+    }
+    for (_, template) in upd.visit_code_templates.iter().flatten() {
+        check::<VisitTemplate>(template.as_ref(), &policies.visit)?;
+    }
+    Ok(())
+}
+
+/// A template given by a client. Templates that are not valid are kept, rather than failing to
+/// parse, so that every invalid template in a request can be reported together.
+#[derive(Debug)]
+struct InputTemplate<S: PathSpec> {
+    text: String,
+    template: Result<PathTemplate<S::Field>, String>,
+}
+
+impl<S: PathSpec> InputTemplate<S> {
+    /// The template if it is valid, otherwise the reason it is not is added to `errors`
+    fn checked(self, field: &str, errors: &mut Vec<FieldError>) -> Option<PathTemplate<S::Field>> {
+        self.template
+            .map_err(|message| {
+                errors.push(FieldError {
+                    field: field.into(),
+                    message,
+                })
+            })
+            .ok()
+    }
+}
+
+impl<S, F> InputType for InputTemplate<S>
+where
+    F: Send + Sync + TryFrom<String> + Display,
+    S: PathSpec<Field = F> + Send + Sync,
+{
+    type RawValueType = PathTemplate<F>;
+    fn parse(value: Option<Value>) -> InputValueResult<Self> {
+        match value {
+            Some(Value::String(text)) => Ok(Self {
+                template: S::new_checked(&text)
+                    .map_err(|e| format!("Invalid {} template: {e}", S::KIND)),
+                text,
+            }),
+            Some(other) => Err(InputValueError::expected_type(other)),
+            None => Err(InputValueError::expected_type(Value::Null)),
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.text.clone())
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        // best effort remove the `numtracker::paths::` prefix
+        any::type_name::<S>()
+            .split("::")
+            .last()
+            .expect("There is always a last value for a split")
+            .into()
+    }
+
+    fn create_type_info(registry: &mut Registry) -> String {
+        registry.create_input_type::<Self, _>(MetaTypeId::Scalar, |_| MetaType::Scalar {
+            name: Self::type_name().into(),
+            description: Some(S::describe().into()),
+            is_valid: Some(Arc::new(|v| matches!(v, Value::String(_)))),
+            visible: None,
+            inaccessible: false,
+            tags: vec![],
+            specified_by_url: None,
+            directive_invocations: vec![],
+        })
+    }
+
+    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+        self.template.as_ref().ok()
+    }
+}
+
+/// A relative path within the scan directory.
+///
+/// Subdirectories are normalised to a canonical form when parsed: segments are separated by a
+/// single `/` with no leading or trailing separator, and empty (`a//b`) or current directory
+/// (`a/./b`) segments are removed. A path with no remaining segments (eg `""`, `.` or `./`) is
+/// the empty subdirectory. Paths starting with `/` (including a lone `/`) are absolute and
+/// parent directory (`..`) segments are not permitted. The server may also limit how deep
+/// subdirectories can be and whether hidden (`.` prefixed) segments are allowed.
+// Derived Default is OK without validation as empty path is a valid subdirectory
+#[derive(Debug, Default, Clone, Description)]
+pub struct Subdirectory(String);
+
+#[derive(Debug)]
+pub enum InvalidSubdirectory {
+    InvalidComponent(usize),
+    AbsolutePath,
+}
+
+#[Scalar(use_type_description)]
+impl ScalarType for Subdirectory {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        if let Value::String(path) = value {
+            if path.starts_with('/') {
+                return Err(InputValueError::custom(InvalidSubdirectory::AbsolutePath));
+            }
+            let mut segments = Vec::new();
+            for (i, seg) in path.split('/').filter(|s| !s.is_empty()).enumerate() {
+                match seg {
+                    "." => continue,
+                    ".." => {
+                        return Err(InputValueError::custom(
+                            InvalidSubdirectory::InvalidComponent(i),
+                        ))
+                    }
+                    seg => segments.push(seg),
+                }
+            }
+            Ok(Self(segments.join("/")))
+        } else {
+            Err(InputValueError::expected_type(value))
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.to_string())
+    }
+}
+
+impl Display for InvalidSubdirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidSubdirectory::InvalidComponent(s) => {
+                write!(f, "Segment {s} of path is not valid for a subdirectory")
+            }
+            InvalidSubdirectory::AbsolutePath => f.write_str("Subdirectory cannot be absolute"),
+        }
+    }
+}
+
+impl Error for InvalidSubdirectory {}
+
+impl Subdirectory {
+    /// Iterate through the directory names that make up this subdirectory
+    fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/').filter(|seg| !seg.is_empty())
+    }
+
+    /// Check that this subdirectory satisfies the rules of the server, as optionally tightened
+    /// by the client
+    fn check(
+        &self,
+        ctx: &Context<'_>,
+        requested: Option<&SubdirectoryRules>,
+    ) -> async_graphql::Result<()> {
+        let limits = ctx
+            .data_opt::<SubdirectoryLimits>()
+            .copied()
+            .unwrap_or_default();
+        let (max_depth, allow_hidden) = limits.resolve(requested)?;
+        let invalid = |msg: String| {
+            async_graphql::Error::new(msg)
+                .extend_with(|_, ext| ext.set("code", "INVALID_SUBDIRECTORY"))
+        };
+        let depth = self.segments().count();
+        if max_depth.is_some_and(|max| depth > max) {
+            return Err(invalid(format!(
+                "Subdirectory {:?} is deeper than the maximum of {}",
+                self.0,
+                max_depth.unwrap_or_default()
+            )));
+        }
+        if !allow_hidden && self.segments().any(|seg| seg.starts_with('.')) {
+            return Err(invalid(format!(
+                "Subdirectory {:?} includes a hidden directory",
+                self.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The subdirectory rules set by the server. Requests may make the rules stricter, up to these
+/// limits, but never looser.
+#[derive(Debug, Default, Clone, Copy)]
+struct SubdirectoryLimits {
+    /// The deepest subdirectory that any request may use
+    max_depth: Option<usize>,
+    /// The deepest subdirectory for requests that do not set their own limit
+    default_depth: Option<usize>,
+    hidden: HiddenSubdirectories,
+}
+
+impl SubdirectoryLimits {
+    /// Find the (maximum depth, allow hidden) rules for a request, failing if the request asks
+    /// for looser rules than the server allows
+    fn resolve(
+        self,
+        requested: Option<&SubdirectoryRules>,
+    ) -> async_graphql::Result<(Option<usize>, bool)> {
+        let exceeded = |msg: &str| {
+            async_graphql::Error::new(msg)
+                .extend_with(|_, ext| ext.set("code", "SUBDIRECTORY_RULES_EXCEEDED"))
+        };
+        let max_depth = match requested.and_then(|r| r.max_depth) {
+            None => self.default_depth,
+            Some(depth) if self.max_depth.is_some_and(|max| depth > max) => {
+                return Err(exceeded(&format!(
+                    "Requested subdirectory depth {depth} is deeper than the server allows"
+                )));
+            }
+            Some(depth) => Some(depth),
+        };
+        let allow_hidden = match requested.and_then(|r| r.allow_hidden) {
+            None => self.hidden == HiddenSubdirectories::Allow,
+            Some(true) if self.hidden == HiddenSubdirectories::Deny => {
+                return Err(exceeded(
+                    "Hidden subdirectories are not allowed by the server",
+                ));
+            }
+            Some(allow) => allow,
+        };
+        Ok((max_depth, allow_hidden))
+    }
+}
+
+/// Rules for the subdirectory of a request. Unset rules use the server's defaults. Rules
+/// looser than the server allows are rejected.
+#[derive(Debug, Default, InputObject)]
+struct SubdirectoryRules {
+    /// The maximum number of directories in the subdirectory
+    max_depth: Option<usize>,
+    /// Whether directories starting with `.` are allowed
+    allow_hidden: Option<bool>,
+}
+
+impl Display for Subdirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The name of a detector as given by the client. Names are normalised using the beamline's
+/// rules when paths are generated.
+#[derive(Debug, Description)]
+pub struct Detector(String);
+
+#[Scalar(use_type_description)]
+impl ScalarType for Detector {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        if let Value::String(name) = value {
+            Ok(Self(name))
+        } else {
+            Err(InputValueError::expected_type(value))
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.clone())
+    }
+}
+
+/// A detector with options for where its file should be written
+#[derive(Debug, InputObject)]
+pub struct DetectorRequest {
+    /// The name of the detector, normalised in the same way as plain detector names
+    name: String,
+    /// A subdirectory of the directory the detector's file would otherwise be written in. This
+    /// follows the same rules as the `Subdirectory` scalar.
+    subdirectory: Option<Subdirectory>,
+    /// The group whose template should be used instead of the default detector template
+    group: Option<String>,
+}
+
+impl From<Detector> for DetectorRequest {
+    fn from(value: Detector) -> Self {
+        Self {
+            name: value.0,
+            subdirectory: None,
+            group: None,
+        }
+    }
+}
+
+impl DetectorRequest {
+    /// Plain detector names followed by the detectors requested with options
+    fn all(names: Vec<Detector>, requests: Vec<DetectorRequest>) -> Vec<Self> {
+        names.into_iter().map(Self::from).chain(requests).collect()
+    }
+
+    fn as_str(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Move a rendered detector path into this detector's subdirectory if it has one
+    fn place(&self, path: PathBuf) -> PathBuf {
+        match (&self.subdirectory, path.parent(), path.file_name()) {
+            (Some(sub), Some(parent), Some(file)) => parent.join(&sub.0).join(file),
+            _ => path,
+        }
+    }
+}
+
+/// The start date of a visit (YYYY-MM-DD)
+#[derive(Debug, Description)]
+pub struct VisitDate(NaiveDate);
+
+#[Scalar(use_type_description)]
+impl ScalarType for VisitDate {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        if let Value::String(date) = &value {
+            date.parse()
+                .map(Self)
+                .map_err(|_| InputValueError::custom("Visit date must be in the form YYYY-MM-DD"))
+        } else {
+            Err(InputValueError::expected_type(value))
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.to_string())
+    }
+}
+
+/// The extension used for files in the fallback tracker directory. It cannot be empty and can
+/// only contain alphanumeric characters, '_' or '-'.
+#[derive(Debug, Description)]
+pub struct TrackerExtension(String);
+
+#[Scalar(use_type_description)]
+impl ScalarType for TrackerExtension {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match value {
+            Value::String(ext) if ext.is_empty() => {
+                Err(InputValueError::custom("Extension cannot be empty"))
+            }
+            Value::String(ext) if NumTracker::valid_extension(&ext) => Ok(Self(ext)),
+            Value::String(_) => Err(InputValueError::custom(
+                "Extension must only contain alphanumeric characters, '_' or '-'",
+            )),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.clone())
+    }
+}
+
+/// The instrument used in paths for a beamline. It cannot be empty, contain path separators or
+/// be a `.` or `..` path segment.
+#[derive(Debug, Description)]
+pub struct Instrument(String);
+
+#[Scalar(use_type_description)]
+impl ScalarType for Instrument {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match value {
+            Value::String(inst) if inst.is_empty() => {
+                Err(InputValueError::custom("Instrument cannot be empty"))
+            }
+            Value::String(inst) if inst.contains(['/', '\\']) || inst == "." || inst == ".." => {
+                Err(InputValueError::custom(
+                    "Instrument must be a single path segment",
+                ))
+            }
+            Value::String(inst) => Ok(Self(inst)),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.clone())
+    }
+}
+
+/// A single character used in place of invalid characters in detector names. It must be an
+/// alphanumeric character, '_' or '-'.
+#[derive(Debug, Description)]
+pub struct Replacement(char);
+
+#[Scalar(use_type_description)]
+impl ScalarType for Replacement {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        let Value::String(text) = &value else {
+            return Err(InputValueError::expected_type(value));
+        };
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if DetectorNormalisation::valid_replacement(c) => Ok(Self(c)),
+            _ => Err(InputValueError::custom(
+                "Replacement must be a single alphanumeric character, '_' or '-'",
+            )),
+        }
+    }
+    fn to_value(&self) -> Value {
+        Value::String(self.0.into())
+    }
+}
+
+#[cfg(test)]
+mod readiness_tests {
+    use httpmock::MockServer;
+
+    use super::auth::PolicyCheck;
+    use super::Readiness;
+    use crate::cli::PolicyOptions;
+    use crate::db_service::SqliteScanPathService;
+
+    fn policy(host: String) -> Option<PolicyCheck> {
+        Some(PolicyCheck::new(PolicyOptions {
+            policy_host: host,
+            ..Default::default()
+        }))
+    }
+
+    /// Address with nothing listening on it
+    fn closed_port() -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn db_only() {
+        let ready = Readiness {
+            db: SqliteScanPathService::memory().await,
+            policy: None,
+        };
+        ready.check().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reachable_policy() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("HEAD");
+            then.status(200);
+        });
+        let ready = Readiness {
+            db: SqliteScanPathService::memory().await,
+            policy: policy(server.url("")),
+        };
+        ready.check().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreachable_policy() {
+        let ready = Readiness {
+            db: SqliteScanPathService::memory().await,
+            policy: policy(closed_port()),
+        };
+        let reason = ready.check().await.unwrap_err();
+        assert!(
+            reason.starts_with("Policy server is not available"),
+            "Unexpected reason: {reason}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod bind_tests {
+    use assert_matches::assert_matches;
+
+    use super::{bind, ServeError};
+
+    #[tokio::test]
+    async fn port_in_use() {
+        let existing = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = existing.local_addr().unwrap();
+        let err = bind(addr).await.unwrap_err();
+        assert_matches!(err, ServeError::Bind { addr: a, .. } if a == addr);
+        assert_eq!(
+            err.to_string(),
+            format!("Could not bind to {addr}: address is already in use")
+        );
+    }
+
+    #[tokio::test]
+    async fn unavailable_address() {
+        // TEST-NET-1 address reserved for documentation is never assigned to a local interface
+        let addr = "192.0.2.1:8000".parse().unwrap();
+        let err = bind(addr).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not bind to 192.0.2.1:8000: address is not available on this host"
+        );
+    }
+}
+
+#[cfg(test)]
+mod unix_socket_tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::{fs, io};
+
+    use assert_matches::assert_matches;
+    use async_graphql::{EmptySubscription, Schema};
+    use axum::routing::{get, post};
+    use axum::{Extension, Router};
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::net::UnixStream;
+    use tokio::sync::{oneshot, Notify};
+
+    use super::auth::PolicyCheck;
+    use super::{
+        bind_unix, graphql_handler, serve_unix, Mutation, Query, ServeError, GRAPHQL_PATH,
+    };
+
+    #[tokio::test]
+    async fn request_over_socket() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("numtracker.sock");
+        let schema = Schema::build(Query, Mutation, EmptySubscription)
+            .data(None::<PolicyCheck>)
+            .finish();
+        let app = Router::new()
+            .route(GRAPHQL_PATH, post(graphql_handler))
+            .layer(Extension(schema));
+        let listener = bind_unix(&path).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_unix(listener, app, async {
+            _ = stopped.await;
+        }));
+
+        let body = r#"{"query": "{ validateVisit(visit: \"cm12345-3\") { __typename } }"}"#;
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST {GRAPHQL_PATH} HTTP/1.1\r\nHost: localhost\r\n\
+                    Content-Type: application/json\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(
+            response.contains(r#"{"data":{"validateVisit":{"__typename":"ValidVisit"}}"#),
+            "{response}"
+        );
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn requests_finish_before_shutdown() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("numtracker.sock");
+        let started = Arc::new(Notify::new());
+        let finished = Arc::new(AtomicBool::new(false));
+        let app = Router::new().route(
+            "/slow",
+            get({
+                let started = started.clone();
+                let finished = finished.clone();
+                || async move {
+                    started.notify_one();
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    finished.store(true, Ordering::SeqCst);
+                    "done"
+                }
+            }),
+        );
+        let listener = bind_unix(&path).unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_unix(listener, app, async {
+            _ = stopped.await;
+        }));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        started.notified().await;
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(
+            finished.load(Ordering::SeqCst),
+            "server stopped before request finished"
+        );
+        // The connection is closed after the response instead of being kept alive
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn stale_socket_replaced() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("numtracker.sock");
+        drop(bind_unix(&path).unwrap());
+        assert!(path.exists());
+        bind_unix(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn live_socket_not_replaced() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("numtracker.sock");
+        let _listener = bind_unix(&path).unwrap();
+        let err = bind_unix(&path).unwrap_err();
+        assert_matches!(
+            err,
+            ServeError::BindUnix { path: p, source }
+                if p == path && source.kind() == io::ErrorKind::AddrInUse
+        );
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn existing_file_not_replaced() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("numtracker.sock");
+        fs::write(&path, "not a socket").unwrap();
+        let err = bind_unix(&path).unwrap_err();
+        assert_matches!(err, ServeError::BindUnix { path: p, .. } if p == path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    }
+}
+
+#[cfg(test)]
+mod graphiql_tests {
+    use axum::body::to_bytes;
+    use axum::response::IntoResponse as _;
+
+    use super::{graphiql, GRAPHQL_PATH};
+
+    #[tokio::test]
+    async fn graphiql_targets_graphql_route() {
+        let body = to_bytes(graphiql().await.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            html.contains(&format!("url: createUrl('{GRAPHQL_PATH}')")),
+            "{html}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod error_response_tests {
+    use async_graphql::ParseRequestError;
+    use async_graphql_axum::rejection::GraphQLRejection;
+    use axum::body::to_bytes;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse as _;
+    use serde_json::{json, Value};
+
+    use super::{not_found, ErrorResponse};
+
+    async fn body(err: ErrorResponse) -> (StatusCode, Value) {
+        let response = err.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_route() {
+        let (status, body) = body(not_found().await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            json!({"error": {"code": "NOT_FOUND", "message": "No route found"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_request() {
+        let err = serde_json::from_str::<Value>("{").unwrap_err();
+        let rejection = GraphQLRejection(ParseRequestError::InvalidRequest(Box::new(err)));
+        let (status, body) = body(rejection.into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "BAD_REQUEST");
+        assert!(
+            body["error"]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid request: "),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn payload_too_large() {
+        let rejection = GraphQLRejection(ParseRequestError::PayloadTooLarge);
+        let (status, body) = body(rejection.into()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "PAYLOAD_TOO_LARGE");
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use axum::body::Body;
+    use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use rstest::rstest;
+    use tower::ServiceExt as _;
+
+    use super::compression;
+
+    #[rstest]
+    #[case::large(2000, Some("gzip"), Some("gzip"))]
+    #[case::small(100, Some("gzip"), None)]
+    #[case::brotli(2000, Some("br"), Some("br"))]
+    #[case::not_accepted(2000, None, None)]
+    #[tokio::test]
+    async fn compress_large_responses(
+        #[case] size: usize,
+        #[case] accept: Option<&str>,
+        #[case] encoding: Option<&str>,
+    ) {
+        let app = Router::new()
+            .route("/", get(move || async move { "x".repeat(size) }))
+            .layer(compression(1024));
+        let mut req = Request::builder().uri("/");
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT_ENCODING, accept);
+        }
+        let response = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_ENCODING)
+                .map(|enc| enc.to_str().unwrap()),
+            encoding
+        );
+    }
+}
+
+#[cfg(test)]
+mod debug_timings_tests {
+    use async_graphql::{EmptySubscription, Schema};
+    use axum::body::{to_bytes, Body};
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::Request;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use rstest::rstest;
+    use serde_json::Value;
+    use tower::ServiceExt as _;
+
+    use super::auth::PolicyCheck;
+    use super::{
+        graphql_handler, DebugTimings, Mutation, Query, DEBUG_TIMINGS_HEADER, GRAPHQL_PATH,
+    };
+
+    #[rstest]
+    #[case::neither(false, false, false)]
+    #[case::header_without_flag(false, true, false)]
+    #[case::flag_without_header(true, false, false)]
+    #[case::flag_and_header(true, true, true)]
+    #[tokio::test]
+    async fn timings_need_flag_and_header(
+        #[case] enabled: bool,
+        #[case] header: bool,
+        #[case] timed: bool,
+    ) {
+        let schema = Schema::build(Query, Mutation, EmptySubscription)
+            .data(None::<PolicyCheck>)
+            .finish();
+        let app = Router::new()
+            .route(GRAPHQL_PATH, post(graphql_handler))
+            .layer(Extension(schema))
+            .layer(Extension(DebugTimings(enabled)));
+        let mut req = Request::post(GRAPHQL_PATH).header(CONTENT_TYPE, "application/json");
+        if header {
+            req = req.header(DEBUG_TIMINGS_HEADER, "1");
+        }
+        let body = r#"{"query": "{ validateVisit(visit: \"cm12345-3\") { __typename } }"}"#;
+        let response = app
+            .oneshot(req.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["data"]["validateVisit"]["__typename"], "ValidVisit");
+        assert_eq!(body["extensions"].get("timings").is_some(), timed, "{body}");
+    }
+}
+
+#[cfg(test)]
+mod path_to_string_tests {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt as _;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Weak};
+
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_sdk::metrics::data::{ResourceMetrics, Sum};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+    };
+    use opentelemetry_sdk::Resource;
+    use rstest::rstest;
+
+    use super::{has_empty_segment, path_to_string, PathMetrics, PathSeparator};
+
+    /// A reader that can still be read from after a clone of it is given to a meter provider
+    #[derive(Debug, Clone, Default)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+        fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+            self.0.collect(rm)
+        }
+        fn force_flush(&self) -> MetricResult<()> {
+            self.0.force_flush()
+        }
+        fn shutdown(&self) -> MetricResult<()> {
+            self.0.shutdown()
+        }
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl SharedReader {
+        /// The total of the named counter across all attributes
+        fn counter(&self, name: &str) -> u64 {
+            let mut rm = ResourceMetrics {
+                resource: Resource::empty(),
+                scope_metrics: vec![],
+            };
+            self.collect(&mut rm).unwrap();
+            rm.scope_metrics
+                .iter()
+                .flat_map(|scope| &scope.metrics)
+                .filter(|metric| metric.name == name)
+                .filter_map(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+                .flat_map(|sum| &sum.data_points)
+                .map(|point| point.value)
+                .sum()
+        }
+    }
+
+    #[test]
+    fn unicode_path() {
+        let path = PathBuf::from("/tmp/i22/data");
+        assert_eq!(
+            path_to_string(None, "i22", path.clone(), PathSeparator::Slash).unwrap(),
+            "/tmp/i22/data"
+        );
+        assert_eq!(
+            path_to_string(None, "i22", path, PathSeparator::Backslash).unwrap(),
+            r"\tmp\i22\data"
+        );
+    }
+
+    #[test]
+    fn non_unicode_path() {
+        let reader = SharedReader::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        let metrics = PathMetrics::new(&provider.meter("test"));
+
+        let path = PathBuf::from("/tmp/i22/data");
+        path_to_string(Some(&metrics), "i22", path, PathSeparator::Slash).unwrap();
+        assert_eq!(reader.counter("non_unicode_paths"), 0);
+
+        let path = PathBuf::from(OsString::from_vec(b"/tmp/i22/\xff".to_vec()));
+        let err = path_to_string(Some(&metrics), "i22", path, PathSeparator::Slash).unwrap_err();
+        assert_eq!(err.to_string(), "Path contains non-unicode characters");
+        assert_eq!(reader.counter("non_unicode_paths"), 1);
+    }
+
+    #[rstest]
+    #[case::absolute("/tmp/i22/cm12345-3", false)]
+    #[case::relative("sub/i22-123", false)]
+    #[case::doubled("/tmp/i22//cm12345-3", true)]
+    #[case::trailing("i22-123/", true)]
+    #[case::leading_relative("/i22-123", false)]
+    #[case::doubled_root("//tmp/i22", true)]
+    #[case::empty("", true)]
+    fn empty_segments(#[case] path: &str, #[case] empty: bool) {
+        assert_eq!(has_empty_segment(path), empty);
+    }
+}
+
+#[cfg(test)]
+mod render_context_tests {
+    use std::collections::BTreeMap;
+
+    use super::render_context;
+
+    #[test]
+    fn fields_in_one_line() {
+        let fields = BTreeMap::from([
+            ("visit".to_string(), "cm12345-3".to_string()),
+            ("instrument".to_string(), "i22".to_string()),
+        ]);
+        assert_eq!(
+            render_context(&fields),
+            r#"instrument="i22", visit="cm12345-3""#
+        );
+    }
+
+    #[test]
+    fn no_fields() {
+        assert_eq!(render_context(&BTreeMap::new()), "no fields");
+    }
+}
+
+#[cfg(test)]
+mod subdirectory_tests {
+    use async_graphql::{InputType as _, InputValueResult, Number, Value};
+
+    use super::Subdirectory;
+    fn parse_str(sub: &str) -> InputValueResult<Subdirectory> {
+        Subdirectory::parse(Some(Value::String(sub.into())))
+    }
+
+    #[test]
+    fn valid_subdirectory() {
+        parse_str("valid/subdirectory").unwrap();
+    }
+
+    #[test]
+    fn invalid_subdirectory() {
+        parse_str("../parent").unwrap_err();
+        parse_str("/absolute/path").unwrap_err();
+        Subdirectory::parse(Some(Value::Number(Number::from_f64(42f64).unwrap()))).unwrap_err();
+    }
+
+    #[test]
+    fn back_to_value() {
+        let sub = parse_str("./subdirectory").unwrap();
+        assert_eq!(sub.to_value(), Value::String("subdirectory".into()))
+    }
+
+    #[rstest::rstest]
+    #[case::plain("foo/bar", "foo/bar")]
+    #[case::trailing_slash("foo/bar/", "foo/bar")]
+    #[case::doubled_separator("foo//bar", "foo/bar")]
+    #[case::current_directory("foo/./bar/.", "foo/bar")]
+    #[case::leading_current_directory(".//foo", "foo")]
+    #[case::empty("", "")]
+    #[case::only_current_directory("./", "")]
+    #[case::only_separators(".//./", "")]
+    fn canonical_form(#[case] sub: &str, #[case] expected: &str) {
+        assert_eq!(parse_str(sub).unwrap().to_string(), expected);
+    }
+
+    #[rstest::rstest]
+    #[case::root("/")]
+    #[case::doubled_root("//foo")]
+    #[case::parent_after_empty("foo//../bar")]
+    #[case::trailing_parent("foo/..")]
+    fn rejected(#[case] sub: &str) {
+        parse_str(sub).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod detector_tests {
+    use std::path::PathBuf;
+
+    use async_graphql::{value, InputType as _, Number, Value};
+
+    use super::{Detector, DetectorRequest};
+
+    #[rstest::rstest]
+    #[case::unchanged("camera")]
+    #[case::punctuation("foo+bar")]
+    #[case::multiple_punctuation("foo+-?!bar")]
+    fn raw_name(#[case] input: &str) {
+        let det = Detector::parse(Some(Value::String(input.into()))).unwrap();
+        let value = det.to_value();
+        let Value::String(s) = value else {
+            panic!("Unexpected value from detector: {value}");
+        };
+        assert_eq!(s, input);
+        assert_eq!(DetectorRequest::from(det).as_str(), input);
+    }
+
+    #[test]
+    fn invalid_value() {
+        Detector::parse(Number::from_f64(42f64).map(Value::Number)).unwrap_err();
+        Detector::parse(Some(value!({"name": "camera"}))).unwrap_err();
+        Detector::parse(None).unwrap_err();
+    }
+
+    #[test]
+    fn with_subdirectory() {
+        let det =
+            DetectorRequest::parse(Some(value!({"name": "camera", "subdirectory": "./cams/"})))
+                .unwrap();
+        assert_eq!(det.as_str(), "camera");
+        assert_eq!(
+            det.place("sub/i22-1-camera".into()),
+            PathBuf::from("sub/cams/i22-1-camera")
+        );
+    }
+
+    #[test]
+    fn without_subdirectory() {
+        let det = DetectorRequest::parse(Some(value!({"name": "camera"}))).unwrap();
+        assert_eq!(
+            det.place("i22-1-camera".into()),
+            PathBuf::from("i22-1-camera")
+        );
+    }
+
+    #[test]
+    fn plain_names_first() {
+        let names = vec![Detector("one".into())];
+        let requests = vec![DetectorRequest::parse(Some(value!({"name": "two"}))).unwrap()];
+        let all = DetectorRequest::all(names, requests);
+        assert_eq!(
+            all.iter().map(DetectorRequest::as_str).collect::<Vec<_>>(),
+            ["one", "two"]
+        );
+    }
+
+    #[rstest::rstest]
+    #[case::plain_name(value!("camera"))]
+    #[case::missing_name(value!({"subdirectory": "cams"}))]
+    #[case::numeric_name(value!({"name": 42}))]
+    #[case::absolute(value!({"name": "camera", "subdirectory": "/cams"}))]
+    #[case::parent(value!({"name": "camera", "subdirectory": "../cams"}))]
+    fn invalid_request(#[case] value: Value) {
+        DetectorRequest::parse(Some(value)).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod tracker_extension_tests {
+    use async_graphql::{InputType as _, Value};
+    use rstest::rstest;
+
+    use super::TrackerExtension;
+
+    #[rstest]
+    #[case::alphanumeric("i22")]
+    #[case::separators("nexus_file-ext")]
+    fn valid_extension(#[case] input: &str) {
+        let ext = TrackerExtension::parse(Some(Value::String(input.into()))).unwrap();
+        assert_eq!(ext.to_value(), Value::String(input.into()));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::space("ext space")]
+    #[case::traversal("i22/../beamline")]
+    #[case::absolute("/tmp/ext")]
+    #[case::dotted("tar.gz")]
+    fn invalid_extension(#[case] input: &str) {
+        TrackerExtension::parse(Some(Value::String(input.into()))).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod replacement_tests {
+    use async_graphql::{InputType as _, Value};
+    use rstest::rstest;
+
+    use super::Replacement;
+
+    #[rstest]
+    #[case::underscore("_")]
+    #[case::hyphen("-")]
+    #[case::letter("x")]
+    fn valid_replacement(#[case] input: &str) {
+        let rep = Replacement::parse(Some(Value::String(input.into()))).unwrap();
+        assert_eq!(rep.to_value(), Value::String(input.into()));
+    }
+
+    #[rstest]
+    #[case::empty("")]
+    #[case::multiple("__")]
+    #[case::separator("/")]
+    #[case::dot(".")]
+    fn invalid_replacement(#[case] input: &str) {
+        Replacement::parse(Some(Value::String(input.into()))).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod input_template_tests {
+    use async_graphql::{InputType as _, Value};
+
+    use super::InputTemplate;
+    use crate::paths::{DetectorTemplate, ScanTemplate, VisitTemplate};
+
+    #[test]
+    fn valid_visit_template() {
+        InputTemplate::<VisitTemplate>::parse(Some(Value::String(
+            "/tmp/{instrument}/data/{visit}".into(),
+        )))
+        .unwrap()
+        .template
+        .unwrap();
+    }
+
+    #[rstest::rstest]
+    #[case::relative("tmp/{instrument}/{visit}")]
+    #[case::missing_instrument("/tmp/{visit}")]
+    #[case::missing_visit("/tmp/{instrument}/data")]
+    #[case::invalid_template("/tmp/{nested{placeholder}}")]
+    fn invalid_visit_template(#[case] path: String) {
+        InputTemplate::<VisitTemplate>::parse(Some(Value::String(path)))
+            .unwrap()
+            .template
+            .unwrap_err();
+    }
+
+    #[rstest::rstest]
+    #[case::absolute("/tmp/{instrument}")]
+    #[case::missing_scan_number("scan_file")]
+    #[case::invalid_template("tmp/{nested{placeholder}}")]
+    fn invalid_scan(#[case] path: String) {
+        InputTemplate::<ScanTemplate>::parse(Some(Value::String(path)))
+            .unwrap()
+            .template
+            .unwrap_err();
+    }
+
+    #[rstest::rstest]
+    #[case::relative("tmp/{instrument}/{visit}")]
+    #[case::missing_scan_number("{detector}")]
+    #[case::missing_detector("{scan_number}")]
+    #[case::invalid_template("tmp/{nested{placeholder}}")]
+    fn invalid_detector_template(#[case] path: String) {
+        InputTemplate::<DetectorTemplate>::parse(Some(Value::String(path)))
+            .unwrap()
+            .template
+            .unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod graphql_tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use async_graphql::{value, EmptySubscription, Schema, SchemaBuilder, Value};
+    use axum_extra::headers::Authorization;
+    use chrono::{DateTime, Local, TimeZone as _};
+    use httpmock::MockServer;
+    use rstest::{fixture, rstest};
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    use super::auth::PolicyCheck;
+    use super::{
+        execute, execute_tagged, execute_timed, Clock, IdempotencyKeys, MaxDetectors, Mutation,
+        Query, ReadOnly, ScanRateLimit, ServedBeamlines, SubdirectoryLimits,
+    };
+    use crate::cli::{
+        HiddenSubdirectories, MissingTrackerDirectory, PolicyOptions, VisitDirectoryCheck,
+    };
+    use crate::db_service::{BeamlineConfigurationUpdate, SqliteScanPathService};
+    use crate::numtracker::NumTracker;
+    use crate::overlay::ConfigOverlay;
+    use crate::paths::{
+        DetectorTemplate, PathSpec as _, ScanTemplate, TemplatePolicies, VisitTemplate,
+    };
+    use crate::template::PathTemplate;
+    use crate::visit::ProposalCodes;
+
+    type NtSchema = Schema<Query, Mutation, EmptySubscription>;
+
+    /// Clock that always returns the same time
+    struct FixedClock(DateTime<Local>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Local> {
+            self.0
+        }
+    }
+
+    fn fixed_clock(y: i32, m: u32, d: u32, h: u32, min: u32, s: u32) -> Box<dyn Clock> {
+        Box::new(FixedClock(
+            Local.with_ymd_and_hms(y, m, d, h, min, s).unwrap(),
+        ))
+    }
+
+    #[fixture]
+    async fn schema() -> NtSchema {
+        schema_at(fixed_clock(2024, 6, 1, 12, 0, 0)).await
+    }
+
+    async fn schema_at(clock: Box<dyn Clock>) -> NtSchema {
+        schema_with(clock, NumTracker::for_root_directory(None::<&str>).unwrap()).await
+    }
+
+    async fn schema_with(clock: Box<dyn Clock>, nt: NumTracker) -> NtSchema {
+        build_schema(i22_db().await, clock, nt)
+    }
+
+    /// In memory DB with a single i22 beamline configured
+    async fn i22_db() -> SqliteScanPathService {
+        let db = SqliteScanPathService::memory().await;
+        BeamlineConfigurationUpdate {
+            scan_number: Some(122),
+            visit: VisitTemplate::new_checked("/tmp/{instrument}/data/{year}/{visit}").ok(),
+            scan: ScanTemplate::new_checked("{subdirectory}/{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked(
+                "{subdirectory}/{instrument}-{scan_number}-{detector}",
+            )
+            .ok(),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        db
+    }
+
+    fn build_schema(db: SqliteScanPathService, clock: Box<dyn Clock>, nt: NumTracker) -> NtSchema {
+        build_strict_schema(db, clock, nt, MissingTrackerDirectory::Allow)
+    }
+
+    fn build_strict_schema(
+        db: SqliteScanPathService,
+        clock: Box<dyn Clock>,
+        nt: NumTracker,
+        missing: MissingTrackerDirectory,
+    ) -> NtSchema {
+        schema_builder(db)
+            .data(nt)
+            .data(missing)
+            .data(clock)
+            .finish()
+    }
+
+    /// Schema builder with the defaults used by most tests. Data added to the builder replaces
+    /// the default of the same type.
+    fn schema_builder(
+        db: SqliteScanPathService,
+    ) -> SchemaBuilder<Query, Mutation, EmptySubscription> {
+        Schema::build(Query, Mutation, EmptySubscription)
+            .data(db)
+            .data(NumTracker::for_root_directory(None::<&str>).unwrap())
+            .data(None::<PolicyCheck>)
+            .data(MissingTrackerDirectory::Allow)
+            .data(IdempotencyKeys::new(Duration::from_secs(60)))
+            .data(fixed_clock(2024, 6, 1, 12, 0, 0))
+    }
+
+    /// Allocate a scan for i22 and return its scan number
+    async fn scan_with_key(schema: &NtSchema, key: &str) -> Value {
+        let result = schema
+            .execute(format!(
+                r#"mutation {{
+                    scan(beamline: "i22", visit: "cm12345-3", idempotencyKey: "{key}") {{
+                        scanNumber
+                    }}
+                }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        result.data
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_idempotency_key(#[future(awt)] schema: NtSchema) {
+        let (first, second) =
+            tokio::join!(scan_with_key(&schema, "abc"), scan_with_key(&schema, "abc"));
+        assert_eq!(first, value!({"scan": {"scanNumber": 123}}));
+        assert_eq!(second, first);
+
+        // A retry after the first request has completed also gets the original scan
+        assert_eq!(scan_with_key(&schema, "abc").await, first);
+
+        assert_eq!(
+            scan_with_key(&schema, "def").await,
+            value!({"scan": {"scanNumber": 124}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn idempotency_key_reused_for_different_request(#[future(awt)] schema: NtSchema) {
+        assert_eq!(
+            scan_with_key(&schema, "abc").await,
+            value!({"scan": {"scanNumber": 123}})
+        );
+        for args in [
+            r#"visit: "cm12345-4""#,
+            r#"visit: "cm12345-3", sub: "other""#,
+            r#"visit: "cm12345-3", year: 2023"#,
+            r#"visit: "cm12345-3", visitDate: "2024-01-01""#,
+        ] {
+            let result = schema
+                .execute(format!(
+                    r#"mutation {{
+                        scan(beamline: "i22", {args}, idempotencyKey: "abc") {{
+                            visit {{ directory }}
+                        }}
+                    }}"#
+                ))
+                .await;
+            assert_eq!(result.errors.len(), 1, "{args}");
+            assert_eq!(
+                result.errors[0]
+                    .extensions
+                    .as_ref()
+                    .and_then(|ext| ext.get("code")),
+                Some(&value!("IDEMPOTENCY_KEY_REUSED"))
+            );
+        }
+        // The original request can still be retried
+        assert_eq!(
+            scan_with_key(&schema, "abc").await,
+            value!({"scan": {"scanNumber": 123}})
+        );
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_repeats_warnings() {
+        let schema = build_strict_schema(
+            i22_db().await,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(None::<&str>).unwrap(),
+            MissingTrackerDirectory::Warn,
+        );
+        let query = r#"mutation {
+            scan(beamline: "i22", visit: "cm12345-3", idempotencyKey: "abc") { scanNumber }
+        }"#;
+        let first = execute(&schema, async_graphql::Request::new(query)).await;
+        let retry = execute(&schema, async_graphql::Request::new(query)).await;
+        assert!(retry.errors.is_empty(), "{:?}", retry.errors);
+        assert_eq!(retry.data, first.data);
+        assert_eq!(
+            retry.extensions.get("warnings"),
+            Some(&value!([
+                r#"Beamline "i22" does not have a tracker directory"#
+            ]))
+        );
+        assert_eq!(
+            retry.extensions.get("warnings"),
+            first.extensions.get("warnings")
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_expired_idempotency_key() {
+        let schema = schema_builder(i22_db().await)
+            .data(IdempotencyKeys::new(Duration::ZERO))
+            .finish();
+        assert_eq!(
+            scan_with_key(&schema, "abc").await,
+            value!({"scan": {"scanNumber": 123}})
+        );
+        assert_eq!(
+            scan_with_key(&schema, "abc").await,
+            value!({"scan": {"scanNumber": 124}})
+        );
+    }
+
+    #[rstest]
+    #[case::served(&["i22", "b21"], true)]
+    #[case::not_served(&["b21"], false)]
+    #[case::all_served(&[], true)]
+    #[tokio::test]
+    async fn served_beamlines(#[case] served: &[&str], #[case] serves_i22: bool) {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        let schema = schema_builder(i22_db().await)
+            .data(NumTracker::for_root_directory(Some(root.path())).unwrap())
+            .data(ServedBeamlines::new(served.iter().map(|bl| bl.to_string())))
+            .finish();
+        let result = schema.execute("{ beamlines }").await;
+        let expected: &[&str] = if serves_i22 { &["i22"] } else { &[] };
+        assert_eq!(result.data, value!({ "beamlines": expected }));
+
+        let result = schema
+            .execute("{ health { trackerDirectories { beamline } } }")
+            .await;
+        let expected = expected
+            .iter()
+            .map(|bl| value!({ "beamline": *bl }))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            result.data,
+            value!({ "health": { "trackerDirectories": expected } })
+        );
+
+        for query in [
+            r#"{ paths(beamline: "i22", visit: "cm12345-3") { directory } }"#,
+            r#"{ configuration(beamline: "i22") { name } }"#,
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#,
+        ] {
+            let result = schema.execute(query).await;
+            if serves_i22 {
+                assert!(result.errors.is_empty(), "{query}: {:?}", result.errors);
+            } else {
+                assert_eq!(result.errors.len(), 1, "{query}");
+                assert_eq!(
+                    result.errors[0]
+                        .extensions
+                        .as_ref()
+                        .and_then(|ext| ext.get("code")),
+                    Some(&value!("MISSING_BEAMLINE"))
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_mode() {
+        let db = i22_db().await;
+        let schema = schema_builder(db.clone()).data(ReadOnly(true)).finish();
+        for mutation in [
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#,
+            r#"mutation { configure(beamline: "i22", config: {scanNumber: 1}) { latestScanNumber } }"#,
+        ] {
+            let result = schema.execute(mutation).await;
+            assert_eq!(result.errors.len(), 1, "{mutation}");
+            assert_eq!(result.errors[0].message, "Service is in read-only mode");
+            assert_eq!(
+                result.errors[0]
+                    .extensions
+                    .as_ref()
+                    .and_then(|ext| ext.get("code")),
+                Some(&value!("READ_ONLY"))
+            );
+        }
+        let conf = db.current_configuration("i22").await.unwrap();
+        assert_eq!(conf.scan_number(), 122);
+
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3") { directory } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": "/tmp/i22/data/2024/cm12345-3"}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn paths_batch(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"{
+                    pathsBatch(requests: [
+                        {beamline: "i22", visit: "cm12345-1"},
+                        {beamline: "b21", visit: "cm12345-2"},
+                        {beamline: "i22", visit: "cm12345-3"},
+                    ]) {
+                        ... on VisitPath { directory }
+                        ... on BatchError { beamline visit code }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"pathsBatch": [
+                {"directory": "/tmp/i22/data/2024/cm12345-1"},
+                {"beamline": "b21", "visit": "cm12345-2", "code": "MISSING_BEAMLINE"},
+                {"directory": "/tmp/i22/data/2024/cm12345-3"},
+            ]})
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_rate_limited() {
+        let db = i22_db().await;
+        let schema = schema_builder(db.clone())
+            .data(ScanRateLimit::new(Some(2)))
+            .finish();
+        let query = r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#;
+        for expected in [123, 124] {
+            let result = schema.execute(query).await;
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            assert_eq!(result.data, value!({"scan": {"scanNumber": expected}}));
+        }
+        let result = schema.execute(query).await;
+        assert_eq!(result.errors.len(), 1);
+        let ext = result.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(ext.get("code"), Some(&value!("SCAN_RATE_LIMITED")));
+        assert_matches!(ext.get("retryAfterMs"), Some(Value::Number(ms)) if ms.as_u64().is_some_and(|ms| (1..=1000).contains(&ms)));
+        // The throttled request did not use a scan number
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            124
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn configure_nothing(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(r#"mutation { configure(beamline: "i22", config: {}) { latestScanNumber } }"#)
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("NOTHING_TO_UPDATE"))
+        );
+    }
+
+    #[rstest]
+    #[case::allowed("/tmp/{instrument}/{proposal}/{visit}", None)]
+    #[case::missing_required("/tmp/{instrument}/{visit}", Some("TEMPLATE_POLICY"))]
+    #[case::forbidden("/tmp/{instrument}/{proposal}/{year}/{visit}", Some("TEMPLATE_POLICY"))]
+    #[tokio::test]
+    async fn configure_with_template_policy(#[case] visit: &str, #[case] error: Option<&str>) {
+        let db = i22_db().await;
+        let schema = schema_builder(db.clone())
+            .data(TemplatePolicies::new(
+                &["visit=proposal".parse().unwrap()],
+                &["visit=year".parse().unwrap()],
+            ))
+            .finish();
+        let result = schema
+            .execute(format!(
+                r#"mutation {{
+                    configure(beamline: "i22", config: {{ visit: "{visit}" }}) {{ visitTemplate }}
+                }}"#
+            ))
+            .await;
+        match error {
+            None => {
+                assert!(result.errors.is_empty(), "{:?}", result.errors);
+                assert_eq!(result.data, value!({"configure": {"visitTemplate": visit}}));
+            }
+            Some(code) => {
+                assert_eq!(result.errors.len(), 1);
+                assert_eq!(
+                    result.errors[0]
+                        .extensions
+                        .as_ref()
+                        .and_then(|ext| ext.get("code")),
+                    Some(&value!(code))
+                );
+                // The existing template is unchanged
+                assert_eq!(
+                    db.current_configuration("i22")
+                        .await
+                        .unwrap()
+                        .visit()
+                        .unwrap()
+                        .to_string(),
+                    "/tmp/{instrument}/data/{year}/{visit}"
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_number_format(#[future(awt)] schema: NtSchema) {
+        let query = r#"mutation {
+            scan(beamline: "i22", visit: "cm12345-3") {
+                scanFile
+                scanNumberFormat { radix spec }
+            }
+        }"#;
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {
+                "scanFile": "i22-123",
+                "scanNumberFormat": {"radix": 10, "spec": "dec"}
+            }})
+        );
+
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {scan: "{instrument}-{scan_number:hex}"}) {
+                        scanNumberFormat { radix spec }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"scanNumberFormat": {"radix": 16, "spec": "hex"}}})
+        );
+        let result = schema.execute(query).await;
+        assert_eq!(
+            result.data,
+            value!({"scan": {
+                "scanFile": "i22-7c",
+                "scanNumberFormat": {"radix": 16, "spec": "hex"}
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn federated_beamline_entity() {
+        let schema = schema_builder(i22_db().await).finish();
+        let result = schema
+            .execute(
+                r#"{ _entities(representations: [
+                    {__typename: "BeamlineConfiguration", name: "i22"}
+                ]) { ... on BeamlineConfiguration { name latestScanNumber } } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"_entities": [{"name": "i22", "latestScanNumber": 122}]})
+        );
+
+        let result = schema.execute("{ _service { sdl } }").await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let sdl = result.data.into_json().unwrap();
+        assert!(sdl["_service"]["sdl"]
+            .as_str()
+            .unwrap()
+            .contains(r#"type BeamlineConfiguration @key(fields: "name")"#));
+    }
+
+    #[test]
+    fn entity_resolver_not_a_query_field() {
+        // Only the federation SDL (from _service) includes the federation directives
+        let sdl = Schema::new(Query, Mutation, EmptySubscription).sdl();
+        assert!(!sdl.contains("@key"));
+        assert!(!sdl.contains("findBeamlineByName"));
+    }
+
+    #[tokio::test]
+    async fn config_overlay() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        fs::File::create(root.path().join("i22").join("130.i22")).unwrap();
+        let sandbox = tempdir().unwrap();
+        fs::File::create(sandbox.path().join("150.i22")).unwrap();
+
+        let overlay: ConfigOverlay = serde_json::from_value(serde_json::json!({
+            "beamlines": {"i22": {"trackerDirectory": sandbox.path(), "year": 2023}}
+        }))
+        .unwrap();
+        let nt = NumTracker::for_root_directory(Some(root.path()))
+            .unwrap()
+            .with_directories(overlay.tracker_directories());
+        let schema = schema_builder(i22_db().await)
+            .data(nt)
+            .data(overlay)
+            .finish();
+        let result = schema
+            .execute(
+                r#"mutation { scan(beamline: "i22", visit: "cm12345-3") {
+                    scanNumber
+                    visit { directory }
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {
+                "scanNumber": 151,
+                "visit": {"directory": "/tmp/i22/data/2023/cm12345-3"}
+            }})
+        );
+        assert!(sandbox.path().join("151.i22").exists());
+        // The tracker directory in the root is left alone
+        assert!(root.path().join("i22").join("130.i22").exists());
+        assert!(!root.path().join("i22").join("151.i22").exists());
+
+        // A year requested by the client still takes precedence
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3", year: 2022) { directory } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": "/tmp/i22/data/2022/cm12345-3"}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn normalize_detectors(#[future(awt)] schema: NtSchema) {
+        let query = |beamline: &str| {
+            format!(
+                r#"{{ normalizeDetectors(names: ["foo.bar", "foo bar", "Camera"]{beamline}) {{
+                    name normalised collision
+                }} }}"#
+            )
+        };
+        let result = schema.execute(query("")).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"normalizeDetectors": [
+                {"name": "foo.bar", "normalised": "foo_bar", "collision": true},
+                {"name": "foo bar", "normalised": "foo_bar", "collision": true},
+                {"name": "Camera", "normalised": "Camera", "collision": false},
+            ]})
+        );
+
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: {
+                    detectorLowercase: true, detectorReplacement: "-"
+                }) { latestScanNumber } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = schema.execute(query(r#", beamline: "i22""#)).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"normalizeDetectors": [
+                {"name": "foo.bar", "normalised": "foo-bar", "collision": true},
+                {"name": "foo bar", "normalised": "foo-bar", "collision": true},
+                {"name": "Camera", "normalised": "camera", "collision": false},
+            ]})
+        );
+
+        let result = schema.execute(query(r#", beamline: "i11""#)).await;
+        assert_eq!(result.errors.len(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn custom_scalars_described(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute("{ __schema { types { name kind description } } }")
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let json = result.data.into_json().unwrap();
+        let scalars = json["__schema"]["types"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|t| t["kind"] == "SCALAR")
+            .map(|t| (t["name"].as_str().unwrap(), t["description"].as_str()))
+            .collect::<HashMap<_, _>>();
+        for name in [
+            "Detector",
+            "DetectorTemplate",
+            "Instrument",
+            "Replacement",
+            "ScanTemplate",
+            "Subdirectory",
+            "TrackerExtension",
+            "VisitDate",
+            "VisitTemplate",
+        ] {
+            assert!(scalars[name].is_some(), "{name} has no description");
+        }
+        assert!(scalars["Subdirectory"]
+            .unwrap()
+            .contains("parent directory (`..`) segments are not permitted"));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn next_scan_number(#[future(awt)] schema: NtSchema) {
+        for _ in 0..2 {
+            let result = schema
+                .execute(r#"{ nextScanNumber(beamline: "i22") }"#)
+                .await;
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            assert_eq!(result.data, value!({"nextScanNumber": 123}));
+        }
+        let result = schema
+            .execute(r#"{ configuration(beamline: "i22") { latestScanNumber } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"configuration": {"latestScanNumber": 122}})
+        );
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_number_step(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {scanNumberStep: 5}) { scanNumberStep }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"configure": {"scanNumberStep": 5}}));
+        let result = schema
+            .execute(r#"{ nextScanNumber(beamline: "i22") }"#)
+            .await;
+        assert_eq!(result.data, value!({"nextScanNumber": 127}));
+        for expected in [127, 132] {
+            let result = schema
+                .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+                .await;
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            assert_eq!(result.data, value!({"scan": {"scanNumber": expected}}));
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn instrument_differs_from_name(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(r#"{ configuration(beamline: "i22") { name instrument } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"configuration": {"name": "i22", "instrument": "i22"}})
+        );
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {instrument: "BL22I"}) { name instrument }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"name": "i22", "instrument": "BL22I"}})
+        );
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        visit { beamline directory }
+                        scanFile
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {
+                "visit": {"beamline": "i22", "directory": "/tmp/BL22I/data/2024/cm12345-3"},
+                "scanFile": "BL22I-123",
+            }})
+        );
+    }
+
+    #[rstest]
+    #[case::empty("", "Instrument cannot be empty")]
+    #[case::separator("i22/extra", "Instrument must be a single path segment")]
+    #[case::parent("..", "Instrument must be a single path segment")]
+    #[tokio::test]
+    async fn invalid_instrument(
+        #[future(awt)] schema: NtSchema,
+        #[case] instrument: &str,
+        #[case] message: &str,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"mutation {{
+                    configure(beamline: "i22", config: {{instrument: "{instrument}"}}) {{ name }}
+                }}"#
+            ))
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert!(
+            result.errors[0].message.contains(message),
+            "{}",
+            result.errors[0].message
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn zero_scan_number_step(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {scanNumberStep: 0}) { scanNumberStep }
+                }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        let ext = result.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(ext.get("code"), Some(&value!("INVALID_CONFIGURATION")));
+        assert_eq!(
+            ext.get("errors"),
+            Some(&value!([{
+                "field": "scanNumberStep",
+                "message": "Scan number step must be positive"
+            }]))
+        );
+        let result = schema
+            .execute(r#"{ configuration(beamline: "i22") { scanNumberStep } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"configuration": {"scanNumberStep": 1}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn configuration_preview(#[future(awt)] schema: NtSchema) {
+        let query = r#"{
+            configurationPreview(beamline: "i22", config: { scanNumber: 200, extension: "i22" }) {
+                field oldValue newValue
+            }
+        }"#;
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configurationPreview": [
+                {"field": "scan_number", "oldValue": "122", "newValue": "200"}
+            ]})
+        );
+        // Previewing the change does not make it
+        let result = schema.execute(query).await;
+        assert_eq!(
+            result.data,
+            value!({"configurationPreview": [
+                {"field": "scan_number", "oldValue": "122", "newValue": "200"}
+            ]})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_includes_visit_directory(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3", sub: "sample") {
+                        scanFile
+                        scanNumber
+                        visit { beamline visit directory }
+                        detectors(names: ["det one", "camera"]) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({
+                "scan": {
+                    "scanFile": "sample/i22-123",
+                    "scanNumber": 123,
+                    "visit": {
+                        "beamline": "i22",
+                        "visit": "cm12345-3",
+                        "directory": "/tmp/i22/data/2024/cm12345-3",
+                    },
+                    "detectors": [
+                        {"name": "det_one", "path": "sample/i22-123-det_one"},
+                        {"name": "camera", "path": "sample/i22-123-camera"},
+                    ],
+                }
+            })
+        );
+        assert!(result.extensions.is_empty());
+    }
+
+    #[rstest]
+    #[case::positional(
+        "",
+        value!([
+            {"name": "det_one", "path": "i22-123-det_one"},
+            {"name": "camera", "path": "i22-123-camera"},
+            {"name": "det_one", "path": "i22-123-det_one"},
+            {"name": "camera", "path": "i22-123-camera"},
+        ])
+    )]
+    #[case::explicit_positional(
+        ", distinct: false",
+        value!([
+            {"name": "det_one", "path": "i22-123-det_one"},
+            {"name": "camera", "path": "i22-123-camera"},
+            {"name": "det_one", "path": "i22-123-det_one"},
+            {"name": "camera", "path": "i22-123-camera"},
+        ])
+    )]
+    #[case::distinct(
+        ", distinct: true",
+        value!([
+            {"name": "det_one", "path": "i22-123-det_one"},
+            {"name": "camera", "path": "i22-123-camera"},
+        ])
+    )]
+    #[tokio::test]
+    async fn overlapping_detectors(
+        #[future(awt)] schema: NtSchema,
+        #[case] args: &str,
+        #[case] expected: Value,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"mutation {{
+                    scan(beamline: "i22", visit: "cm12345-3") {{
+                        detectors(names: ["det one", "camera", "det-one", "camera"]{args}) {{
+                            name path
+                        }}
+                    }}
+                }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"detectors": expected}}));
+    }
+
+    #[rstest]
+    #[case::at_limit(&["one", "two"], None)]
+    #[case::over_limit(&["one", "two", "three"], Some("TOO_MANY_DETECTORS"))]
+    #[tokio::test]
+    async fn detector_limit(#[case] names: &[&str], #[case] error: Option<&str>) {
+        let schema = schema_builder(i22_db().await)
+            .data(MaxDetectors(2))
+            .finish();
+        let result = schema
+            .execute(format!(
+                r#"{{ scanPaths(beamline: "i22", visit: "cm12345-3", scanNumber: 12) {{
+                    detectors(names: {names:?}) {{ name }}
+                }} }}"#
+            ))
+            .await;
+        match error {
+            None => {
+                assert!(result.errors.is_empty(), "{:?}", result.errors);
+                assert_eq!(
+                    result.data,
+                    value!({"scanPaths": {"detectors": [{"name": "one"}, {"name": "two"}]}})
+                );
+            }
+            Some(code) => {
+                assert_eq!(result.errors.len(), 1);
+                assert_eq!(
+                    result.errors[0]
+                        .extensions
+                        .as_ref()
+                        .and_then(|ext| ext.get("code")),
+                    Some(&value!(code))
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    #[case::at_limit(r#"["one"]"#, None)]
+    #[case::over_limit(r#"["one", "two"]"#, Some("TOO_MANY_DETECTORS"))]
+    #[tokio::test]
+    async fn scan_detector_paths_limit(#[case] second: &str, #[case] error: Option<&str>) {
+        let schema = schema_builder(i22_db().await)
+            .data(MaxDetectors(3))
+            .finish();
+        // The limit applies to the total across scans rather than to each scan
+        let result = schema
+            .execute(format!(
+                r#"{{ scanDetectorPaths(beamline: "i22", visit: "cm12345-3", scans: [
+                    {{scanNumber: 12, detectors: ["one", "two"]}},
+                    {{scanNumber: 13, detectors: {second}}}
+                ]) {{ scanNumber }} }}"#
+            ))
+            .await;
+        let code = result
+            .errors
+            .first()
+            .and_then(|e| e.extensions.as_ref())
+            .and_then(|ext| ext.get("code"));
+        assert_eq!(
+            code,
+            error.map(|c| value!(c)).as_ref(),
+            "{:?}",
+            result.errors
+        );
+    }
+
+    #[rstest]
+    #[case::allowed("cm12345-3", None)]
+    #[case::unknown_code("ab12345-3", Some("UNKNOWN_PROPOSAL_CODE"))]
+    #[tokio::test]
+    async fn proposal_code_allowlist(#[case] visit: &str, #[case] error: Option<&str>) {
+        let schema = schema_builder(i22_db().await)
+            .data(ProposalCodes::new(["cm".into(), "mx".into()]))
+            .finish();
+        let result = schema
+            .execute(format!(
+                r#"{{
+                    paths(beamline: "i22", visit: "{visit}") {{ directory }}
+                    validateVisit(visit: "{visit}") {{ __typename }}
+                }}"#
+            ))
+            .await;
+        match error {
+            None => {
+                assert!(result.errors.is_empty(), "{:?}", result.errors);
+                assert_eq!(
+                    result.data,
+                    value!({
+                        "paths": {"directory": "/tmp/i22/data/2024/cm12345-3"},
+                        "validateVisit": {"__typename": "ValidVisit"}
+                    })
+                );
+            }
+            Some(code) => {
+                assert_eq!(result.errors.len(), 1);
+                assert_eq!(
+                    result.errors[0]
+                        .extensions
+                        .as_ref()
+                        .and_then(|ext| ext.get("code")),
+                    Some(&value!(code))
+                );
+                let result = schema
+                    .execute(format!(
+                        r#"{{ validateVisit(visit: "{visit}") {{
+                            ... on InvalidVisitDetails {{ reason }}
+                        }} }}"#
+                    ))
+                    .await;
+                assert_eq!(
+                    result.data,
+                    value!({"validateVisit": {"reason": "UNKNOWN_CODE"}})
+                );
+            }
+        }
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn detectors_with_subdirectories(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3", sub: "sample") {
+                        detectors(names: ["camera"], requests: [
+                            {name: "det one", subdirectory: "dets/one"},
+                            {name: "det two", subdirectory: null},
+                        ]) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({
+                "scan": {
+                    "detectors": [
+                        {"name": "camera", "path": "sample/i22-123-camera"},
+                        {"name": "det_one", "path": "sample/dets/one/i22-123-det_one"},
+                        {"name": "det_two", "path": "sample/i22-123-det_two"},
+                    ],
+                }
+            })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn distinct_detectors_with_options(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        detectors(names: ["det one"], requests: [
+                            {name: "camera", subdirectory: "cams"},
+                            {name: "det-one"},
+                            {name: "camera", subdirectory: "cams"},
+                        ], distinct: true) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({
+                "scan": {
+                    "detectors": [
+                        {"name": "det_one", "path": "i22-123-det_one"},
+                        {"name": "camera", "path": "cams/i22-123-camera"},
+                    ],
+                }
+            })
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn conflicting_distinct_detectors(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        detectors(names: ["camera"], requests: [
+                            {name: "camera", subdirectory: "cams"},
+                        ], distinct: true) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert_eq!(result.data, Value::Null);
+        let err = &result.errors[0];
+        assert_eq!(
+            err.message,
+            r#"Detector "camera" is requested more than once with different options"#
+        );
+        let code = err.extensions.as_ref().and_then(|ext| ext.get("code"));
+        assert_eq!(code, Some(&value!("CONFLICTING_DETECTORS")));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn detector_groups(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {
+                        detectorGroups: [
+                            {name: "scalers", template: "{subdirectory}/scalers/{instrument}-{scan_number}-{detector}"}
+                        ]
+                    }) { detectorGroups { name template } }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"detectorGroups": [
+                {"name": "scalers", "template": "{subdirectory}/scalers/{instrument}-{scan_number}-{detector}"}
+            ]}})
+        );
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3", sub: "sample") {
+                        detectors(names: ["camera"], requests: [
+                            {name: "counter", group: "scalers"},
+                            {name: "det", subdirectory: "dets", group: "scalers"},
+                        ]) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"detectors": [
+                {"name": "camera", "path": "sample/i22-123-camera"},
+                {"name": "counter", "path": "sample/scalers/i22-123-counter"},
+                {"name": "det", "path": "sample/scalers/dets/i22-123-det"},
+            ]}})
+        );
+    }
+
+    #[rstest]
+    #[case::mapped_code("in12345-1", "/industrial/i22/in12345-1")]
+    #[case::mapped_over_commissioning("cm12345-1", "/cm/i22/2024/cm12345-1")]
+    #[case::commissioning_code("nt12345-1", "/commissioning/i22/nt12345-1")]
+    #[case::default("mx12345-1", "/tmp/i22/data/2024/mx12345-1")]
+    #[tokio::test]
+    async fn visit_code_templates(
+        #[future(awt)] schema: NtSchema,
+        #[case] visit: &str,
+        #[case] expected: &str,
+    ) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {
+                        commissioningVisit: "/commissioning/{instrument}/{visit}",
+                        commissioningCodes: ["cm", "nt"],
+                        visitCodeTemplates: [
+                            {code: "in", template: "/industrial/{instrument}/{visit}"},
+                            {code: "cm", template: "/cm/{instrument}/{year}/{visit}"},
+                        ]
+                    }) { visitCodeTemplates { code template } }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"visitCodeTemplates": [
+                {"code": "cm", "template": "/cm/{instrument}/{year}/{visit}"},
+                {"code": "in", "template": "/industrial/{instrument}/{visit}"},
+            ]}})
+        );
+        let result = schema
+            .execute(format!(
+                r#"{{ paths(beamline: "i22", visit: "{visit}") {{
+                    directory
+                    templateStatus {{ visit {{ valid }} }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": expected, "templateStatus": {"visit": {"valid": true}}}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn remove_visit_code_template(#[future(awt)] schema: NtSchema) {
+        let configure = |templates: &str| {
+            format!(
+                r#"mutation {{
+                    configure(beamline: "i22", config: {{ visitCodeTemplates: {templates} }}) {{
+                        visitCodeTemplates {{ code }}
+                    }}
+                }}"#
+            )
+        };
+        let directory = r#"{ paths(beamline: "i22", visit: "in12345-1") { directory } }"#;
+        let result = schema
+            .execute(configure(
+                r#"[{code: "in", template: "/industrial/{instrument}/{visit}"}]"#,
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = schema.execute(directory).await;
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": "/industrial/i22/in12345-1"}})
+        );
+
+        let result = schema
+            .execute(configure(r#"[{code: "in", template: null}]"#))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"visitCodeTemplates": []}})
+        );
+        let result = schema.execute(directory).await;
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": "/tmp/i22/data/2024/in12345-1"}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn invalid_visit_code_template(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {
+                        visitCodeTemplates: [{code: "in", template: "industrial/{visit}"}]
+                    }) { name }
+                }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        let ext = result.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(ext.get("code"), Some(&value!("INVALID_CONFIGURATION")));
+        assert_eq!(
+            ext.get("errors"),
+            Some(&value!([{
+                "field": "visitCodeTemplates.in",
+                "message": "Invalid visit template: Path should be absolute"
+            }]))
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn unknown_detector_group(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        detectors(requests: [{name: "counter", group: "scalers"}]) { path }
+                    }
+                }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("UNKNOWN_DETECTOR_GROUP"))
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn detector_with_invalid_subdirectory(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        detectors(requests: [{name: "camera", subdirectory: "../up"}]) { path }
+                    }
+                }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.data, Value::Null);
+    }
+
+    #[rstest]
+    #[case::default("", "Foo_Bar_Baz")]
+    #[case::lowercase("detectorLowercase: true", "foo_bar_baz")]
+    #[case::no_collapse("detectorCollapse: false", "Foo_Bar__Baz")]
+    #[case::replacement(r#"detectorReplacement: "-""#, "Foo-Bar-Baz")]
+    #[case::all(
+        r#"detectorLowercase: true, detectorCollapse: false, detectorReplacement: "-""#,
+        "foo-bar--baz"
+    )]
+    #[tokio::test]
+    async fn configured_detector_normalisation(
+        #[future(awt)] schema: NtSchema,
+        #[case] config: &str,
+        #[case] name: &str,
+    ) {
+        if !config.is_empty() {
+            let result = schema
+                .execute(format!(
+                    r#"mutation {{ configure(beamline: "i22", config: {{ {config} }}) {{ latestScanNumber }} }}"#
+                ))
+                .await;
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+        }
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3", sub: "sample") {
+                        detectors(names: ["Foo Bar..Baz"]) { name path }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"detectors": [
+                {"name": name, "path": format!("sample/i22-123-{name}")}
+            ]}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn invalid_detector_names(#[future(awt)] schema: NtSchema) {
+        let query = r#"mutation {
+            scan(beamline: "i22", visit: "cm12345-3") {
+                detectors(names: ["foo.bar", "Camera"]) { name path }
+            }
+        }"#;
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"detectors": [
+                {"name": "foo_bar", "path": "i22-123-foo_bar"},
+                {"name": "Camera", "path": "i22-123-Camera"},
+            ]}})
+        );
+
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: { detectorRejectInvalid: true }) {
+                        detectorRejectInvalid
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {"detectorRejectInvalid": true}})
+        );
+
+        let result = schema.execute(query).await;
+        assert_eq!(result.errors.len(), 1, "{:?}", result.errors);
+        assert_eq!(
+            result.errors[0].message,
+            r#"Detector name "foo.bar" contains invalid characters"#
+        );
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("INVALID_DETECTOR"))
+        );
+
+        // The scan was rejected before a number was allocated for it
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 124}}));
+    }
+
+    #[rstest]
+    #[case::valid(
+        "cm12345-3",
+        value!({"validateVisit": {
+            "__typename": "ValidVisit", "code": "cm", "proposal": 12345, "session": 3
+        }})
+    )]
+    #[case::missing_session(
+        "cm12345",
+        value!({"validateVisit": {
+            "__typename": "InvalidVisitDetails",
+            "reason": "MISSING_SESSION",
+            "message": "Visit is missing a session number"
+        }})
+    )]
+    #[case::invalid_proposal(
+        "cm12x45-3",
+        value!({"validateVisit": {
+            "__typename": "InvalidVisitDetails",
+            "reason": "INVALID_PROPOSAL",
+            "message": "Visit has an invalid proposal number"
+        }})
+    )]
+    #[tokio::test]
+    async fn validate_visit(
+        #[future(awt)] schema: NtSchema,
+        #[case] visit: &str,
+        #[case] expected: Value,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"{{ validateVisit(visit: "{visit}") {{
+                    __typename
+                    ... on ValidVisit {{ code proposal session }}
+                    ... on InvalidVisitDetails {{ reason message }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, expected);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn validate_visits(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"{ validateVisits(visits: ["cm12345-3", "cm12345", "mx54321-1", "cm12345-x", "cm12x45-3"]) {
+                    __typename
+                    ... on ValidVisit { code proposal session }
+                    ... on InvalidVisitDetails { reason }
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"validateVisits": [
+                {"__typename": "ValidVisit", "code": "cm", "proposal": 12345, "session": 3},
+                {"__typename": "InvalidVisitDetails", "reason": "MISSING_SESSION"},
+                {"__typename": "ValidVisit", "code": "mx", "proposal": 54321, "session": 1},
+                {"__typename": "InvalidVisitDetails", "reason": "INVALID_SESSION"},
+                {"__typename": "InvalidVisitDetails", "reason": "INVALID_PROPOSAL"},
+            ]})
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_tracker_directory() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        let nt = NumTracker::for_root_directory(Some(root.path())).unwrap();
+        // Directory is removed after the service has started
+        fs::remove_dir(root.path().join("i22")).unwrap();
+
+        let schema = schema_with(fixed_clock(2024, 6, 1, 12, 0, 0), nt).await;
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+    }
+
+    #[rstest]
+    #[case::earlier_visit(r#", visitDate: "2023-11-20""#, "/tmp/i22/data/2023/cm12345-3")]
+    #[case::no_visit_date("", "/tmp/i22/data/2024/cm12345-3")]
+    #[tokio::test]
+    async fn year_from_visit_date(
+        #[future(awt)] schema: NtSchema,
+        #[case] date: &str,
+        #[case] directory: &str,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"{{ paths(beamline: "i22", visit: "cm12345-3"{date}) {{ directory }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"paths": {"directory": directory}}));
+
+        let result = schema
+            .execute(format!(
+                r#"mutation {{ scan(beamline: "i22", visit: "cm12345-3"{date}) {{
+                    visit {{ directory }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"visit": {"directory": directory}}})
+        );
+    }
+
+    #[rstest]
+    #[case::paths(r#"{ paths(beamline: "i22", visit: "cm12345-3", year: 2019) { directory } }"#)]
+    #[case::scan_paths(
+        r#"{
+        scanPaths(beamline: "i22", visit: "cm12345-3", scanNumber: 12, year: 2019) {
+            visit { directory }
+        }
+    }"#
+    )]
+    #[case::scan(
+        r#"mutation {
+        scan(beamline: "i22", visit: "cm12345-3", visitDate: "2023-11-20", year: 2019) {
+            visit { directory }
+        }
+    }"#
+    )]
+    #[tokio::test]
+    async fn year_override(#[future(awt)] schema: NtSchema, #[case] query: &str) {
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let data = result.data.into_json().unwrap();
+        assert_eq!(
+            data.pointer("/paths/directory")
+                .or(data.pointer("/scanPaths/visit/directory"))
+                .or(data.pointer("/scan/visit/directory")),
+            Some(&serde_json::json!("/tmp/i22/data/2019/cm12345-3"))
+        );
+    }
+
+    /// Schema using the given server for authorization
+    async fn policy_schema(server: &MockServer) -> NtSchema {
+        schema_with_policy(server.url("")).await
+    }
+
+    async fn schema_with_policy(host: String) -> NtSchema {
+        schema_builder(i22_db().await)
+            .data(Some(PolicyCheck::new(PolicyOptions {
+                policy_host: host,
+                access_query: "demo/access".into(),
+                admin_query: "demo/admin".into(),
+                ..Default::default()
+            })))
+            .finish()
+    }
+
+    /// Address of a policy server with nothing listening on it
+    fn unreachable_policy() -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        format!("http://{addr}")
+    }
+
+    async fn execute_with_token(schema: &NtSchema, query: &str) -> async_graphql::Response {
+        schema
+            .execute(
+                async_graphql::Request::new(query)
+                    .data(Some(Authorization::bearer("token").unwrap())),
+            )
+            .await
+    }
+
+    #[rstest]
+    #[case::configuration(r#"{ configuration(beamline: "i22") { latestScanNumber } }"#)]
+    #[case::year_override(
+        r#"{ paths(beamline: "i22", visit: "cm12345-3", year: 2019) { directory } }"#
+    )]
+    #[case::history(r#"{ configurationHistory(beamline: "i22") { field } }"#)]
+    #[case::preview(
+        r#"{ configurationPreview(beamline: "i22", config: { scanNumber: 5 }) { field } }"#
+    )]
+    #[case::entity(
+        r#"{ _entities(representations: [{__typename: "BeamlineConfiguration", name: "i22"}]) {
+            ... on BeamlineConfiguration { latestScanNumber }
+        } }"#
+    )]
+    #[tokio::test]
+    async fn admin_reads_fail_closed(#[case] query: &str) {
+        let schema = schema_with_policy(unreachable_policy()).await;
+        let result = execute_with_token(&schema, query).await;
+        assert_eq!(result.errors.len(), 1, "{:?}", result.errors);
+        assert_eq!(
+            result.errors[0].message,
+            "Invalid authorization configuration"
+        );
+    }
+
+    #[tokio::test]
+    async fn next_scan_number_needs_no_authorization() {
+        let schema = schema_with_policy(unreachable_policy()).await;
+        let result = schema
+            .execute(r#"{ nextScanNumber(beamline: "i22") }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"nextScanNumber": 123}));
+    }
+
+    #[rstest]
+    #[case::scan(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)]
+    #[case::configure(
+        r#"mutation { configure(beamline: "i22", config: { scanNumber: 5 }) { latestScanNumber } }"#
+    )]
+    #[tokio::test]
+    async fn writes_fail_closed(#[case] query: &str) {
+        let schema = schema_with_policy(unreachable_policy()).await;
+        let result = execute_with_token(&schema, query).await;
+        assert_eq!(result.errors.len(), 1, "{:?}", result.errors);
+        assert_eq!(
+            result.errors[0].message,
+            "Invalid authorization configuration"
+        );
+    }
+
+    /// Schema using a policy server that allows access to all visits but grants admin rights to
+    /// no-one.
+    async fn restricted_schema(server: &MockServer) -> NtSchema {
+        server
+            .mock_async(|when, then| {
+                when.method("POST").path("/demo/access");
+                then.status(200)
+                    .json_body(serde_json::json!({"result": true}));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("POST").path("/demo/admin");
+                then.status(200)
+                    .json_body(serde_json::json!({"result": false}));
+            })
+            .await;
+        policy_schema(server).await
+    }
+
+    #[tokio::test]
+    async fn request_timings() {
+        let server = MockServer::start_async().await;
+        let schema = restricted_schema(&server).await;
+        let query = r#"mutation {
+            scan(beamline: "i22", visit: "cm12345-3") { scanFile visit { directory } }
+        }"#;
+        let req = || {
+            async_graphql::Request::new(query).data(Some(Authorization::bearer("token").unwrap()))
+        };
+        let result = execute_timed(&schema, req()).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let Some(Value::Object(timings)) = result.extensions.get("timings") else {
+            panic!("Missing timings: {:?}", result.extensions);
+        };
+        assert_eq!(
+            timings.keys().map(|k| k.as_str()).collect::<Vec<_>>(),
+            ["auth", "db", "render"]
+        );
+
+        let result = execute(&schema, req()).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert!(!result.extensions.contains_key("timings"));
+    }
+
+    #[tokio::test]
+    async fn missing_beamline_skips_authorization() {
+        let server = MockServer::start_async().await;
+        let policy = server
+            .mock_async(|when, then| {
+                when.any_request();
+                then.status(200)
+                    .json_body(serde_json::json!({"result": true}));
+            })
+            .await;
+        let schema = policy_schema(&server).await;
+        let result = schema
+            .execute(
+                async_graphql::Request::new(
+                    r#"mutation { scan(beamline: "b21", visit: "cm12345-3") { scanNumber } }"#,
+                )
+                .data(Some(Authorization::bearer("token").unwrap())),
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1, "{:?}", result.errors);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("MISSING_BEAMLINE"))
+        );
+        policy.assert_hits_async(0).await;
+    }
+
+    #[rstest]
+    #[case::no_visit_date(", year: 2019")]
+    #[case::different_visit_date(r#", visitDate: "2023-11-20", year: 2019"#)]
+    #[case::matching_visit_date(r#", visitDate: "2023-11-20", year: 2023"#)]
+    #[case::visit_date_only(r#", visitDate: "2023-11-20""#)]
+    #[tokio::test]
+    async fn year_override_requires_admin(#[case] args: &str) {
+        let server = MockServer::start_async().await;
+        let schema = restricted_schema(&server).await;
+        let result = schema
+            .execute(
+                async_graphql::Request::new(format!(
+                    r#"mutation {{
+                        scan(beamline: "i22", visit: "cm12345-3"{args}) {{
+                            scanNumber
+                        }}
+                    }}"#
+                ))
+                .data(Some(Authorization::bearer("token").unwrap())),
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1, "{:?}", result.errors);
+        assert_eq!(result.errors[0].message, "Authentication failed");
+        assert_eq!(result.data, Value::Null);
+    }
+
+    #[rstest]
+    #[case::year(", year: 2024")]
+    #[case::visit_date(r#", visitDate: "2024-01-10""#)]
+    #[tokio::test]
+    async fn current_year_is_not_an_override(#[case] args: &str) {
+        let server = MockServer::start_async().await;
+        let schema = restricted_schema(&server).await;
+        let result = schema
+            .execute(
+                async_graphql::Request::new(format!(
+                    r#"mutation {{
+                        scan(beamline: "i22", visit: "cm12345-3"{args}) {{
+                            visit {{ directory }}
+                        }}
+                    }}"#
+                ))
+                .data(Some(Authorization::bearer("token").unwrap())),
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"visit": {"directory": "/tmp/i22/data/2024/cm12345-3"}}})
+        );
+    }
+
+    const SAMPLE: &str = r#"[
+        { field: "instrument", value: "i22" },
+        { field: "year", value: "2024" },
+        { field: "visit", value: "cm12345-3" },
+        { field: "scan_number", value: "42" },
+        { field: "subdirectory", value: "sample" },
+        { field: "detector", value: "camera" },
+    ]"#;
+
+    #[rstest]
+    #[case::visit(
+        "VISIT",
+        "/tmp/{instrument}/data/{year}/{visit}",
+        "/tmp/i22/data/2024/cm12345-3"
+    )]
+    #[case::scan("SCAN", "{subdirectory}/{instrument}-{scan_number}", "sample/i22-42")]
+    #[case::detector(
+        "DETECTOR",
+        "{subdirectory}/{scan_number}/{instrument}-{scan_number}-{detector}",
+        "sample/42/i22-42-camera"
+    )]
+    #[tokio::test]
+    async fn render_template(
+        #[future(awt)] schema: NtSchema,
+        #[case] kind: &str,
+        #[case] template: &str,
+        #[case] path: &str,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"{{ renderTemplate(kind: {kind}, template: "{template}", sampleContext: {SAMPLE}) {{
+                    ... on RenderedTemplate {{ path }}
+                    ... on InvalidTemplateDetails {{ message }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"renderTemplate": {"path": path}}));
+    }
+
+    #[rstest]
+    #[case::unparseable("SCAN", "{scan_number", "Unclosed placeholder")]
+    #[case::relative_visit("VISIT", "data/{visit}", "should be absolute")]
+    #[case::missing_field("DETECTOR", "{scan_number}", "detector")]
+    #[case::unknown_field("SCAN", "{scan_number}-{foo}", "Invalid placeholder")]
+    #[case::no_sample(
+        "SCAN",
+        "{scan_number}/{proposal}",
+        "No sample values given for fields: proposal"
+    )]
+    #[tokio::test]
+    async fn render_invalid_template(
+        #[future(awt)] schema: NtSchema,
+        #[case] kind: &str,
+        #[case] template: &str,
+        #[case] message: &str,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"{{ renderTemplate(kind: {kind}, template: "{template}", sampleContext: {SAMPLE}) {{
+                    ... on RenderedTemplate {{ path }}
+                    ... on InvalidTemplateDetails {{ message }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let data = result.data.into_json().unwrap();
+        let actual = data["renderTemplate"]["message"].as_str().unwrap();
+        assert!(
+            actual.contains(message),
+            "{actual:?} does not contain {message:?}"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn configuration_history(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: { scanNumber: 200 }) {
+                    latestScanNumber
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = schema
+            .execute(
+                r#"{ configurationHistory(beamline: "i22", limit: 1) {
+                    field oldValue newValue
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configurationHistory": [
+                {"field": "scan_number", "oldValue": "122", "newValue": "200"}
+            ]})
+        );
+    }
+
+    #[rstest]
+    #[case::default(
+        "",
+        "/tmp/i22/data/2024/cm12345-3",
+        "sample/i22-123",
+        "sample/i22-123-camera"
+    )]
+    #[case::slash(
+        "(separator: SLASH)",
+        "/tmp/i22/data/2024/cm12345-3",
+        "sample/i22-123",
+        "sample/i22-123-camera"
+    )]
+    #[case::backslash(
+        "(separator: BACKSLASH)",
+        r"\tmp\i22\data\2024\cm12345-3",
+        r"sample\i22-123",
+        r"sample\i22-123-camera"
+    )]
+    #[tokio::test]
+    async fn path_separator(
+        #[future(awt)] schema: NtSchema,
+        #[case] sep: &str,
+        #[case] directory: &str,
+        #[case] scan_file: &str,
+        #[case] detector: &str,
+    ) {
+        let det_sep = sep.replace('(', ", ").replace(')', "");
+        let result = schema
+            .execute(format!(
+                r#"mutation {{ scan(beamline: "i22", visit: "cm12345-3", sub: "sample") {{
+                    visit {{ directory{sep} }}
+                    scanFile{sep}
+                    detectors(names: ["camera"]{det_sep}) {{ path }}
+                }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {
+                "visit": {"directory": directory},
+                "scanFile": scan_file,
+                "detectors": [{"path": detector}],
+            }})
+        );
+    }
+
+    #[rstest]
+    #[case::allow(MissingTrackerDirectory::Allow, None)]
+    #[case::warn(
+        MissingTrackerDirectory::Warn,
+        Some(value!(["Beamline \"i22\" does not have a tracker directory"]))
+    )]
+    #[tokio::test]
+    async fn scan_without_tracker_directory(
+        #[case] missing: MissingTrackerDirectory,
+        #[case] warnings: Option<Value>,
+    ) {
+        let schema = build_strict_schema(
+            i22_db().await,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(None::<&str>).unwrap(),
+            missing,
+        );
+        let result = execute(
+            &schema,
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#.into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+        assert_eq!(result.extensions.get("warnings"), warnings.as_ref());
+    }
+
+    /// Schema for a single beamline whose visit directories are in the given root directory
+    async fn visit_check_schema(root: &Path, check: VisitDirectoryCheck) -> NtSchema {
+        let db = SqliteScanPathService::memory().await;
+        BeamlineConfigurationUpdate {
+            visit: VisitTemplate::new_checked(&format!(
+                "{}/{{instrument}}/{{visit}}",
+                root.display()
+            ))
+            .ok(),
+            scan: ScanTemplate::new_checked("{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").ok(),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        schema_builder(db).data(check).finish()
+    }
+
+    #[rstest]
+    #[case::off(VisitDirectoryCheck::Off, "cm12345-3", Value::Null)]
+    #[case::exists(VisitDirectoryCheck::Report, "cm12345-3", value!(true))]
+    #[case::missing(VisitDirectoryCheck::Report, "cm12345-4", value!(false))]
+    #[case::required(VisitDirectoryCheck::Require, "cm12345-3", value!(true))]
+    #[tokio::test]
+    async fn visit_directory_exists(
+        #[case] check: VisitDirectoryCheck,
+        #[case] visit: &str,
+        #[case] exists: Value,
+    ) {
+        let root = tempdir().unwrap();
+        fs::create_dir_all(root.path().join("i22/cm12345-3")).unwrap();
+        let schema = visit_check_schema(root.path(), check).await;
+        let result = schema
+            .execute(format!(
+                r#"{{ paths(beamline: "i22", visit: "{visit}") {{ exists }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"paths": {"exists": exists}}));
+    }
+
+    #[tokio::test]
+    async fn visit_directory_unknown() {
+        // The beamline directory is not there so the visit directory might be on a filesystem
+        // that is not available
+        let root = tempdir().unwrap();
+        let schema = visit_check_schema(root.path(), VisitDirectoryCheck::Require).await;
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3") { exists } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"paths": {"exists": null}}));
+    }
+
+    #[tokio::test]
+    async fn visit_directory_required() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        let schema = visit_check_schema(root.path(), VisitDirectoryCheck::Require).await;
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3") { directory } }"#)
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("MISSING_VISIT_DIRECTORY"))
+        );
+    }
+
+    #[rstest]
+    #[case::unlimited(None, HiddenSubdirectories::Allow, "", "a/b/c/.d", None)]
+    #[case::default_depth(
+        Some(2),
+        HiddenSubdirectories::Allow,
+        "",
+        "a/b/c",
+        Some("INVALID_SUBDIRECTORY")
+    )]
+    #[case::stricter_depth(
+        Some(4),
+        HiddenSubdirectories::Allow,
+        "maxDepth: 1",
+        "a/b",
+        Some("INVALID_SUBDIRECTORY")
+    )]
+    #[case::looser_depth(Some(4), HiddenSubdirectories::Allow, "maxDepth: 3", "a/b/c", None)]
+    #[case::too_deep(
+        Some(4),
+        HiddenSubdirectories::Allow,
+        "maxDepth: 5",
+        "a",
+        Some("SUBDIRECTORY_RULES_EXCEEDED")
+    )]
+    #[case::hidden_denied(
+        None,
+        HiddenSubdirectories::Allow,
+        "allowHidden: false",
+        "a/.b",
+        Some("INVALID_SUBDIRECTORY")
+    )]
+    #[case::opt_in_default(
+        None,
+        HiddenSubdirectories::OptIn,
+        "",
+        "a/.b",
+        Some("INVALID_SUBDIRECTORY")
+    )]
+    #[case::opt_in(None, HiddenSubdirectories::OptIn, "allowHidden: true", "a/.b", None)]
+    #[case::never_hidden(
+        None,
+        HiddenSubdirectories::Deny,
+        "allowHidden: true",
+        "a",
+        Some("SUBDIRECTORY_RULES_EXCEEDED")
+    )]
+    #[tokio::test]
+    async fn subdirectory_rules(
+        #[case] max_depth: Option<usize>,
+        #[case] hidden: HiddenSubdirectories,
+        #[case] rules: &str,
+        #[case] sub: &str,
+        #[case] error: Option<&str>,
+    ) {
+        let schema = schema_builder(i22_db().await)
+            .data(SubdirectoryLimits {
+                max_depth,
+                default_depth: max_depth.map(|max| max.min(2)),
+                hidden,
+            })
+            .finish();
+        let result = schema
+            .execute(format!(
+                r#"{{ scanPaths(beamline: "i22", visit: "cm12345-3", scanNumber: 12,
+                    sub: "{sub}", subdirectoryRules: {{ {rules} }}) {{ scanNumber }} }}"#
+            ))
+            .await;
+        match error {
+            None => assert!(result.errors.is_empty(), "{:?}", result.errors),
+            Some(code) => {
+                assert_eq!(result.errors.len(), 1);
+                assert_eq!(
+                    result.errors[0]
+                        .extensions
+                        .as_ref()
+                        .and_then(|ext| ext.get("code")),
+                    Some(&value!(code))
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_subdirectory_does_not_allocate() {
+        let db = i22_db().await;
+        let schema = schema_builder(db.clone())
+            .data(SubdirectoryLimits {
+                max_depth: Some(1),
+                default_depth: Some(1),
+                ..Default::default()
+            })
+            .finish();
+        let result = schema
+            .execute(
+                r#"mutation { scan(beamline: "i22", visit: "cm12345-3", sub: "a/b") { scanNumber } }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("INVALID_SUBDIRECTORY"))
+        );
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            122
+        );
+    }
+
+    #[rstest]
+    #[case::too_deep(r#"{name: "cam", subdirectory: "a/b"}"#)]
+    #[case::hidden(r#"{name: "cam", subdirectory: ".a"}"#)]
+    #[tokio::test]
+    async fn invalid_detector_subdirectory(#[case] detector: &str) {
+        let db = i22_db().await;
+        let schema = schema_builder(db.clone())
+            .data(SubdirectoryLimits {
+                max_depth: Some(1),
+                default_depth: Some(1),
+                hidden: HiddenSubdirectories::Deny,
+            })
+            .finish();
+        for query in [
+            format!(
+                r#"{{ scanPaths(beamline: "i22", visit: "cm12345-3", scanNumber: 12) {{
+                    detectors(requests: [{detector}]) {{ path }}
+                }} }}"#
+            ),
+            format!(
+                r#"{{ scanDetectorPaths(beamline: "i22", visit: "cm12345-3",
+                    scans: [{{scanNumber: 12, requests: [{detector}]}}]) {{ scanNumber }} }}"#
+            ),
+            format!(
+                r#"mutation {{ scan(beamline: "i22", visit: "cm12345-3") {{
+                    detectors(requests: [{detector}]) {{ path }}
+                }} }}"#
+            ),
+        ] {
+            let result = schema.execute(&query).await;
+            assert_eq!(result.errors.len(), 1, "{query}: {:?}", result.errors);
+            assert_eq!(
+                result.errors[0]
+                    .extensions
+                    .as_ref()
+                    .and_then(|ext| ext.get("code")),
+                Some(&value!("INVALID_SUBDIRECTORY"))
+            );
+        }
+        // The scan was rejected before a number was allocated
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            122
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_without_tracker_directory_denied() {
+        let db = i22_db().await;
+        let schema = build_strict_schema(
+            db.clone(),
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(None::<&str>).unwrap(),
+            MissingTrackerDirectory::Deny,
+        );
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("MISSING_TRACKER_DIRECTORY"))
+        );
+        // No scan number was allocated
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            122
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_with_tracker_directory_allowed_when_strict() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        let schema = build_strict_schema(
+            i22_db().await,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(Some(root.path())).unwrap(),
+            MissingTrackerDirectory::Deny,
+        );
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+    }
+
+    #[rstest]
+    #[case::allow(MissingTrackerDirectory::Allow, None, 124)]
+    #[case::deny(MissingTrackerDirectory::Deny, Some("TRACKER_WRITE_FAILED"), 123)]
+    #[tokio::test]
+    async fn scan_with_unwritable_tracker(
+        #[case] missing: MissingTrackerDirectory,
+        #[case] code: Option<&str>,
+        #[case] next: u32,
+    ) {
+        let root = tempdir().unwrap();
+        // A directory where the next number file should be prevents the file being created
+        fs::create_dir_all(root.path().join("i22").join("123.i22")).unwrap();
+        let schema = build_strict_schema(
+            i22_db().await,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(Some(root.path())).unwrap(),
+            missing,
+        );
+        let query = r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#;
+        let result = schema.execute(query).await;
+        let codes = result
+            .errors
+            .iter()
+            .map(|e| {
+                e.extensions
+                    .as_ref()
+                    .and_then(|ext| ext.get("code"))
+                    .cloned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            code.map(|c| Some(value!(c)))
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+
+        // A failed write should not use up the scan number
+        fs::remove_dir(root.path().join("i22").join("123.i22")).unwrap();
+        let result = schema.execute(query).await;
+        assert_eq!(result.errors, &[]);
+        assert_eq!(result.data, value!({"scan": {"scanNumber": next}}));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn template_fields(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: {
+                    detector: "{subdirectory}/{scan_number}/{instrument}-{scan_number}-{detector}"
+                }) {
+                    visitTemplateFields
+                    scanTemplateFields
+                    detectorTemplateFields
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {
+                "visitTemplateFields": ["instrument", "year", "visit"],
+                "scanTemplateFields": ["subdirectory", "instrument", "scan_number"],
+                "detectorTemplateFields": ["subdirectory", "scan_number", "instrument", "detector"],
+            }})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn invalid_visit_date(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"{ paths(beamline: "i22", visit: "cm12345-3", visitDate: "20/11/2023") {
+                    directory
+                } }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn detector_paths_for_multiple_scans(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"{
+                    scanDetectorPaths(
+                        beamline: "i22",
+                        visit: "cm12345-3",
+                        scans: [
+                            { scanNumber: 42, detectors: ["camera", "det one"] },
+                            { scanNumber: 43, sub: "sample", detectors: ["camera"] },
+                            { scanNumber: 44, detectors: [] },
+                        ]
+                    ) { scanNumber detectors { name path } }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scanDetectorPaths": [
+                {"scanNumber": 42, "detectors": [
+                    {"name": "camera", "path": "i22-42-camera"},
+                    {"name": "det_one", "path": "i22-42-det_one"},
+                ]},
+                {"scanNumber": 43, "detectors": [
+                    {"name": "camera", "path": "sample/i22-43-camera"},
+                ]},
+                {"scanNumber": 44, "detectors": []},
+            ]})
+        );
+
+        // No scan numbers are allocated
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn missing_beamline_code(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(r#"{ paths(beamline: "b21", visit: "cm12345-3") { directory } }"#)
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("MISSING_BEAMLINE"))
+        );
+    }
+
+    #[tokio::test]
+    async fn template_status() {
+        let db = i22_db().await;
+        BeamlineConfigurationUpdate {
+            visit: VisitTemplate::new_checked("/tmp/{instrument}/{visit}").ok(),
+            // Valid path template but not valid as a scan template as it is absolute
+            scan: PathTemplate::new("/{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").ok(),
+            ..BeamlineConfigurationUpdate::empty("b21")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        let schema = build_schema(
+            db,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(None::<&str>).unwrap(),
+        );
+        let result = schema
+            .execute(
+                r#"{ paths(beamline: "b21", visit: "cm12345-3") {
+                    directory
+                    templateStatus {
+                        visit { valid error }
+                        scan { valid error }
+                        detector { valid error }
+                    }
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"paths": {
+                "directory": "/tmp/b21/cm12345-3",
+                "templateStatus": {
+                    "visit": {"valid": true, "error": null},
+                    "scan": {"valid": false, "error": "Path should be relative"},
+                    "detector": {"valid": true, "error": null},
+                },
+            }})
+        );
+    }
+
+    #[rstest]
+    #[case::visit(r#"{ paths(beamline: "b21", visit: "cm12345-3") { directory } }"#)]
+    #[case::config(r#"{ configuration(beamline: "b21") { visitTemplate } }"#)]
+    #[tokio::test]
+    async fn invalid_template_code(#[case] query: &str) {
+        let db = i22_db().await;
+        BeamlineConfigurationUpdate {
+            // Valid path template but not valid as a visit template as it is not absolute
+            visit: PathTemplate::new("data/{instrument}/{visit}").ok(),
+            scan: ScanTemplate::new_checked("{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").ok(),
+            ..BeamlineConfigurationUpdate::empty("b21")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        let schema = build_schema(
+            db,
+            fixed_clock(2024, 6, 1, 12, 0, 0),
+            NumTracker::for_root_directory(None::<&str>).unwrap(),
+        );
+        let result = schema.execute(query).await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("TEMPLATE_NOT_CONFIGURED"))
+        );
+    }
+
+    #[rstest]
+    #[case::visit("visit", "relative/{instrument}/{visit}", "Path should be absolute")]
+    #[case::scan("scan", "/abs/{scan_number}", "Path should be relative")]
+    #[case::detector(
+        "detector",
+        "{scan_number}-{detector",
+        "Error parsing template: Unclosed placeholder at 23"
+    )]
+    #[tokio::test]
+    async fn invalid_template_input_names_kind(
+        #[future(awt)] schema: NtSchema,
+        #[case] kind: &str,
+        #[case] template: &str,
+        #[case] problem: &str,
+    ) {
+        let query = format!(
+            r#"mutation {{ configure(beamline: "i22", config: {{ {kind}: "{template}" }}) {{ latestScanNumber }} }}"#
+        );
+        let result = schema.execute(query).await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0].message,
+            format!("Invalid {kind} template: {problem}")
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn all_invalid_templates_reported(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: {
+                    visit: "relative/{instrument}/{visit}"
+                    scan: "{subdirectory}/{scan_number}"
+                    detector: "{scan_number}"
+                    detectorGroups: [{name: "scalers", template: "/abs/{scan_number}-{detector}"}]
+                }) { latestScanNumber } }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        let ext = result.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(ext.get("code"), Some(&value!("INVALID_CONFIGURATION")));
+        assert_eq!(
+            ext.get("errors"),
+            Some(&value!([
+                {"field": "visit", "message": "Invalid visit template: Path should be absolute"},
+                {
+                    "field": "detector",
+                    "message": "Invalid detector template: Template should reference missing field: \"detector\""
+                },
+                {
+                    "field": "detectorGroups.scalers",
+                    "message": "Invalid detector template: Path should be relative"
+                },
+            ]))
+        );
+        // Nothing is changed
+        let result = schema
+            .execute(r#"{ configuration(beamline: "i22") { visitTemplate } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"configuration": {"visitTemplate": "/tmp/{instrument}/data/{year}/{visit}"}})
+        );
+    }
+
+    #[rstest]
+    #[case::decimal("{instrument}-{scan_number}", "i22-123")]
+    #[case::hex("{instrument}-{scan_number:hex}", "i22-7b")]
+    #[tokio::test]
+    async fn scan_number_radix(
+        #[future(awt)] schema: NtSchema,
+        #[case] template: &str,
+        #[case] expected: &str,
+    ) {
+        let result = schema
+            .execute(format!(
+                r#"mutation {{ configure(beamline: "i22", config: {{ scan: "{template}" }}) {{ scanTemplate }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanFile } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanFile": expected}}));
+    }
+
+    #[rstest]
+    #[case::proposal_only("cm12345")]
+    #[case::with_session("cm12345-3")]
+    #[tokio::test]
+    async fn proposal_visit_template(#[future(awt)] schema: NtSchema, #[case] visit: &str) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: { visit: "/tmp/{instrument}/{proposal}" }) {
+                        name
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = schema
+            .execute(format!(
+                r#"{{ paths(beamline: "i22", visit: "{visit}") {{ directory }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"paths": {"directory": "/tmp/i22/cm12345"}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn proposal_only_visit_needing_session(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345") { directory } }"#)
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0].message,
+            r#"Visit template for beamline "i22" requires a session but "cm12345" does not have one"#
+        );
+        assert_eq!(
+            result.errors[0]
+                .extensions
+                .as_ref()
+                .and_then(|ext| ext.get("code")),
+            Some(&value!("MISSING_SESSION"))
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn generated_at_from_clock(#[future(awt)] schema: NtSchema) {
+        let expected = Local
+            .with_ymd_and_hms(2024, 6, 1, 12, 0, 0)
+            .unwrap()
+            .to_rfc3339();
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3") { generatedAt } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"paths": {"generatedAt": expected.clone()}})
+        );
+        let result = schema
+            .execute(
+                r#"mutation {
+                    scan(beamline: "i22", visit: "cm12345-3") {
+                        generatedAt
+                        visit { generatedAt }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"generatedAt": expected.clone(), "visit": {"generatedAt": expected}}})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_paths_for_existing_scan(#[future(awt)] schema: NtSchema) {
+        let query = r#"{
+            scanPaths(beamline: "i22", visit: "cm12345-3", scanNumber: 42, sub: "sample") {
+                scanFile
+                scanNumber
+                detectors(names: ["camera"]) { path }
+            }
+        }"#;
+        let expected = value!({"scanPaths": {
+            "scanFile": "sample/i22-42",
+            "scanNumber": 42,
+            "detectors": [{"path": "sample/i22-42-camera"}],
+        }});
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, expected);
+
+        // Repeated queries do not allocate new scan numbers
+        let result = schema.execute(query).await;
+        assert_eq!(result.data, expected);
+        let result = schema
+            .execute(r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanNumber } }"#)
+            .await;
+        assert_eq!(result.data, value!({"scan": {"scanNumber": 123}}));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn scan_paths_for_missing_beamline(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"{ scanPaths(beamline: "b21", visit: "cm12345-3", scanNumber: 42) { scanFile } }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.data, Value::Null);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn tracker_file_extension(#[future(awt)] schema: NtSchema) {
+        let query = r#"{ configuration(beamline: "i22") {
+            trackerFileExtension trackerFileExtensionDefaulted
+        } }"#;
+        let result = schema.execute(query).await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configuration": {
+                "trackerFileExtension": "i22",
+                "trackerFileExtensionDefaulted": true,
+            }})
+        );
+
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: { extension: "ext" }) {
+                    trackerFileExtension trackerFileExtensionDefaulted
+                } }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {
+                "trackerFileExtension": "ext",
+                "trackerFileExtensionDefaulted": false,
+            }})
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn traversing_extension_rejected(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation { configure(beamline: "i22", config: { extension: "../../tmp" }) {
+                    trackerFileExtension
+                } }"#,
+            )
+            .await;
+        assert_eq!(result.errors.len(), 1);
+        let result = schema
+            .execute(r#"{ configuration(beamline: "i22") { trackerFileExtension } }"#)
+            .await;
+        assert_eq!(
+            result.data,
+            value!({"configuration": {"trackerFileExtension": "i22"}})
+        );
+    }
+
+    #[rstest]
+    #[case::new_years_eve(fixed_clock(2024, 12, 31, 23, 59, 59), "/tmp/i22/data/2024/cm12345-3")]
+    #[case::new_years_day(fixed_clock(2025, 1, 1, 0, 0, 0), "/tmp/i22/data/2025/cm12345-3")]
+    #[tokio::test]
+    async fn year_from_clock(#[case] clock: Box<dyn Clock>, #[case] directory: &str) {
+        let schema = schema_at(clock).await;
+        let result = schema
+            .execute(r#"{ paths(beamline: "i22", visit: "cm12345-3") { directory } }"#)
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"paths": {"directory": directory}}));
+    }
+
+    #[rstest]
+    #[case::commissioning("cm12345-3", "/tmp/i22/commissioning/cm12345-3")]
+    #[case::commissioning_sub_session("cm12345-3-2", "/tmp/i22/commissioning/cm12345-3-2")]
+    #[case::user("mx12345-3", "/tmp/i22/data/2024/mx12345-3")]
+    #[case::user_session_suffix("mx12345-3a", "/tmp/i22/data/2024/mx12345-3a")]
+    #[case::unparseable("not_a_visit", "/tmp/i22/data/2024/not_a_visit")]
+    #[tokio::test]
+    async fn commissioning_visit_directory(
+        #[future(awt)] schema: NtSchema,
+        #[case] visit: &str,
+        #[case] directory: &str,
+    ) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(
+                        beamline: "i22",
+                        config: { commissioningVisit: "/tmp/{instrument}/commissioning/{visit}" }
+                    ) { commissioningVisitTemplate commissioningProposalCodes }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"configure": {
+                "commissioningVisitTemplate": "/tmp/{instrument}/commissioning/{visit}",
+                "commissioningProposalCodes": ["cm"],
+            }})
+        );
+        let result = schema
+            .execute(format!(
+                r#"{{ paths(beamline: "i22", visit: "{visit}") {{ directory }} }}"#
+            ))
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"paths": {"directory": directory}}));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn empty_segment_warns(#[future(awt)] schema: NtSchema) {
+        let result = schema
+            .execute(
+                r#"mutation {
+                    configure(beamline: "i22", config: {
+                        scan: "{instrument}-{scan_number}/{subdirectory}"
+                    }) { name }
+                }"#,
+            )
+            .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let result = execute(
+            &schema,
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3") { scanFile } }"#.into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.data, value!({"scan": {"scanFile": "i22-123/"}}));
+        assert_eq!(
+            result.extensions.get("warnings"),
+            Some(&value!([
+                "Path \"i22-123/\" rendered from template \"{instrument}-{scan_number}/{subdirectory}\" has an empty segment"
+            ]))
+        );
+
+        // Paths without empty segments do not warn
+        let result = execute(
+            &schema,
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3", sub: "sample") { scanFile } }"#
+                .into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(result.extensions.get("warnings"), None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn overlapping_subdirectory_warns(#[future(awt)] schema: NtSchema) {
+        let result = execute(
+            &schema,
+            r#"mutation {
+                scan(beamline: "i22", visit: "cm12345-3", sub: "i22-123", checkOverlap: true) {
+                    scanFile
+                }
+            }"#
+            .into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"scanFile": "i22-123/i22-123"}})
+        );
+        assert_eq!(
+            result.extensions.get("warnings"),
+            Some(&value!([
+                "Subdirectory segment \"i22-123\" duplicates a segment generated by the scan template"
+            ]))
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn distinct_subdirectory_has_no_warnings(#[future(awt)] schema: NtSchema) {
+        let result = execute(
+            &schema,
+            r#"mutation {
+                scan(beamline: "i22", visit: "cm12345-3", sub: "i22/123", checkOverlap: true) {
+                    scanFile
+                }
+            }"#
+            .into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert!(result.extensions.is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn overlapping_subdirectory_unchecked_by_default(#[future(awt)] schema: NtSchema) {
+        let result = execute(
+            &schema,
+            r#"mutation { scan(beamline: "i22", visit: "cm12345-3", sub: "i22-123") { scanFile } }"#
+                .into(),
+        )
+        .await;
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            result.data,
+            value!({"scan": {"scanFile": "i22-123/i22-123"}})
+        );
+        assert!(result.extensions.is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn request_ids_are_unique(#[future(awt)] schema: NtSchema) {
+        let query = r#"{ paths(beamline: "i22", visit: "cm12345-3") { directory } }"#;
+        let first = execute_tagged(&schema, query.into()).await;
+        let second = execute_tagged(&schema, query.into()).await;
+        let id = |resp: &async_graphql::Response| match resp.extensions.get("requestId") {
+            Some(Value::String(id)) => Uuid::parse_str(id).expect("Request ID should be a UUID"),
+            other => panic!("Missing request ID: {other:?}"),
+        };
+        assert_ne!(id(&first), id(&second));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn failed_requests_include_request_id(#[future(awt)] schema: NtSchema) {
+        let result = execute_tagged(
+            &schema,
+            r#"{ paths(beamline: "b21", visit: "cm12345-3") { directory } }"#.into(),
+        )
+        .await;
+        assert!(!result.errors.is_empty());
+        assert_matches!(result.extensions.get("requestId"), Some(Value::String(_)));
     }
 }
@@ -13,16 +13,19 @@
 // limitations under the License.
 
 use std::fmt::Display;
-use std::str::FromStr;
+use std::time::Duration;
 
 use axum_extra::headers::authorization::Bearer;
 use axum_extra::headers::Authorization;
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{info, trace};
 
-use crate::cli::PolicyOptions;
+use crate::cli::{PolicyOptions, PolicyVisitField};
+use crate::visit::{InvalidVisit, Visit};
 
 const AUDIENCE: &str = "account";
+/// The visit fields sent to the policy server if none are configured
+const VISIT_FIELDS: [PolicyVisitField; 2] = [PolicyVisitField::Proposal, PolicyVisitField::Session];
 
 type Token = Authorization<Bearer>;
 
@@ -37,23 +40,45 @@ struct Response {
 pub struct AccessRequest<'a> {
     token: &'a str,
     audience: &'a str,
-    proposal: u32,
-    visit: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proposal: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visit: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visit_name: Option<&'a str>,
     beamline: &'a str,
 }
 
 impl<'a> AccessRequest<'a> {
-    fn new(token: Option<&'a Token>, visit: Visit, beamline: &'a str) -> Result<Self, AuthError> {
+    /// Build a request including only the given parts of the visit
+    fn new(
+        token: Option<&'a Token>,
+        audience: &'a str,
+        visit: &'a VisitName<'a>,
+        fields: &[PolicyVisitField],
+        beamline: &'a str,
+    ) -> Result<Self, AuthError> {
+        let field = |f| fields.contains(&f);
         Ok(Self {
             token: token.ok_or(AuthError::Missing)?.token(),
-            audience: AUDIENCE,
-            proposal: visit.proposal,
-            visit: visit.session,
+            audience,
+            code: field(PolicyVisitField::Code).then_some(visit.parsed.code.as_str()),
+            proposal: field(PolicyVisitField::Proposal).then_some(visit.parsed.proposal),
+            visit: field(PolicyVisitField::Session).then_some(visit.parsed.session),
+            visit_name: field(PolicyVisitField::Visit).then_some(visit.name),
             beamline,
         })
     }
 }
 
+/// A visit along with the string it was parsed from
+struct VisitName<'a> {
+    name: &'a str,
+    parsed: Visit,
+}
+
 #[derive(Debug, Serialize)]
 #[cfg_attr(test, derive(Deserialize))]
 pub struct AdminRequest<'a> {
@@ -63,44 +88,32 @@ pub struct AdminRequest<'a> {
 }
 
 impl<'r> AdminRequest<'r> {
-    fn new(token: Option<&'r Token>, beamline: &'r str) -> Result<Self, AuthError> {
+    fn new(
+        token: Option<&'r Token>,
+        audience: &'r str,
+        beamline: &'r str,
+    ) -> Result<Self, AuthError> {
         Ok(Self {
             token: token.ok_or(AuthError::Missing)?.token(),
-            audience: AUDIENCE,
+            audience,
             beamline,
         })
     }
 }
 
-#[derive(Debug)]
-struct InvalidVisit;
-
-#[cfg_attr(test, derive(Debug))]
-struct Visit {
-    proposal: u32,
-    session: u16,
-}
-impl FromStr for Visit {
-    type Err = InvalidVisit;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (code_prop, vis) = s.split_once('-').ok_or(InvalidVisit)?;
-        let prop = code_prop
-            .chars()
-            .skip_while(|p| !p.is_ascii_digit())
-            .collect::<String>();
-        let proposal = prop.parse().map_err(|_| InvalidVisit)?;
-        let session = vis.parse().map_err(|_| InvalidVisit)?;
-        Ok(Self { proposal, session })
-    }
-}
-
+#[derive(Clone)]
 pub(crate) struct PolicyCheck {
     client: reqwest::Client,
+    /// Root URL of the policy server
+    host: String,
     /// Rego query for getting admin rights
     admin: String,
     /// Rego query for getting access rights
     access: String,
+    /// Audiences that tokens may have been issued for
+    audiences: Vec<String>,
+    /// The parts of a visit included in access requests
+    visit_fields: Vec<PolicyVisitField>,
 }
 
 impl PolicyCheck {
@@ -109,21 +122,53 @@ impl PolicyCheck {
             "Checking authorization against {:?} using {:?} for admin and {:?} for access",
             endpoint.policy_host, endpoint.admin_query, endpoint.access_query
         );
+        let mut audiences = endpoint.audiences;
+        if audiences.is_empty() {
+            audiences.push(AUDIENCE.into());
+        }
+        let mut visit_fields = endpoint.visit_fields;
+        if visit_fields.is_empty() {
+            visit_fields.extend(VISIT_FIELDS);
+        }
+        let keepalive = Some(endpoint.tcp_keepalive)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        let client = reqwest::Client::builder()
+            .pool_idle_timeout(Duration::from_secs(endpoint.pool_idle_timeout))
+            .pool_max_idle_per_host(endpoint.pool_max_idle)
+            .tcp_keepalive(keepalive)
+            .build()
+            .expect("Failed to build client for policy server");
         Self {
-            client: reqwest::Client::new(),
+            client,
+            host: endpoint.policy_host.clone(),
             admin: format!("{}/{}", endpoint.policy_host, endpoint.admin_query),
             access: format!("{}/{}", endpoint.policy_host, &endpoint.access_query),
+            audiences,
+            visit_fields,
         }
     }
+    /// Check that the policy server can be reached. Any response from the server is accepted,
+    /// only failing to get a response is an error.
+    pub async fn ping(&self, timeout: Duration) -> Result<(), AuthError> {
+        self.client.head(&self.host).timeout(timeout).send().await?;
+        Ok(())
+    }
+
     pub async fn check_access(
         &self,
         token: Option<&Authorization<Bearer>>,
         beamline: &str,
         visit: &str,
     ) -> Result<(), AuthError> {
-        let visit: Visit = visit.parse().map_err(|_| AuthError::Failed)?;
-        self.authorise(&self.access, AccessRequest::new(token, visit, beamline)?)
-            .await
+        let visit = VisitName {
+            name: visit,
+            parsed: visit.parse()?,
+        };
+        self.authorise_any(&self.access, |aud| {
+            AccessRequest::new(token, aud, &visit, &self.visit_fields, beamline)
+        })
+        .await
     }
 
     pub async fn check_admin(
@@ -131,17 +176,29 @@ impl PolicyCheck {
         token: Option<&Authorization<Bearer>>,
         beamline: &str,
     ) -> Result<(), AuthError> {
-        self.authorise(&self.admin, AdminRequest::new(token, beamline)?)
+        self.authorise_any(&self.admin, |aud| AdminRequest::new(token, aud, beamline))
             .await
     }
 
-    async fn authorise(&self, query: &str, input: impl Serialize) -> Result<(), AuthError> {
-        let response = self.client.post(query).json(&input).send().await?;
-        if response.json::<Response>().await?.result {
-            Ok(())
-        } else {
-            Err(AuthError::Failed)
+    /// Check the request against each accepted audience in turn, succeeding as soon as the
+    /// policy server accepts one of them.
+    async fn authorise_any<'a, R, F>(&'a self, query: &str, request: F) -> Result<(), AuthError>
+    where
+        R: Serialize,
+        F: Fn(&'a str) -> Result<R, AuthError>,
+    {
+        for audience in &self.audiences {
+            if self.authorise(query, request(audience)?).await? {
+                return Ok(());
+            }
+            trace!("Authorization refused for audience {audience:?}");
         }
+        Err(AuthError::Failed)
+    }
+
+    async fn authorise(&self, query: &str, input: impl Serialize) -> Result<bool, AuthError> {
+        let response = self.client.post(query).json(&input).send().await?;
+        Ok(response.json::<Response>().await?.result)
     }
 }
 
@@ -150,6 +207,7 @@ pub enum AuthError {
     ServerError(reqwest::Error),
     Failed,
     Missing,
+    InvalidVisit(InvalidVisit),
 }
 
 impl Display for AuthError {
@@ -158,6 +216,7 @@ impl Display for AuthError {
             AuthError::ServerError(_) => write!(f, "Invalid authorization configuration"),
             AuthError::Failed => write!(f, "Authentication failed"),
             AuthError::Missing => f.write_str("No authentication token was provided"),
+            AuthError::InvalidVisit(e) => write!(f, "Invalid visit: {e}"),
         }
     }
 }
@@ -166,6 +225,7 @@ impl std::error::Error for AuthError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             AuthError::ServerError(e) => Some(e),
+            AuthError::InvalidVisit(e) => Some(e),
             _ => None,
         }
     }
@@ -177,22 +237,29 @@ impl From<reqwest::Error> for AuthError {
     }
 }
 
+impl From<InvalidVisit> for AuthError {
+    fn from(value: InvalidVisit) -> Self {
+        Self::InvalidVisit(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr as _;
+    use std::time::Duration;
 
-    use assert_matches::assert_matches;
     use axum::http::HeaderValue;
     use axum_extra::headers::authorization::{Bearer, Credentials};
     use axum_extra::headers::Authorization;
     use httpmock::MockServer;
     use rstest::rstest;
+    use serde_json::json;
 
     use super::{
-        AccessRequest, AdminRequest, AuthError, InvalidVisit, PolicyCheck, Response, Visit,
-        AUDIENCE,
+        AccessRequest, AdminRequest, AuthError, PolicyCheck, Response, VisitName, AUDIENCE,
+        VISIT_FIELDS,
     };
-    use crate::cli::PolicyOptions;
+    use crate::cli::{PolicyOptions, PolicyVisitField};
+    use crate::visit::InvalidVisit;
 
     fn token(name: &'static str) -> Option<Authorization<Bearer>> {
         Some(Authorization(
@@ -200,21 +267,59 @@ mod tests {
         ))
     }
 
-    #[test]
-    fn valid_visit() {
-        let visit = Visit::from_str("cm12345-1").unwrap();
-        assert_eq!(visit.session, 1);
-        assert_eq!(visit.proposal, 12345);
+    #[rstest]
+    #[case::default(&VISIT_FIELDS, json!({"proposal": 1234, "visit": 4}))]
+    #[case::with_code(
+        &[PolicyVisitField::Code, PolicyVisitField::Proposal, PolicyVisitField::Session],
+        json!({"code": "cm", "proposal": 1234, "visit": 4})
+    )]
+    #[case::full_visit(&[PolicyVisitField::Visit], json!({"visit_name": "cm1234-4"}))]
+    #[case::none(&[], json!({}))]
+    fn access_request_fields(
+        #[case] fields: &[PolicyVisitField],
+        #[case] visit_fields: serde_json::Value,
+    ) {
+        let token = token("token");
+        let visit = VisitName {
+            name: "cm1234-4",
+            parsed: "cm1234-4".parse().unwrap(),
+        };
+        let request = AccessRequest::new(token.as_ref(), AUDIENCE, &visit, fields, "i22").unwrap();
+        let mut expected = json!({"token": "token", "audience": AUDIENCE, "beamline": "i22"});
+        expected
+            .as_object_mut()
+            .unwrap()
+            .extend(visit_fields.as_object().unwrap().clone());
+        assert_eq!(serde_json::to_value(request).unwrap(), expected);
     }
 
-    #[rstest]
-    #[case::no_proposal("cm-3")]
-    #[case::no_session("cm12345")]
-    #[case::invalid_session("cm12345-abc")]
-    #[case::invalid_proposal("cm123abc-12")]
-    #[case::negative_session("cm1234--12")]
-    fn invalid_visit(#[case] visit: &str) {
-        assert_matches!(Visit::from_str(visit), Err(InvalidVisit))
+    #[tokio::test]
+    async fn access_check_with_full_visit() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("POST").path("/demo/access").json_body(json!({
+                    "token": "token",
+                    "audience": AUDIENCE,
+                    "visit_name": "cm1234-4",
+                    "beamline": "i22",
+                }));
+                then.status(200).json_body_obj(&Response { result: true });
+            })
+            .await;
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: server.url(""),
+            access_query: "demo/access".into(),
+            admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            visit_fields: vec![PolicyVisitField::Visit],
+            ..Default::default()
+        });
+        check
+            .check_access(token("token").as_ref(), "i22", "cm1234-4")
+            .await
+            .unwrap();
+        mock.assert();
     }
 
     #[tokio::test]
@@ -227,8 +332,10 @@ mod tests {
                     .json_body_obj(&AccessRequest {
                         token: "token",
                         beamline: "i22",
-                        visit: 4,
-                        proposal: 1234,
+                        proposal: Some(1234),
+                        visit: Some(4),
+                        code: None,
+                        visit_name: None,
                         audience: AUDIENCE,
                     });
                 then.status(200).json_body_obj(&Response { result: true });
@@ -238,6 +345,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
         check
             .check_access(token("token").as_ref(), "i22", "cm1234-4")
@@ -265,6 +374,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
         check
             .check_admin(token("token").as_ref(), "i22")
@@ -283,8 +394,10 @@ mod tests {
                     .json_body_obj(&AccessRequest {
                         token: "token",
                         beamline: "i22",
-                        proposal: 1234,
-                        visit: 4,
+                        proposal: Some(1234),
+                        visit: Some(4),
+                        code: None,
+                        visit_name: None,
                         audience: AUDIENCE,
                     });
                 then.status(200).json_body_obj(&Response { result: false });
@@ -294,6 +407,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
 
         let result = check
@@ -324,14 +439,147 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
+        });
+        let result = check.check_admin(token("token").as_ref(), "i22").await;
+        let Err(AuthError::Failed) = result else {
+            panic!("Unexpected result from unauthorised check: {result:?}");
+        };
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn alternative_audience_check() {
+        let server = MockServer::start();
+        let default_audience = server
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path("/demo/access")
+                    .json_body_obj(&AccessRequest {
+                        token: "token",
+                        beamline: "i22",
+                        proposal: Some(1234),
+                        visit: Some(4),
+                        code: None,
+                        visit_name: None,
+                        audience: AUDIENCE,
+                    });
+                then.status(200).json_body_obj(&Response { result: false });
+            })
+            .await;
+        let other_audience = server
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path("/demo/access")
+                    .json_body_obj(&AccessRequest {
+                        token: "token",
+                        beamline: "i22",
+                        proposal: Some(1234),
+                        visit: Some(4),
+                        code: None,
+                        visit_name: None,
+                        audience: "other",
+                    });
+                then.status(200).json_body_obj(&Response { result: true });
+            })
+            .await;
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: server.url(""),
+            access_query: "demo/access".into(),
+            admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into(), "other".into()],
+            ..Default::default()
+        });
+        check
+            .check_access(token("token").as_ref(), "i22", "cm1234-4")
+            .await
+            .unwrap();
+        default_audience.assert();
+        other_audience.assert();
+    }
+
+    #[tokio::test]
+    async fn no_accepted_audience() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("POST").path("/demo/admin");
+                then.status(200).json_body_obj(&Response { result: false });
+            })
+            .await;
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: server.url(""),
+            access_query: "demo/access".into(),
+            admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into(), "other".into()],
+            ..Default::default()
         });
         let result = check.check_admin(token("token").as_ref(), "i22").await;
         let Err(AuthError::Failed) = result else {
             panic!("Unexpected result from unauthorised check: {result:?}");
         };
+        mock.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn malformed_visit_check() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|_, _| {
+                // mock that rejects every request
+            })
+            .await;
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: server.url(""),
+            access_query: "demo/access".into(),
+            admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
+        });
+        let result = check
+            .check_access(token("token").as_ref(), "i22", "cm1234")
+            .await;
+        let Err(AuthError::InvalidVisit(InvalidVisit::MissingSession)) = result else {
+            panic!("Unexpected result from malformed visit check: {result:?}");
+        };
+        mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn reachable_policy_server() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("HEAD");
+                then.status(404);
+            })
+            .await;
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: server.url(""),
+            ..Default::default()
+        });
+        check.ping(Duration::from_secs(1)).await.unwrap();
         mock.assert();
     }
 
+    #[tokio::test]
+    async fn unreachable_policy_server() {
+        // Bind and immediately drop a listener to find a port with nothing listening
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let check = PolicyCheck::new(PolicyOptions {
+            policy_host: format!("http://{addr}"),
+            ..Default::default()
+        });
+        let result = check.ping(Duration::from_secs(1)).await;
+        let Err(AuthError::ServerError(_)) = result else {
+            panic!("Unexpected result from unreachable server: {result:?}");
+        };
+    }
+
     #[tokio::test]
     async fn unauthorised_access_check() {
         let server = MockServer::start();
@@ -344,6 +592,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
         let result = check.check_access(None, "i22", "cm1234-4").await;
         let Err(AuthError::Missing) = result else {
@@ -364,6 +614,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
         let result = check.check_admin(None, "i22").await;
         let Err(AuthError::Missing) = result else {
@@ -385,6 +637,8 @@ mod tests {
             policy_host: server.url(""),
             access_query: "demo/access".into(),
             admin_query: "demo/admin".into(),
+            audiences: vec![AUDIENCE.into()],
+            ..Default::default()
         });
         let result = check.check_admin(token("token").as_ref(), "i22").await;
         let Err(AuthError::ServerError(_)) = result else {
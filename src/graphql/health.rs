@@ -0,0 +1,252 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_graphql::{Enum, SimpleObject};
+use futures::future::join_all;
+use tokio::time::timeout;
+
+use super::auth::PolicyCheck;
+use super::ServedBeamlines;
+use crate::db_service::SqliteScanPathService;
+use crate::numtracker::{DirectoryStatus, NumTracker};
+
+/// How long any single check can take before its dependency is considered unavailable
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The state of a single dependency, ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Enum)]
+pub enum HealthStatus {
+    /// The dependency is available
+    Ok,
+    /// The dependency is available but not in its expected state
+    Degraded,
+    /// The dependency could not be reached in time
+    Unavailable,
+}
+
+/// The result of checking a single dependency
+#[derive(Debug, SimpleObject)]
+pub struct DependencyHealth {
+    status: HealthStatus,
+    /// How long the check took in milliseconds
+    latency_ms: f64,
+    /// Why the dependency is not available, if it is not
+    error: Option<String>,
+}
+
+/// The result of checking the tracker directory for a beamline
+#[derive(Debug, SimpleObject)]
+pub struct TrackerDirectoryHealth {
+    beamline: String,
+    status: HealthStatus,
+    directory: DirectoryStatus,
+    /// How long the check took in milliseconds
+    latency_ms: f64,
+}
+
+/// The state of every dependency of the service
+#[derive(Debug, SimpleObject)]
+pub struct Health {
+    /// The worst status of any dependency
+    status: HealthStatus,
+    database: DependencyHealth,
+    /// The policy server, null if authorization is not enabled
+    policy: Option<DependencyHealth>,
+    /// The tracker directories of served beamlines that have one
+    tracker_directories: Vec<TrackerDirectoryHealth>,
+}
+
+impl Health {
+    /// Check every dependency, each limited to a few seconds so that the health of the service
+    /// can be reported even if some dependencies are not responding. Only the tracker
+    /// directories of served beamlines are checked.
+    pub async fn check(
+        db: &SqliteScanPathService,
+        policy: Option<&PolicyCheck>,
+        nt: Option<&NumTracker>,
+        served: Option<&ServedBeamlines>,
+    ) -> Self {
+        let database = DependencyHealth::from_check(db.ping(), |e| e.to_string()).await;
+        let policy = match policy {
+            Some(policy) => Some(
+                DependencyHealth::from_check(policy.ping(CHECK_TIMEOUT), |e| format!("{e:?}"))
+                    .await,
+            ),
+            None => None,
+        };
+        let tracker_directories = match (nt, database.status) {
+            (Some(nt), HealthStatus::Ok) => tracker_directories(db, nt, served).await,
+            // Without the DB there is no list of beamlines to check
+            _ => Vec::new(),
+        };
+        let status = [database.status]
+            .into_iter()
+            .chain(policy.as_ref().map(|p| p.status))
+            .chain(tracker_directories.iter().map(|dir| dir.status))
+            .max()
+            .unwrap_or(HealthStatus::Ok);
+        Self {
+            status,
+            database,
+            policy,
+            tracker_directories,
+        }
+    }
+}
+
+impl DependencyHealth {
+    async fn from_check<T, E>(
+        check: impl Future<Output = Result<T, E>>,
+        describe: impl Fn(E) -> String,
+    ) -> Self {
+        let (latency_ms, result) = timed(check).await;
+        let (status, error) = match result {
+            Some(Ok(_)) => (HealthStatus::Ok, None),
+            Some(Err(e)) => (HealthStatus::Unavailable, Some(describe(e))),
+            None => (HealthStatus::Unavailable, Some("Timed out".into())),
+        };
+        Self {
+            status,
+            latency_ms,
+            error,
+        }
+    }
+}
+
+/// Check the tracker directory of every served beamline that has one
+async fn tracker_directories(
+    db: &SqliteScanPathService,
+    nt: &NumTracker,
+    served: Option<&ServedBeamlines>,
+) -> Vec<TrackerDirectoryHealth> {
+    let Ok(Ok(beamlines)) = timeout(CHECK_TIMEOUT, db.beamlines()).await else {
+        return Vec::new();
+    };
+    let beamlines = beamlines
+        .iter()
+        .filter(|bl| served.is_none_or(|served| served.contains(bl)));
+    let checks = beamlines.map(|bl| async move {
+        let (latency_ms, directory) = timed(nt.directory_status(bl)).await;
+        let directory = directory.unwrap_or(DirectoryStatus::Inaccessible);
+        let status = match directory {
+            DirectoryStatus::Unconfigured => return None,
+            DirectoryStatus::Available => HealthStatus::Ok,
+            DirectoryStatus::Missing => HealthStatus::Degraded,
+            DirectoryStatus::Inaccessible => HealthStatus::Unavailable,
+        };
+        Some(TrackerDirectoryHealth {
+            beamline: bl.clone(),
+            status,
+            directory,
+            latency_ms,
+        })
+    });
+    join_all(checks).await.into_iter().flatten().collect()
+}
+
+/// Run a check with a timeout, returning how long it took in milliseconds along with its
+/// result, or None if it timed out.
+async fn timed<T>(check: impl Future<Output = T>) -> (f64, Option<T>) {
+    let start = Instant::now();
+    let result = timeout(CHECK_TIMEOUT, check).await.ok();
+    (start.elapsed().as_secs_f64() * 1000.0, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{Health, HealthStatus};
+    use crate::cli::PolicyOptions;
+    use crate::db_service::{BeamlineConfigurationUpdate, SqliteScanPathService};
+    use crate::graphql::auth::PolicyCheck;
+    use crate::numtracker::{DirectoryStatus, NumTracker};
+    use crate::paths::{DetectorTemplate, PathSpec as _, ScanTemplate, VisitTemplate};
+
+    async fn db_with(beamlines: &[&str]) -> SqliteScanPathService {
+        let db = SqliteScanPathService::memory().await;
+        for bl in beamlines {
+            BeamlineConfigurationUpdate {
+                visit: VisitTemplate::new_checked("/tmp/{instrument}/{visit}").ok(),
+                scan: ScanTemplate::new_checked("{instrument}-{scan_number}").ok(),
+                detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}")
+                    .ok(),
+                ..BeamlineConfigurationUpdate::empty(*bl)
+            }
+            .insert_new(&db)
+            .await
+            .unwrap();
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn healthy_without_dependencies() {
+        let health = Health::check(&db_with(&["i22"]).await, None, None, None).await;
+        assert_eq!(health.status, HealthStatus::Ok);
+        assert_eq!(health.database.status, HealthStatus::Ok);
+        assert!(health.policy.is_none());
+        assert!(health.tracker_directories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_policy_server() {
+        // Bind and immediately drop a listener to find a port with nothing listening
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let policy = PolicyCheck::new(PolicyOptions {
+            policy_host: format!("http://{addr}"),
+            ..Default::default()
+        });
+        let health = Health::check(&db_with(&[]).await, Some(&policy), None, None).await;
+        assert_eq!(health.status, HealthStatus::Unavailable);
+        assert_eq!(health.database.status, HealthStatus::Ok);
+        let policy = health.policy.unwrap();
+        assert_eq!(policy.status, HealthStatus::Unavailable);
+        assert!(policy.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn tracker_directories() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        fs::create_dir(root.path().join("b21")).unwrap();
+        let nt = NumTracker::for_root_directory(Some(root.path())).unwrap();
+        // b21 existed on startup but has since been removed
+        fs::remove_dir(root.path().join("b21")).unwrap();
+
+        let db = db_with(&["i22", "b21", "i11"]).await;
+        let health = Health::check(&db, None, Some(&nt), None).await;
+        assert_eq!(health.status, HealthStatus::Degraded);
+        let dirs = health
+            .tracker_directories
+            .iter()
+            .map(|dir| (dir.beamline.as_str(), dir.status, dir.directory))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dirs,
+            [
+                ("b21", HealthStatus::Degraded, DirectoryStatus::Missing),
+                ("i22", HealthStatus::Ok, DirectoryStatus::Available),
+            ]
+        );
+    }
+}
@@ -14,14 +14,15 @@
 
 use opentelemetry::trace::{TraceError, TracerProvider as _};
 use opentelemetry::{global, KeyValue};
-use opentelemetry_otlp::{SpanExporter, WithExportConfig as _};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig as _};
+use opentelemetry_sdk::metrics::{MetricResult, PeriodicReader, SdkMeterProvider};
 use opentelemetry_sdk::trace::TracerProvider;
 use opentelemetry_sdk::{runtime, Resource};
 use opentelemetry_semantic_conventions::resource::{SERVICE_NAME, SERVICE_VERSION};
 use opentelemetry_semantic_conventions::SCHEMA_URL;
 use tracing::{Level, Subscriber};
 use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::filter::{Directive, LevelFilter};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::util::SubscriberInitExt as _;
@@ -40,14 +41,25 @@ fn resource() -> Resource {
     )
 }
 
-fn init_stdout<S>(level: Option<Level>) -> impl Layer<S>
+/// Log everything at or above the given level except where a module has its own filter, eg
+/// `info` with `numtracker::db_service=warn` gives `numtracker::db_service=warn,info`
+fn stdout_filter(level: Level, filters: &[Directive]) -> EnvFilter {
+    filters.iter().cloned().fold(
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::from_level(level).into())
+            .parse_lossy(""),
+        EnvFilter::add_directive,
+    )
+}
+
+fn init_stdout<S>(level: Option<Level>, filters: &[Directive]) -> impl Layer<S>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
     level.map(|lvl| {
         tracing_subscriber::fmt::layer()
             .with_writer(std::io::stderr)
-            .with_filter(LevelFilter::from_level(lvl))
+            .with_filter(stdout_filter(lvl, filters))
     })
 }
 
@@ -76,9 +88,31 @@ where
     }
 }
 
-pub fn init(logging: Option<Level>, tracing: &TracingOptions) -> Result<(), TraceError> {
-    let log_layer = init_stdout(logging);
+/// Export metrics to the same collector as traces. Without an endpoint, metrics are recorded
+/// by the default no-op provider and discarded.
+fn init_metrics(endpoint: Option<Url>) -> MetricResult<()> {
+    if let Some(endpoint) = endpoint {
+        let exporter = MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter, runtime::Tokio).build())
+            .with_resource(resource())
+            .build();
+        global::set_meter_provider(provider);
+    }
+    Ok(())
+}
+
+pub fn init(
+    logging: Option<Level>,
+    filters: &[Directive],
+    tracing: &TracingOptions,
+) -> Result<(), TraceError> {
+    let log_layer = init_stdout(logging, filters);
     let trace_layer = init_tracing(tracing.tracing_url(), tracing.level())?;
+    init_metrics(tracing.tracing_url()).map_err(|e| TraceError::Other(e.into()))?;
 
     // Whatever level is set for logging/tracing, ignore the noise from the low-level libraries
     let filter = EnvFilter::new("trace") // let everything through
@@ -12,27 +12,87 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::error::Error;
+use std::process::ExitCode;
 
 use cli::{Cli, Command};
-use tracing::debug;
+use tracing::{debug, info};
 
+mod audit;
 mod cli;
+mod config;
 mod db_service;
+mod diff;
 mod graphql;
 mod logging;
 mod numtracker;
+mod overlay;
 mod paths;
+mod reset;
 mod template;
+mod visit;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> ExitCode {
     let args = Cli::init();
-    let _ = logging::init(args.log_level(), args.tracing());
+    let _ = logging::init(args.log_level(), args.log_filters(), args.tracing());
     debug!(?args, "Starting numtracker service");
+    let (db, source) = args.db();
+    info!("Using database {} (from {source})", db.display());
     match args.command {
-        Command::Serve(opts) => graphql::serve_graphql(&args.db, opts).await,
+        Command::Serve(opts) => {
+            if let Err(e) = graphql::serve_graphql(&db, opts).await {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Config(opts) => {
+            if let Err(e) = config::print_configuration(&db, opts).await {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Audit(opts) => {
+            if let Err(e) = audit::print_allocations(&db, opts).await {
+                eprintln!("Could not read scan allocations: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Export(opts) => {
+            if let Err(e) = audit::export_allocations(&db, opts).await {
+                eprintln!("Could not export scan allocations: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::ResetScanNumber(opts) => {
+            if let Err(e) = reset::reset_scan_number(&db, opts).await {
+                eprintln!("Could not reset scan number: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::DiffTemplate(opts) => {
+            if let Err(e) = diff::print_template_diff(&db, opts).await {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
         Command::Schema => graphql::graphql_schema(),
+        Command::Migrate(opts) => {
+            let status = if opts.check_only {
+                db_service::check_schema(&db).await
+            } else {
+                db_service::apply_migrations(&db).await
+            };
+            return match status {
+                Ok(status) => {
+                    println!("{status}");
+                    ExitCode::from(status.exit_code())
+                }
+                Err(e) => {
+                    eprintln!("Could not check DB schema: {e}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
     }
-    Ok(())
+    ExitCode::SUCCESS
 }
@@ -14,70 +14,242 @@
 
 use std::collections::HashMap;
 use std::fmt::{self, Display};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as SyncMutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 
+use serde::Serialize;
 use tokio::fs as async_fs;
-use tokio::sync::{Mutex, MutexGuard};
-use tracing::{instrument, trace};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tracing::{info, instrument, trace};
 
 /// Central controller to access external directory trackers. Prevents concurrent access to the same
 /// beamline's directory.
 pub struct NumTracker {
-    bl_locks: HashMap<String, Mutex<PathBuf>>,
+    bl_locks: SyncMutex<HashMap<String, DirectoryLock>>,
+    root: Option<PathBuf>,
+    /// Create directories for beamlines that do not have one when they are first used
+    create: bool,
+    /// How long to keep the file for a previous number after it has been replaced
+    grace: Duration,
+    /// Recently checked status of each beamline's directory
+    statuses: SyncMutex<HashMap<String, (Instant, DirectoryStatus)>>,
+}
+
+/// The lock guarding a beamline's tracker directory. The path is also kept outside the lock so
+/// that it can be checked while the directory is in use.
+#[derive(Debug, Clone)]
+struct DirectoryLock {
+    path: PathBuf,
+    lock: Arc<Mutex<PathBuf>>,
+}
+
+impl DirectoryLock {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            lock: Arc::new(Mutex::new(path.clone())),
+            path,
+        }
+    }
+}
+
+/// How long the status of a tracker directory is reused before the directory is checked again.
+/// Keeps repeated requests from hammering a slow mount.
+const DIRECTORY_STATUS_TTL: Duration = Duration::from_secs(5);
+
+/// Whether a beamline's tracker directory can currently be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, async_graphql::Enum)]
+#[serde(rename_all = "camelCase")]
+pub enum DirectoryStatus {
+    /// There is no tracker directory for the beamline
+    Unconfigured,
+    /// The directory exists and can be read
+    Available,
+    /// The directory is expected (eg it existed on startup) but does not currently exist
+    Missing,
+    /// The directory exists but cannot be read, or is not a directory
+    Inaccessible,
 }
 
 impl NumTracker {
     /// Build a numtracker than will provide locked access to subdirectories that exists and no-op
     /// trackers for beamlines that do not have subdirectories.
     pub fn for_root_directory<P: AsRef<Path>>(root: Option<P>) -> Result<Self, Error> {
-        let mut bl_locks: HashMap<String, Mutex<PathBuf>> = Default::default();
-        if let Some(dir) = root {
+        let mut bl_locks: HashMap<String, DirectoryLock> = Default::default();
+        if let Some(dir) = &root {
             for entry in dir.as_ref().read_dir()? {
                 let dir = entry?;
                 if dir.file_type()?.is_dir() {
                     if let Ok(name) = dir.file_name().into_string() {
-                        bl_locks.insert(name, Mutex::new(dir.path()));
+                        bl_locks.insert(name, DirectoryLock::new(dir.path()));
                     }
                 }
             }
         }
 
-        Ok(Self { bl_locks })
+        Ok(Self {
+            bl_locks: SyncMutex::new(bl_locks),
+            root: root.map(|r| r.as_ref().to_path_buf()),
+            create: false,
+            grace: Duration::ZERO,
+            statuses: Default::default(),
+        })
+    }
+
+    /// Create the subdirectory (and any parents) for a beamline the first time a number file is
+    /// written for it instead of using a no-op tracker. Has no effect if there is no root
+    /// directory.
+    pub fn create_missing(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Use the given directories for beamlines' tracker files in place of any found in the root
+    /// directory. The directories do not have to be within the root directory.
+    pub fn with_directories<'a>(self, dirs: impl IntoIterator<Item = (&'a str, &'a Path)>) -> Self {
+        {
+            let mut locks = self.bl_locks.lock().unwrap_or_else(PoisonError::into_inner);
+            for (bl, dir) in dirs {
+                locks.insert(bl.into(), DirectoryLock::new(dir.to_path_buf()));
+            }
+        }
+        self
+    }
+
+    /// Keep the file for a previous number for a while after it is replaced instead of removing
+    /// it immediately, so that slow readers of the file do not find it missing. Files are
+    /// removed by the first allocation for the same beamline after the grace period has passed,
+    /// including files replaced before the service was restarted.
+    pub fn removal_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
     }
 
     /// Create a wrapper around a subdirectory if one exists for the given beamline, or a no-op
     /// tracker if a directory does not exist.
-    pub async fn for_beamline<'nt, 'bl>(
-        &'nt self,
+    pub async fn for_beamline<'bl>(
+        &self,
         bl: &'bl str,
         ext: Option<&'bl str>,
-    ) -> Result<DirectoryTracker<'nt, 'bl>, InvalidExtension> {
+    ) -> Result<DirectoryTracker<'bl>, InvalidExtension> {
         if !ext.is_none_or(Self::valid_extension) {
             return Err(InvalidExtension);
         }
-        Ok(match self.bl_locks.get(bl) {
+        Ok(match self.beamline_lock(bl) {
             Some(dir) => DirectoryTracker::GdaDirectory(GdaNumTracker {
                 ext: ext.unwrap_or(bl),
-                directory: dir.lock().await,
+                directory: dir.lock_owned().await,
+                create: self.create,
+                grace: self.grace,
             }),
             None => DirectoryTracker::NoDirectory,
         })
     }
 
-    fn valid_extension(name: &str) -> bool {
+    /// Get the lock for a beamline's directory, adding one for a new directory if missing
+    /// directories should be created.
+    fn beamline_lock(&self, bl: &str) -> Option<Arc<Mutex<PathBuf>>> {
+        let mut locks = self.bl_locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(dir) = locks.get(bl) {
+            return Some(dir.lock.clone());
+        }
+        let root = self.root.as_ref().filter(|_| self.create)?;
+        if !Self::valid_extension(bl) {
+            return None;
+        }
+        let dir = DirectoryLock::new(root.join(bl));
+        let lock = dir.lock.clone();
+        locks.insert(bl.into(), dir);
+        Some(lock)
+    }
+
+    /// Check whether a beamline's tracker directory currently exists and is readable. This is
+    /// distinct from whether the beamline has a tracker directory configured at all, eg when
+    /// the root directory is an external mount that may not be available.
+    pub async fn directory_status(&self, bl: &str) -> DirectoryStatus {
+        let Some(dir) = self.configured_directory(bl) else {
+            return DirectoryStatus::Unconfigured;
+        };
+        if let Some((checked, status)) = self
+            .statuses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(bl)
+        {
+            if checked.elapsed() < DIRECTORY_STATUS_TTL {
+                return *status;
+            }
+        }
+        let status = match async_fs::metadata(&dir).await {
+            Ok(meta) if meta.is_dir() => match async_fs::read_dir(&dir).await {
+                Ok(_) => DirectoryStatus::Available,
+                Err(_) => DirectoryStatus::Inaccessible,
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => DirectoryStatus::Missing,
+            Ok(_) | Err(_) => DirectoryStatus::Inaccessible,
+        };
+        trace!(bl, ?status, "Checked tracker directory");
+        self.statuses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(bl.into(), (Instant::now(), status));
+        status
+    }
+
+    /// The highest number in a beamline's tracker directory, read without waiting for another
+    /// request to finish with the directory. The number may have changed by the time it is used
+    /// so it must only be used for display. `None` if the beamline does not have a directory.
+    pub async fn peek_latest(&self, bl: &str, ext: Option<&str>) -> Result<Option<u32>, Error> {
+        if !ext.is_none_or(Self::valid_extension) {
+            return Err(Error::new(ErrorKind::InvalidInput, InvalidExtension));
+        }
+        match self.configured_directory(bl) {
+            Some(dir) => Ok(Some(
+                latest_number(&dir, ext.unwrap_or(bl), self.create).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// The directory that would be used for a beamline, if it has (or could have) one. This is
+    /// the same directory that [`for_beamline`](Self::for_beamline) would use.
+    fn configured_directory(&self, bl: &str) -> Option<PathBuf> {
+        let known = self
+            .bl_locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(bl)
+            .map(|dir| dir.path.clone());
+        known.or_else(|| {
+            self.root
+                .as_ref()
+                .filter(|_| self.create && Self::valid_extension(bl))
+                .map(|root| root.join(bl))
+        })
+    }
+
+    /// Check that an extension (or beamline name) can be used in a file name without risk of
+    /// directory traversal
+    pub fn valid_extension(name: &str) -> bool {
         name.chars()
             .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
     }
 }
 
 /// Number tracker for a directory that may or may not exist
-pub enum DirectoryTracker<'nt, 'bl> {
+pub enum DirectoryTracker<'bl> {
     NoDirectory,
-    GdaDirectory(GdaNumTracker<'nt, 'bl>),
+    GdaDirectory(GdaNumTracker<'bl>),
 }
 
-impl DirectoryTracker<'_, '_> {
+impl DirectoryTracker<'_> {
+    /// Whether there is a directory backing this tracker (or one will be created when it is
+    /// first written to)
+    pub fn has_directory(&self) -> bool {
+        matches!(self, DirectoryTracker::GdaDirectory(_))
+    }
+
     pub async fn prev(&self) -> Result<Option<u32>, Error> {
         match self {
             DirectoryTracker::NoDirectory => Ok(None),
@@ -85,21 +257,32 @@ impl DirectoryTracker<'_, '_> {
         }
     }
 
-    pub async fn set(&self, num: u32) -> Result<(), Error> {
+    pub async fn set(&mut self, num: u32) -> Result<(), Error> {
         match self {
             DirectoryTracker::NoDirectory => Ok(()),
             DirectoryTracker::GdaDirectory(gnt) => gnt.create_num_file(num).await,
         }
     }
+
+    /// Make the given number the highest in the directory, removing the files for any higher
+    /// numbers. Unlike `set`, this can lower the latest number.
+    pub async fn reset(&mut self, num: u32) -> Result<(), Error> {
+        match self {
+            DirectoryTracker::NoDirectory => Ok(()),
+            DirectoryTracker::GdaDirectory(gnt) => gnt.reset_num_files(num).await,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct GdaNumTracker<'nt, 'bl> {
+pub struct GdaNumTracker<'bl> {
     ext: &'bl str,
-    directory: MutexGuard<'nt, PathBuf>,
+    directory: OwnedMutexGuard<PathBuf>,
+    create: bool,
+    grace: Duration,
 }
 
-impl GdaNumTracker<'_, '_> {
+impl GdaNumTracker<'_> {
     /// Build the path of the file that would correspond to the given number
     fn file_name(&self, num: u32) -> PathBuf {
         self.directory
@@ -108,50 +291,141 @@ impl GdaNumTracker<'_, '_> {
     }
 
     /// Create a file named for the given number and, if present, remove the file for the previous
-    /// number.
+    /// number once the grace period has passed.
     #[instrument]
-    async fn create_num_file(&self, num: u32) -> Result<(), Error> {
+    async fn create_num_file(&mut self, num: u32) -> Result<(), Error> {
         trace!("Creating new scan number file: {num}.{}", self.ext);
+        if self.create && !async_fs::try_exists(&*self.directory).await? {
+            info!("Creating tracker directory: {:?}", *self.directory);
+            async_fs::create_dir_all(&*self.directory)
+                .await
+                .map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!(
+                            "Could not create tracker directory {:?}: {e}",
+                            *self.directory
+                        ),
+                    )
+                })?;
+        }
         let next = self.file_name(num);
         async_fs::OpenOptions::new()
             .create_new(true)
             .write(true)
             .open(next)
             .await?;
-        if let Some(prev) = num.checked_sub(1) {
-            let prev = self.file_name(prev);
-            let _ = async_fs::remove_file(prev).await;
+        self.remove_replaced(num).await;
+        Ok(())
+    }
+
+    /// Remove the files for any numbers higher than the given number and create the file for it
+    /// if it does not already exist
+    #[instrument]
+    async fn reset_num_files(&mut self, num: u32) -> Result<(), Error> {
+        match async_fs::read_dir(&*self.directory).await {
+            Ok(mut dir) => {
+                while let Some(file) = dir.next_entry().await? {
+                    let path = file.path();
+                    if self.file_num(&path).is_some_and(|n| n > num)
+                        && file.file_type().await?.is_file()
+                    {
+                        info!("Removing scan number file: {path:?}");
+                        async_fs::remove_file(path).await?;
+                    }
+                }
+            }
+            // Directory will be created when the number file is written
+            Err(e) if self.create && e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if num > 0 && !async_fs::try_exists(self.file_name(num)).await? {
+            self.create_num_file(num).await?;
         }
         Ok(())
     }
 
+    /// Remove the files for the numbers before the given one that were replaced longer ago than
+    /// the grace period.
+    ///
+    /// A number's file is replaced when the file for the next number is created, so the time it
+    /// was replaced is the modification time of the next file. This is read from the directory
+    /// instead of being remembered so that files replaced before a restart are still removed.
+    /// Only the unbroken run of numbers below the given one is checked, so files for earlier
+    /// non-consecutive numbers are left alone. Without a grace period no file is ever kept so
+    /// only the file for the previous number is checked.
+    async fn remove_replaced(&self, num: u32) {
+        let now = SystemTime::now();
+        let oldest = if self.grace.is_zero() {
+            num.saturating_sub(1)
+        } else {
+            0
+        };
+        let mut replaced = modified(&self.file_name(num)).await;
+        for prev in (oldest..num).rev() {
+            let prev = self.file_name(prev);
+            // Read before the file is removed as it is when the number before it was replaced
+            let Ok(prev_modified) = modified(&prev).await else {
+                break;
+            };
+            let expired = replaced.is_ok_and(|replaced| {
+                now.duration_since(replaced).unwrap_or_default() >= self.grace
+            });
+            if expired {
+                trace!("Removing previous scan number file: {prev:?}");
+                let _ = async_fs::remove_file(&prev).await;
+            }
+            replaced = Ok(prev_modified);
+        }
+    }
+
     /// Read the number corresponding to the given file if it is a valid file name
     ///
     /// Does not check that the file is a child of the current tracker's directory.
     fn file_num(&self, file: &Path) -> Option<u32> {
-        if self.ext != file.extension()?.to_str()? {
-            return None;
-        }
-        match file.file_stem()?.to_str()?.parse() {
-            Ok(val) => Some(val),
-            Err(_) => None,
-        }
+        file_num(file, self.ext)
     }
 
     /// Find the highest number that has a corresponding number file in this tracker's directory
     async fn latest_scan_number(&self) -> Result<u32, Error> {
-        let mut high = 0;
-        let mut dir = async_fs::read_dir(&*self.directory).await?;
-        while let Some(file) = dir.next_entry().await? {
-            if !file.file_type().await?.is_file() {
-                continue;
-            }
-            if let Some(val) = self.file_num(&file.path()) {
-                high = high.max(val);
-            }
+        latest_number(&self.directory, self.ext, self.create).await
+    }
+}
+
+/// Read the number corresponding to a file if it is a valid file name for the given extension
+fn file_num(file: &Path, ext: &str) -> Option<u32> {
+    if ext != file.extension()?.to_str()? {
+        return None;
+    }
+    file.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Find the highest number that has a corresponding number file in a tracker directory. A
+/// missing directory has no numbers if it would be created when first written to.
+///
+/// Entries are read as they are listed rather than collected first, and only entries that
+/// would raise the number are checked to be files, so that directories that have built up
+/// many old number files can still be read quickly.
+async fn latest_number(directory: &Path, ext: &str, create: bool) -> Result<u32, Error> {
+    let mut high = 0;
+    let mut dir = match async_fs::read_dir(directory).await {
+        Ok(dir) => dir,
+        // Directory will be created when the first number file is written
+        Err(e) if create && e.kind() == ErrorKind::NotFound => return Ok(high),
+        Err(e) => return Err(e),
+    };
+    while let Some(file) = dir.next_entry().await? {
+        match file_num(Path::new(&file.file_name()), ext) {
+            Some(val) if val > high && file.file_type().await?.is_file() => high = val,
+            _ => {}
         }
-        Ok(high)
     }
+    Ok(high)
+}
+
+/// The time a file was last modified
+async fn modified(path: &Path) -> Result<SystemTime, Error> {
+    async_fs::metadata(path).await?.modified()
 }
 
 /// Error returned when an extension would result in directory traversal - eg '.foo/../../bar'
@@ -170,13 +444,13 @@ impl std::error::Error for InvalidExtension {}
 mod tests {
     use std::fs;
     use std::ops::Deref;
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime};
 
     use rstest::{fixture, rstest};
     use tempfile::{tempdir, TempDir};
     use tokio::time::timeout;
 
-    use super::{InvalidExtension, NumTracker};
+    use super::{DirectoryStatus, InvalidExtension, NumTracker};
 
     /// Wrapper around a NumTracker to ensure the tempdir is not dropped while it is still required
     struct TempTracker(NumTracker, TempDir);
@@ -211,13 +485,33 @@ mod tests {
         let i22 = nt.for_beamline("i22", None).await;
 
         // difficult to test but this should be locked until i22 is dropped
-        nt.bl_locks.get("i22").unwrap().try_lock().unwrap_err();
-        nt.bl_locks.get("i22").unwrap().try_lock().unwrap_err();
-        nt.bl_locks.get("i22").unwrap().try_lock().unwrap_err();
+        nt.beamline_lock("i22").unwrap().try_lock().unwrap_err();
+        nt.beamline_lock("i22").unwrap().try_lock().unwrap_err();
+        nt.beamline_lock("i22").unwrap().try_lock().unwrap_err();
 
         drop(i22);
         // lock should now be free
-        _ = nt.bl_locks.get("i22").unwrap().try_lock().unwrap();
+        _ = nt.beamline_lock("i22").unwrap().try_lock().unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn directory_overrides(nt: TempTracker) {
+        let sandbox = tempdir().unwrap();
+        fs::File::create(sandbox.path().join("42.i22")).unwrap();
+        let TempTracker(nt, root) = nt;
+        let nt = nt.with_directories([("i22", sandbox.path()), ("i11", sandbox.path())]);
+
+        let mut i22 = nt.for_beamline("i22", None).await.unwrap();
+        assert_eq!(i22.prev().await.unwrap(), Some(42));
+        i22.set(43).await.unwrap();
+        assert!(sandbox.path().join("43.i22").exists());
+        assert!(!root.path().join("i22").join("43.i22").exists());
+
+        // Beamlines without a directory in the root can be given one
+        let i11 = nt.for_beamline("i11", None).await.unwrap();
+        assert!(i11.has_directory());
+        assert_eq!(i11.prev().await.unwrap(), Some(0));
     }
 
     #[rstest]
@@ -248,10 +542,21 @@ mod tests {
         .expect("Timed out waiting for unmanaged trackers");
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn peek_does_not_wait_for_lock(nt: TempTracker) {
+        let _i22 = nt.for_beamline("i22", None).await.unwrap();
+        let latest = timeout(Duration::from_secs(1), nt.peek_latest("i22", None))
+            .await
+            .expect("Timed out waiting for locked directory");
+        assert_eq!(latest.unwrap(), Some(122));
+        assert_eq!(nt.peek_latest("i11", None).await.unwrap(), None);
+    }
+
     #[rstest]
     #[tokio::test]
     async fn unmanaged_beamline_has_no_numbers(nt: TempTracker) {
-        let i11 = nt.for_beamline("i11", None).await.unwrap();
+        let mut i11 = nt.for_beamline("i11", None).await.unwrap();
         if let Some(num) = i11.prev().await.unwrap() {
             panic!("Unmanaged beamline returned previous number: {num}");
         }
@@ -265,7 +570,7 @@ mod tests {
     #[rstest]
     #[tokio::test]
     async fn bump_numbers(nt: TempTracker) {
-        let i22 = nt.for_beamline("i22", None).await.unwrap();
+        let mut i22 = nt.for_beamline("i22", None).await.unwrap();
         assert_eq!(i22.prev().await.unwrap(), Some(122));
         i22.set(123).await.unwrap();
         assert_eq!(i22.prev().await.unwrap(), Some(123));
@@ -275,10 +580,96 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn previous_file_kept_during_grace(root: TempDir) {
+        let grace = Duration::from_secs(60);
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .removal_grace(grace);
+        let i22 = root.as_ref().join("i22");
+        {
+            let mut tracker = nt.for_beamline("i22", None).await.unwrap();
+            tracker.set(123).await.unwrap();
+            tracker.set(124).await.unwrap();
+            assert_eq!(tracker.prev().await.unwrap(), Some(124));
+        }
+        assert!(
+            fs::exists(i22.join("122.i22")).unwrap(),
+            "previous number file deleted during grace period"
+        );
+        assert!(fs::exists(i22.join("123.i22")).unwrap());
+
+        // Pretend 122 was replaced before the grace period and that the service has restarted
+        fs::File::options()
+            .write(true)
+            .open(i22.join("123.i22"))
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * grace)
+            .unwrap();
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .removal_grace(grace);
+        nt.for_beamline("i22", None)
+            .await
+            .unwrap()
+            .set(125)
+            .await
+            .unwrap();
+        assert!(
+            !fs::exists(i22.join("122.i22")).unwrap(),
+            "previous number file not deleted after grace period"
+        );
+        // The files replaced within the grace period are kept
+        assert!(fs::exists(i22.join("123.i22")).unwrap());
+        assert!(fs::exists(i22.join("124.i22")).unwrap());
+        assert!(fs::exists(i22.join("125.i22")).unwrap());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn expired_consecutive_files_removed(root: TempDir) {
+        let grace = Duration::from_secs(60);
+        let i22 = root.as_ref().join("i22");
+        for (num, age) in [(120, 5), (121, 4), (122, 3)] {
+            fs::File::create(i22.join(format!("{num}.i22")))
+                .unwrap()
+                .set_modified(SystemTime::now() - age * grace)
+                .unwrap();
+        }
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .removal_grace(grace);
+        nt.for_beamline("i22", None)
+            .await
+            .unwrap()
+            .set(123)
+            .await
+            .unwrap();
+        // 122 was only just replaced but the earlier files were all replaced long ago
+        assert!(!fs::exists(i22.join("120.i22")).unwrap());
+        assert!(!fs::exists(i22.join("121.i22")).unwrap());
+        assert!(fs::exists(i22.join("122.i22")).unwrap());
+        assert!(fs::exists(i22.join("123.i22")).unwrap());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn only_previous_file_removed_without_grace(nt: TempTracker) {
+        let i22 = nt.1.as_ref().join("i22");
+        fs::File::create(i22.join("120.i22")).unwrap();
+        fs::File::create(i22.join("121.i22")).unwrap();
+        let mut tracker = nt.for_beamline("i22", None).await.unwrap();
+        tracker.set(123).await.unwrap();
+        assert!(fs::exists(i22.join("120.i22")).unwrap());
+        assert!(fs::exists(i22.join("121.i22")).unwrap());
+        assert!(!fs::exists(i22.join("122.i22")).unwrap());
+    }
+
     #[rstest]
     #[tokio::test]
     async fn non_consecutive_files_left(nt: TempTracker) {
-        let i22 = nt.for_beamline("i22", None).await.unwrap();
+        let mut i22 = nt.for_beamline("i22", None).await.unwrap();
         assert_eq!(i22.prev().await.unwrap(), Some(122));
         i22.set(244).await.unwrap();
         assert_eq!(i22.prev().await.unwrap(), Some(244));
@@ -288,13 +679,36 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn directories_are_not_numbers(nt: TempTracker) {
+        fs::create_dir(nt.1.as_ref().join("i22").join("500.i22")).unwrap();
+        let i22 = nt.for_beamline("i22", None).await.unwrap();
+        assert_eq!(i22.prev().await.unwrap(), Some(122));
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn many_number_files(root: TempDir) {
+        let dir = root.as_ref().join("b21");
+        for num in 1..=20_000 {
+            fs::File::create(dir.join(format!("{num}.b21"))).unwrap();
+        }
+        let nt = NumTracker::for_root_directory(Some(&root)).unwrap();
+        let b21 = nt.for_beamline("b21", None).await.unwrap();
+        let prev = timeout(Duration::from_secs(5), b21.prev())
+            .await
+            .expect("Reading many number files took too long");
+        assert_eq!(prev.unwrap(), Some(20_000));
+    }
+
     #[rstest]
     #[tokio::test]
     async fn alternative_extensions(nt: TempTracker) {
         let i22 = nt.for_beamline("i22", None).await.unwrap(); // default i22 extension
         assert_eq!(i22.prev().await.unwrap(), Some(122));
         drop(i22);
-        let i22 = nt.for_beamline("i22", Some("alt")).await.unwrap();
+        let mut i22 = nt.for_beamline("i22", Some("alt")).await.unwrap();
         assert_eq!(i22.prev().await.unwrap(), Some(0));
         i22.set(1234).await.unwrap();
         assert!(
@@ -325,6 +739,150 @@ mod tests {
         assert_eq!(InvalidExtension.to_string(), "Extension is not valid");
     }
 
+    #[rstest]
+    #[tokio::test]
+    async fn missing_directories_not_created_by_default(nt: TempTracker) {
+        let mut i11 = nt.for_beamline("i11", None).await.unwrap();
+        i11.set(111).await.unwrap();
+        assert!(
+            !fs::exists(nt.1.as_ref().join("i11")).unwrap(),
+            "Directory was created without being enabled"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn create_missing_directory(root: TempDir) {
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .create_missing(true);
+        let mut i11 = nt.for_beamline("i11", None).await.unwrap();
+        assert_eq!(i11.prev().await.unwrap(), Some(0));
+        i11.set(1).await.unwrap();
+        assert!(
+            fs::exists(root.as_ref().join("i11").join("1.i11")).unwrap(),
+            "Number file was not created in new directory"
+        );
+        drop(i11);
+        // new directory is now tracked like any other
+        let i11 = nt.for_beamline("i11", None).await.unwrap();
+        assert_eq!(i11.prev().await.unwrap(), Some(1));
+        nt.beamline_lock("i11").unwrap().try_lock().unwrap_err();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn recreate_removed_directory(root: TempDir) {
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .create_missing(true);
+        fs::remove_dir_all(root.as_ref().join("i22")).unwrap();
+        let mut i22 = nt.for_beamline("i22", None).await.unwrap();
+        i22.set(123).await.unwrap();
+        assert!(fs::exists(root.as_ref().join("i22").join("123.i22")).unwrap());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn invalid_beamline_directory_not_created(root: TempDir) {
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .create_missing(true);
+        let mut bl = nt.for_beamline("../i11", Some("ext")).await.unwrap();
+        bl.set(1).await.unwrap();
+        assert!(!fs::exists(root.as_ref().join("i11")).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[tokio::test]
+    async fn directory_creation_failure(root: TempDir) {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .create_missing(true);
+        fs::set_permissions(&root, fs::Permissions::from_mode(0o555)).unwrap();
+        let mut i11 = nt.for_beamline("i11", None).await.unwrap();
+        let result = i11.set(1).await;
+        fs::set_permissions(&root, fs::Permissions::from_mode(0o755)).unwrap();
+        if fs::exists(root.as_ref().join("i11")).unwrap() {
+            // Permissions are not enforced (eg running as root)
+            return;
+        }
+        let err = result.unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Could not create tracker directory"),
+            "Unexpected error: {err}"
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn directory_status(nt: TempTracker) {
+        assert_eq!(nt.directory_status("i22").await, DirectoryStatus::Available);
+        assert_eq!(
+            nt.directory_status("i11").await,
+            DirectoryStatus::Unconfigured
+        );
+
+        fs::remove_dir(nt.1.as_ref().join("b21")).unwrap();
+        assert_eq!(nt.directory_status("b21").await, DirectoryStatus::Missing);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn directory_status_is_cached(nt: TempTracker) {
+        assert_eq!(nt.directory_status("b21").await, DirectoryStatus::Available);
+        fs::remove_dir(nt.1.as_ref().join("b21")).unwrap();
+        assert_eq!(nt.directory_status("b21").await, DirectoryStatus::Available);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn directory_status_not_a_directory(root: TempDir) {
+        fs::File::create(root.as_ref().join("i11")).unwrap();
+        let nt = NumTracker::for_root_directory(Some(&root))
+            .unwrap()
+            .create_missing(true);
+        assert_eq!(
+            nt.directory_status("i11").await,
+            DirectoryStatus::Inaccessible
+        );
+        // Directories to be created on first use are reported as missing until then
+        assert_eq!(nt.directory_status("p45").await, DirectoryStatus::Missing);
+    }
+
+    #[tokio::test]
+    async fn directory_status_without_root() {
+        let nt = NumTracker::for_root_directory(None::<&str>).unwrap();
+        assert_eq!(
+            nt.directory_status("i22").await,
+            DirectoryStatus::Unconfigured
+        );
+    }
+
+    #[rstest]
+    #[case::with_root(true)]
+    #[case::without_root(false)]
+    #[tokio::test]
+    async fn directory_status_of_override(nt: TempTracker, #[case] with_root: bool) {
+        let sandbox = tempdir().unwrap();
+        let nt = if with_root {
+            nt.0
+        } else {
+            NumTracker::for_root_directory(None::<&str>).unwrap()
+        };
+        let nt = nt.with_directories([("i22", sandbox.path())]);
+        assert_eq!(nt.directory_status("i22").await, DirectoryStatus::Available);
+
+        let nt = NumTracker::for_root_directory(None::<&str>)
+            .unwrap()
+            .with_directories([("i22", sandbox.path().join("missing").as_path())]);
+        assert_eq!(nt.directory_status("i22").await, DirectoryStatus::Missing);
+    }
+
     #[rstest]
     #[tokio::test]
     async fn non_number_files(nt: TempTracker) {
@@ -0,0 +1,159 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use serde::Deserialize;
+use tracing::info;
+
+/// Per-environment overrides applied to beamline configurations when they are used, without
+/// changing what is stored in the DB. Loaded once at startup from a JSON file, eg
+///
+/// ```json
+/// {"beamlines": {"i22": {"trackerDirectory": "/sandbox/i22", "year": 2023}}}
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConfigOverlay {
+    #[serde(default)]
+    beamlines: BTreeMap<String, BeamlineOverlay>,
+}
+
+/// The fields that can be overridden for a single beamline
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct BeamlineOverlay {
+    /// Directory to use for the beamline's tracker files in place of the one in the tracker
+    /// root directory
+    tracker_directory: Option<PathBuf>,
+    /// Year to use for paths in place of the current year or visit date. A year requested by
+    /// a client still takes precedence.
+    year: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum OverlayError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayError::Read(path, e) => {
+                write!(f, "Could not read config overlay {}: {e}", path.display())
+            }
+            OverlayError::Parse(path, e) => {
+                write!(f, "Invalid config overlay {}: {e}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OverlayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OverlayError::Read(_, e) => Some(e),
+            OverlayError::Parse(_, e) => Some(e),
+        }
+    }
+}
+
+impl ConfigOverlay {
+    /// Read an overlay from a JSON file and log the overrides it contains
+    pub fn load(path: &Path) -> Result<Self, OverlayError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| OverlayError::Read(path.to_path_buf(), e))?;
+        let overlay: Self = serde_json::from_str(&content)
+            .map_err(|e| OverlayError::Parse(path.to_path_buf(), e))?;
+        info!("Using config overlay from {}", path.display());
+        for (bl, over) in &overlay.beamlines {
+            if let Some(dir) = &over.tracker_directory {
+                info!("Overlay: {bl} tracker directory is {}", dir.display());
+            }
+            if let Some(year) = over.year {
+                info!("Overlay: {bl} year is {year}");
+            }
+        }
+        Ok(overlay)
+    }
+
+    /// The tracker directories overridden for each beamline
+    pub fn tracker_directories(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.beamlines.iter().filter_map(|(bl, over)| {
+            over.tracker_directory
+                .as_deref()
+                .map(|dir| (bl.as_str(), dir))
+        })
+    }
+
+    /// The year to use for a beamline's paths if it has been overridden
+    pub fn year(&self, beamline: &str) -> Option<i32> {
+        self.beamlines.get(beamline).and_then(|over| over.year)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use assert_matches::assert_matches;
+    use tempfile::tempdir;
+
+    use super::{ConfigOverlay, OverlayError};
+
+    #[test]
+    fn load_overlay() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("overlay.json");
+        fs::write(
+            &path,
+            r#"{"beamlines": {
+                "i22": {"trackerDirectory": "/sandbox/i22", "year": 2023},
+                "b21": {"year": 2022}
+            }}"#,
+        )
+        .unwrap();
+        let overlay = ConfigOverlay::load(&path).unwrap();
+        assert_eq!(
+            overlay.tracker_directories().collect::<Vec<_>>(),
+            [("i22", Path::new("/sandbox/i22"))]
+        );
+        assert_eq!(overlay.year("i22"), Some(2023));
+        assert_eq!(overlay.year("b21"), Some(2022));
+        assert_eq!(overlay.year("i11"), None);
+    }
+
+    #[test]
+    fn unknown_field() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("overlay.json");
+        fs::write(&path, r#"{"beamlines": {"i22": {"visit": "/tmp"}}}"#).unwrap();
+        assert_matches!(
+            ConfigOverlay::load(&path),
+            Err(OverlayError::Parse(p, _)) if p == path
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("overlay.json");
+        assert_matches!(ConfigOverlay::load(&path), Err(OverlayError::Read(..)));
+    }
+}
@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
+use std::str::FromStr;
 
-use crate::template::{PathTemplate, PathTemplateError};
+use async_graphql::Enum;
+use clap::ValueEnum;
+
+use crate::template::{FieldSource, PathTemplate, PathTemplateError};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BeamlineField {
@@ -30,10 +35,68 @@ pub enum BeamlineField {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ScanField {
     Subdirectory,
-    ScanNumber,
+    ScanNumber(Radix),
     Beamline(BeamlineField),
 }
 
+/// The base used to render a scan number, eg `{scan_number:hex}`. Defaults to decimal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Radix(u32);
+
+impl Radix {
+    pub const DECIMAL: Self = Self(10);
+
+    /// The base as a number, eg 16 for hex
+    pub fn base(self) -> u32 {
+        self.0
+    }
+
+    /// Render a number in this base using lowercase letters for digits above 9
+    pub fn format(self, mut value: u32) -> String {
+        let mut digits = Vec::new();
+        loop {
+            digits.push(
+                char::from_digit(value % self.0, self.0).expect("Digit is always less than radix"),
+            );
+            value /= self.0;
+            if value == 0 {
+                break;
+            }
+        }
+        digits.iter().rev().collect()
+    }
+}
+
+impl Display for Radix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            2 => f.write_str("bin"),
+            8 => f.write_str("oct"),
+            10 => f.write_str("dec"),
+            16 => f.write_str("hex"),
+            r => write!(f, "{r}"),
+        }
+    }
+}
+
+impl FromStr for Radix {
+    type Err = ();
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec {
+            "bin" => Ok(Self(2)),
+            "oct" => Ok(Self(8)),
+            "dec" => Ok(Self(10)),
+            "hex" => Ok(Self(16)),
+            // Only plain digits (no sign etc) are valid as a radix
+            r if r.bytes().all(|b| b.is_ascii_digit()) => match r.parse() {
+                Ok(r @ 2..=36) => Ok(Self(r)),
+                _ => Err(()),
+            },
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DetectorField {
     Detector,
@@ -55,7 +118,8 @@ impl Display for ScanField {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ScanField::Subdirectory => f.write_str("subdirectory"),
-            ScanField::ScanNumber => f.write_str("scan_number"),
+            ScanField::ScanNumber(radix) if radix.0 == 10 => f.write_str("scan_number"),
+            ScanField::ScanNumber(radix) => write!(f, "scan_number:{radix}"),
             ScanField::Beamline(bl) => write!(f, "{bl}"),
         }
     }
@@ -97,8 +161,14 @@ impl TryFrom<String> for BeamlineField {
 impl TryFrom<String> for ScanField {
     type Error = InvalidKey;
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(radix) = value.strip_prefix("scan_number:") {
+            return match radix.parse() {
+                Ok(radix) => Ok(ScanField::ScanNumber(radix)),
+                Err(_) => Err(InvalidKey(value)),
+            };
+        }
         match value.as_str() {
-            "scan_number" => Ok(ScanField::ScanNumber),
+            "scan_number" => Ok(ScanField::ScanNumber(Radix::DECIMAL)),
             "subdirectory" => Ok(ScanField::Subdirectory),
             _ => Ok(ScanField::Beamline(BeamlineField::try_from(value)?)),
         }
@@ -115,14 +185,46 @@ impl TryFrom<String> for DetectorField {
     }
 }
 
-#[allow(unused)] // not actually unused: see github.com/rust-lang/rust/issues/128839
-pub trait PathField: TryFrom<String> + Eq + Hash + Display + 'static {}
-impl<F> PathField for F where F: TryFrom<String> + Eq + Hash + Display + 'static {}
+pub trait PathField: TryFrom<String> + Clone + Eq + Hash + Display + 'static {
+    /// The field without any formatting options, used to check which fields a template
+    /// references. A scan number rendered in any radix is still the scan number, so templates
+    /// using any radix satisfy the requirement for a `{scan_number}` placeholder.
+    fn kind(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl PathField for BeamlineField {}
+
+impl PathField for ScanField {
+    fn kind(&self) -> Self {
+        match self {
+            ScanField::ScanNumber(_) => ScanField::ScanNumber(Radix::DECIMAL),
+            field => *field,
+        }
+    }
+}
+
+impl PathField for DetectorField {
+    fn kind(&self) -> Self {
+        match self {
+            DetectorField::Scan(field) => DetectorField::Scan(field.kind()),
+            field => *field,
+        }
+    }
+}
+
+/// The kinds of field referenced by a template
+fn referenced_kinds<F: PathField>(template: &PathTemplate<F>) -> HashSet<F> {
+    template.referenced_fields().map(F::kind).collect()
+}
 
 pub trait PathSpec {
     type Field: PathField;
     const REQUIRED: &'static [Self::Field];
     const ABSOLUTE: bool;
+    /// The name of this kind of template to use in error messages
+    const KIND: &'static str;
 
     fn new_checked(path: &str) -> Result<PathTemplate<Self::Field>, InvalidPathTemplate> {
         let template = PathTemplate::new(path)?;
@@ -131,15 +233,21 @@ pub trait PathSpec {
             (false, true) => Err(InvalidPathTemplate::ShouldBeRelative),
             _ => Ok(()),
         }?;
-        let fields = template.referenced_fields().collect::<HashSet<_>>();
+        let fields = referenced_kinds(&template);
         for f in Self::REQUIRED {
-            if !fields.contains(f) {
+            if !fields.contains(&f.kind()) {
                 return Err(InvalidPathTemplate::MissingField(f.to_string()));
             }
         }
+        Self::validate(&template)?;
         Ok(template)
     }
 
+    /// Any additional checks specific to this kind of template
+    fn validate(_template: &PathTemplate<Self::Field>) -> Result<(), InvalidPathTemplate> {
+        Ok(())
+    }
+
     fn describe() -> &'static str;
 }
 
@@ -149,6 +257,7 @@ pub enum InvalidPathTemplate {
     ShouldBeAbsolute,
     ShouldBeRelative,
     MissingField(String),
+    ForbiddenField(String),
 }
 
 impl Display for InvalidPathTemplate {
@@ -160,6 +269,9 @@ impl Display for InvalidPathTemplate {
             InvalidPathTemplate::MissingField(fld) => {
                 write!(f, "Template should reference missing field: {fld:?}")
             }
+            InvalidPathTemplate::ForbiddenField(fld) => {
+                write!(f, "Template should not reference field: {fld:?}")
+            }
         }
     }
 }
@@ -179,6 +291,94 @@ impl From<PathTemplateError> for InvalidPathTemplate {
     }
 }
 
+/// Fields that must or must not be used in one kind of template, in addition to the
+/// requirements of the template kind itself
+#[derive(Debug, Clone)]
+pub struct FieldPolicy<F> {
+    required: Vec<F>,
+    forbidden: Vec<F>,
+}
+
+impl<F> Default for FieldPolicy<F> {
+    fn default() -> Self {
+        Self {
+            required: Vec::new(),
+            forbidden: Vec::new(),
+        }
+    }
+}
+
+impl<F: PathField> FieldPolicy<F> {
+    pub fn check(&self, template: &PathTemplate<F>) -> Result<(), InvalidPathTemplate> {
+        let fields = referenced_kinds(template);
+        if let Some(f) = self.required.iter().find(|f| !fields.contains(&f.kind())) {
+            return Err(InvalidPathTemplate::MissingField(f.to_string()));
+        }
+        if let Some(f) = self.forbidden.iter().find(|f| fields.contains(&f.kind())) {
+            return Err(InvalidPathTemplate::ForbiddenField(f.to_string()));
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, field: F, forbid: bool) {
+        if forbid {
+            self.forbidden.push(field);
+        } else {
+            self.required.push(field);
+        }
+    }
+}
+
+/// A field for a kind of template, eg `visit=proposal` for the proposal field in visit
+/// templates
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateFieldRule {
+    Visit(BeamlineField),
+    Scan(ScanField),
+    Detector(DetectorField),
+}
+
+impl FromStr for TemplateFieldRule {
+    type Err = String;
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let Some((kind, field)) = rule.split_once('=') else {
+            return Err(format!("Expected <kind>=<field>, not {rule:?}"));
+        };
+        let field = field.to_string();
+        match kind {
+            VisitTemplate::KIND => field.try_into().map(Self::Visit),
+            ScanTemplate::KIND => field.try_into().map(Self::Scan),
+            DetectorTemplate::KIND => field.try_into().map(Self::Detector),
+            _ => return Err(format!("Unknown template kind: {kind:?}")),
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// The field policies for every kind of template
+#[derive(Debug, Clone, Default)]
+pub struct TemplatePolicies {
+    pub visit: FieldPolicy<BeamlineField>,
+    pub scan: FieldPolicy<ScanField>,
+    pub detector: FieldPolicy<DetectorField>,
+}
+
+impl TemplatePolicies {
+    pub fn new(required: &[TemplateFieldRule], forbidden: &[TemplateFieldRule]) -> Self {
+        let mut policies = Self::default();
+        for (rules, forbid) in [(required, false), (forbidden, true)] {
+            for rule in rules {
+                match rule.clone() {
+                    TemplateFieldRule::Visit(f) => policies.visit.add(f, forbid),
+                    TemplateFieldRule::Scan(f) => policies.scan.add(f, forbid),
+                    TemplateFieldRule::Detector(f) => policies.detector.add(f, forbid),
+                }
+            }
+        }
+        policies
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct VisitTemplate;
 #[derive(Debug, Clone, Copy)]
@@ -189,20 +389,42 @@ pub struct DetectorTemplate;
 impl PathSpec for VisitTemplate {
     type Field = BeamlineField;
 
-    const REQUIRED: &'static [Self::Field] = &[BeamlineField::Instrument, BeamlineField::Visit];
+    const REQUIRED: &'static [Self::Field] = &[BeamlineField::Instrument];
 
     const ABSOLUTE: bool = true;
+    const KIND: &'static str = "visit";
+
+    /// Templates must reference either the visit or, for directories shared by every session
+    /// of a proposal, the proposal
+    fn validate(template: &PathTemplate<BeamlineField>) -> Result<(), InvalidPathTemplate> {
+        if !template
+            .referenced_fields()
+            .any(|f| matches!(f, BeamlineField::Visit | BeamlineField::Proposal))
+        {
+            return Err(InvalidPathTemplate::MissingField(
+                BeamlineField::Visit.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     fn describe() -> &'static str {
-        "A template describing the path to the visit directory for a beamline"
+        concat!(
+            "A template describing the path to the visit directory for a beamline",
+            "\n\n",
+            "It should contain placeholders for {instrument} and either {visit} or, ",
+            "if the directory is shared by every session of a proposal, {proposal}."
+        )
     }
 }
 
 impl PathSpec for ScanTemplate {
     type Field = ScanField;
 
-    const REQUIRED: &'static [Self::Field] = &[ScanField::ScanNumber];
+    const REQUIRED: &'static [Self::Field] = &[ScanField::ScanNumber(Radix::DECIMAL)];
 
     const ABSOLUTE: bool = false;
+    const KIND: &'static str = "scan";
     fn describe() -> &'static str {
         "A template describing the location within a visit directory where the root scan file should be written"
     }
@@ -213,10 +435,12 @@ impl PathSpec for DetectorTemplate {
 
     const REQUIRED: &'static [Self::Field] = &[
         DetectorField::Detector,
-        DetectorField::Scan(ScanField::ScanNumber),
+        DetectorField::Scan(ScanField::ScanNumber(Radix::DECIMAL)),
     ];
 
     const ABSOLUTE: bool = false;
+    const KIND: &'static str = "detector";
+
     fn describe() -> &'static str {
         concat!(
             "A template describing the location within a visit directory where ",
@@ -229,12 +453,212 @@ impl PathSpec for DetectorTemplate {
     }
 }
 
+/// The kinds of template that can be configured for a beamline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, ValueEnum)]
+pub enum TemplateKind {
+    Visit,
+    Scan,
+    Detector,
+}
+
+/// Sample values for template placeholders, keyed by placeholder name
+pub struct SampleContext(HashMap<String, String>);
+
+impl FromIterator<(String, String)> for SampleContext {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<F: Display> FieldSource<F> for SampleContext {
+    fn resolve(&self, field: &F) -> Cow<'_, str> {
+        self.0
+            .get(&field.to_string())
+            .map_or("".into(), |value| value.as_str().into())
+    }
+}
+
+impl SampleContext {
+    /// Parse and validate a template as the given kind of path and render it using these
+    /// sample values. Every placeholder in the template must have a sample value.
+    pub fn render<S: PathSpec>(&self, template: &str) -> Result<String, String> {
+        let template = S::new_checked(template).map_err(|e| e.to_string())?;
+        let missing = template
+            .fields_used()
+            .iter()
+            .map(|f| f.to_string())
+            .filter(|f| !self.0.contains_key(f))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(format!(
+                "No sample values given for fields: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(template.render(self).display().to_string())
+    }
+
+    /// Render a template as the given kind of template. See [`render`](Self::render).
+    pub fn render_as(&self, kind: TemplateKind, template: &str) -> Result<String, String> {
+        match kind {
+            TemplateKind::Visit => self.render::<VisitTemplate>(template),
+            TemplateKind::Scan => self.render::<ScanTemplate>(template),
+            TemplateKind::Detector => self.render::<DetectorTemplate>(template),
+        }
+    }
+
+    /// Add a sample value for a placeholder unless one has already been given
+    pub fn set_default(&mut self, field: &str, value: &str) {
+        self.0.entry(field.into()).or_insert_with(|| value.into());
+    }
+}
+
+/// Rules for converting detector names into strings that are safe to use in file names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectorNormalisation {
+    /// Convert names to lowercase
+    pub lowercase: bool,
+    /// Replace runs of invalid characters with a single replacement (and drop any at the start
+    /// or end of the name) instead of replacing each one individually
+    pub collapse: bool,
+    /// The character used in place of any non-alphanumeric characters
+    pub replacement: char,
+}
+
+impl Default for DetectorNormalisation {
+    fn default() -> Self {
+        Self {
+            lowercase: false,
+            collapse: true,
+            replacement: '_',
+        }
+    }
+}
+
+impl DetectorNormalisation {
+    const INVALID: fn(char) -> bool = |c| !c.is_ascii_alphanumeric();
+
+    /// Check whether a character can be used as a replacement without producing unsafe paths
+    pub fn valid_replacement(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '_' || c == '-'
+    }
+
+    pub fn apply(&self, name: &str) -> String {
+        let name = if self.lowercase {
+            Cow::Owned(name.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(name)
+        };
+        if self.collapse {
+            name.split(Self::INVALID)
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(self.replacement.encode_utf8(&mut [0; 4]))
+        } else {
+            name.chars()
+                .map(|c| {
+                    if Self::INVALID(c) {
+                        self.replacement
+                    } else {
+                        c
+                    }
+                })
+                .collect()
+        }
+    }
+
+    /// Whether applying these rules would change a name other than by changing its case
+    pub fn rewrites(&self, name: &str) -> bool {
+        let rules = Self {
+            lowercase: false,
+            ..*self
+        };
+        rules.apply(name) != name
+    }
+}
+
+#[cfg(test)]
+mod detector_normalisation_tests {
+    use rstest::rstest;
+
+    use super::DetectorNormalisation;
+
+    #[rstest]
+    #[case::default(false, true, '_', "Foo_Bar_Baz")]
+    #[case::lowercase(true, true, '_', "foo_bar_baz")]
+    #[case::no_collapse(false, false, '_', "Foo_Bar__Baz")]
+    #[case::lowercase_no_collapse(true, false, '_', "foo_bar__baz")]
+    #[case::hyphen(false, true, '-', "Foo-Bar-Baz")]
+    #[case::lowercase_hyphen(true, true, '-', "foo-bar-baz")]
+    #[case::hyphen_no_collapse(false, false, '-', "Foo-Bar--Baz")]
+    #[case::lowercase_hyphen_no_collapse(true, false, '-', "foo-bar--baz")]
+    fn rule_combinations(
+        #[case] lowercase: bool,
+        #[case] collapse: bool,
+        #[case] replacement: char,
+        #[case] expected: &str,
+    ) {
+        let rules = DetectorNormalisation {
+            lowercase,
+            collapse,
+            replacement,
+        };
+        assert_eq!(rules.apply("Foo Bar..Baz"), expected);
+    }
+
+    #[rstest]
+    #[case::unchanged("camera", "camera")]
+    #[case::punctuation("foo+bar", "foo_bar")]
+    #[case::multiple_punctuation("foo+-?!bar", "foo_bar")]
+    #[case::surrounding_punctuation("..foo..", "foo")]
+    #[case::mixed_case("Foo Bar..Baz", "Foo_Bar_Baz")]
+    fn default_rules(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(DetectorNormalisation::default().apply(input), expected);
+    }
+
+    #[test]
+    fn uncollapsed_surrounding_punctuation() {
+        let rules = DetectorNormalisation {
+            collapse: false,
+            ..Default::default()
+        };
+        assert_eq!(rules.apply("..foo."), "__foo_");
+    }
+
+    #[rstest]
+    #[case::valid("Camera", false)]
+    #[case::replacement("foo_bar", false)]
+    #[case::punctuation("foo.bar", true)]
+    #[case::surrounding_replacement("_foo", true)]
+    fn rewritten_names(#[case] name: &str, #[case] rewritten: bool) {
+        let rules = DetectorNormalisation {
+            lowercase: true,
+            ..Default::default()
+        };
+        assert_eq!(rules.rewrites(name), rewritten);
+    }
+
+    #[rstest]
+    #[case::underscore('_', true)]
+    #[case::hyphen('-', true)]
+    #[case::letter('x', true)]
+    #[case::slash('/', false)]
+    #[case::dot('.', false)]
+    #[case::space(' ', false)]
+    fn replacement_characters(#[case] c: char, #[case] valid: bool) {
+        assert_eq!(DetectorNormalisation::valid_replacement(c), valid);
+    }
+}
+
 #[cfg(test)]
 mod paths_tests {
+    use std::error::Error as _;
     use std::fmt::Debug;
 
     use super::{
-        DetectorTemplate, InvalidPathTemplate, PathSpec as _, ScanTemplate, VisitTemplate,
+        BeamlineField, DetectorField, DetectorTemplate, InvalidPathTemplate, PathField as _,
+        PathSpec as _, Radix, ScanField, ScanTemplate, TemplateFieldRule, TemplatePolicies,
+        VisitTemplate,
     };
     use crate::template::{ErrorKind, PathTemplateError};
 
@@ -263,10 +687,19 @@ mod paths_tests {
         }
     }
 
+    #[rstest::rstest]
+    #[case::visit("/{instrument}/data/{visit}")]
+    #[case::proposal("/{instrument}/data/{proposal}")]
+    #[case::both("/{instrument}/{proposal}/{visit}")]
+    fn valid_visit(#[case] template: &str) {
+        VisitTemplate::new_checked(template).unwrap();
+    }
+
     #[rstest::rstest]
     #[case::relative("relative/visit/path", InvalidPathTemplate::ShouldBeAbsolute)]
     #[case::missing_visit("/{instrument}/data", InvalidPathTemplate::MissingField("visit".into()))]
     #[case::missing_instrument("/data/{visit}", InvalidPathTemplate::MissingField("instrument".into()))]
+    #[case::missing_instrument_with_proposal("/data/{proposal}", InvalidPathTemplate::MissingField("instrument".into()))]
     #[case::invalid_path_incomplete("/data/{unclosed", TemplateErrorType::Incomplete)]
     #[case::invalid_path_empty("/data/{}", TemplateErrorType::Empty)]
     #[case::invalid_path_nested("/data/{nes{ted}}", TemplateErrorType::Nested)]
@@ -309,4 +742,165 @@ mod paths_tests {
         let e = DetectorTemplate::new_checked(template).unwrap_err();
         assert_eq!(err, e);
     }
+
+    #[rstest::rstest]
+    #[case::incomplete("/data/{unclosed", "Error parsing template: Unclosed placeholder at 9")]
+    #[case::relative("relative/{instrument}/{visit}", "Path should be absolute")]
+    #[case::missing_field(
+        "/data/{instrument}",
+        r#"Template should reference missing field: "visit""#
+    )]
+    fn invalid_visit_message(#[case] template: &str, #[case] message: &str) {
+        let e = VisitTemplate::new_checked(template).unwrap_err();
+        assert_eq!(e.to_string(), message);
+    }
+
+    #[rstest::rstest]
+    #[case::empty(
+        "data/{}/{scan_number}",
+        "Error parsing template: Empty placeholder at 1"
+    )]
+    #[case::nested(
+        "data/{nes{ted}}/{scan_number}",
+        "Error parsing template: Nested placeholder at 4"
+    )]
+    #[case::unrecognised(
+        "data/{detector}/{scan_number}",
+        "Error parsing template: Invalid placeholder at 9"
+    )]
+    #[case::absolute("/data/{scan_number}", "Path should be relative")]
+    fn invalid_scan_message(#[case] template: &str, #[case] message: &str) {
+        let e = ScanTemplate::new_checked(template).unwrap_err();
+        assert_eq!(e.to_string(), message);
+    }
+
+    #[test]
+    fn template_error_source() {
+        let e = ScanTemplate::new_checked("data/{unclosed").unwrap_err();
+        let source = e.source().expect("Template errors should have a source");
+        assert_eq!(source.to_string(), e.to_string());
+        assert!(InvalidPathTemplate::ShouldBeRelative.source().is_none());
+    }
+
+    #[rstest::rstest]
+    #[case::decimal("{scan_number}", 10, "{scan_number}")]
+    #[case::explicit_decimal("{scan_number:dec}", 10, "{scan_number}")]
+    #[case::hex("{scan_number:hex}", 16, "{scan_number:hex}")]
+    #[case::octal("{scan_number:oct}", 8, "{scan_number:oct}")]
+    #[case::binary("{scan_number:bin}", 2, "{scan_number:bin}")]
+    #[case::numeric("{scan_number:36}", 36, "{scan_number:36}")]
+    #[case::numeric_hex("{scan_number:16}", 16, "{scan_number:hex}")]
+    fn scan_number_radix(#[case] template: &str, #[case] radix: u32, #[case] canonical: &str) {
+        let template = ScanTemplate::new_checked(template).unwrap();
+        let fields = template.referenced_fields().collect::<Vec<_>>();
+        let [ScanField::ScanNumber(r)] = fields[..] else {
+            panic!("Unexpected fields: {fields:?}");
+        };
+        assert_eq!(r.0, radix);
+        assert_eq!(template.to_string(), canonical);
+    }
+
+    #[test]
+    fn radix_distinguishes_scan_numbers() {
+        let hex = ScanField::ScanNumber(Radix(16));
+        assert_ne!(hex, ScanField::ScanNumber(Radix::DECIMAL));
+        assert_eq!(hex.kind(), ScanField::ScanNumber(Radix::DECIMAL));
+        assert_eq!(
+            DetectorField::Scan(hex).kind(),
+            DetectorField::Scan(ScanField::ScanNumber(Radix::DECIMAL))
+        );
+        DetectorTemplate::new_checked("{detector}/{scan_number:hex}").unwrap();
+    }
+
+    #[rstest::rstest]
+    #[case::unknown_name("{scan_number:hexadecimal}")]
+    #[case::too_small("{scan_number:1}")]
+    #[case::too_large("{scan_number:37}")]
+    #[case::signed("{scan_number:+16}")]
+    #[case::empty("{scan_number:}")]
+    fn invalid_scan_number_radix(#[case] template: &str) {
+        let e = ScanTemplate::new_checked(template).unwrap_err();
+        assert_eq!(TemplateErrorType::Unrecognised, e);
+    }
+
+    #[rstest::rstest]
+    #[case::decimal(Radix::DECIMAL, 1234, "1234")]
+    #[case::zero(Radix(16), 0, "0")]
+    #[case::hex(Radix(16), 48879, "beef")]
+    #[case::binary(Radix(2), 5, "101")]
+    #[case::base_36(Radix(36), 1295, "zz")]
+    #[case::max(Radix(16), u32::MAX, "ffffffff")]
+    fn format_radix(#[case] radix: Radix, #[case] value: u32, #[case] expected: &str) {
+        assert_eq!(radix.format(value), expected);
+    }
+
+    #[test]
+    fn distinct_detector_paths() {
+        DetectorTemplate::new_checked("{scan_number}/{detector}").unwrap();
+        DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").unwrap();
+    }
+
+    #[test]
+    fn required_field_policy() {
+        let policies = TemplatePolicies::new(&["visit=proposal".parse().unwrap()], &[]);
+        let template = VisitTemplate::new_checked("/{instrument}/{proposal}/{visit}").unwrap();
+        policies.visit.check(&template).unwrap();
+
+        let template = VisitTemplate::new_checked("/{instrument}/{visit}").unwrap();
+        let e = policies.visit.check(&template).unwrap_err();
+        assert_eq!(e, InvalidPathTemplate::MissingField("proposal".into()));
+    }
+
+    #[test]
+    fn forbidden_field_policy() {
+        let policies = TemplatePolicies::new(&[], &["scan=subdirectory".parse().unwrap()]);
+        let template = ScanTemplate::new_checked("{instrument}-{scan_number}").unwrap();
+        policies.scan.check(&template).unwrap();
+
+        let template = ScanTemplate::new_checked("{subdirectory}/{scan_number}").unwrap();
+        let e = policies.scan.check(&template).unwrap_err();
+        assert_eq!(
+            e,
+            InvalidPathTemplate::ForbiddenField("subdirectory".into())
+        );
+        assert_eq!(
+            e.to_string(),
+            r#"Template should not reference field: "subdirectory""#
+        );
+    }
+
+    #[test]
+    fn policy_ignores_radix() {
+        let policies = TemplatePolicies::new(&["scan=scan_number".parse().unwrap()], &[]);
+        let template = ScanTemplate::new_checked("{instrument}-{scan_number:hex}").unwrap();
+        policies.scan.check(&template).unwrap();
+    }
+
+    #[test]
+    fn default_policies_allow_anything() {
+        let template = DetectorTemplate::new_checked("{detector}/{scan_number}").unwrap();
+        TemplatePolicies::default()
+            .detector
+            .check(&template)
+            .unwrap();
+    }
+
+    #[rstest::rstest]
+    #[case::visit(
+        "visit=proposal",
+        Ok(TemplateFieldRule::Visit(BeamlineField::Proposal))
+    )]
+    #[case::detector(
+        "detector=detector",
+        Ok(TemplateFieldRule::Detector(DetectorField::Detector))
+    )]
+    #[case::no_separator("visit", Err("Expected <kind>=<field>, not \"visit\""))]
+    #[case::unknown_kind("path=visit", Err("Unknown template kind: \"path\""))]
+    #[case::unknown_field("scan=detector", Err("Unrecognised key: detector"))]
+    fn parse_field_rule(#[case] rule: &str, #[case] expected: Result<TemplateFieldRule, &str>) {
+        assert_eq!(
+            rule.parse::<TemplateFieldRule>(),
+            expected.map_err(String::from)
+        );
+    }
 }
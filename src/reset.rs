@@ -0,0 +1,362 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::cli::ResetOptions;
+use crate::db_service::{
+    BeamlineConfigurationUpdate, ConfigurationError, OpenError, SqliteScanPathService,
+    UpdateConfigurationError,
+};
+use crate::numtracker::{InvalidExtension, NumTracker};
+use crate::overlay::{ConfigOverlay, OverlayError};
+
+/// The latest scan number for a beamline before and after a reset
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScanNumberReset {
+    pub previous: u32,
+    pub current: u32,
+}
+
+#[derive(Debug)]
+pub enum ResetError {
+    Configuration(ConfigurationError),
+    Update(UpdateConfigurationError),
+    Extension(InvalidExtension),
+    Tracker(io::Error),
+    Open(OpenError),
+    Overlay(OverlayError),
+    WouldLower {
+        beamline: String,
+        current: u32,
+        requested: u32,
+    },
+    Cancelled,
+}
+
+impl Display for ResetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetError::Configuration(e) => write!(f, "{e}"),
+            ResetError::Update(e) => write!(f, "{e}"),
+            ResetError::Extension(e) => write!(f, "Invalid tracker file extension: {e}"),
+            ResetError::Tracker(e) => write!(f, "Could not update tracker directory: {e}"),
+            ResetError::Open(e) => write!(f, "Could not open DB: {e}"),
+            ResetError::Overlay(e) => write!(f, "{e}"),
+            ResetError::WouldLower {
+                beamline,
+                current,
+                requested,
+            } => write!(
+                f,
+                "Scan number for {beamline:?} is {current}. Lowering it to {requested} \
+                requires --force"
+            ),
+            ResetError::Cancelled => f.write_str("Cancelled"),
+        }
+    }
+}
+
+impl Error for ResetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResetError::Configuration(e) => Some(e),
+            ResetError::Update(e) => Some(e),
+            ResetError::Extension(e) => Some(e),
+            ResetError::Tracker(e) => Some(e),
+            ResetError::Open(e) => Some(e),
+            ResetError::Overlay(e) => Some(e),
+            ResetError::WouldLower { .. } | ResetError::Cancelled => None,
+        }
+    }
+}
+
+impl From<ConfigurationError> for ResetError {
+    fn from(value: ConfigurationError) -> Self {
+        Self::Configuration(value)
+    }
+}
+
+impl From<UpdateConfigurationError> for ResetError {
+    fn from(value: UpdateConfigurationError) -> Self {
+        Self::Update(value)
+    }
+}
+
+impl From<InvalidExtension> for ResetError {
+    fn from(value: InvalidExtension) -> Self {
+        Self::Extension(value)
+    }
+}
+
+impl From<io::Error> for ResetError {
+    fn from(value: io::Error) -> Self {
+        Self::Tracker(value)
+    }
+}
+
+/// Reset the latest scan number for a beamline after asking the user to confirm the change
+///
+/// Scans allocated by a running service are not blocked while this happens so the service
+/// should be stopped first.
+pub async fn reset_scan_number(db: &Path, opts: ResetOptions) -> Result<(), ResetError> {
+    let db = SqliteScanPathService::open_existing(db, false)
+        .await
+        .map_err(ResetError::Open)?;
+    let overlay = match opts.config_overlay {
+        Some(path) => ConfigOverlay::load(&path).map_err(ResetError::Overlay)?,
+        None => ConfigOverlay::default(),
+    };
+    let nt = NumTracker::for_root_directory(opts.root_directory)?
+        .with_directories(overlay.tracker_directories());
+    let current = latest_scan_number(&db, &nt, &opts.beamline).await?;
+    check_lowering(&opts.beamline, current, opts.scan_number, opts.force)?;
+    if opts.scan_number < current {
+        eprintln!(
+            "Warning: lowering the scan number for {:?} from {current} to {}. Existing scan \
+            numbers will be reused",
+            opts.beamline, opts.scan_number
+        );
+    }
+    if !opts.yes {
+        let prompt = format!(
+            "Reset the scan number for {:?} from {current} to {}?",
+            opts.beamline, opts.scan_number
+        );
+        if !confirm(&prompt, io::stdin().lock())? {
+            return Err(ResetError::Cancelled);
+        }
+    }
+    let reset = reset(&db, &nt, &opts.beamline, opts.scan_number, opts.force).await?;
+    println!(
+        "Scan number for {:?} reset from {} to {}",
+        opts.beamline, reset.previous, reset.current
+    );
+    Ok(())
+}
+
+/// Ask the user a yes/no question, defaulting to no
+fn confirm(prompt: &str, mut input: impl BufRead) -> io::Result<bool> {
+    print!("{prompt} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// The latest scan number for a beamline, the higher of the DB and the tracker directory
+async fn latest_scan_number(
+    db: &SqliteScanPathService,
+    nt: &NumTracker,
+    beamline: &str,
+) -> Result<u32, ResetError> {
+    let config = db.current_configuration(beamline).await?;
+    let dir = nt.for_beamline(beamline, config.extension()).await?;
+    Ok(config.scan_number().max(dir.prev().await?.unwrap_or(0)))
+}
+
+/// Lowering the scan number is only allowed if it is forced
+fn check_lowering(
+    beamline: &str,
+    current: u32,
+    requested: u32,
+    force: bool,
+) -> Result<(), ResetError> {
+    if requested < current && !force {
+        return Err(ResetError::WouldLower {
+            beamline: beamline.into(),
+            current,
+            requested,
+        });
+    }
+    Ok(())
+}
+
+/// Set the latest scan number for a beamline in both the DB and its tracker directory so that
+/// the next scan allocated is one more than the given number.
+async fn reset(
+    db: &SqliteScanPathService,
+    nt: &NumTracker,
+    beamline: &str,
+    scan_number: u32,
+    force: bool,
+) -> Result<ScanNumberReset, ResetError> {
+    let config = db.current_configuration(beamline).await?;
+    // Holding the directory lock only prevents allocations by this process. A running service
+    // could still allocate scans in between.
+    let mut dir = nt.for_beamline(beamline, config.extension()).await?;
+    let previous = config.scan_number().max(dir.prev().await?.unwrap_or(0));
+    check_lowering(beamline, previous, scan_number, force)?;
+    BeamlineConfigurationUpdate {
+        scan_number: Some(scan_number),
+        ..BeamlineConfigurationUpdate::empty(beamline)
+    }
+    .update_beamline(db)
+    .await?;
+    dir.reset(scan_number).await?;
+    Ok(ScanNumberReset {
+        previous,
+        current: scan_number,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use assert_matches::assert_matches;
+    use tempfile::{tempdir, TempDir};
+
+    use super::{confirm, reset, ResetError, ScanNumberReset};
+    use crate::db_service::{BeamlineConfigurationUpdate, SqliteScanPathService};
+    use crate::numtracker::NumTracker;
+    use crate::overlay::ConfigOverlay;
+    use crate::paths::{DetectorTemplate, PathSpec as _, ScanTemplate, VisitTemplate};
+
+    /// DB with i22 at scan 122 and a tracker directory containing the given number files
+    async fn setup(files: &[u32]) -> (SqliteScanPathService, NumTracker, TempDir) {
+        let db = SqliteScanPathService::memory().await;
+        BeamlineConfigurationUpdate {
+            scan_number: Some(122),
+            visit: VisitTemplate::new_checked("/tmp/{instrument}/data/{year}/{visit}").ok(),
+            scan: ScanTemplate::new_checked("{instrument}-{scan_number}").ok(),
+            detector: DetectorTemplate::new_checked("{instrument}-{scan_number}-{detector}").ok(),
+            ..BeamlineConfigurationUpdate::empty("i22")
+        }
+        .insert_new(&db)
+        .await
+        .unwrap();
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("i22")).unwrap();
+        for num in files {
+            fs::File::create(root.path().join(format!("i22/{num}.i22"))).unwrap();
+        }
+        let nt = NumTracker::for_root_directory(Some(root.path())).unwrap();
+        (db, nt, root)
+    }
+
+    fn number_files(root: &Path) -> Vec<String> {
+        let mut files = fs::read_dir(root.join("i22"))
+            .unwrap()
+            .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn raise() {
+        let (db, nt, root) = setup(&[]).await;
+        let result = reset(&db, &nt, "i22", 200, false).await.unwrap();
+        assert_eq!(
+            result,
+            ScanNumberReset {
+                previous: 122,
+                current: 200
+            }
+        );
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            200
+        );
+        assert_eq!(number_files(root.path()), ["200.i22"]);
+    }
+
+    #[tokio::test]
+    async fn overlaid_tracker_directory() {
+        let (db, nt, root) = setup(&[122]).await;
+        let sandbox = tempdir().unwrap();
+        fs::File::create(sandbox.path().join("130.i22")).unwrap();
+        let overlay: ConfigOverlay = serde_json::from_value(serde_json::json!({
+            "beamlines": {"i22": {"trackerDirectory": sandbox.path()}}
+        }))
+        .unwrap();
+        let nt = nt.with_directories(overlay.tracker_directories());
+        // The overlaid directory is ahead of the DB so lowering is checked against it
+        assert_matches!(
+            reset(&db, &nt, "i22", 125, false).await,
+            Err(ResetError::WouldLower { current: 130, .. })
+        );
+        reset(&db, &nt, "i22", 200, false).await.unwrap();
+        let mut files = fs::read_dir(sandbox.path())
+            .unwrap()
+            .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["130.i22", "200.i22"]);
+        // The directory in the tracker root is not used for overlaid beamlines
+        assert_eq!(number_files(root.path()), ["122.i22"]);
+    }
+
+    #[tokio::test]
+    async fn blocked_lower() {
+        // The directory is ahead of the DB so is the current number
+        let (db, nt, root) = setup(&[125]).await;
+        let result = reset(&db, &nt, "i22", 123, false).await;
+        assert_matches!(
+            result,
+            Err(ResetError::WouldLower {
+                current: 125,
+                requested: 123,
+                ..
+            })
+        );
+        assert_eq!(
+            db.current_configuration("i22").await.unwrap().scan_number(),
+            122
+        );
+        assert_eq!(number_files(root.path()), ["125.i22"]);
+    }
+
+    #[tokio::test]
+    async fn forced_lower() {
+        let (db, nt, root) = setup(&[122, 125]).await;
+        let result = reset(&db, &nt, "i22", 100, true).await.unwrap();
+        assert_eq!(
+            result,
+            ScanNumberReset {
+                previous: 125,
+                current: 100
+            }
+        );
+        assert_eq!(number_files(root.path()), ["100.i22"]);
+        // The next scan follows on from the reset number instead of the removed files
+        let mut dir = nt.for_beamline("i22", None).await.unwrap();
+        let next = db
+            .next_scan_configuration("i22", "cm12345-3", dir.prev().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(next.scan_number(), 101);
+        dir.set(next.scan_number()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_beamline() {
+        let (db, nt, _root) = setup(&[]).await;
+        let result = reset(&db, &nt, "b21", 100, true).await;
+        assert_matches!(result, Err(ResetError::Configuration(_)));
+    }
+
+    #[test]
+    fn confirmation() {
+        assert!(confirm("Continue?", "y\n".as_bytes()).unwrap());
+        assert!(confirm("Continue?", "yes\n".as_bytes()).unwrap());
+        assert!(!confirm("Continue?", "\n".as_bytes()).unwrap());
+        assert!(!confirm("Continue?", "no\n".as_bytes()).unwrap());
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::{Debug, Display};
 use std::path::{Component, PathBuf};
@@ -25,13 +26,15 @@ pub trait FieldSource<F> {
 enum Part<Field> {
     Literal(String),
     Field(Field),
+    /// A field with a value to use in its place if it resolves to an empty string
+    Defaulted(Field, String),
 }
 
 impl<Field> Part<Field> {
     fn field(&self) -> Option<&Field> {
         match self {
             Part::Literal(_) => None,
-            Part::Field(f) => Some(f),
+            Part::Field(f) | Part::Defaulted(f, _) => Some(f),
         }
     }
 }
@@ -47,6 +50,7 @@ impl<F: Display> Display for Template<F> {
             match p {
                 Part::Literal(lit) => f.write_str(lit.as_str())?,
                 Part::Field(fld) => write!(f, "{{{fld}}}")?,
+                Part::Defaulted(fld, default) => write!(f, "{{{fld}|{default}}}")?,
             }
         }
         Ok(())
@@ -156,6 +160,8 @@ pub enum ErrorKind {
     Incomplete,
     /// The placeholder was not a recognised key
     Unrecognised,
+    /// The default value for a placeholder was empty or contained a path separator
+    InvalidDefault,
 }
 
 impl Display for ErrorKind {
@@ -165,6 +171,7 @@ impl Display for ErrorKind {
             ErrorKind::Empty => f.write_str("Empty placeholder"),
             ErrorKind::Incomplete => f.write_str("Unclosed placeholder"),
             ErrorKind::Unrecognised => f.write_str("Invalid placeholder"),
+            ErrorKind::InvalidDefault => f.write_str("Invalid placeholder default"),
         }
     }
 }
@@ -185,6 +192,9 @@ impl TemplateError {
     fn unknown(position: usize) -> Self {
         Self::new(position, ErrorKind::Unrecognised)
     }
+    fn invalid_default(position: usize) -> Self {
+        Self::new(position, ErrorKind::InvalidDefault)
+    }
     #[cfg(test)]
     pub fn kind(&self) -> ErrorKind {
         self.kind
@@ -215,11 +225,7 @@ impl<F: TryFrom<String>> Template<F> {
                         return Err(TemplateError::empty(i))
                     }
                     ParseState::PartialKey(key) => {
-                        match F::try_from(key) {
-                            Ok(field) => parts.push(Part::Field(field)),
-                            Err(_) => return Err(TemplateError::unknown(i)),
-                        }
-                        // parts.push(Part::Field(F::try_from(key)));
+                        parts.push(Self::placeholder(key, i)?);
                         state = ParseState::Init;
                     }
                     ParseState::PendingLiteral(_) => return Err(TemplateError::empty(i)),
@@ -251,6 +257,23 @@ impl<F: TryFrom<String>> Template<F> {
         }
         Ok(Self { parts })
     }
+
+    /// Parse the contents of a placeholder, either a plain `key` or a `key|default` pair
+    fn placeholder(key: String, position: usize) -> Result<Part<F>, TemplateError> {
+        let Some((key, default)) = key.split_once('|') else {
+            return F::try_from(key)
+                .map(Part::Field)
+                .map_err(|_| TemplateError::unknown(position));
+        };
+        if key.trim().is_empty() {
+            return Err(TemplateError::empty(position));
+        }
+        if default.is_empty() || default.contains(['/', '\\']) {
+            return Err(TemplateError::invalid_default(position));
+        }
+        let field = F::try_from(key.into()).map_err(|_| TemplateError::unknown(position))?;
+        Ok(Part::Defaulted(field, default.into()))
+    }
 }
 
 impl<F> Template<F> {
@@ -260,6 +283,10 @@ impl<F> Template<F> {
             match part {
                 Part::Literal(text) => buf.push_str(text),
                 Part::Field(f) => buf.push_str(&src.resolve(f)),
+                Part::Defaulted(f, default) => match src.resolve(f) {
+                    value if value.is_empty() => buf.push_str(default),
+                    value => buf.push_str(&value),
+                },
             }
         }
         buf
@@ -303,15 +330,48 @@ impl<F> PathTemplate<F> {
         path
     }
 
+    /// Render this template and also return the value each referenced field resolved to,
+    /// keyed by field name. Useful for seeing how a surprising path was built.
+    pub fn render_debug<Src>(&self, src: &Src) -> (PathBuf, BTreeMap<String, String>)
+    where
+        F: Display,
+        Src: FieldSource<F>,
+    {
+        let fields = self
+            .referenced_fields()
+            .map(|f| (f.to_string(), src.resolve(f).into_owned()))
+            .collect();
+        (self.render(src), fields)
+    }
+
     pub fn is_absolute(&self) -> bool {
         self.kind == PathType::Absolute
     }
 
+    /// Iterate through the templates for each segment of this path
+    pub fn segments(&self) -> impl Iterator<Item = &Template<F>> {
+        self.parts.iter()
+    }
+
     /// Iterate through all the fields in this path. Fields may be duplicated if they are
     /// referenced multiple times in the path.
     pub fn referenced_fields(&self) -> impl Iterator<Item = &F> {
         self.parts.iter().flat_map(Template::referenced_fields)
     }
+
+    /// The distinct fields referenced by this path in the order they are first used
+    pub fn fields_used(&self) -> Vec<F>
+    where
+        F: Clone + PartialEq,
+    {
+        let mut fields = Vec::new();
+        for field in self.referenced_fields() {
+            if !fields.contains(field) {
+                fields.push(field.clone());
+            }
+        }
+        fields
+    }
 }
 
 #[cfg(test)]
@@ -417,6 +477,32 @@ mod parser_tests {
         )
     }
 
+    #[test]
+    fn defaulted_key() {
+        let temp = StrTemplate::new("{visit}_{subdirectory|raw}").unwrap();
+        assert_eq!(
+            temp.parts,
+            vec![
+                field("visit"),
+                literal("_"),
+                Defaulted("subdirectory".into(), "raw".into())
+            ]
+        );
+        assert_eq!(temp.to_string(), "{visit}_{subdirectory|raw}");
+    }
+
+    #[test]
+    fn invalid_default() {
+        let temp = StrTemplate::new("{subdirectory|}").unwrap_err();
+        assert_eq!(temp, error!(14, InvalidDefault));
+
+        let temp = StrTemplate::new(r"{subdirectory|a\b}").unwrap_err();
+        assert_eq!(temp, error!(17, InvalidDefault));
+
+        let temp = StrTemplate::new("{|raw}").unwrap_err();
+        assert_eq!(temp, error!(5, Empty));
+    }
+
     #[test]
     fn nested_keys() {
         let temp = StrTemplate::new("{nested{keys}}").unwrap_err();
@@ -484,6 +570,46 @@ mod path_template_tests {
         PathTemplate::new(fmt).unwrap().render(src)
     }
 
+    #[test]
+    fn render_debug_fields() {
+        let (path, fields) =
+            PathTemplate::<String>::new("/tmp/{instrument}/{visit}/{visit}_{scan}")
+                .unwrap()
+                .render_debug(&EchoSource);
+        assert_eq!(path, PathBuf::from("/tmp/INSTRUMENT/VISIT/VISIT_SCAN"));
+        assert_eq!(
+            fields.into_iter().collect::<Vec<_>>(),
+            [
+                ("instrument".into(), "INSTRUMENT".into()),
+                ("scan".into(), "SCAN".into()),
+                ("visit".into(), "VISIT".into()),
+            ]
+        );
+    }
+
+    #[rstest::rstest]
+    #[case::literal("/absolute/literal/path", &[])]
+    #[case::single("/tmp/{visit}", &["visit"])]
+    #[case::repeated("{scan}/{instrument}-{scan}", &["scan", "instrument"])]
+    #[case::mixed(
+        "/tmp/{instrument}/{year}/{visit}/{visit}_{scan}-{year}",
+        &["instrument", "year", "visit", "scan"]
+    )]
+    #[case::escaped("/tmp/escaped_{{literal/{visit}", &["visit"])]
+    fn fields_used(#[case] template: &str, #[case] expected: &[&str]) {
+        let fields = PathTemplate::<String>::new(template).unwrap().fields_used();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn render_debug_literal() {
+        let (path, fields) = PathTemplate::<String>::new("/absolute/literal/path")
+            .unwrap()
+            .render_debug(&EchoSource);
+        assert_eq!(path, PathBuf::from("/absolute/literal/path"));
+        assert!(fields.is_empty());
+    }
+
     #[test]
     fn literal_absolute_path() {
         let path = from_template("/absolute/literal/path", &EchoSource);
@@ -532,6 +658,15 @@ mod path_template_tests {
         assert_eq!(path, PathBuf::from("_part/of/relative"))
     }
 
+    #[test]
+    fn defaulted_field() {
+        let path = from_template("/with/{optional|default}/parts", &EchoSource);
+        assert_eq!(path, PathBuf::from("/with/OPTIONAL/parts"));
+
+        let path = from_template("/with/{optional|default}/parts", &NullSource);
+        assert_eq!(path, PathBuf::from("/with/default/parts"));
+    }
+
     #[test]
     fn current_directory_normalised() {
         let path = from_template("./subdirectory", &NullSource);
@@ -553,6 +688,7 @@ mod path_template_tests {
     #[case::unclosed("unclosed/partial_{place/holder")]
     #[case::empty("empty/{}/placeholder")]
     #[case::nested("nested/{place{holder}}")]
+    #[case::default_separator("default/{place|holder/path}")]
     fn invalid_path_template(#[case] template: &str) {
         let e = PathTemplate::<String>::new(template).unwrap_err();
         let PathTemplateError::TemplateError(_) = e else {
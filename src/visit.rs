@@ -0,0 +1,206 @@
+// Copyright 2024 Diamond Light Source
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The components of a visit string, eg `cm12345-3`
+///
+/// Sessions may include a suffix (eg `cm12345-3a` or `cm12345-3-2`) but only the numeric part is
+/// kept here. The original visit string should be used when rendering paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Visit {
+    /// The proposal code, eg `cm` for commissioning visits
+    pub code: String,
+    /// The proposal number
+    pub proposal: u32,
+    /// The session within the proposal
+    pub session: u16,
+}
+
+/// A proposal without a session, eg `cm12345`, for lookups that only need the proposal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proposal {
+    /// The proposal code, eg `cm` for commissioning visits
+    pub code: String,
+    /// The proposal number
+    pub number: u32,
+}
+
+/// The reason a visit string could not be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, async_graphql::Enum)]
+pub enum InvalidVisit {
+    /// There was no `-` separating the proposal from the session
+    MissingSession,
+    /// The proposal was not a code followed by a number
+    InvalidProposal,
+    /// The session was not a number
+    InvalidSession,
+    /// The proposal code is not one accepted by the service
+    UnknownCode,
+}
+
+impl Display for InvalidVisit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidVisit::MissingSession => f.write_str("Visit is missing a session number"),
+            InvalidVisit::InvalidProposal => f.write_str("Visit has an invalid proposal number"),
+            InvalidVisit::InvalidSession => f.write_str("Visit has an invalid session number"),
+            InvalidVisit::UnknownCode => f.write_str("Visit has an unknown proposal code"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidVisit {}
+
+/// The proposal codes accepted by the service. If there are none, any code is accepted.
+#[derive(Debug, Clone, Default)]
+pub struct ProposalCodes(HashSet<String>);
+
+impl ProposalCodes {
+    pub fn new(codes: impl IntoIterator<Item = String>) -> Self {
+        Self(codes.into_iter().collect())
+    }
+
+    pub fn check(&self, code: &str) -> Result<(), InvalidVisit> {
+        if self.0.is_empty() || self.0.contains(code) {
+            Ok(())
+        } else {
+            Err(InvalidVisit::UnknownCode)
+        }
+    }
+}
+
+impl FromStr for Visit {
+    type Err = InvalidVisit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (code_prop, vis) = s.split_once('-').ok_or(InvalidVisit::MissingSession)?;
+        let Proposal { code, number } = code_prop.parse()?;
+        let session = parse_session(vis)?;
+        Ok(Self {
+            code,
+            proposal: number,
+            session,
+        })
+    }
+}
+
+impl FromStr for Proposal {
+    type Err = InvalidVisit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (code, prop) = s.split_at(s.find(|p: char| p.is_ascii_digit()).unwrap_or(s.len()));
+        let number = prop.parse().map_err(|_| InvalidVisit::InvalidProposal)?;
+        Ok(Self {
+            code: code.into(),
+            number,
+        })
+    }
+}
+
+/// Parse the numeric part of a session, allowing an optional alphanumeric suffix either directly
+/// after the number or separated from it by a single `-`.
+fn parse_session(session: &str) -> Result<u16, InvalidVisit> {
+    let (number, suffix) = session.split_at(
+        session
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(session.len()),
+    );
+    let valid_suffix = match suffix.strip_prefix('-') {
+        Some(sub) => !sub.is_empty() && sub.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => suffix.chars().all(|c| c.is_ascii_alphanumeric()),
+    };
+    if !valid_suffix {
+        return Err(InvalidVisit::InvalidSession);
+    }
+    number.parse().map_err(|_| InvalidVisit::InvalidSession)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr as _;
+
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::{InvalidVisit, Proposal, ProposalCodes, Visit};
+
+    #[test]
+    fn valid_visit() {
+        let visit = Visit::from_str("cm12345-1").unwrap();
+        assert_eq!(visit.code, "cm");
+        assert_eq!(visit.session, 1);
+        assert_eq!(visit.proposal, 12345);
+    }
+
+    #[rstest]
+    #[case::numeric("cm12345-3", 3)]
+    #[case::letter_suffix("cm12345-3a", 3)]
+    #[case::numeric_sub_session("cm12345-3-2", 3)]
+    #[case::alphanumeric_sub_session("cm12345-12-b2", 12)]
+    fn session_suffix(#[case] visit: &str, #[case] session: u16) {
+        let visit = Visit::from_str(visit).unwrap();
+        assert_eq!(visit.code, "cm");
+        assert_eq!(visit.proposal, 12345);
+        assert_eq!(visit.session, session);
+    }
+
+    #[rstest]
+    #[case::no_proposal("cm-3", InvalidVisit::InvalidProposal)]
+    #[case::no_session("cm12345", InvalidVisit::MissingSession)]
+    #[case::invalid_session("cm12345-abc", InvalidVisit::InvalidSession)]
+    #[case::invalid_proposal("cm123abc-12", InvalidVisit::InvalidProposal)]
+    #[case::negative_session("cm1234--12", InvalidVisit::InvalidSession)]
+    #[case::suffix_only("cm1234-a", InvalidVisit::InvalidSession)]
+    #[case::empty_session("cm1234-", InvalidVisit::InvalidSession)]
+    #[case::trailing_separator("cm1234-3-", InvalidVisit::InvalidSession)]
+    #[case::double_separator("cm1234-3--2", InvalidVisit::InvalidSession)]
+    #[case::nested_sub_session("cm1234-3-2-1", InvalidVisit::InvalidSession)]
+    #[case::invalid_suffix("cm1234-3_a", InvalidVisit::InvalidSession)]
+    #[case::session_overflow("cm1234-70000", InvalidVisit::InvalidSession)]
+    fn invalid_visit(#[case] visit: &str, #[case] reason: InvalidVisit) {
+        assert_matches!(Visit::from_str(visit), Err(e) if e == reason)
+    }
+
+    #[test]
+    fn proposal_only() {
+        let proposal = Proposal::from_str("cm12345").unwrap();
+        assert_eq!(proposal.code, "cm");
+        assert_eq!(proposal.number, 12345);
+    }
+
+    #[rstest]
+    #[case::no_number("cm")]
+    #[case::with_session("cm12345-3")]
+    #[case::invalid_number("cm123abc")]
+    #[case::empty("")]
+    fn invalid_proposal(#[case] proposal: &str) {
+        assert_matches!(
+            Proposal::from_str(proposal),
+            Err(InvalidVisit::InvalidProposal)
+        )
+    }
+
+    #[test]
+    fn proposal_codes() {
+        let any = ProposalCodes::default();
+        assert_eq!(any.check("xy"), Ok(()));
+
+        let codes = ProposalCodes::new(["cm".into(), "mx".into()]);
+        assert_eq!(codes.check("mx"), Ok(()));
+        assert_eq!(codes.check("xy"), Err(InvalidVisit::UnknownCode));
+    }
+}